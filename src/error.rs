@@ -0,0 +1,109 @@
+// Merge errors - what the library returns instead of bare strings, so callers
+// can tell a ledger that won't open from a check that failed
+
+use std::fmt;
+
+/// Why a merge step failed. Every variant carries the full message; `Display`
+/// prints only the message, so the text is the same whichever it is.
+#[derive(Debug, Clone, PartialEq, Eq, thiserror::Error)]
+pub enum MergeError {
+    /// A ledger's blockstore could not be opened or created
+    #[error("{0}")]
+    BlockstoreOpen(String),
+    /// A genesis config could not be opened, unpacked or deserialized
+    #[error("{0}")]
+    Genesis(String),
+    /// A bank could not be loaded from, or built in place of, a snapshot
+    #[error("{0}")]
+    SnapshotLoad(String),
+    /// Enumerating or indexing a bank's accounts failed
+    #[error("{0}")]
+    AccountScan(String),
+    /// Storing accounts into the merged bank failed
+    #[error("{0}")]
+    AccountCopy(String),
+    /// An account's data could not be decoded or rewritten
+    #[error("{0}")]
+    AccountData(String),
+    /// Accounts on both sides of the merge disagreed under
+    /// `ConflictPolicy::Fail`
+    #[error("{0}")]
+    Conflict(String),
+    /// Writing the snapshot archive failed
+    #[error("{0}")]
+    ArchiveCreation(String),
+    /// Reading or writing one of the merge's own files failed
+    #[error("{0}")]
+    Io(String),
+    /// A check of a bank or of the merge's output found a problem
+    #[error("{0}")]
+    Verification(String),
+    /// An argument, option combination or input file is invalid
+    #[error("{0}")]
+    InvalidInput(String),
+    /// Fetching accounts over RPC failed
+    #[error("{0}")]
+    Rpc(String),
+    /// Downloading a snapshot archive or genesis config failed
+    #[error("{0}")]
+    Download(String),
+    /// Uploading the merged snapshot to object storage failed
+    #[error("{0}")]
+    Upload(String),
+    /// The merge ran past `MergeConfig::deadline`
+    #[error("{0}")]
+    DeadlineExceeded(String),
+}
+
+impl MergeError {
+    pub fn message(&self) -> &str {
+        match self {
+            Self::BlockstoreOpen(message)
+            | Self::Genesis(message)
+            | Self::SnapshotLoad(message)
+            | Self::AccountScan(message)
+            | Self::AccountCopy(message)
+            | Self::AccountData(message)
+            | Self::Conflict(message)
+            | Self::ArchiveCreation(message)
+            | Self::Io(message)
+            | Self::Verification(message)
+            | Self::InvalidInput(message)
+            | Self::Rpc(message)
+            | Self::Download(message)
+            | Self::Upload(message)
+            | Self::DeadlineExceeded(message) => message,
+        }
+    }
+
+    /// Whether running the merge again may succeed without changing
+    /// anything: the network failures of fetching, downloading and
+    /// uploading. Archive creation already retries its transient failures
+    /// itself, see `MergeConfig::snapshot_retries`, and every other variant
+    /// fails the same way again.
+    pub fn is_retryable(&self) -> bool {
+        matches!(self, Self::Rpc(_) | Self::Download(_) | Self::Upload(_))
+    }
+
+    /// Prefixes the message with `context`, keeping the variant
+    pub fn context(self, context: impl fmt::Display) -> Self {
+        let map = |message: String| format!("{}: {}", context, message);
+        match self {
+            Self::BlockstoreOpen(message) => Self::BlockstoreOpen(map(message)),
+            Self::Genesis(message) => Self::Genesis(map(message)),
+            Self::SnapshotLoad(message) => Self::SnapshotLoad(map(message)),
+            Self::AccountScan(message) => Self::AccountScan(map(message)),
+            Self::AccountCopy(message) => Self::AccountCopy(map(message)),
+            Self::AccountData(message) => Self::AccountData(map(message)),
+            Self::Conflict(message) => Self::Conflict(map(message)),
+            Self::ArchiveCreation(message) => Self::ArchiveCreation(map(message)),
+            Self::Io(message) => Self::Io(map(message)),
+            Self::Verification(message) => Self::Verification(map(message)),
+            Self::InvalidInput(message) => Self::InvalidInput(map(message)),
+            Self::Rpc(message) => Self::Rpc(map(message)),
+            Self::Download(message) => Self::Download(map(message)),
+            Self::Upload(message) => Self::Upload(map(message)),
+            Self::DeadlineExceeded(message) => Self::DeadlineExceeded(map(message)),
+        }
+    }
+}
@@ -0,0 +1,236 @@
+// Fetch - downloads a full snapshot archive over HTTP(S) or from a public
+// S3/GCS bucket, so a merge can start from a URL instead of a local ledger
+
+use {
+    crate::error::MergeError,
+    log::*,
+    reqwest::{blocking::Client, header::RANGE, StatusCode, Url},
+    sha2::{Digest, Sha256},
+    solana_genesis_config::DEFAULT_GENESIS_ARCHIVE,
+    solana_runtime::snapshot_archive_info::FullSnapshotArchiveInfo,
+    std::{
+        fs::OpenOptions,
+        io::Read,
+        path::{Path, PathBuf},
+        time::Duration,
+    },
+};
+
+/// The path an RPC node serves its latest full snapshot at, redirecting to
+/// the archive's real name
+pub const RPC_SNAPSHOT_PATH: &str = "/snapshot.tar.zst";
+const CONNECT_TIMEOUT: Duration = Duration::from_secs(30);
+
+fn split_bucket_object(rest: &str) -> Option<(&str, &str)> {
+    rest.split_once('/')
+        .filter(|(bucket, key)| !bucket.is_empty() && !key.is_empty())
+}
+
+/// The HTTPS URL `url` is downloaded from: `s3://bucket/key` and
+/// `gs://bucket/key` map to the public endpoints of the bucket, `http://` and
+/// `https://` URLs are kept as they are
+pub fn resolve_url(url: &str) -> Result<Url, MergeError> {
+    let invalid =
+        |reason: &str| MergeError::InvalidInput(format!("Invalid URL {}: {}", url, reason));
+    let bucket_object =
+        |rest| split_bucket_object(rest).ok_or_else(|| invalid("expected <bucket>/<key>"));
+    let resolved = if let Some(rest) = url.strip_prefix("s3://") {
+        let (bucket, key) = bucket_object(rest)?;
+        format!("https://{}.s3.amazonaws.com/{}", bucket, key)
+    } else if let Some(rest) = url.strip_prefix("gs://") {
+        let (bucket, key) = bucket_object(rest)?;
+        format!("https://storage.googleapis.com/{}/{}", bucket, key)
+    } else {
+        url.to_string()
+    };
+    let resolved = Url::parse(&resolved).map_err(|e| invalid(&e.to_string()))?;
+    match resolved.scheme() {
+        "http" | "https" => Ok(resolved),
+        scheme => Err(invalid(&format!("unsupported scheme {}", scheme))),
+    }
+}
+
+/// Where an RPC node serving a snapshot at `url` serves its genesis archive;
+/// `None` unless `url` is the node's [`RPC_SNAPSHOT_PATH`]
+pub fn rpc_genesis_url(url: &Url) -> Option<Url> {
+    (url.path() == RPC_SNAPSHOT_PATH).then(|| {
+        let mut genesis = url.clone();
+        genesis.set_path(DEFAULT_GENESIS_ARCHIVE);
+        genesis
+    })
+}
+
+/// The file name of the full snapshot archive at `url`, its last path segment
+pub fn archive_file_name(url: &Url) -> Result<String, MergeError> {
+    let name = url
+        .path_segments()
+        .and_then(|mut segments| segments.next_back())
+        .unwrap_or_default();
+    FullSnapshotArchiveInfo::new_from_path(PathBuf::from(name))
+        .map(|_| name.to_string())
+        .map_err(|e| {
+            MergeError::InvalidInput(format!("{} is not a full snapshot archive: {:?}", url, e))
+        })
+}
+
+/// The SHA-256 of the file at `path`, as lowercase hex like `sha256sum`
+/// prints it
+pub fn sha256_file(path: &Path) -> Result<String, MergeError> {
+    let read_error = |e| MergeError::Io(format!("Failed to read {:?}: {:?}", path, e));
+    let mut file = std::fs::File::open(path).map_err(read_error)?;
+    let mut hasher = Sha256::new();
+    let mut buffer = vec![0; 1 << 20];
+    loop {
+        let read = file.read(&mut buffer).map_err(read_error)?;
+        if read == 0 {
+            break;
+        }
+        hasher.update(&buffer[..read]);
+    }
+    Ok(hasher
+        .finalize()
+        .iter()
+        .map(|byte| format!("{:02x}", byte))
+        .collect())
+}
+
+fn client() -> Result<Client, MergeError> {
+    // No overall timeout: a mainnet snapshot takes a while to download
+    Client::builder()
+        .connect_timeout(CONNECT_TIMEOUT)
+        .build()
+        .map_err(|e| MergeError::Download(format!("Failed to create HTTP client: {}", e)))
+}
+
+/// Downloads `url` to `partial`, continuing after the bytes already in it if
+/// the server takes range requests. Returns the length of the download.
+fn download_resuming(client: &Client, url: &Url, partial: &Path) -> Result<u64, MergeError> {
+    let download_error = |e: &dyn std::fmt::Display| {
+        MergeError::Download(format!("Failed to download {}: {}", url, e))
+    };
+    let offset = std::fs::metadata(partial).map_or(0, |metadata| metadata.len());
+    let mut request = client.get(url.clone());
+    if offset > 0 {
+        info!("Resuming the download of {} after {} bytes", url, offset);
+        request = request.header(RANGE, format!("bytes={}-", offset));
+    }
+    let response = request.send().map_err(|e| download_error(&e))?;
+    // Nothing past the end: the previous run got every byte but stopped
+    // before renaming the file
+    if offset > 0 && response.status() == StatusCode::RANGE_NOT_SATISFIABLE {
+        return Ok(offset);
+    }
+    let mut response = response
+        .error_for_status()
+        .map_err(|e| download_error(&e))?;
+    let resumed = response.status() == StatusCode::PARTIAL_CONTENT;
+    if offset > 0 && !resumed {
+        warn!(
+            "{} ignored the range request, downloading from the start",
+            url
+        );
+    }
+    let expected = response
+        .content_length()
+        .map(|length| if resumed { offset + length } else { length });
+
+    let mut file = OpenOptions::new()
+        .create(true)
+        .write(true)
+        .append(resumed)
+        .truncate(!resumed)
+        .open(partial)
+        .map_err(|e| MergeError::Io(format!("Failed to open {:?}: {:?}", partial, e)))?;
+    let copied = std::io::copy(&mut response, &mut file).map_err(|e| download_error(&e))?;
+    let length = if resumed { offset + copied } else { copied };
+    match expected {
+        Some(expected) if expected != length => Err(download_error(&format!(
+            "got {} of {} bytes; run again to resume",
+            length, expected
+        ))),
+        _ => Ok(length),
+    }
+}
+
+/// Downloads the full snapshot archive at `url` into `download_dir`, keeping
+/// its name, and returns its path. `url` is an RPC node's
+/// [`RPC_SNAPSHOT_PATH`], an archive URL or a public `s3://` or `gs://`
+/// object. An interrupted download is resumed and an archive already in
+/// `download_dir` is reused; with `expected_sha256`, an archive that hashes
+/// differently is removed and the download fails.
+pub fn download_snapshot(
+    url: &str,
+    download_dir: &Path,
+    expected_sha256: Option<&str>,
+) -> Result<PathBuf, MergeError> {
+    let url = resolve_url(url)?;
+    std::fs::create_dir_all(download_dir).map_err(|e| {
+        MergeError::Io(format!(
+            "Failed to create download directory {:?}: {:?}",
+            download_dir, e
+        ))
+    })?;
+    let client = client()?;
+
+    // Follow an RPC node's redirect first, the archive's name is only known
+    // from where it ends up
+    let resolved = client
+        .head(url.clone())
+        .send()
+        .and_then(|response| response.error_for_status())
+        .map_err(|e| MergeError::Download(format!("Failed to resolve {}: {}", url, e)))?
+        .url()
+        .clone();
+    let name = archive_file_name(&resolved)?;
+    let archive = download_dir.join(&name);
+    if archive.is_file() {
+        info!("Using the already downloaded {:?}", archive);
+    } else {
+        let partial = download_dir.join(format!("{}.partial", name));
+        info!("Downloading {} to {:?}", resolved, archive);
+        let length = download_resuming(&client, &resolved, &partial)?;
+        std::fs::rename(&partial, &archive).map_err(|e| {
+            MergeError::Io(format!(
+                "Failed to rename {:?} to {:?}: {:?}",
+                partial, archive, e
+            ))
+        })?;
+        info!("Downloaded {} bytes", length);
+    }
+
+    if let Some(expected) = expected_sha256 {
+        let actual = sha256_file(&archive)?;
+        if !actual.eq_ignore_ascii_case(expected) {
+            // Removed so the next run downloads it again
+            let _ = std::fs::remove_file(&archive);
+            return Err(MergeError::Verification(format!(
+                "{:?} has SHA-256 {} instead of {}",
+                archive, actual, expected
+            )));
+        }
+        info!("Verified the SHA-256 of {:?}", archive);
+    }
+    Ok(archive)
+}
+
+/// Downloads the genesis archive of the RPC node whose snapshot `url` points
+/// at into `download_dir`, unless it is already there. Returns its path, or
+/// `None` if `url` isn't an RPC node's [`RPC_SNAPSHOT_PATH`].
+pub fn download_rpc_genesis(url: &str, download_dir: &Path) -> Result<Option<PathBuf>, MergeError> {
+    let Some(genesis_url) = rpc_genesis_url(&resolve_url(url)?) else {
+        return Ok(None);
+    };
+    let genesis = download_dir.join(DEFAULT_GENESIS_ARCHIVE);
+    if !genesis.is_file() {
+        let partial = download_dir.join(format!("{}.partial", DEFAULT_GENESIS_ARCHIVE));
+        info!("Downloading {} to {:?}", genesis_url, genesis);
+        download_resuming(&client()?, &genesis_url, &partial)?;
+        std::fs::rename(&partial, &genesis).map_err(|e| {
+            MergeError::Io(format!(
+                "Failed to rename {:?} to {:?}: {:?}",
+                partial, genesis, e
+            ))
+        })?;
+    }
+    Ok(Some(genesis))
+}
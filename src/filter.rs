@@ -0,0 +1,372 @@
+// Account filters - the chain of filters that decides which mainnet accounts
+// are copied, which the built-in options and library users plug into
+
+use {
+    crate::{
+        merge::functions::{self, FeatureSetPolicy},
+        spl::TokenAccounting,
+        sysvars::{self, Sysvar, SysvarPolicy},
+    },
+    solana_account::{AccountSharedData, ReadableAccount},
+    solana_pubkey::Pubkey,
+    solana_runtime::bank::Bank,
+    std::{
+        cell::RefCell,
+        collections::{HashMap, HashSet},
+        fmt,
+        sync::Arc,
+    },
+};
+
+/// Name of the [`ExcludePubkeys`] filter of the mainnet vote accounts
+pub const VOTE_ACCOUNTS: &str = "vote accounts";
+/// Name of the [`ExcludePubkeys`] filter of the mainnet stake accounts
+pub const STAKE_ACCOUNTS: &str = "stake accounts";
+/// Name of the [`ExcludePubkeys`] filter of `MergeConfig::subtract_ledger`
+pub const SUBTRACT_LEDGER: &str = "subtract ledger";
+/// Name of the [`ExcludePubkeys`] filter of `MergeConfig::exclude_accounts`
+pub const ACCOUNT_DENYLIST: &str = "account denylist";
+
+/// The names of the built-in filters, which custom filters can't take
+pub const BUILTIN_NAMES: [&str; 15] = [
+    VOTE_ACCOUNTS,
+    STAKE_ACCOUNTS,
+    SUBTRACT_LEDGER,
+    ACCOUNT_DENYLIST,
+    IncludePubkeys::NAME,
+    FeatureAccounts::NAME,
+    RewrittenSysvars::NAME,
+    LookupTables::NAME,
+    ExcludeOwners::NAME,
+    IncludeOwners::NAME,
+    ZeroLamports::NAME,
+    MinLamports::NAME,
+    MaxDataSize::NAME,
+    InBank::NAME,
+    TokenAccounts::NAME,
+];
+
+/// What an [`AccountFilter`] makes of an account
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum FilterDecision {
+    Keep,
+    Exclude,
+}
+
+impl FilterDecision {
+    /// `Exclude` when `exclude` holds
+    pub fn exclude_if(exclude: bool) -> Self {
+        if exclude {
+            Self::Exclude
+        } else {
+            Self::Keep
+        }
+    }
+}
+
+/// One reason to leave mainnet accounts out of the copy. A filter in a
+/// [`FilterChain`] only sees the accounts every earlier filter kept.
+pub trait AccountFilter {
+    /// Names the filter in logs and in the exclusion counts of the stats
+    fn name(&self) -> &str;
+
+    fn keep(&self, pubkey: &Pubkey, account: &AccountSharedData) -> FilterDecision;
+}
+
+impl<F: AccountFilter + ?Sized> AccountFilter for Arc<F> {
+    fn name(&self) -> &str {
+        (**self).name()
+    }
+
+    fn keep(&self, pubkey: &Pubkey, account: &AccountSharedData) -> FilterDecision {
+        (**self).keep(pubkey, account)
+    }
+}
+
+impl fmt::Debug for dyn AccountFilter + Send + Sync {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_tuple("AccountFilter").field(&self.name()).finish()
+    }
+}
+
+/// [`AccountFilter`]s applied in order, counting the accounts each excludes
+#[derive(Default)]
+pub struct FilterChain<'a> {
+    filters: Vec<(Box<dyn AccountFilter + 'a>, usize)>,
+}
+
+impl<'a> FilterChain<'a> {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn push(&mut self, filter: impl AccountFilter + 'a) {
+        self.filters.push((Box::new(filter), 0));
+    }
+
+    pub fn with(mut self, filter: impl AccountFilter + 'a) -> Self {
+        self.push(filter);
+        self
+    }
+
+    pub fn len(&self) -> usize {
+        self.filters.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.filters.is_empty()
+    }
+
+    /// Whether every filter keeps the account; an excluded account is counted
+    /// against the first filter that excludes it and not shown to the rest
+    pub fn keep(&mut self, pubkey: &Pubkey, account: &AccountSharedData) -> bool {
+        for (filter, excluded) in &mut self.filters {
+            if filter.keep(pubkey, account) == FilterDecision::Exclude {
+                *excluded += 1;
+                return false;
+            }
+        }
+        true
+    }
+
+    /// The accounts excluded by the filters named `name`, 0 if there are none
+    pub fn excluded_by(&self, name: &str) -> usize {
+        self.exclusions()
+            .filter(|(filter, _)| *filter == name)
+            .map(|(_, excluded)| excluded)
+            .sum()
+    }
+
+    /// The name of each filter and the accounts it excluded, in chain order
+    pub fn exclusions(&self) -> impl Iterator<Item = (&str, usize)> {
+        self.filters
+            .iter()
+            .map(|(filter, excluded)| (filter.name(), *excluded))
+    }
+}
+
+/// Leaves out the accounts at `pubkeys`
+#[derive(Debug, Clone, Copy)]
+pub struct ExcludePubkeys<'a> {
+    pub name: &'static str,
+    pub pubkeys: &'a HashSet<Pubkey>,
+}
+
+impl AccountFilter for ExcludePubkeys<'_> {
+    fn name(&self) -> &str {
+        self.name
+    }
+
+    fn keep(&self, pubkey: &Pubkey, _account: &AccountSharedData) -> FilterDecision {
+        FilterDecision::exclude_if(self.pubkeys.contains(pubkey))
+    }
+}
+
+/// Leaves out every account not at one of these pubkeys
+#[derive(Debug, Clone, Copy)]
+pub struct IncludePubkeys<'a>(pub &'a HashSet<Pubkey>);
+
+impl IncludePubkeys<'_> {
+    pub const NAME: &'static str = "account allowlist";
+}
+
+impl AccountFilter for IncludePubkeys<'_> {
+    fn name(&self) -> &str {
+        Self::NAME
+    }
+
+    fn keep(&self, pubkey: &Pubkey, _account: &AccountSharedData) -> FilterDecision {
+        FilterDecision::exclude_if(!self.0.contains(pubkey))
+    }
+}
+
+/// Leaves out the feature accounts `policy` doesn't copy from mainnet
+#[derive(Debug, Clone, Copy)]
+pub struct FeatureAccounts<'a> {
+    pub policy: FeatureSetPolicy,
+    pub feature_list: &'a HashSet<Pubkey>,
+}
+
+impl FeatureAccounts<'_> {
+    pub const NAME: &'static str = "feature accounts";
+}
+
+impl AccountFilter for FeatureAccounts<'_> {
+    fn name(&self) -> &str {
+        Self::NAME
+    }
+
+    fn keep(&self, pubkey: &Pubkey, account: &AccountSharedData) -> FilterDecision {
+        FilterDecision::exclude_if(
+            functions::is_feature_account(account)
+                && !self.policy.copies_from_mainnet(pubkey, self.feature_list),
+        )
+    }
+}
+
+/// Leaves out the sysvars `SysvarPolicy::Rewrite` leaves to the merged bank,
+/// which updates them for every slot the copy advances it to
+#[derive(Debug, Clone, Copy)]
+pub struct RewrittenSysvars<'a>(pub &'a HashMap<Sysvar, SysvarPolicy>);
+
+impl RewrittenSysvars<'_> {
+    pub const NAME: &'static str = "rewritten sysvars";
+}
+
+impl AccountFilter for RewrittenSysvars<'_> {
+    fn name(&self) -> &str {
+        Self::NAME
+    }
+
+    fn keep(&self, pubkey: &Pubkey, _account: &AccountSharedData) -> FilterDecision {
+        FilterDecision::exclude_if(Sysvar::ALL.into_iter().any(|sysvar| {
+            sysvar.id() == *pubkey && sysvars::policy(self.0, sysvar) == SysvarPolicy::Rewrite
+        }))
+    }
+}
+
+/// Leaves out address lookup tables, for `AltPolicy::Skip`
+#[derive(Debug, Clone, Copy)]
+pub struct LookupTables;
+
+impl LookupTables {
+    pub const NAME: &'static str = "lookup tables";
+}
+
+impl AccountFilter for LookupTables {
+    fn name(&self) -> &str {
+        Self::NAME
+    }
+
+    fn keep(&self, _pubkey: &Pubkey, account: &AccountSharedData) -> FilterDecision {
+        FilterDecision::exclude_if(functions::is_address_lookup_table(account))
+    }
+}
+
+/// Leaves out the accounts owned by one of these programs
+#[derive(Debug, Clone, Copy)]
+pub struct ExcludeOwners<'a>(pub &'a HashSet<Pubkey>);
+
+impl ExcludeOwners<'_> {
+    pub const NAME: &'static str = "owner denylist";
+}
+
+impl AccountFilter for ExcludeOwners<'_> {
+    fn name(&self) -> &str {
+        Self::NAME
+    }
+
+    fn keep(&self, _pubkey: &Pubkey, account: &AccountSharedData) -> FilterDecision {
+        FilterDecision::exclude_if(self.0.contains(account.owner()))
+    }
+}
+
+/// Leaves out every account not owned by one of these programs
+#[derive(Debug, Clone, Copy)]
+pub struct IncludeOwners<'a>(pub &'a HashSet<Pubkey>);
+
+impl IncludeOwners<'_> {
+    pub const NAME: &'static str = "owner allowlist";
+}
+
+impl AccountFilter for IncludeOwners<'_> {
+    fn name(&self) -> &str {
+        Self::NAME
+    }
+
+    fn keep(&self, _pubkey: &Pubkey, account: &AccountSharedData) -> FilterDecision {
+        FilterDecision::exclude_if(!self.0.contains(account.owner()))
+    }
+}
+
+/// Leaves out the accounts holding no lamports
+#[derive(Debug, Clone, Copy)]
+pub struct ZeroLamports;
+
+impl ZeroLamports {
+    pub const NAME: &'static str = "zero lamports";
+}
+
+impl AccountFilter for ZeroLamports {
+    fn name(&self) -> &str {
+        Self::NAME
+    }
+
+    fn keep(&self, _pubkey: &Pubkey, account: &AccountSharedData) -> FilterDecision {
+        FilterDecision::exclude_if(account.lamports() == 0)
+    }
+}
+
+/// Leaves out the accounts holding fewer lamports than this
+#[derive(Debug, Clone, Copy)]
+pub struct MinLamports(pub u64);
+
+impl MinLamports {
+    pub const NAME: &'static str = "min lamports";
+}
+
+impl AccountFilter for MinLamports {
+    fn name(&self) -> &str {
+        Self::NAME
+    }
+
+    fn keep(&self, _pubkey: &Pubkey, account: &AccountSharedData) -> FilterDecision {
+        FilterDecision::exclude_if(account.lamports() < self.0)
+    }
+}
+
+/// Leaves out the accounts with more data bytes than this
+#[derive(Debug, Clone, Copy)]
+pub struct MaxDataSize(pub usize);
+
+impl MaxDataSize {
+    pub const NAME: &'static str = "max data size";
+}
+
+impl AccountFilter for MaxDataSize {
+    fn name(&self) -> &str {
+        Self::NAME
+    }
+
+    fn keep(&self, _pubkey: &Pubkey, account: &AccountSharedData) -> FilterDecision {
+        FilterDecision::exclude_if(account.data().len() > self.0)
+    }
+}
+
+/// Leaves out the accounts whose pubkey the bank lacks, for
+/// `MergeConfig::keep_base_accounts_only`
+#[derive(Clone, Copy)]
+pub struct InBank<'a>(pub &'a Bank);
+
+impl InBank<'_> {
+    pub const NAME: &'static str = "outside base";
+}
+
+impl AccountFilter for InBank<'_> {
+    fn name(&self) -> &str {
+        Self::NAME
+    }
+
+    fn keep(&self, pubkey: &Pubkey, _account: &AccountSharedData) -> FilterDecision {
+        FilterDecision::exclude_if(self.0.get_account(pubkey).is_none())
+    }
+}
+
+/// Leaves out the token accounts the [`TokenAccounting`] filter excludes,
+/// tallying the token supply of the ones kept
+#[derive(Debug, Clone, Copy)]
+pub struct TokenAccounts<'a>(pub &'a RefCell<TokenAccounting>);
+
+impl TokenAccounts<'_> {
+    pub const NAME: &'static str = "token filter";
+}
+
+impl AccountFilter for TokenAccounts<'_> {
+    fn name(&self) -> &str {
+        Self::NAME
+    }
+
+    fn keep(&self, pubkey: &Pubkey, account: &AccountSharedData) -> FilterDecision {
+        FilterDecision::exclude_if(!self.0.borrow_mut().keep(pubkey, account))
+    }
+}
@@ -1,3 +1,15 @@
-// Library module for snapshot merger - exposes functions for testing
+// Library module for snapshot merger - exposes functions for testing and the merge
+// orchestration for embedding
 
+pub mod error;
+pub mod fetch;
+pub mod filter;
+pub mod logging;
 pub mod merge;
+pub mod merger;
+pub mod rpc_fetch;
+pub mod self_test;
+pub mod spl;
+pub mod sysvars;
+pub mod transform;
+pub mod upload;
@@ -0,0 +1,81 @@
+// Logger setup - env_logger text output or one JSON object per record
+
+use {
+    log::kv::{self, Key, Value, VisitSource},
+    serde_json::{json, Map},
+    std::{io::Write, str::FromStr},
+};
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum LogFormat {
+    /// env_logger's default human-readable lines
+    #[default]
+    Text,
+    /// One JSON object per line with timestamp, level, target, message and
+    /// any key-value fields attached to the record
+    Json,
+}
+
+impl FromStr for LogFormat {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "text" => Ok(Self::Text),
+            "json" => Ok(Self::Json),
+            _ => Err(format!(
+                "Invalid log format '{}', expected 'text' or 'json'",
+                s
+            )),
+        }
+    }
+}
+
+struct FieldCollector(Map<String, serde_json::Value>);
+
+impl<'kvs> VisitSource<'kvs> for FieldCollector {
+    fn visit_pair(&mut self, key: Key<'kvs>, value: Value<'kvs>) -> Result<(), kv::Error> {
+        let value = if let Some(n) = value.to_u64() {
+            json!(n)
+        } else if let Some(n) = value.to_i64() {
+            json!(n)
+        } else if let Some(b) = value.to_bool() {
+            json!(b)
+        } else {
+            json!(value.to_string())
+        };
+        self.0.insert(key.to_string(), value);
+        Ok(())
+    }
+}
+
+/// Renders a record as a single-line JSON object. The message is trimmed so
+/// the blank lines around step banners don't end up in it.
+pub fn json_record(record: &log::Record, timestamp: &str) -> String {
+    let mut fields = FieldCollector(Map::new());
+    // Collecting into a map can't fail
+    let _ = record.key_values().visit(&mut fields);
+    let mut object = json!({
+        "timestamp": timestamp,
+        "level": record.level().as_str(),
+        "target": record.target(),
+        "message": record.args().to_string().trim(),
+    });
+    if !fields.0.is_empty() {
+        object["fields"] = serde_json::Value::Object(fields.0);
+    }
+    object.to_string()
+}
+
+/// Initializes the global logger at info level in the given format
+pub fn init(format: LogFormat) {
+    let mut builder = env_logger::Builder::from_default_env();
+    builder.filter_level(log::LevelFilter::Info);
+    if format == LogFormat::Json {
+        builder.format(|buf, record| {
+            let timestamp = buf.timestamp_millis().to_string();
+            writeln!(buf, "{}", json_record(record, &timestamp))
+        });
+    }
+    builder.init();
+}
@@ -7,472 +7,1754 @@
 //
 // Result: Ledger-to-merge's genesis and validators + mainnet's state (excluding mainnet validators)
 
+use snapshot_merger::error::MergeError;
 use snapshot_merger::merge::functions;
+use snapshot_merger::merger::{
+    load_ledger, merge_snapshots, merge_snapshots_split_by_owner, preflight,
+    preflight_with_mainnet_url, render_validators_table, stats_json, MergeConfig, MergeStats,
+    SNAPSHOT_MANIFEST_FILE, SPLIT_MANIFEST_FILE,
+};
+use snapshot_merger::{fetch, logging, self_test, spl, sysvars};
 use {
-    clap::{crate_description, crate_name, value_t, value_t_or_exit, App, Arg},
-    log::*,
-    solana_accounts_db::{accounts_db::AccountsDbConfig, hardened_unpack::open_genesis_config},
-    solana_clock::Slot,
-    solana_genesis_config::GenesisConfig,
-    solana_ledger::{
-        bank_forks_utils,
-        blockstore::{Blockstore, BlockstoreError},
-        blockstore_options::{AccessType, BlockstoreOptions},
-        blockstore_processor::ProcessOptions,
-    },
-    solana_runtime::{
-        bank::Bank,
-        snapshot_archive_info::SnapshotArchiveInfoGetter,
-        snapshot_bank_utils,
-        snapshot_config::{SnapshotConfig, SnapshotUsage},
-        snapshot_utils::{ArchiveFormat, SnapshotVersion, ZstdConfig},
+    clap::{
+        crate_description, crate_name, value_t, value_t_or_exit, App, AppSettings, Arg, ArgMatches,
+        SubCommand,
     },
+    solana_accounts_db::accounts_index::{AccountsIndexConfig, IndexLimitMb},
+    solana_pubkey::Pubkey,
     std::{
+        collections::{HashMap, HashSet},
+        fmt::Display,
         path::{Path, PathBuf},
         process::exit,
-        sync::Arc,
+        str::FromStr,
+        time::Instant,
     },
 };
 
-#[derive(Debug)]
-struct MergeStats {
-    mainnet_total_accounts: usize,
-    merge_total_accounts: usize,
-    mainnet_vote_accounts_excluded: usize,
-    mainnet_stake_accounts_excluded: usize,
-    mainnet_accounts_copied: usize,
-    merge_system_accounts_preserved: usize,
-    final_total_accounts: usize,
-    capitalization_before: u64,
-    capitalization_after: u64,
-    snapshot_path: String,
-}
-
-fn open_blockstore(ledger_path: &Path) -> Result<Blockstore, BlockstoreError> {
-    info!("Opening blockstore at {:?}", ledger_path);
-    Blockstore::open_with_options(
-        ledger_path,
-        BlockstoreOptions {
-            access_type: AccessType::Secondary,
-            enforce_ulimit_nofile: false,
-            ..BlockstoreOptions::default()
-        },
-    )
-}
+fn list_programs(ledger_path: &Path, output_format: &str) -> Result<(), MergeError> {
+    let (_genesis_config, bank) = load_ledger(ledger_path)?;
+    let programs = functions::list_programs(&bank)?;
 
-fn load_bank_from_snapshot(
-    ledger_path: &Path,
-    genesis_config: &GenesisConfig,
-) -> Result<Arc<Bank>, String> {
-    info!("Loading snapshot from {:?}", ledger_path);
+    if output_format == "json" {
+        let programs: Vec<_> = programs
+            .iter()
+            .map(|program| {
+                serde_json::json!({
+                    "address": program.address.to_string(),
+                    "loader": functions::loader_name(&program.loader),
+                    "data_len": program.data_len,
+                    "executable": program.executable,
+                    "upgrade_authority": program.upgrade_authority.map(|a| a.to_string()),
+                })
+            })
+            .collect();
+        let output = serde_json::to_string_pretty(&programs)
+            .map_err(|e| MergeError::Io(format!("Failed to serialize programs: {:?}", e)))?;
+        println!("{}", output);
+        return Ok(());
+    }
 
-    let blockstore = Arc::new(
-        open_blockstore(ledger_path).map_err(|e| format!("Failed to open blockstore: {:?}", e))?,
+    println!(
+        "{:<44}  {:<22}  {:>12}  {:<10}  Upgrade Authority",
+        "Program", "Loader", "Data Bytes", "Executable"
     );
+    for program in &programs {
+        println!(
+            "{:<44}  {:<22}  {:>12}  {:<10}  {}",
+            program.address.to_string(),
+            functions::loader_name(&program.loader),
+            program.data_len,
+            program.executable,
+            program
+                .upgrade_authority
+                .map_or_else(|| "none".to_string(), |a| a.to_string())
+        );
+    }
+    println!("\n{} programs at slot {}", programs.len(), bank.slot());
+    Ok(())
+}
 
-    let snapshot_config = SnapshotConfig {
-        usage: SnapshotUsage::LoadOnly,
-        full_snapshot_archives_dir: ledger_path.to_path_buf(),
-        incremental_snapshot_archives_dir: ledger_path.to_path_buf(),
-        bank_snapshots_dir: ledger_path.join("bank_snapshots"),
-        ..SnapshotConfig::default()
-    };
+fn run_list_programs(matches: &ArgMatches) {
+    let ledger_path = PathBuf::from(value_t_or_exit!(matches, "ledger", String));
+    let output_format = matches.value_of("output").unwrap_or("text");
+    if let Err(e) = list_programs(&ledger_path, output_format) {
+        eprintln!("❌ Error: {}", e);
+        exit(1);
+    }
+}
 
-    // Use minimal accounts DB config for loading
-    let accounts_db_config = Some(AccountsDbConfig::default());
-    let process_options = ProcessOptions {
-        accounts_db_config,
-        ..ProcessOptions::default()
+fn run_self_test(matches: &ArgMatches) {
+    let temp_dir;
+    let work_dir = match matches.value_of("work_dir") {
+        Some(path) => {
+            let path = PathBuf::from(path);
+            if let Err(e) = std::fs::create_dir_all(&path) {
+                eprintln!("❌ Error: Failed to create {:?}: {:?}", path, e);
+                exit(1);
+            }
+            path
+        }
+        None => {
+            temp_dir = tempfile::Builder::new()
+                .prefix("snapshot-merger-self-test-")
+                .tempdir()
+                .unwrap_or_else(|e| {
+                    eprintln!("❌ Error: Failed to create temporary directory: {:?}", e);
+                    exit(1);
+                });
+            temp_dir.path().to_path_buf()
+        }
     };
 
-    let (bank_forks, _leader_schedule_cache, _starting_snapshot_hashes, ..) =
-        bank_forks_utils::load_bank_forks(
-            genesis_config,
-            &*blockstore,
-            vec![ledger_path.join("accounts")],
-            &snapshot_config,
-            &process_options,
-            None,
-            None,
-            None,
-            Arc::new(std::sync::atomic::AtomicBool::new(false)),
-        )
-        .map_err(|e| format!("Failed to load bank forks: {:?}", e))?;
-
-    let bank = bank_forks.read().unwrap().working_bank();
-    info!("Loaded bank at slot {}", bank.slot());
+    let report = self_test::run_self_test(&work_dir);
+    println!("\nSelf-test in {:?}:", work_dir);
+    for step in &report.steps {
+        match &step.result {
+            Ok(details) => println!("  ✅ {}: {}", step.name, details),
+            Err(e) => println!("  ❌ {}: {}", step.name, e),
+        }
+    }
+    if !report.passed() {
+        println!("\n❌ Self-test failed");
+        exit(1);
+    }
+    println!("\n✅ Self-test passed");
+}
 
-    Ok(bank)
+/// Writes `stats` to `--stats-file` when given, and prints it in place of the
+/// text summary under `--output-format json`. Returns whether it was printed.
+fn emit_stats_json(matches: &ArgMatches, stats: &serde_json::Value) -> bool {
+    let rendered = format!("{:#}", stats);
+    if let Some(stats_file) = matches.value_of("stats_file") {
+        if let Err(e) = std::fs::write(stats_file, format!("{}\n", rendered)) {
+            eprintln!(
+                "❌ Error: Failed to write stats file {:?}: {:?}",
+                stats_file, e
+            );
+            exit(1);
+        }
+    }
+    if matches.value_of("output_format") == Some("json") {
+        println!("{}", rendered);
+        return true;
+    }
+    false
 }
 
-fn create_snapshot_from_bank(bank: &Bank, output_dir: &Path) -> Result<String, String> {
-    info!("Preparing bank for snapshot at slot {}", bank.slot());
+/// Problems found while building the merge config from the command line,
+/// collected so that all of them are reported at once
+#[derive(Debug, Default)]
+struct Problems(Vec<String>);
 
-    // Ensure bank is complete by filling it with ticks if needed
-    if !bank.is_complete() {
-        info!("Bank is not complete, filling with ticks...");
-        bank.fill_bank_with_ticks_for_tests();
-        info!(
-            "Bank now complete (tick_height: {} / max_tick_height: {})",
-            bank.tick_height(),
-            bank.max_tick_height()
-        );
+impl Problems {
+    fn push(&mut self, problem: impl Into<String>) {
+        self.0.push(problem.into());
     }
 
-    // Force flush accounts cache to ensure all accounts are written to storage
-    info!("Flushing accounts cache to disk...");
-    bank.force_flush_accounts_cache();
-    info!("Accounts cache flushed");
-
-    // Create necessary subdirectories
-    let bank_snapshots_dir = output_dir.join("bank_snapshots");
-    std::fs::create_dir_all(&bank_snapshots_dir)
-        .map_err(|e| format!("Failed to create bank snapshots directory: {:?}", e))?;
+    /// The value of `result`, or None with its error recorded
+    fn check<T, E: Display>(&mut self, result: Result<T, E>) -> Option<T> {
+        result.map_err(|e| self.push(e.to_string())).ok()
+    }
 
-    info!("Creating full snapshot archive...");
-    let archive_format = ArchiveFormat::TarZstd {
-        config: ZstdConfig::default(),
-    };
-    let snapshot_archive_info = snapshot_bank_utils::bank_to_full_snapshot_archive(
-        &bank_snapshots_dir,
-        bank,
-        Some(SnapshotVersion::default()),
-        output_dir,
-        output_dir,
-        archive_format,
-    )
-    .map_err(|e| format!("Failed to create snapshot archive: {:?}", e))?;
+    /// `value` of `flag` parsed as `T`, or None with the error recorded
+    fn parse<T: FromStr>(&mut self, flag: &str, value: &str) -> Option<T>
+    where
+        T::Err: Display,
+    {
+        value
+            .parse()
+            .map_err(|e| self.push(format!("Invalid {} '{}': {}", flag, value, e)))
+            .ok()
+    }
 
-    let snapshot_path = snapshot_archive_info.path().to_string_lossy().to_string();
-    info!("Successfully created snapshot archive: {}", snapshot_path);
+    /// The value of `arg` parsed as `T`; None if it wasn't given or doesn't
+    /// parse, which is recorded
+    fn value<T: FromStr>(&mut self, matches: &ArgMatches, arg: &str) -> Option<T>
+    where
+        T::Err: Display,
+    {
+        let value = matches.value_of(arg)?;
+        self.parse(&format!("--{}", arg.replace('_', "-")), value)
+    }
 
-    Ok(snapshot_path)
+    /// Every value of the repeatable `arg` that parses as `T`
+    fn values<T: FromStr, C: FromIterator<T>>(&mut self, matches: &ArgMatches, arg: &str) -> C
+    where
+        T::Err: Display,
+    {
+        let flag = format!("--{}", arg.replace('_', "-"));
+        matches
+            .values_of(arg)
+            .into_iter()
+            .flatten()
+            .filter_map(|value| self.parse(&flag, value))
+            .collect()
+    }
 }
 
-fn merge_snapshots(
-    mainnet_ledger: &Path,
-    ledger_to_merge: &Path,
-    output_snapshot_dir: &Path,
-    warp_slot: Option<Slot>,
-) -> Result<MergeStats, String> {
-    info!("=== Starting Snapshot Merge ===");
-    info!("Mainnet ledger: {:?}", mainnet_ledger);
-    info!("Ledger to merge: {:?}", ledger_to_merge);
-    info!("Output directory: {:?}", output_snapshot_dir);
-
-    // Load genesis configs
-    info!("\n=== Step 1: Loading Genesis Configs ===");
-    let mainnet_genesis_config = open_genesis_config(mainnet_ledger, 10485760)
-        .map_err(|e| format!("Failed to open mainnet genesis config: {:?}", e))?;
-    let merge_genesis_config = open_genesis_config(ledger_to_merge, 10485760)
-        .map_err(|e| format!("Failed to open ledger genesis config: {:?}", e))?;
-    info!("Loaded both genesis configs successfully");
-
-    // Load mainnet snapshot
-    info!("\n=== Step 2: Loading Mainnet Snapshot ===");
-    let mainnet_bank = load_bank_from_snapshot(mainnet_ledger, &mainnet_genesis_config)?;
-    let mainnet_total_accounts = functions::count_total_accounts(&mainnet_bank)?;
-    info!(
-        "Mainnet bank loaded with {} total accounts",
-        mainnet_total_accounts
-    );
-    info!(
-        "Mainnet bank genesis creation time: {}",
-        mainnet_bank.genesis_creation_time()
-    );
-    info!(
-        "Mainnet genesis config creation time: {}",
-        mainnet_genesis_config.creation_time
-    );
-    info!(
-        "Mainnet genesis config hash: {}",
-        mainnet_genesis_config.hash()
-    );
-
-    // Load merge ledger snapshot (this will be our base)
-    info!("\n=== Step 3: Loading Ledger to Merge ===");
-    let merge_bank = load_bank_from_snapshot(ledger_to_merge, &merge_genesis_config)?;
-    let merge_total_accounts = functions::count_total_accounts(&merge_bank)?;
-    info!(
-        "Merge ledger loaded with {} total accounts",
-        merge_total_accounts
-    );
-    info!(
-        "Merge bank genesis creation time: {}",
-        merge_bank.genesis_creation_time()
-    );
-    info!(
-        "Merge genesis config creation time: {}",
-        merge_genesis_config.creation_time
-    );
-    info!("Merge genesis config hash: {}", merge_genesis_config.hash());
-
-    // Extract mainnet vote and stake accounts (to filter them out)
-    info!("\n=== Step 4: Extracting Mainnet Validators (to exclude) ===");
-    let mainnet_vote_accounts = functions::extract_vote_accounts(&mainnet_bank)?;
-    let mainnet_stake_accounts = functions::extract_stake_accounts(&mainnet_bank)?;
-    info!(
-        "Found {} vote and {} stake accounts in mainnet to exclude",
-        mainnet_vote_accounts.len(),
-        mainnet_stake_accounts.len()
-    );
-
-    // Get ALL mainnet accounts and filter out vote/stake
-    info!("\n=== Step 5: Extracting Mainnet Accounts (excluding validators) ===");
-    let all_mainnet_accounts = mainnet_bank
-        .get_all_accounts(false)
-        .map_err(|e| format!("Failed to get all mainnet accounts: {:?}", e))?;
+/// Records the flag combinations clap can't express
+fn check_flag_combinations(matches: &ArgMatches, problems: &mut Problems) {
+    // Each phase of a two-phase merge only reads one of the ledgers
+    for (phase, ledger_args, flag) in [
+        (
+            "extract",
+            &["mainnet_ledger", "mainnet_snapshot", "mainnet_snapshot_url"][..],
+            "--mainnet-ledger, --mainnet-snapshot or --mainnet-snapshot-url",
+        ),
+        ("apply", &["ledger_to_merge"][..], "--ledger-to-merge"),
+    ] {
+        if matches.value_of("phase") == Some(phase)
+            && !ledger_args.iter().any(|arg| matches.is_present(arg))
+        {
+            problems.push(format!("--phase {} requires {}", phase, flag));
+        }
+    }
+    // Checked here since clap would also apply `requires` and
+    // `conflicts_with` to the default values
+    if matches.occurrences_of("source_conflict_policy") > 0 && !matches.is_present("source_ledger")
+    {
+        problems.push("--source-conflict-policy requires --source-ledger");
+    }
+    if matches.is_present("incremental_base") && matches.value_of("snapshot_type") == Some("full") {
+        problems.push("--incremental-base requires --snapshot-type incremental or both");
+    }
+    if matches.is_present("match_mainnet_features") && matches.occurrences_of("feature_set") > 0 {
+        problems.push("--match-mainnet-features is the old spelling of --feature-set mainnet");
+    }
+    if matches.is_present("feature_list_file")
+        != (matches.value_of("feature_set") == Some("list-file"))
+    {
+        problems.push("--feature-list-file and --feature-set list-file require each other");
+    }
+    if matches.is_present("zstd_level") && matches.value_of("archive_format") != Some("tar-zstd") {
+        problems.push("--zstd-level requires --archive-format tar-zstd");
+    }
+    if matches.occurrences_of("conflict_policy") > 0 && matches.is_present("merge_fields") {
+        problems.push("--conflict-policy can't be combined with --merge-fields");
+    }
+    // Slots are only recorded by the mainnet scan and only when it enumerates
+    // every account
+    if matches.value_of("conflict_policy") == Some("prefer-newer-slot")
+        && matches.is_present("copy_diff_only")
+    {
+        problems
+            .push("--conflict-policy prefer-newer-slot can't be combined with --copy-diff-only");
+    }
+    if matches.value_of("source_conflict_policy") == Some("prefer-newer-slot") {
+        problems.push("prefer-newer-slot is only supported by --conflict-policy");
+    }
+    if matches.is_present("capitalization_mode")
+        && matches.value_of("capitalization_mode") == Some("mint-delta")
+        && (!matches.is_present("treasury") || !matches.is_present("target_capitalization"))
+    {
+        problems.push(
+            "--capitalization-mode mint-delta requires --treasury and --target-capitalization",
+        );
+    }
+}
 
-    let mut mainnet_accounts_to_copy = std::collections::HashMap::new();
-    let mut filtered_vote_count = 0;
-    let mut filtered_stake_count = 0;
+/// The pubkeys of the account list file passed as `arg`, if any
+fn account_list(
+    matches: &ArgMatches,
+    arg: &str,
+    problems: &mut Problems,
+) -> Option<HashSet<Pubkey>> {
+    let path = matches.value_of(arg)?;
+    problems
+        .check(functions::read_account_list(Path::new(path)))
+        .map(|pubkeys| pubkeys.into_iter().collect())
+}
 
-    for (pubkey, account, _slot) in all_mainnet_accounts {
-        if mainnet_vote_accounts.contains_key(&pubkey) {
-            filtered_vote_count += 1;
+/// The `OWNER=VALUE` entries of the repeatable `arg` parsed by `parse`, keyed
+/// by owner. An owner listed twice is recorded rather than one entry quietly
+/// replacing the other.
+fn owner_entries<T>(
+    matches: &ArgMatches,
+    arg: &str,
+    parse: fn(&str) -> Result<(Pubkey, T), MergeError>,
+    problems: &mut Problems,
+) -> HashMap<Pubkey, T> {
+    let mut entries = HashMap::new();
+    for value in matches.values_of(arg).into_iter().flatten() {
+        let Some((owner, entry)) = problems.check(parse(value)) else {
             continue;
+        };
+        if entries.insert(owner, entry).is_some() {
+            problems.push(format!(
+                "--{} lists owner {} more than once",
+                arg.replace('_', "-"),
+                owner
+            ));
         }
-        if mainnet_stake_accounts.contains_key(&pubkey) {
-            filtered_stake_count += 1;
-            continue;
-        }
-        mainnet_accounts_to_copy.insert(pubkey, account);
     }
+    entries
+}
 
-    info!(
-        "Prepared {} mainnet accounts to copy (excluded {} vote, {} stake accounts)",
-        mainnet_accounts_to_copy.len(),
-        filtered_vote_count,
-        filtered_stake_count
-    );
-
-    // Extract system accounts from merge ledger (to preserve them)
-    info!("\n=== Step 6: Extracting System Accounts from Merge Ledger ===");
-    let merge_system_accounts = functions::extract_system_accounts(&merge_bank)?;
-    info!(
-        "Found {} system accounts in merge ledger to preserve",
-        merge_system_accounts.len()
-    );
-
-    // Create child bank from merge ledger (this keeps merge ledger genesis and validators)
-    info!("\n=== Step 7: Creating Child Bank from Merge Ledger ===");
-    let mut merged_bank = Arc::new(Bank::new_from_parent(
-        merge_bank.clone(),
-        merge_bank.collector_id(),
-        merge_bank.slot() + 1,
-    ));
-    info!("Created child bank at slot {}", merged_bank.slot());
-    info!(
-        "Merged bank genesis creation time: {} (should match merge ledger: {})",
-        merged_bank.genesis_creation_time(),
-        merge_genesis_config.creation_time
-    );
-
-    let capitalization_before = merged_bank.capitalization();
-
-    // Add all non-validator accounts from mainnet
-    info!("\n=== Step 8: Adding Mainnet Accounts (excluding validators) ===");
-    const SLOT_BYTE_LIMIT: u64 = 4 * 1024 * 1024 * 1024; // 4 GiB safety margin below AppendVec cap
-    merged_bank = functions::add_accounts(
-        Arc::clone(&merged_bank),
-        &mainnet_accounts_to_copy,
-        "mainnet",
-        SLOT_BYTE_LIMIT,
-    )?;
-
-    // Re-apply system accounts from merge ledger (to preserve funded accounts like validator identities)
-    info!("\n=== Step 9: Preserving System Accounts from Merge Ledger ===");
-    merged_bank = functions::add_accounts(
-        Arc::clone(&merged_bank),
-        &merge_system_accounts,
-        "merge ledger system",
-        SLOT_BYTE_LIMIT,
-    )?;
-
-    // Recalculate capitalization
-    info!("\n=== Step 10: Recalculating Capitalization ===");
-    let new_capitalization = merged_bank.calculate_capitalization_for_tests();
-    merged_bank.set_capitalization_for_tests(new_capitalization);
-    let capitalization_after = merged_bank.capitalization();
-
-    info!(
-        "Capitalization changed from {} to {} ({:+})",
-        capitalization_before,
-        capitalization_after,
-        capitalization_after as i128 - capitalization_before as i128
-    );
-
-    // Warp if requested, otherwise squash the merged bank
-    let final_bank = if let Some(warp_slot) = warp_slot {
-        info!("\n=== Step 11: Warping to Slot {} ===", warp_slot);
-        info!("Squashing merged bank before warp...");
-        merged_bank.squash();
-        merged_bank.force_flush_accounts_cache();
-        let collector_id = merged_bank.collector_id();
-        let warped = Arc::new(Bank::warp_from_parent(
-            Arc::clone(&merged_bank),
-            collector_id,
-            warp_slot,
-        ));
-        info!(
-            "Warped bank genesis creation time: {} (should still match merge ledger: {})",
-            warped.genesis_creation_time(),
-            merge_genesis_config.creation_time
+/// The owner allowlist from `--include-program` and `--include-owners-file`,
+/// or None when neither was given
+fn include_owners(matches: &ArgMatches, problems: &mut Problems) -> Option<HashSet<Pubkey>> {
+    if !matches.is_present("include_program") && !matches.is_present("include_owners_file") {
+        return None;
+    }
+    let mut owners: HashSet<Pubkey> = problems.values(matches, "include_program");
+    if let Some(path) = matches.value_of("include_owners_file") {
+        owners.extend(
+            problems
+                .check(functions::read_owner_list(Path::new(path)))
+                .unwrap_or_default(),
         );
-        warped
-    } else {
-        info!("\n=== Step 11: Finalizing Bank ===");
-        info!("Squashing merged bank...");
-        merged_bank.squash();
-        merged_bank.force_flush_accounts_cache();
-        info!("Bank squashed and accounts cache flushed");
-        Arc::clone(&merged_bank)
-    };
-
-    let final_total_accounts = functions::count_total_accounts(&final_bank)?;
-
-    // Verify the bank's genesis creation time matches what we're going to write
-    info!("\n=== Step 12: Verifying Genesis Consistency ===");
-    let bank_genesis_creation_time = final_bank.genesis_creation_time();
-    let merge_genesis_creation_time = merge_genesis_config.creation_time;
-    let merge_genesis_hash = merge_genesis_config.hash();
-
-    info!(
-        "Final bank genesis creation time: {}",
-        bank_genesis_creation_time
-    );
-    info!(
-        "Merge ledger genesis creation time: {}",
-        merge_genesis_creation_time
-    );
-    info!("Merge ledger genesis hash: {}", merge_genesis_hash);
-
-    if bank_genesis_creation_time != merge_genesis_creation_time {
-        return Err(format!(
-            "Genesis creation time mismatch! Bank has creation time {} but merge ledger has {}. This indicates the bank was created from the wrong genesis.",
-            bank_genesis_creation_time, merge_genesis_creation_time
-        ));
     }
-    info!("✓ Genesis creation times match");
-
-    // Create snapshot
-    info!("\n=== Step 13: Creating Merged Snapshot ===");
-    std::fs::create_dir_all(output_snapshot_dir)
-        .map_err(|e| format!("Failed to create output directory: {:?}", e))?;
-
-    let snapshot_path = create_snapshot_from_bank(&final_bank, output_snapshot_dir)?;
+    Some(owners)
+}
 
-    // Write the merge ledger genesis config to the output directory
-    info!("Writing merge ledger genesis config to output directory...");
-    let genesis_path = output_snapshot_dir.join("genesis.bin");
-    let genesis_file = std::fs::File::create(&genesis_path)
-        .map_err(|e| format!("Failed to create genesis file: {:?}", e))?;
-    bincode::serialize_into(genesis_file, &merge_genesis_config)
-        .map_err(|e| format!("Failed to serialize genesis config: {:?}", e))?;
-    info!("Genesis config saved to: {:?}", genesis_path);
+/// Builds the merge config and owner groups from the merge options, recording
+/// every option that doesn't parse or fit the others in `problems`. Unless
+/// `preflight`, a `--mainnet-snapshot-url` archive is downloaded first.
+fn merge_config(
+    matches: &ArgMatches,
+    started: Instant,
+    preflight: bool,
+    problems: &mut Problems,
+) -> (MergeConfig, Vec<functions::OwnerGroup>) {
+    check_flag_combinations(matches, problems);
+    let defaults = MergeConfig::new(PathBuf::new(), PathBuf::new(), PathBuf::new());
 
-    let stats = MergeStats {
-        mainnet_total_accounts,
-        merge_total_accounts,
-        mainnet_vote_accounts_excluded: filtered_vote_count,
-        mainnet_stake_accounts_excluded: filtered_stake_count,
-        mainnet_accounts_copied: mainnet_accounts_to_copy.len(),
-        merge_system_accounts_preserved: merge_system_accounts.len(),
-        final_total_accounts,
-        capitalization_before,
-        capitalization_after,
-        snapshot_path,
+    let mainnet_snapshot = match matches.value_of("mainnet_snapshot_url") {
+        // Nothing is downloaded for a merge that won't run
+        Some(_) if preflight || !problems.0.is_empty() => None,
+        Some(url) => {
+            let download_dir = matches
+                .value_of("download_directory")
+                .map(PathBuf::from)
+                .unwrap_or_else(|| std::env::temp_dir().join("snapshot-merger-download"));
+            problems.check(
+                fetch::download_snapshot(
+                    url,
+                    &download_dir,
+                    matches.value_of("mainnet_snapshot_sha256"),
+                )
+                .and_then(|archive| {
+                    if !matches.is_present("mainnet_genesis") {
+                        fetch::download_rpc_genesis(url, &download_dir)?;
+                    }
+                    Ok(archive)
+                }),
+            )
+        }
+        None => matches.value_of("mainnet_snapshot").map(PathBuf::from),
+    };
+    let config = MergeConfig {
+        // The directory of a mainnet snapshot archive stands in for the ledger
+        mainnet_ledger: matches
+            .value_of("mainnet_ledger")
+            .map(PathBuf::from)
+            .or_else(|| {
+                mainnet_snapshot.as_deref().map(|archive| {
+                    archive
+                        .parent()
+                        .filter(|directory| !directory.as_os_str().is_empty())
+                        .unwrap_or(Path::new("."))
+                        .to_path_buf()
+                })
+            })
+            .unwrap_or_default(),
+        mainnet_snapshot,
+        mainnet_genesis: matches.value_of("mainnet_genesis").map(PathBuf::from),
+        ledger_to_merge: matches
+            .value_of("ledger_to_merge")
+            .map(PathBuf::from)
+            .unwrap_or_default(),
+        source_ledgers: matches
+            .values_of("source_ledger")
+            .into_iter()
+            .flatten()
+            .map(PathBuf::from)
+            .collect(),
+        source_conflict_policy: problems
+            .value(matches, "source_conflict_policy")
+            .unwrap_or(defaults.source_conflict_policy),
+        output_directory: matches
+            .value_of("output_directory")
+            .map(PathBuf::from)
+            .unwrap_or_default(),
+        warp_slot: problems.value(matches, "warp_slot"),
+        warp_preserve_capitalization: matches.is_present("warp_preserve_capitalization"),
+        load_scratch_directory: matches.value_of("load_scratch_dir").map(PathBuf::from),
+        cleanup_load_scratch: matches.is_present("cleanup_load_scratch"),
+        progress_file: matches.value_of("progress_file").map(PathBuf::from),
+        progress_bar: matches.is_present("progress_bar"),
+        throughput_log: matches.value_of("throughput_log").map(PathBuf::from),
+        throughput_interval: matches
+            .value_of("throughput_interval")
+            .and_then(|interval| problems.check(functions::parse_duration(interval)))
+            .unwrap_or(defaults.throughput_interval),
+        parallel_load: matches.is_present("parallel_load"),
+        account_index_file: matches.value_of("account_index_file").map(PathBuf::from),
+        base_diff_file: matches.value_of("base_diff_file").map(PathBuf::from),
+        copy_shards: problems.value(matches, "copy_shards"),
+        copy_memory_budget: problems.value(matches, "copy_memory_budget"),
+        reference_snapshot: matches.value_of("reference_snapshot").map(PathBuf::from),
+        deep_compare: matches.is_present("deep_compare"),
+        max_reported_divergences: problems
+            .value(matches, "max_reported_divergences")
+            .unwrap_or(defaults.max_reported_divergences),
+        snapshot_retries: problems
+            .value(matches, "snapshot_retries")
+            .unwrap_or(defaults.snapshot_retries),
+        archive_name: matches.value_of("archive_name").map(String::from),
+        ledger_layout: matches.is_present("ledger_layout"),
+        upload_to: problems.value(matches, "upload_to"),
+        snapshot_type: problems
+            .value(matches, "snapshot_type")
+            .unwrap_or(defaults.snapshot_type),
+        archive_format: problems
+            .value(matches, "archive_format")
+            .unwrap_or(defaults.archive_format),
+        zstd_level: problems.value(matches, "zstd_level").filter(|level| {
+            let valid = functions::ZSTD_LEVELS.contains(level);
+            if !valid {
+                problems.push(format!(
+                    "--zstd-level must be between {} and {}",
+                    functions::ZSTD_LEVELS.start(),
+                    functions::ZSTD_LEVELS.end()
+                ));
+            }
+            valid
+        }),
+        incremental_base: matches.value_of("incremental_base").map(PathBuf::from),
+        bank_completion: problems
+            .value(matches, "complete_to_tick_height")
+            .unwrap_or(defaults.bank_completion),
+        capitalization_tolerance: problems
+            .value(matches, "capitalization_tolerance")
+            .unwrap_or(defaults.capitalization_tolerance),
+        capitalization_mode: match matches.value_of("capitalization_mode") {
+            // A missing --treasury or --target-capitalization is recorded by
+            // check_flag_combinations
+            Some("mint-delta") => match (
+                problems.value(matches, "treasury"),
+                problems.value(matches, "target_capitalization"),
+            ) {
+                (Some(treasury), Some(target)) => {
+                    functions::CapitalizationMode::MintDelta { treasury, target }
+                }
+                _ => defaults.capitalization_mode,
+            },
+            Some("preserve-base") => functions::CapitalizationMode::PreserveBase,
+            _ => functions::CapitalizationMode::Recalculate,
+        },
+        field_merge_policy: matches
+            .value_of("merge_fields")
+            .and_then(|policy| problems.check(policy.parse()))
+            .unwrap_or_default(),
+        conflict_policy: problems
+            .value(matches, "conflict_policy")
+            .unwrap_or(defaults.conflict_policy),
+        conflict_report_file: matches.value_of("conflict_report_file").map(PathBuf::from),
+        accounts_index: AccountsIndexConfig {
+            index_limit_mb: match matches.value_of("accounts_index_memory_limit") {
+                Some("in-mem-only") => IndexLimitMb::InMemOnly,
+                _ => IndexLimitMb::Minimal,
+            },
+            drives: matches
+                .values_of("accounts_index_path")
+                .map(|paths| paths.map(PathBuf::from).collect()),
+            bins: problems.value(matches, "accounts_index_bins"),
+            ..AccountsIndexConfig::default()
+        },
+        account_enumeration: if matches.is_present("get_all_accounts") {
+            functions::AccountEnumeration::GetAll
+        } else {
+            functions::AccountEnumeration::Scan
+        },
+        scan_consistency: problems
+            .value(matches, "scan_consistency")
+            .unwrap_or(defaults.scan_consistency),
+        changelog_file: matches.value_of("changelog_file").map(PathBuf::from),
+        alt_policy: problems
+            .value(matches, "alt_policy")
+            .unwrap_or(defaults.alt_policy),
+        skip_unchanged: matches.is_present("skip_unchanged"),
+        copy_diff_only: matches.is_present("copy_diff_only"),
+        rebuild_from_genesis: matches.is_present("rebuild_from_genesis"),
+        keep_base_accounts_only: matches.is_present("keep_accounts_from_base_only"),
+        prune_deactivated_stakes: matches.is_present("prune_deactivated_stakes"),
+        validator_source: problems
+            .value(matches, "validator_source")
+            .unwrap_or(defaults.validator_source),
+        regenerate_epoch_stakes: matches.is_present("regenerate_epoch_stakes"),
+        subtract_ledger: matches.value_of("subtract_ledger").map(PathBuf::from),
+        allow_empty_copy: matches.is_present("allow_empty_copy"),
+        exclude_owners: problems.values(matches, "exclude_owner"),
+        token_filter: spl::TokenFilter {
+            exclude_mints: problems.values(matches, "exclude_mint"),
+            min_balance: problems.value(matches, "min_token_balance"),
+        },
+        min_lamports: problems.value(matches, "min_lamports"),
+        max_account_data_size: problems.value(matches, "max_account_data_size"),
+        skip_zero_lamport_accounts: matches.is_present("skip_zero_lamport_accounts"),
+        custom_filters: Vec::new(),
+        account_transforms: Vec::new(),
+        exclude_accounts: account_list(matches, "exclude_accounts_file", problems)
+            .unwrap_or_default(),
+        include_accounts: account_list(matches, "include_accounts_file", problems),
+        copy_owners: include_owners(matches, problems),
+        feature_set: if matches.is_present("match_mainnet_features") {
+            log::warn!("--match-mainnet-features is deprecated, use --feature-set mainnet");
+            functions::FeatureSetPolicy::Mainnet
+        } else {
+            problems
+                .value(matches, "feature_set")
+                .unwrap_or(defaults.feature_set)
+        },
+        feature_list: account_list(matches, "feature_list_file", problems).unwrap_or_default(),
+        max_account_age_epochs: problems.value(matches, "max_account_age_epochs"),
+        expect_accounts_data_size_max: problems.value(matches, "expect_accounts_data_size_max"),
+        deadline: matches
+            .value_of("max_runtime")
+            .and_then(|max_runtime| problems.check(functions::parse_duration(max_runtime)))
+            .map(|max_runtime| functions::Deadline::new(started, max_runtime)),
+        normalize_rent_epoch: if matches.is_present("normalize_rent_epoch_to") {
+            problems.value(matches, "normalize_rent_epoch_to")
+        } else {
+            matches
+                .is_present("normalize_rent_epoch")
+                .then(functions::RentEpochTarget::default)
+        },
+        token_balance_authority: problems.value(matches, "copy_token_balances_only"),
+        validate_accounts: matches.is_present("validate_accounts"),
+        strict_validation: matches.is_present("strict"),
+        max_data_bytes_per_owner: owner_entries(
+            matches,
+            "max_data_bytes_per_owner",
+            functions::parse_owner_data_cap,
+            problems,
+        ),
+        owner_remaps: owner_entries(
+            matches,
+            "owner_remap",
+            functions::parse_owner_remap,
+            problems,
+        ),
+        clone_accounts: problems.values(matches, "clone_account"),
+        rpc_url: matches.value_of("rpc_url").map(String::from),
+        account_overrides: matches
+            .value_of("account_overrides")
+            .and_then(|path| problems.check(functions::read_account_overrides(Path::new(path))))
+            .unwrap_or_default(),
+        upgrade_authority_override: problems.value(matches, "override_upgrade_authority").map(
+            |authority| functions::UpgradeAuthorityOverride {
+                authority,
+                programs: problems.values(matches, "program"),
+            },
+        ),
+        epoch_rewards_policy: problems
+            .value(matches, "epoch_rewards")
+            .unwrap_or(defaults.epoch_rewards_policy),
+        nonce_policy: problems
+            .value(matches, "nonce_policy")
+            .unwrap_or(defaults.nonce_policy),
+        sysvar_policies: matches
+            .values_of("sysvar_policy")
+            .into_iter()
+            .flatten()
+            .filter_map(|policy| problems.check(sysvars::parse_sysvar_policy(policy)))
+            .collect(),
+        report_top_accounts: problems
+            .value(matches, "report_top_accounts")
+            .unwrap_or(defaults.report_top_accounts),
+        validators_summary: matches.is_present("validators_summary"),
+        slot_split: if matches.is_present("accounts_per_slot") {
+            match problems.value(matches, "accounts_per_slot") {
+                Some(0) => {
+                    problems.push("--accounts-per-slot must be at least 1");
+                    defaults.slot_split
+                }
+                Some(n) => functions::SlotSplit::Accounts(n),
+                None => defaults.slot_split,
+            }
+        } else {
+            match problems.value(matches, "slot_byte_limit") {
+                Some(0) => {
+                    problems.push("--slot-byte-limit must be at least 1");
+                    defaults.slot_split
+                }
+                Some(n) => functions::SlotSplit::Bytes(n),
+                None => defaults.slot_split,
+            }
+        },
+        max_slots_for_copy: problems.value(matches, "max_slots_for_copy"),
+        deterministic: matches.is_present("deterministic"),
+        flush_policy: if matches.is_present("adaptive_flush") {
+            let memory_budget: Option<u64> = problems.value(matches, "memory_budget");
+            match problems.value::<f64>(matches, "adaptive_flush_fraction") {
+                Some(fraction) if fraction > 0.0 && fraction <= 1.0 => {
+                    memory_budget.map_or(defaults.flush_policy, |memory_budget| {
+                        functions::FlushPolicy::Adaptive {
+                            rss_threshold_bytes: (memory_budget as f64 * fraction) as u64,
+                        }
+                    })
+                }
+                Some(_) => {
+                    problems.push("--adaptive-flush-fraction must be in (0, 1]");
+                    defaults.flush_policy
+                }
+                None => defaults.flush_policy,
+            }
+        } else {
+            match problems.value(matches, "flush_interval_accounts") {
+                Some(0) => {
+                    problems.push("--flush-interval-accounts must be at least 1");
+                    defaults.flush_policy
+                }
+                Some(accounts) => functions::FlushPolicy::Interval { accounts },
+                None => defaults.flush_policy,
+            }
+        },
+        hashes_per_tick: problems.value(matches, "hashes_per_tick"),
+        ticks_per_slot: problems.value(matches, "ticks_per_slot"),
+        program_scan_fallback_threshold: problems.value(matches, "program_scan_fallback_threshold"),
+        provenance_memo: if matches.is_present("provenance_memo") {
+            if matches.is_present("provenance_address") {
+                problems.value(matches, "provenance_address")
+            } else {
+                Some(functions::provenance_address())
+            }
+        } else {
+            None
+        },
+        verify_genesis_hash: matches.is_present("verify_genesis_hash"),
+        expected_mainnet_snapshot_hash: problems.value(matches, "expected_mainnet_snapshot_hash"),
+        expected_base_snapshot_hash: problems.value(matches, "expected_base_snapshot_hash"),
+        verify_no_duplicate_storage: matches.is_present("verify_no_duplicate_storage"),
+        dry_run: matches.is_present("dry_run"),
+        // Validated by possible_values, so only a missing --phase fails
+        phase: value_t!(matches, "phase", functions::MergePhase).unwrap_or_default(),
+        copy_set_file: matches.value_of("copy_set_file").map(PathBuf::from),
+        checkpoint: matches.is_present("checkpoint") || matches.is_present("resume"),
+        resume: matches.is_present("resume"),
+        archive_compression_ratio: match problems.value::<f64>(matches, "archive_compression_ratio")
+        {
+            Some(ratio) if ratio > 0.0 && ratio.is_finite() => ratio,
+            Some(_) => {
+                problems.push("--archive-compression-ratio must be a positive number");
+                defaults.archive_compression_ratio
+            }
+            None => defaults.archive_compression_ratio,
+        },
     };
 
-    info!("\n=== Merge Complete ===");
-    info!("Statistics:");
-    info!("  Mainnet total accounts: {}", stats.mainnet_total_accounts);
-    info!(
-        "  Merge ledger total accounts: {}",
-        stats.merge_total_accounts
-    );
-    info!(
-        "  Mainnet vote accounts excluded: {}",
-        stats.mainnet_vote_accounts_excluded
-    );
-    info!(
-        "  Mainnet stake accounts excluded: {}",
-        stats.mainnet_stake_accounts_excluded
-    );
-    info!(
-        "  Mainnet accounts copied: {}",
-        stats.mainnet_accounts_copied
-    );
-    info!(
-        "  Merge ledger system accounts preserved: {}",
-        stats.merge_system_accounts_preserved
-    );
-    info!("  Final total accounts: {}", stats.final_total_accounts);
-    info!(
-        "  Capitalization before: {} lamports",
-        stats.capitalization_before
-    );
-    info!(
-        "  Capitalization after: {} lamports",
-        stats.capitalization_after
-    );
+    let groups = matches
+        .values_of("split_by_owner")
+        .into_iter()
+        .flatten()
+        .filter_map(|group| problems.check(functions::parse_owner_group(group)))
+        .collect();
+    (config, groups)
+}
 
-    Ok(stats)
+/// Runs the `preflight` subcommand: builds the config from the merge options
+/// and reports every problem with them or the input paths, loading nothing
+fn run_preflight(matches: &ArgMatches, started: Instant) {
+    let mut problems = Problems::default();
+    let (config, groups) = merge_config(matches, started, true, &mut problems);
+    problems
+        .0
+        .extend(match matches.value_of("mainnet_snapshot_url") {
+            Some(url) => preflight_with_mainnet_url(&config, &groups, url),
+            None => preflight(&config, &groups),
+        });
+    if problems.0.is_empty() {
+        println!("✅ Preflight passed, the merge is ready to run");
+        return;
+    }
+    eprintln!("❌ Preflight found {} problems:", problems.0.len());
+    for problem in &problems.0 {
+        eprintln!("  • {}", problem);
+    }
+    exit(1);
 }
 
-fn main() {
-    env_logger::Builder::from_default_env()
-        .filter_level(log::LevelFilter::Info)
-        .init();
+/// The options of a merge, shared by the top-level command and `preflight`
+fn merge_args() -> Vec<Arg<'static, 'static>> {
+    vec![
+        Arg::with_name("mainnet_ledger")
+            .long("mainnet-ledger")
+            .value_name("PATH")
+            .takes_value(true)
+            .required_unless_one(&["phase", "mainnet_snapshot", "mainnet_snapshot_url"])
+            .help("Path to mainnet-beta ledger directory"),
+        Arg::with_name("mainnet_snapshot")
+            .long("mainnet-snapshot")
+            .value_name("ARCHIVE")
+            .takes_value(true)
+            .conflicts_with("mainnet_ledger")
+            .help(
+                "Full snapshot archive to load mainnet from instead of a ledger directory; \
+                 the genesis config is read from the archive's directory unless \
+                 --mainnet-genesis is given",
+            ),
+        Arg::with_name("mainnet_snapshot_url")
+            .long("mainnet-snapshot-url")
+            .value_name("URL")
+            .takes_value(true)
+            .conflicts_with_all(&["mainnet_ledger", "mainnet_snapshot"])
+            .help(
+                "Download the full snapshot archive to load mainnet from first: an RPC \
+                 node's /snapshot.tar.zst, an archive URL or a public s3:// or gs:// \
+                 object. An RPC node's genesis is downloaded alongside.",
+            ),
+        Arg::with_name("mainnet_snapshot_sha256")
+            .long("mainnet-snapshot-sha256")
+            .value_name("HEX")
+            .takes_value(true)
+            .requires("mainnet_snapshot_url")
+            .help("Expected SHA-256 of the downloaded snapshot archive"),
+        Arg::with_name("download_directory")
+            .long("download-directory")
+            .value_name("DIR")
+            .takes_value(true)
+            .requires("mainnet_snapshot_url")
+            .help(
+                "Where the snapshot is downloaded to; a rerun resumes or reuses the \
+                 download [default: snapshot-merger-download in the temp directory]",
+            ),
+        Arg::with_name("mainnet_genesis")
+            .long("mainnet-genesis")
+            .value_name("PATH")
+            .takes_value(true)
+            .help(
+                "Mainnet genesis.bin, genesis.tar.bz2 or a directory holding either, \
+                 instead of the one in the mainnet ledger",
+            ),
+        Arg::with_name("expected_mainnet_snapshot_hash")
+            .long("expected-mainnet-snapshot-hash")
+            .value_name("HASH")
+            .takes_value(true)
+            .help(
+                "Abort unless the loaded mainnet bank has this snapshot hash, the one in \
+                 its archive name",
+            ),
+        Arg::with_name("ledger_to_merge")
+            .long("ledger-to-merge")
+            .value_name("PATH")
+            .takes_value(true)
+            .required_unless("phase")
+            .help("Path to ledger directory whose validators should be merged"),
+        Arg::with_name("expected_base_snapshot_hash")
+            .long("expected-base-snapshot-hash")
+            .value_name("HASH")
+            .takes_value(true)
+            .help(
+                "Abort unless the loaded merge ledger bank has this snapshot hash, the one \
+                 in its archive name",
+            ),
+        Arg::with_name("source_ledger")
+            .long("source-ledger")
+            .value_name("PATH")
+            .takes_value(true)
+            .multiple(true)
+            .number_of_values(1)
+            .help(
+                "Another ledger whose accounts are copied like mainnet's, layered over \
+                 mainnet and any earlier --source-ledger (may be repeated; later ones take \
+                 priority)",
+            ),
+        Arg::with_name("source_conflict_policy")
+            .long("source-conflict-policy")
+            .value_name("POLICY")
+            .takes_value(true)
+            .default_value("overwrite")
+            .help(
+                "How a pubkey held by more than one source is resolved: 'overwrite' (the \
+                 later source wins), 'keep-base' (the earlier one wins), 'fail', \
+                 'merge-lamports' or FIELD=SIDE pairs such as 'lamports=base,data=mainnet', \
+                 where mainnet is the later source. Sysvars and builtins keep the mainnet \
+                 ledger's",
+            ),
+        Arg::with_name("output_directory")
+            .long("output-directory")
+            .short("o")
+            .value_name("PATH")
+            .takes_value(true)
+            .required(true)
+            .help("Directory where merged snapshot will be created"),
+        Arg::with_name("warp_slot")
+            .long("warp-slot")
+            .value_name("SLOT")
+            .takes_value(true)
+            .help("Optionally warp the merged bank to this slot"),
+        Arg::with_name("account_index_file")
+            .long("emit-index")
+            .value_name("FILE")
+            .takes_value(true)
+            .help(
+                "Write a sorted index of the merged snapshot with one \
+                 '<pubkey> <owner> <lamports> <data_len>' line per account",
+            ),
+        Arg::with_name("base_diff_file")
+            .long("dump-diff-against-base")
+            .value_name("FILE")
+            .takes_value(true)
+            .help(
+                "Write a JSON line for every copied account the merge ledger lacked or held \
+                 differently, then a summary line with the net lamports change",
+            ),
+        Arg::with_name("parallel_load").long("parallel-load").help(
+            "Load the mainnet and merge ledger snapshots concurrently; both banks are in \
+                 memory at once",
+        ),
+        Arg::with_name("progress_bar").long("progress").help(
+            "Draw a progress bar with accounts, bytes, rate and ETA on stderr while scanning \
+         mainnet and copying accounts",
+        ),
+        Arg::with_name("progress_file")
+            .long("progress-file")
+            .value_name("PATH")
+            .takes_value(true)
+            .help(
+                "Rewrite this file with the current phase, accounts processed, slot and \
+                 elapsed time as JSON while the merge runs",
+            ),
+        Arg::with_name("throughput_log")
+            .long("throughput-log")
+            .value_name("FILE")
+            .takes_value(true)
+            .help(
+                "Append a CSV line with timestamp, phase, accounts copied, accounts/sec since \
+                 the previous line, slot and RSS to this file periodically during the \
+                 account copy",
+            ),
+        Arg::with_name("throughput_interval")
+            .long("throughput-interval")
+            .value_name("DURATION")
+            .takes_value(true)
+            .requires("throughput_log")
+            .help("How often --throughput-log is sampled, e.g. '30s' or '1m' [default: 10s]"),
+        Arg::with_name("load_scratch_dir")
+            .long("load-scratch-dir")
+            .value_name("DIR")
+            .takes_value(true)
+            .help(
+                "Unpack each loaded snapshot's account storage and bank snapshots under a \
+                 subdirectory of DIR instead of the ledger directory",
+            ),
+        Arg::with_name("cleanup_load_scratch")
+            .long("cleanup-load-scratch")
+            .requires("load_scratch_dir")
+            .help("Remove each load's scratch subdirectory once the merge is done with it"),
+        Arg::with_name("warp_preserve_capitalization")
+            .long("warp-preserve-capitalization")
+            .requires("warp_slot")
+            .help("Fail if warping changes the capitalization of the merged bank"),
+        Arg::with_name("copy_shards")
+            .long("copy-shards")
+            .visible_alias("copy-threads")
+            .value_name("K")
+            .takes_value(true)
+            .help(
+                "Copy mainnet accounts in pubkey order using K parallel shards per slot; \
+                 output is identical for any K",
+            ),
+        Arg::with_name("deterministic")
+            .long("deterministic")
+            .conflicts_with("adaptive_flush")
+            .help(
+                "Copy accounts in pubkey order with fixed flush boundaries and date the \
+                 provenance memo by the bank's clock, so identical inputs give the same \
+                 snapshot hash",
+            ),
+        Arg::with_name("copy_memory_budget")
+            .long("copy-memory-budget")
+            .value_name("BYTES")
+            .takes_value(true)
+            .help(
+                "Store mainnet accounts in chunks of about BYTES while scanning instead of \
+                 collecting them all first, bounding the memory the copy holds",
+            ),
+        Arg::with_name("reference_snapshot")
+            .long("reference-snapshot")
+            .value_name("ARCHIVE")
+            .takes_value(true)
+            .help(
+                "Full snapshot archive the merged result must match on account count, \
+                 capitalization and accounts hash",
+            ),
+        Arg::with_name("deep_compare")
+            .long("deep-compare")
+            .requires("reference_snapshot")
+            .help("Also compare every account against the reference snapshot"),
+        Arg::with_name("max_reported_divergences")
+            .long("max-reported-divergences")
+            .value_name("N")
+            .takes_value(true)
+            .default_value("10")
+            .help("Number of diverging accounts to report with --deep-compare"),
+        Arg::with_name("complete_to_tick_height")
+            .long("complete-to-tick-height")
+            .value_name("HEIGHT")
+            .takes_value(true)
+            .default_value("max")
+            .help(
+                "Tick height to complete the merged bank to before freezing it, or 'max'. A \
+                 snapshot archive needs the final slot's max tick height, so HEIGHT is only \
+                 accepted with --warp-slot and must equal 'max' there: (warp slot + 1) times \
+                 the ticks per slot",
+            ),
+        Arg::with_name("archive_name")
+            .long("archive-name")
+            .value_name("NAME")
+            .takes_value(true)
+            .help(
+                "Also make the snapshot archive available as NAME in the output directory, \
+                 a symlink next to the canonical slot-and-hash archive name",
+            ),
+        Arg::with_name("ledger_layout").long("ledger-layout").help(
+            "Also create an empty blockstore in the output directory, making it a \
+                 ledger directory a validator can start from as is",
+        ),
+        Arg::with_name("upload_to")
+            .long("upload-to")
+            .value_name("URL")
+            .takes_value(true)
+            .help(
+                "Upload the snapshot archives and genesis.bin to s3://BUCKET[/PREFIX] or \
+                 gs://BUCKET[/PREFIX] once written; credentials come from AWS_ACCESS_KEY_ID \
+                 and AWS_SECRET_ACCESS_KEY, or GCS_HMAC_ACCESS_KEY_ID and GCS_HMAC_SECRET",
+            ),
+        Arg::with_name("snapshot_type")
+            .long("snapshot-type")
+            .value_name("TYPE")
+            .takes_value(true)
+            .possible_values(&["full", "incremental", "both"])
+            .default_value("full")
+            .help(
+                "Snapshot archives to write: a full one, an incremental one on top of the \
+                 merge ledger's full snapshot for validators that already have it, or both",
+            ),
+        Arg::with_name("archive_format")
+            .long("archive-format")
+            .value_name("FORMAT")
+            .takes_value(true)
+            .default_value("tar-zstd")
+            .help(
+                "Snapshot archive format: tar-zstd, or tar-lz4 for faster, larger archives \
+                 (tar-bz2, tar and dir can't be loaded by validators and are rejected)",
+            ),
+        Arg::with_name("zstd_level")
+            .long("zstd-level")
+            .value_name("N")
+            .takes_value(true)
+            .help(
+                "zstd compression level of a tar-zstd archive, from 1 (fastest) to 22 \
+                 (smallest); zstd's default if not set",
+            ),
+        Arg::with_name("incremental_base")
+            .long("incremental-base")
+            .value_name("ARCHIVE")
+            .takes_value(true)
+            .help(
+                "Full snapshot archive downstream validators hold; checks it has the slot and \
+                 hash of the merge ledger's full snapshot the incremental one applies on top of",
+            ),
+        Arg::with_name("snapshot_retries")
+            .long("snapshot-retries")
+            .value_name("N")
+            .takes_value(true)
+            .default_value("0")
+            .help(
+                "Retry snapshot archive creation up to N times after transient I/O failures, \
+                 removing partial artifacts between attempts",
+            ),
+        Arg::with_name("capitalization_tolerance")
+            .long("capitalization-tolerance")
+            .value_name("LAMPORTS")
+            .takes_value(true)
+            .default_value("0")
+            .help(
+                "Maximum difference between the final bank's recorded and recomputed \
+                 capitalization",
+            ),
+        Arg::with_name("capitalization_mode")
+            .long("capitalization-mode")
+            .value_name("MODE")
+            .takes_value(true)
+            .possible_values(&["recalculate", "preserve-base", "mint-delta"])
+            .default_value("recalculate")
+            .help(
+                "How the merged bank's capitalization is set: recalculate recounts it \
+                 from the accounts, preserve-base keeps the merge ledger's, mint-delta \
+                 credits or debits --treasury so the accounts sum to \
+                 --target-capitalization",
+            ),
+        Arg::with_name("treasury")
+            .long("treasury")
+            .value_name("PUBKEY")
+            .takes_value(true)
+            .help("Account --capitalization-mode mint-delta credits or debits"),
+        Arg::with_name("target_capitalization")
+            .long("target-capitalization")
+            .value_name("LAMPORTS")
+            .takes_value(true)
+            .help("Total supply --capitalization-mode mint-delta adjusts the treasury to"),
+        Arg::with_name("merge_fields")
+            .long("merge-fields")
+            .value_name("FIELD=SIDE,...")
+            .takes_value(true)
+            .help(
+                "For accounts present in both ledgers, take each of lamports, data, owner \
+                 and executable from 'base' or 'mainnet' (default: all from mainnet), \
+                 e.g. lamports=mainnet,data=base",
+            ),
+        Arg::with_name("conflict_policy")
+            .long("conflict-policy")
+            .value_name("POLICY")
+            .takes_value(true)
+            .default_value("overwrite")
+            .help(
+                "How a mainnet account whose pubkey the merge ledger already holds is \
+                 resolved: 'overwrite' or 'keep-source' (mainnet wins), 'keep-base', \
+                 'prefer-newer-slot', 'fail', 'merge-lamports' (mainnet account with both \
+                 balances added up) or FIELD=SIDE pairs as in --merge-fields. Sysvars and \
+                 builtins are always copied from mainnet",
+            ),
+        Arg::with_name("conflict_report_file")
+            .long("conflict-report")
+            .value_name("FILE")
+            .takes_value(true)
+            .help(
+                "Write every copied pubkey the merge ledger already held to FILE, one per \
+                 line",
+            ),
+        Arg::with_name("accounts_index_memory_limit")
+            .long("accounts-index-memory-limit")
+            .value_name("LIMIT")
+            .takes_value(true)
+            .possible_values(&["minimal", "in-mem-only"])
+            .default_value("minimal")
+            .help(
+                "In-memory portion of the accounts index: 'minimal' keeps little in memory \
+                 and spills to the disk index, 'in-mem-only' disables the disk index",
+            ),
+        Arg::with_name("accounts_index_path")
+            .long("accounts-index-path")
+            .value_name("PATH")
+            .takes_value(true)
+            .multiple(true)
+            .number_of_values(1)
+            .help("Directory for the on-disk accounts index (may be repeated)"),
+        Arg::with_name("accounts_index_bins")
+            .long("accounts-index-bins")
+            .value_name("BINS")
+            .takes_value(true)
+            .help("Number of bins to divide the accounts index into"),
+        Arg::with_name("scan_all_accounts")
+            .long("scan-all-accounts")
+            .conflicts_with("get_all_accounts")
+            .help(
+                "Stream mainnet accounts through the copy filter with scan_all_accounts \
+                 (default)",
+            ),
+        Arg::with_name("get_all_accounts")
+            .long("get-all-accounts")
+            .help(
+                "Materialize every mainnet account with get_all_accounts before filtering; \
+                 uses more memory",
+            ),
+        Arg::with_name("scan_consistency")
+            .long("scan-consistency")
+            .value_name("ORDER")
+            .takes_value(true)
+            .possible_values(&["fixed", "latest"])
+            .default_value("fixed")
+            .help(
+                "Order the mainnet enumeration visits accounts in: 'fixed' sorts them by \
+                 pubkey so every run sees the same order, 'latest' takes the accounts \
+                 index's own order and skips the sort",
+            ),
+        Arg::with_name("changelog_file")
+            .long("changelog-file")
+            .value_name("PATH")
+            .takes_value(true)
+            .help(
+                "Where to write a human-readable summary of the merge \
+                 [default: MERGE_README.txt in the output directory]",
+            ),
+        Arg::with_name("alt_policy")
+            .long("alt-policy")
+            .value_name("POLICY")
+            .takes_value(true)
+            .possible_values(&["copy", "skip", "refresh"])
+            .default_value("copy")
+            .help(
+                "Address lookup table handling: copy unchanged, skip, or refresh their \
+                 slots relative to the merged bank",
+            ),
+        Arg::with_name("skip_unchanged")
+            .long("skip-unchanged")
+            .help(
+                "Do not store mainnet accounts whose lamports, owner, data and executable \
+                 flag match the merge ledger's account",
+            ),
+        Arg::with_name("hashes_per_tick")
+            .long("hashes-per-tick")
+            .value_name("N")
+            .takes_value(true)
+            .help(
+                "Override hashes_per_tick in the output genesis and the rebuilt bank; needs \
+                 --rebuild-from-genesis, changes slot timing, test networks only",
+            ),
+        Arg::with_name("ticks_per_slot")
+            .long("ticks-per-slot")
+            .value_name("N")
+            .takes_value(true)
+            .help(
+                "Override ticks_per_slot in the output genesis and the rebuilt bank; needs \
+                 --rebuild-from-genesis, changes slot timing, test networks only",
+            ),
+        Arg::with_name("max_data_bytes_per_owner")
+            .long("max-data-bytes-per-owner")
+            .value_name("PUBKEY=BYTES")
+            .takes_value(true)
+            .multiple(true)
+            .number_of_values(1)
+            .help(
+                "Cap the total data bytes copied for accounts of an owner, taking them in \
+                 pubkey order (may be repeated, once per owner)",
+            ),
+        Arg::with_name("override_upgrade_authority")
+            .long("override-upgrade-authority")
+            .value_name("PUBKEY")
+            .takes_value(true)
+            .help(
+                "Set the upgrade authority of the copied ProgramData accounts to PUBKEY, \
+                 making finalized programs upgradeable again",
+            ),
+        Arg::with_name("program")
+            .long("program")
+            .value_name("PUBKEY")
+            .takes_value(true)
+            .multiple(true)
+            .number_of_values(1)
+            .requires("override_upgrade_authority")
+            .help(
+                "With --override-upgrade-authority, only rewrite this program's ProgramData \
+                 (may be repeated)",
+            ),
+        Arg::with_name("owner_remap")
+            .long("owner-remap")
+            .value_name("OLD_PUBKEY=NEW_PUBKEY")
+            .takes_value(true)
+            .multiple(true)
+            .number_of_values(1)
+            .help(
+                "Reassign copied mainnet accounts owned by OLD_PUBKEY to NEW_PUBKEY \
+                 (may be repeated, once per OLD_PUBKEY)",
+            ),
+        Arg::with_name("clone_account")
+            .long("clone-account")
+            .value_name("PUBKEY")
+            .takes_value(true)
+            .multiple(true)
+            .number_of_values(1)
+            .requires("rpc_url")
+            .help(
+                "Fetch the live state of this account from --rpc-url into the merged bank, \
+                 with its program data if it is an upgradeable program (may be repeated)",
+            ),
+        Arg::with_name("rpc_url")
+            .long("rpc-url")
+            .value_name("URL")
+            .takes_value(true)
+            .requires("clone_account")
+            .help("JSON-RPC endpoint --clone-account fetches from"),
+        Arg::with_name("account_overrides")
+            .long("account-overrides")
+            .value_name("PATH")
+            .takes_value(true)
+            .help(
+                "Store the accounts in PATH over the merged bank's, in the JSON format of \
+                 solana-test-validator --account or the same entries in YAML; fields left out \
+                 keep the bank's values",
+            ),
+        Arg::with_name("copy_diff_only")
+            .long("copy-diff-only")
+            .help(
+                "Copy only the mainnet accounts the merge ledger lacks or holds with \
+                 different contents",
+            ),
+        Arg::with_name("prune_deactivated_stakes")
+            .long("prune-deactivated-stakes")
+            .help(
+                "Remove the merge ledger's stake accounts that are fully deactivated as of \
+                 the merged bank's epoch",
+            ),
+        Arg::with_name("validator_source")
+            .long("validator-source")
+            .value_name("SOURCE")
+            .takes_value(true)
+            .possible_values(&["merge-ledger", "mainnet", "both", "none"])
+            .default_value("merge-ledger")
+            .help(
+                "Whose vote and stake accounts end up in the merged bank: the merge \
+                 ledger's, mainnet's, both or neither",
+            ),
+        Arg::with_name("regenerate_epoch_stakes")
+            .long("regenerate-epoch-stakes")
+            .help(
+                "Recompute the final bank's epoch stakes from its surviving vote and stake \
+                 accounts, so the leader schedule follows the merged validator set. Goes \
+                 through the runtime's test-only constructors, as the runtime only recomputes \
+                 epoch stakes at an epoch boundary",
+            ),
+        Arg::with_name("rebuild_from_genesis")
+            .long("rebuild-from-genesis")
+            .help(
+                "Build the merged bank on a fresh bank from the merge genesis with every \
+                 merge ledger account stored explicitly, instead of as a child of the \
+                 loaded bank; slower",
+            ),
+        Arg::with_name("max_runtime")
+            .long("max-runtime")
+            .value_name("DURATION")
+            .takes_value(true)
+            .help(
+                "Abort the merge, flushing what has been copied and leaving a MERGE_INCOMPLETE \
+                 marker, once it has run this long, e.g. 2h30m",
+            ),
+        Arg::with_name("expect_accounts_data_size_max")
+            .long("expect-accounts-data-size-max")
+            .value_name("BYTES")
+            .takes_value(true)
+            .help("Fail if the merged bank's accounts data size exceeds BYTES"),
+        Arg::with_name("max_account_age_epochs")
+            .long("max-account-age-epochs")
+            .value_name("N")
+            .takes_value(true)
+            .help(
+                "Skip mainnet accounts whose rent_epoch is more than N epochs behind the \
+                 merged bank's epoch; rent-exempt accounts marked u64::MAX are kept",
+            ),
+        Arg::with_name("normalize_rent_epoch")
+            .long("normalize-rent-epoch")
+            .help(
+                "Rewrite the rent_epoch of copied rent-exempt accounts to the merged \
+                 bank's convention (u64::MAX or an epoch number)",
+            ),
+        Arg::with_name("normalize_rent_epoch_to")
+            .long("normalize-rent-epoch-to")
+            .value_name("TARGET")
+            .takes_value(true)
+            .possible_values(&["convention", "max", "current"])
+            .conflicts_with("normalize_rent_epoch")
+            .help(
+                "Rewrite the rent_epoch of copied accounts: convention does what \
+                 --normalize-rent-epoch does, max sets every copied account to u64::MAX, \
+                 current sets every copied account to the merged bank's epoch",
+            ),
+        Arg::with_name("copy_token_balances_only")
+            .long("copy-token-balances-only")
+            .value_name("AUTHORITY")
+            .takes_value(true)
+            .help(
+                "Copy SPL token accounts as fresh accounts owned by AUTHORITY with the \
+                 same mint and amount, clearing their delegates and close authorities",
+            ),
+        Arg::with_name("validate_accounts")
+            .long("validate-accounts")
+            .help(
+                "Check every mainnet account before copying it (data length, owner, \
+                 executable flag) and skip the invalid ones",
+            ),
+        Arg::with_name("strict")
+            .long("strict")
+            .requires("validate_accounts")
+            .help("With --validate-accounts, fail the merge on invalid accounts instead"),
+        Arg::with_name("feature_set")
+            .long("feature-set")
+            .value_name("SOURCE")
+            .takes_value(true)
+            .possible_values(&["merge-ledger", "mainnet", "union", "list-file"])
+            .default_value("merge-ledger")
+            .help(
+                "Whose feature-gate accounts the output keeps: merge-ledger leaves \
+                 mainnet's out, mainnet copies them and closes the merge ledger's, union \
+                 copies those the merge ledger hasn't activated, list-file copies those in \
+                 --feature-list-file",
+            ),
+        Arg::with_name("match_mainnet_features")
+            .long("match-mainnet-features")
+            .help("Deprecated: use --feature-set mainnet"),
+        Arg::with_name("feature_list_file")
+            .long("feature-list-file")
+            .value_name("PATH")
+            .takes_value(true)
+            .help(
+                "With --feature-set list-file, the mainnet feature ids to copy, one per \
+                 line with '#' comments or a YAML list",
+            ),
+        Arg::with_name("split_by_owner")
+            .long("split-by-owner")
+            .value_name("NAME=PUBKEY[,PUBKEY...]")
+            .takes_value(true)
+            .multiple(true)
+            .number_of_values(1)
+            .help(
+                "Write one merged snapshot per owner group into the NAME subdirectory, each \
+                 holding only the group's mainnet accounts (may be repeated)",
+            ),
+        Arg::with_name("include_program")
+            .long("include-program")
+            .value_name("PUBKEY")
+            .takes_value(true)
+            .multiple(true)
+            .number_of_values(1)
+            .alias("include-owner")
+            .conflicts_with("split_by_owner")
+            .help("Copy only mainnet accounts owned by this program (may be repeated)"),
+        Arg::with_name("exclude_accounts_file")
+            .long("exclude-accounts-file")
+            .value_name("PATH")
+            .takes_value(true)
+            .help(
+                "Never copy the mainnet accounts listed in PATH, one pubkey per line with \
+                 '#' comments or a YAML list",
+            ),
+        Arg::with_name("include_accounts_file")
+            .long("include-accounts-file")
+            .value_name("PATH")
+            .takes_value(true)
+            .help(
+                "Copy only the mainnet accounts listed in PATH, one pubkey per line with \
+                 '#' comments or a YAML list",
+            ),
+        Arg::with_name("exclude_owner")
+            .long("exclude-owner")
+            .value_name("PUBKEY")
+            .takes_value(true)
+            .multiple(true)
+            .number_of_values(1)
+            .help(
+                "Never copy mainnet accounts owned by this program, on top of the vote and \
+                 stake accounts (may be repeated)",
+            ),
+        Arg::with_name("exclude_mint")
+            .long("exclude-mint")
+            .value_name("PUBKEY")
+            .takes_value(true)
+            .multiple(true)
+            .number_of_values(1)
+            .help(
+                "Never copy mainnet SPL Token or Token-2022 accounts of this mint; the mint \
+                 itself is still copied (may be repeated)",
+            ),
+        Arg::with_name("min_lamports")
+            .long("min-lamports")
+            .value_name("LAMPORTS")
+            .takes_value(true)
+            .help("Never copy mainnet accounts holding fewer than LAMPORTS lamports"),
+        Arg::with_name("max_account_data_size")
+            .long("max-account-data-size")
+            .value_name("BYTES")
+            .takes_value(true)
+            .help("Never copy mainnet accounts with more than BYTES bytes of data"),
+        Arg::with_name("skip_zero_lamport_accounts")
+            .long("skip-zero-lamport-accounts")
+            .help("Never copy mainnet accounts holding no lamports"),
+        Arg::with_name("min_token_balance")
+            .long("min-token-balance")
+            .value_name("AMOUNT")
+            .takes_value(true)
+            .help(
+                "Never copy mainnet SPL Token or Token-2022 accounts holding fewer than \
+                 AMOUNT base units",
+            ),
+        Arg::with_name("include_owners_file")
+            .long("include-owners-file")
+            .value_name("PATH")
+            .takes_value(true)
+            .conflicts_with("split_by_owner")
+            .help(
+                "Copy only mainnet accounts owned by a program listed in PATH, one pubkey \
+                 per line with '#' comments; combined with --include-program",
+            ),
+        Arg::with_name("allow_empty_copy")
+            .long("allow-empty-copy")
+            .help(
+                "Write the merged snapshot even if the filters leave no mainnet accounts to copy",
+            ),
+        Arg::with_name("subtract_ledger")
+            .long("subtract-ledger")
+            .value_name("PATH")
+            .takes_value(true)
+            .help("Never copy a mainnet account whose pubkey this ledger's snapshot holds"),
+        Arg::with_name("keep_accounts_from_base_only")
+            .long("keep-accounts-from-base-only")
+            .conflicts_with("copy_diff_only")
+            .help(
+                "Copy a mainnet account only if the merge ledger has an account at the same \
+                 pubkey, refreshing existing accounts without adding new ones",
+            ),
+        Arg::with_name("epoch_rewards")
+            .long("epoch-rewards")
+            .value_name("POLICY")
+            .takes_value(true)
+            .possible_values(&["reset", "skip"])
+            .default_value("reset")
+            .help(
+                "Epoch rewards sysvar handling: reset copies it marked inactive, skip keeps \
+                 the merge ledger's own",
+            ),
+        Arg::with_name("nonce_policy")
+            .long("nonce-policy")
+            .value_name("POLICY")
+            .takes_value(true)
+            .possible_values(&["keep", "drop", "zero", "advance"])
+            .default_value("keep")
+            .help(
+                "Durable nonce account handling: keep copies them unchanged, drop leaves \
+                 them out, zero resets their stored nonce to the all-zero hash, advance sets it \
+                 from the merged bank's blockhash",
+            ),
+        Arg::with_name("sysvar_policy")
+            .long("sysvar-policy")
+            .value_name("SYSVAR=POLICY")
+            .takes_value(true)
+            .multiple(true)
+            .number_of_values(1)
+            .help(
+                "How a sysvar mainnet holds is handled: rewrite leaves it out of the copy so \
+                 the merged bank keeps its own, keep copies mainnet's. SYSVAR is one of \
+                 clock, rent, epoch-schedule, slot-hashes, stake-history or \
+                 recent-blockhashes; all are rewritten by default, and slot-hashes=keep \
+                 requires --warp-slot (may be repeated)",
+            ),
+        Arg::with_name("report_top_accounts")
+            .long("report-top-accounts")
+            .value_name("N")
+            .takes_value(true)
+            .default_value("0")
+            .help("Report the N copied accounts holding the most lamports"),
+        Arg::with_name("validators_summary")
+            .long("validators-summary")
+            .help(
+                "Print a table of the merged bank's validators: vote account, node, \
+                 commission and active stake",
+            ),
+        Arg::with_name("adaptive_flush")
+            .long("adaptive-flush")
+            .requires("memory_budget")
+            .help(
+                "Flush the accounts cache during the copy whenever the process RSS is above \
+                 --adaptive-flush-fraction of --memory-budget, instead of every 250k accounts",
+            ),
+        Arg::with_name("memory_budget")
+            .long("memory-budget")
+            .value_name("BYTES")
+            .takes_value(true)
+            .help("Memory the merge process should stay within, for --adaptive-flush"),
+        Arg::with_name("adaptive_flush_fraction")
+            .long("adaptive-flush-fraction")
+            .value_name("FRACTION")
+            .takes_value(true)
+            .default_value("0.8")
+            .help("Fraction of --memory-budget above which --adaptive-flush flushes"),
+        Arg::with_name("slot_byte_limit")
+            .long("slot-byte-limit")
+            .value_name("BYTES")
+            .takes_value(true)
+            .conflicts_with("accounts_per_slot")
+            .help(
+                "Advance to a new slot once the copied accounts reach about this many \
+                 bytes [default: 4 GiB]",
+            ),
+        Arg::with_name("flush_interval_accounts")
+            .long("flush-interval-accounts")
+            .value_name("N")
+            .takes_value(true)
+            .conflicts_with("adaptive_flush")
+            .help("Flush the accounts cache every N copied accounts [default: 250000]"),
+        Arg::with_name("max_slots_for_copy")
+            .long("max-slots-for-copy")
+            .value_name("N")
+            .takes_value(true)
+            .help(
+                "Fail when the mainnet copy would span more than N slots under \
+                 --slot-byte-limit or --accounts-per-slot",
+            ),
+        Arg::with_name("accounts_per_slot")
+            .long("accounts-per-slot")
+            .value_name("N")
+            .takes_value(true)
+            .help("Advance to a new slot after every N copied accounts, regardless of bytes"),
+        Arg::with_name("verify_genesis_hash")
+            .long("verify-genesis-hash")
+            .help(
+                "Fail unless the final bank matches the merge ledger's genesis and the \
+                 written genesis.bin read back from disk",
+            ),
+        Arg::with_name("verify_no_duplicate_storage")
+            .long("verify-no-duplicate-storage")
+            .help(
+                "After snapshotting, fail if an account appears in more than one of the \
+                 storages the merge wrote",
+            ),
+        Arg::with_name("phase")
+            .long("phase")
+            .value_name("PHASE")
+            .takes_value(true)
+            .possible_values(&["extract", "apply"])
+            .conflicts_with_all(&[
+                "split_by_owner",
+                "copy_diff_only",
+                "keep_accounts_from_base_only",
+            ])
+            .help(
+                "Run half of a two-phase merge: 'extract' scans and filters mainnet and \
+                 writes the copy set to a file, 'apply' merges a copy set into the ledger \
+                 to merge without reading mainnet",
+            ),
+        Arg::with_name("copy_set_file")
+            .long("copy-set-file")
+            .value_name("FILE")
+            .takes_value(true)
+            .requires("phase")
+            .help(
+                "Copy set written by --phase extract and read by --phase apply \
+                 [default: copy-set.bin in the output directory]",
+            ),
+        Arg::with_name("checkpoint").long("checkpoint").help(
+            "Once mainnet is scanned, write its copy set and MERGE_CHECKPOINT.json to the \
+         output directory so --resume can skip the scan, and record the position of the \
+         copy in MERGE_CHECKPOINT.json as it goes; both are removed when the merge completes",
+        ),
+        Arg::with_name("resume").long("resume").help(
+            "Continue from the output directory's checkpoint, if any, instead of loading and \
+         scanning mainnet again; implies --checkpoint",
+        ),
+        Arg::with_name("output_format")
+            .long("output-format")
+            .value_name("FORMAT")
+            .takes_value(true)
+            .possible_values(&["text", "json"])
+            .default_value("text")
+            .help("Print the merge summary as text or as the JSON of --stats-file"),
+        Arg::with_name("stats_file")
+            .long("stats-file")
+            .value_name("FILE")
+            .takes_value(true)
+            .help("Also write the merge stats to FILE as JSON"),
+        Arg::with_name("dry_run").long("dry-run").help(
+            "Build the copy set and slot plan, estimate the archive size and project the \
+                 capitalization, then stop without copying or writing anything",
+        ),
+        Arg::with_name("archive_compression_ratio")
+            .long("archive-compression-ratio")
+            .value_name("RATIO")
+            .takes_value(true)
+            .default_value("3")
+            .help("Compression ratio assumed when estimating the archive size"),
+        Arg::with_name("provenance_memo")
+            .long("provenance-memo")
+            .help(
+                "Store a rent-exempt account holding JSON metadata about this merge in the \
+                 output snapshot",
+            ),
+        Arg::with_name("provenance_address")
+            .long("provenance-address")
+            .value_name("PUBKEY")
+            .takes_value(true)
+            .requires("provenance_memo")
+            .help("Store the provenance memo at this address instead of the default PDA"),
+        Arg::with_name("program_scan_fallback_threshold")
+            .long("program-scan-fallback-threshold")
+            .value_name("N")
+            .takes_value(true)
+            .help(
+                "Switch an owner's account scan from get_program_accounts to a full account \
+                 scan once it finds more than N accounts",
+            ),
+    ]
+}
 
+fn main() {
+    let started = Instant::now();
     let matches = App::new(crate_name!())
         .about(crate_description!())
         .version(solana_version::version!())
+        .setting(AppSettings::SubcommandsNegateReqs)
         .arg(
-            Arg::with_name("mainnet_ledger")
-                .long("mainnet-ledger")
-                .value_name("PATH")
+            Arg::with_name("log_format")
+                .long("log-format")
+                .value_name("FORMAT")
                 .takes_value(true)
-                .required(true)
-                .help("Path to mainnet-beta ledger directory"),
+                .global(true)
+                .possible_values(&["text", "json"])
+                .default_value("text")
+                .help("Log output format; json emits one structured record per line"),
         )
-        .arg(
-            Arg::with_name("ledger_to_merge")
-                .long("ledger-to-merge")
-                .value_name("PATH")
-                .takes_value(true)
-                .required(true)
-                .help("Path to ledger directory whose validators should be merged"),
+        .subcommand(
+            SubCommand::with_name("list-programs")
+                .about("List the programs deployed in a ledger's latest snapshot")
+                .arg(
+                    Arg::with_name("ledger")
+                        .long("ledger")
+                        .value_name("PATH")
+                        .takes_value(true)
+                        .required(true)
+                        .help("Path to the ledger directory to inspect"),
+                )
+                .arg(
+                    Arg::with_name("output")
+                        .long("output")
+                        .value_name("FORMAT")
+                        .takes_value(true)
+                        .possible_values(&["text", "json"])
+                        .default_value("text")
+                        .help("Output format"),
+                ),
         )
-        .arg(
-            Arg::with_name("output_directory")
-                .long("output-directory")
-                .short("o")
-                .value_name("PATH")
-                .takes_value(true)
-                .required(true)
-                .help("Directory where merged snapshot will be created"),
+        .subcommand(
+            SubCommand::with_name("self-test")
+                .about("Merge two synthetic ledgers end-to-end to check this environment")
+                .arg(
+                    Arg::with_name("work_dir")
+                        .long("work-dir")
+                        .value_name("DIR")
+                        .takes_value(true)
+                        .help(
+                            "Directory for the synthetic ledgers and output, kept after the \
+                             run [default: a temporary directory]",
+                        ),
+                ),
         )
-        .arg(
-            Arg::with_name("warp_slot")
-                .long("warp-slot")
-                .value_name("SLOT")
-                .takes_value(true)
-                .help("Optionally warp the merged bank to this slot"),
+        .subcommand(
+            SubCommand::with_name("preflight")
+                .about(
+                    "Check the merge options and input paths without loading any snapshot, \
+                     listing every problem found",
+                )
+                .args(&merge_args()),
         )
+        .args(&merge_args())
         .get_matches();
 
-    let mainnet_ledger = PathBuf::from(value_t_or_exit!(matches, "mainnet_ledger", String));
-    let ledger_to_merge = PathBuf::from(value_t_or_exit!(matches, "ledger_to_merge", String));
-    let output_directory = PathBuf::from(value_t_or_exit!(matches, "output_directory", String));
-    let warp_slot = value_t!(matches, "warp_slot", Slot).ok();
+    logging::init(value_t_or_exit!(matches, "log_format", logging::LogFormat));
+
+    match matches.subcommand() {
+        ("list-programs", Some(list_matches)) => {
+            run_list_programs(list_matches);
+            return;
+        }
+        ("self-test", Some(self_test_matches)) => {
+            run_self_test(self_test_matches);
+            return;
+        }
+        ("preflight", Some(preflight_matches)) => {
+            run_preflight(preflight_matches, started);
+            return;
+        }
+        _ => {}
+    }
+
+    let mut problems = Problems::default();
+    let (config, groups) = merge_config(&matches, started, false, &mut problems);
+    if !problems.0.is_empty() {
+        for problem in &problems.0 {
+            eprintln!("❌ Error: {}", problem);
+        }
+        exit(1);
+    }
+
+    if !groups.is_empty() {
+        match merge_snapshots_split_by_owner(&config, &groups) {
+            Ok(all_stats) => {
+                let groups_json: Vec<_> = groups
+                    .iter()
+                    .zip(&all_stats)
+                    .map(|(group, stats)| {
+                        serde_json::json!({ "group": group.name, "stats": stats_json(stats) })
+                    })
+                    .collect();
+                if emit_stats_json(&matches, &serde_json::json!(groups_json)) {
+                    all_stats.iter().for_each(exit_on_upload_error);
+                    return;
+                }
+                println!("\n✅ Split merge completed successfully!");
+                for (group, stats) in groups.iter().zip(&all_stats) {
+                    println!(
+                        "  • {}: copied {} mainnet accounts -> {}",
+                        group.name, stats.mainnet_accounts_copied, stats.snapshot_path
+                    );
+                }
+                println!(
+                    "\nManifest: {}",
+                    config.output_directory.join(SPLIT_MANIFEST_FILE).display()
+                );
+                all_stats.iter().for_each(exit_on_upload_error);
+            }
+            Err(e) => {
+                eprintln!("❌ Error: {}", e);
+                exit(1);
+            }
+        }
+        return;
+    }
 
-    match merge_snapshots(
-        &mainnet_ledger,
-        &ledger_to_merge,
-        &output_directory,
-        warp_slot,
-    ) {
+    let result = merge_snapshots(&config, None);
+    if let Ok(stats) = &result {
+        if emit_stats_json(&matches, &stats_json(stats)) {
+            exit_on_upload_error(stats);
+            return;
+        }
+    }
+    match result {
+        Ok(stats) if config.phase == functions::MergePhase::Extract => {
+            println!("\n✅ Extract phase completed");
+            println!(
+                "  • Wrote {} mainnet accounts to {} (excluded {} vote and {} stake accounts)",
+                stats.mainnet_accounts_copied,
+                config.copy_set_path().display(),
+                stats.mainnet_vote_accounts_excluded,
+                stats.mainnet_stake_accounts_excluded
+            );
+            println!("  • Run again with --phase apply to produce the merged snapshot");
+        }
+        Ok(stats) if config.dry_run => {
+            println!("\n✅ Dry run completed, nothing was copied or written");
+            println!(
+                "  • Would copy {} mainnet accounts into {} slots and preserve {} system accounts",
+                stats.mainnet_accounts_copied,
+                stats.slot_distribution.slots,
+                stats.merge_system_accounts_preserved
+            );
+            if let Some(conflicts) = &stats.base_conflicts {
+                println!(
+                    "  • {} of them are already in the merge ledger, {} would keep the base account",
+                    conflicts.conflicts.len(),
+                    conflicts.kept_base
+                );
+            }
+            if stats.invalid_accounts_skipped > 0 {
+                println!(
+                    "  • Would skip {} accounts that failed validation",
+                    stats.invalid_accounts_skipped
+                );
+            }
+            println!(
+                "  • Capitalization would change from {} to {} ({:+}, projected)",
+                stats.capitalization_before,
+                stats.capitalization_after,
+                stats.capitalization_after as i128 - stats.capitalization_before as i128
+            );
+            println!(
+                "  • estimated_archive_bytes: {} (~{:.2} GiB at {}x compression, an estimate)",
+                stats.estimated_archive_bytes,
+                stats.estimated_archive_bytes as f64 / (1u64 << 30) as f64,
+                config.archive_compression_ratio
+            );
+        }
         Ok(stats) => {
             println!("\n✅ Snapshot merge completed successfully!");
             println!("\nSummary:");
@@ -488,24 +1770,331 @@ fn main() {
                 "  • Excluded {} vote accounts and {} stake accounts from mainnet",
                 stats.mainnet_vote_accounts_excluded, stats.mainnet_stake_accounts_excluded
             );
+            if let Some(regenerated) = &stats.epoch_stakes_regenerated {
+                println!(
+                    "  • Regenerated the epoch stakes of epochs {:?} from {} vote accounts",
+                    regenerated.epochs, regenerated.vote_accounts
+                );
+            }
+            if let Some(removed) = &stats.merge_validators_removed {
+                println!(
+                    "  • Removed the merge ledger's {} vote and {} stake accounts",
+                    removed.vote_accounts, removed.stake_accounts
+                );
+            }
+            if let Some(features) = &stats.mainnet_features {
+                println!(
+                    "  • Copied {} mainnet feature accounts ({} activated, {} unknown and inert)",
+                    features.feature_accounts,
+                    features.activated,
+                    features.unknown.len()
+                );
+            }
+            if stats.merge_feature_accounts_removed > 0 {
+                println!(
+                    "  • Closed the merge ledger's {} feature accounts",
+                    stats.merge_feature_accounts_removed
+                );
+            }
             println!(
                 "  • Copied {} mainnet accounts to merge ledger",
                 stats.mainnet_accounts_copied
             );
+            if stats.mainnet_accounts_field_merged > 0 {
+                println!(
+                    "  • Merged {} colliding accounts field by field",
+                    stats.mainnet_accounts_field_merged
+                );
+            }
+            if let Some(conflicts) = &stats.base_conflicts {
+                println!(
+                    "  • Resolved {} accounts already in the merge ledger, {} kept from the base",
+                    conflicts.conflicts.len(),
+                    conflicts.kept_base
+                );
+            }
+            for cap in &stats.owner_data_caps {
+                println!(
+                    "  • Owner {}: copied {} of {} data bytes, skipped {} accounts over the cap",
+                    cap.owner,
+                    cap.bytes_copied,
+                    cap.bytes_copied + cap.bytes_skipped,
+                    cap.accounts_skipped
+                );
+            }
+            for remap in &stats.owner_remaps {
+                println!(
+                    "  • Remapped owner {} -> {} on {} accounts",
+                    remap.from, remap.to, remap.accounts_remapped
+                );
+            }
+            if stats.mainnet_nonce_accounts > 0 {
+                println!(
+                    "  • Found {} durable nonce accounts ({:?})",
+                    stats.mainnet_nonce_accounts, config.nonce_policy
+                );
+            }
+            if !stats.upgrade_authorities_overridden.is_empty() {
+                println!(
+                    "  • Overrode the upgrade authority of {} programs",
+                    stats.upgrade_authorities_overridden.len()
+                );
+            }
+            let overrides = &stats.account_overrides;
+            if !overrides.replaced.is_empty() || !overrides.created.is_empty() {
+                println!(
+                    "  • Overrode {} accounts, {} of them new",
+                    overrides.replaced.len() + overrides.created.len(),
+                    overrides.created.len()
+                );
+            }
+            if !stats.unpaired_programs.is_empty() {
+                println!(
+                    "  • Left out {} upgradeable programs and {} ProgramData accounts without \
+                     their other half",
+                    stats.unpaired_programs.programs.len(),
+                    stats.unpaired_programs.program_data.len()
+                );
+            }
+            if !stats.sysvars.rewritten.is_empty() {
+                println!(
+                    "  • Kept the merged bank's own {} sysvars instead of mainnet's",
+                    stats.sysvars.rewritten.len()
+                );
+            }
+            if !stats.sysvars.kept.is_empty() {
+                println!(
+                    "  • Kept {} sysvars copied from mainnet",
+                    stats.sysvars.kept.len()
+                );
+            }
+            if let Some(intersection) = stats.base_intersection_accounts {
+                println!(
+                    "  • Refreshed {} accounts present in the merge ledger, skipped {} without one",
+                    intersection, stats.accounts_outside_base_skipped
+                );
+            }
+            if stats.accounts_skipped_identical > 0 {
+                println!(
+                    "  • Skipped {} accounts identical to the merge ledger",
+                    stats.accounts_skipped_identical
+                );
+            }
+            if stats.mainnet_lookup_tables_skipped > 0 {
+                println!(
+                    "  • Skipped {} address lookup tables",
+                    stats.mainnet_lookup_tables_skipped
+                );
+            }
+            if stats.mainnet_lookup_tables_refreshed > 0 {
+                println!(
+                    "  • Refreshed {} address lookup tables",
+                    stats.mainnet_lookup_tables_refreshed
+                );
+            }
+            if stats.mainnet_lookup_tables_malformed > 0 {
+                println!(
+                    "  • Copied {} malformed address lookup tables as they are",
+                    stats.mainnet_lookup_tables_malformed
+                );
+            }
+            if config.subtract_ledger.is_some() {
+                println!(
+                    "  • Subtracted {} accounts held by the subtract ledger",
+                    stats.accounts_subtracted
+                );
+            }
+            if stats.duplicate_pubkeys_resolved > 0 {
+                println!(
+                    "  • Kept the newest version of {} duplicate pubkeys",
+                    stats.duplicate_pubkeys_resolved
+                );
+            }
+            let size_filtered = stats.zero_lamport_accounts_skipped
+                + stats.dust_accounts_excluded
+                + stats.oversized_accounts_excluded;
+            if size_filtered > 0 {
+                println!(
+                    "  • Left out {} zero-lamport, {} dust and {} oversized accounts",
+                    stats.zero_lamport_accounts_skipped,
+                    stats.dust_accounts_excluded,
+                    stats.oversized_accounts_excluded
+                );
+            }
+            if let Some(tokens) = &stats.tokens {
+                println!(
+                    "  • Left out {} token accounts ({} below the minimum balance), copied {} \
+                     holding {} mints",
+                    tokens.accounts_excluded(),
+                    tokens.excluded_below_min_balance,
+                    tokens.token_accounts_copied,
+                    tokens.mints_held
+                );
+            }
+            if stats.accounts_skipped_dormant > 0 {
+                println!(
+                    "  • Skipped {} accounts whose rent_epoch was too old",
+                    stats.accounts_skipped_dormant
+                );
+            }
+            if stats.rent_epochs_normalized > 0 {
+                println!(
+                    "  • Normalized the rent_epoch of {} copied accounts",
+                    stats.rent_epochs_normalized
+                );
+            }
+            if let Some(pruned) = &stats.stakes_pruned {
+                println!(
+                    "  • Pruned {} deactivated merge ledger stake accounts ({} lamports)",
+                    pruned.pruned, pruned.lamports_reclaimed
+                );
+            }
+            if stats.token_balances_reconstructed > 0 {
+                println!(
+                    "  • Reconstructed {} token balances",
+                    stats.token_balances_reconstructed
+                );
+            }
+            if stats.invalid_accounts_skipped > 0 {
+                println!(
+                    "  • Skipped {} invalid mainnet accounts",
+                    stats.invalid_accounts_skipped
+                );
+            }
             println!(
                 "  • Preserved {} system accounts from merge ledger (validator identities, etc.)",
                 stats.merge_system_accounts_preserved
             );
             println!(
-                "  • Final snapshot has {} accounts",
-                stats.final_total_accounts
+                "  • Final snapshot has {} vote and {} stake accounts, {} and {} from mainnet",
+                stats.validator_audit.vote.total(),
+                stats.validator_audit.stake.total(),
+                stats.validator_audit.vote.from_mainnet,
+                stats.validator_audit.stake.from_mainnet
+            );
+            println!(
+                "  • Copied mainnet accounts into {} slots ({} to {} accounts per slot)",
+                stats.slot_distribution.slots,
+                stats.slot_distribution.min_accounts,
+                stats.slot_distribution.max_accounts
+            );
+            println!(
+                "  • Final snapshot has {} accounts ({} bytes of account data)",
+                stats.final_total_accounts, stats.accounts_data_size
             );
             println!(
                 "  • Capitalization: {} -> {} lamports",
                 stats.capitalization_before, stats.capitalization_after
             );
+            if let (Some(before), Some(after)) = (
+                stats.capitalization_before_warp,
+                stats.capitalization_after_warp,
+            ) {
+                println!(
+                    "  • Capitalization across warp: {} -> {} lamports",
+                    before, after
+                );
+            }
+            if let Some(adjustment) = &stats.treasury_adjustment {
+                println!(
+                    "  • Adjusted treasury {} by {:+} lamports",
+                    adjustment.treasury, adjustment.delta
+                );
+            }
+            println!(
+                "  • Capitalization check delta: {:+} lamports",
+                stats.capitalization_check_delta
+            );
+            if !stats.top_accounts.is_empty() {
+                println!("\nLargest copied accounts:");
+                for account in &stats.top_accounts {
+                    println!(
+                        "  • {} – {} lamports (owner {})",
+                        account.pubkey, account.lamports, account.owner
+                    );
+                }
+            }
+            if let Some(diff) = &stats.base_diff {
+                println!(
+                    "  • Against the merge ledger: {} accounts added, {} modified, {:+} lamports",
+                    diff.added, diff.modified, diff.lamports_delta
+                );
+            }
+            if let Some(summary) = &stats.validators_summary {
+                println!("\nValidator set:");
+                print!("{}", render_validators_table(summary));
+            }
+            println!(
+                "  • Loaded both input banks in {:.1}s{}",
+                stats.load_time.as_secs_f64(),
+                if config.parallel_load {
+                    " (in parallel)"
+                } else {
+                    ""
+                }
+            );
             println!("\nSnapshot archive created: {}", stats.snapshot_path);
-            println!("Result: Merge ledger validators + mainnet state (excluding mainnet validators) + merge ledger system accounts");
+            if let Some(alias) = &stats.archive_alias {
+                println!("  • Linked as {}", alias);
+            }
+            if let Some(upload) = &stats.upload {
+                println!("  • Uploaded to {}", upload.archive_url);
+                println!("  • Genesis uploaded to {}", upload.genesis_url);
+                println!("  • Manifest uploaded to {}", upload.manifest_url);
+                if let Some(incremental_url) = &upload.incremental_url {
+                    println!("  • Incremental archive uploaded to {}", incremental_url);
+                }
+            }
+            println!(
+                "  • {} bytes, {:.2}x compression of {} bytes of account data, {:.1} MB/s",
+                stats.compression.archive_bytes,
+                stats.compression.ratio,
+                stats.compression.uncompressed_bytes,
+                stats.compression.throughput_mb_per_sec
+            );
+            println!("Genesis hash: {}", stats.genesis_hash);
+            println!("Bank hash: {}", stats.bank_hash);
+            println!("Bank snapshot hash: {}", stats.bank_snapshot_hash);
+            println!(
+                "Manifest: {}",
+                config
+                    .output_directory
+                    .join(SNAPSHOT_MANIFEST_FILE)
+                    .display()
+            );
+            match &stats.incremental_snapshot {
+                Some(incremental) if incremental.path == stats.snapshot_path => println!(
+                    "Incremental snapshot hash: {} (on top of slot {})",
+                    incremental.hash, incremental.base_slot
+                ),
+                Some(incremental) => {
+                    println!("Full snapshot hash: {}", stats.full_snapshot_hash);
+                    println!(
+                        "Incremental snapshot: {} (hash {}, on top of slot {})",
+                        incremental.path, incremental.hash, incremental.base_slot
+                    );
+                }
+                None => println!("Full snapshot hash: {}", stats.full_snapshot_hash),
+            }
+            if let Some(check) = &stats.storage_check {
+                println!(
+                    "Storage check: {} account entries in {} storages, no duplicates besides {} \
+                     of accounts the merge stores again",
+                    check.entries, check.storages, check.restored_entries
+                );
+            }
+            let validators = match config.validator_source {
+                functions::ValidatorSource::MergeLedger => {
+                    "Merge ledger validators + mainnet state (excluding mainnet validators)"
+                }
+                functions::ValidatorSource::Mainnet => "Mainnet state and validators",
+                functions::ValidatorSource::Both => {
+                    "Merge ledger validators + mainnet state and validators"
+                }
+                functions::ValidatorSource::None => "Mainnet state (without any validators)",
+            };
+            println!("Result: {} + merge ledger system accounts", validators);
+            exit_on_upload_error(&stats);
         }
         Err(e) => {
             eprintln!("❌ Error: {}", e);
@@ -513,3 +2102,15 @@ fn main() {
         }
     }
 }
+
+/// Exits with status 2 if the merged snapshot was written but its upload
+/// failed, so scripts can tell the two failures apart
+fn exit_on_upload_error(stats: &MergeStats) {
+    if let Some(e) = &stats.upload_error {
+        eprintln!(
+            "❌ Error: the merged snapshot is in {} but its upload failed: {}",
+            stats.snapshot_path, e
+        );
+        exit(2);
+    }
+}
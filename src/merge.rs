@@ -1,26 +1,302 @@
 // Snapshot merging functionality
 pub mod functions {
+    pub use crate::error::MergeError;
+    use base64::{prelude::BASE64_STANDARD, Engine};
+    use serde::Serialize;
     use solana_account::{AccountSharedData, ReadableAccount, WritableAccount};
+    use solana_accounts_db::accounts_db::AccountsDbConfig;
+    use solana_accounts_db::accounts_index::AccountsIndexConfig;
+    use solana_accounts_db::accounts_index::{ScanConfig, ScanResult};
+    use solana_address_lookup_table_interface::state::AddressLookupTable;
+    use solana_clock::{Epoch, Slot, MAX_RECENT_BLOCKHASHES};
+    use solana_epoch_rewards::EpochRewards;
+    use solana_genesis_config::{GenesisConfig, DEFAULT_GENESIS_ARCHIVE, DEFAULT_GENESIS_FILE};
+    use solana_loader_v3_interface::state::UpgradeableLoaderState;
+    use solana_nonce::{
+        state::{Data as NonceData, DurableNonce, State as NonceState},
+        versions::Versions as NonceVersions,
+    };
     use solana_pubkey::Pubkey;
-    use solana_runtime::bank::Bank;
+    use solana_runtime::{
+        bank::Bank,
+        epoch_stakes::VersionedEpochStakes,
+        installed_scheduler_pool::BankWithScheduler,
+        runtime_config::RuntimeConfig,
+        snapshot_archive_info::{FullSnapshotArchiveInfo, SnapshotArchiveInfoGetter},
+        snapshot_bank_utils,
+        snapshot_hash::SnapshotHash,
+        snapshot_utils::{ArchiveFormat, SnapshotError, ZstdConfig, TMP_SNAPSHOT_ARCHIVE_PREFIX},
+    };
+    use solana_sdk::hash::{hashv, Hash};
+    use solana_sdk::rent::Rent;
+    use solana_sdk::slot_hashes::SlotHashes;
+    use solana_sdk_ids::{
+        address_lookup_table, bpf_loader, bpf_loader_deprecated, bpf_loader_upgradeable, feature,
+        loader_v4, native_loader, system_program, sysvar,
+    };
+    use solana_stake_interface::{
+        stake_history::StakeHistory,
+        state::{Delegation, StakeStateV2},
+    };
     use solana_stake_program;
     use solana_vote_program;
-    use std::collections::HashMap;
+    use solana_vote_program::vote_state::VoteStateV3;
+    use std::cmp::Reverse;
+    use std::collections::{hash_map::Entry, BTreeMap, BinaryHeap, HashMap, HashSet};
+    use std::path::{Path, PathBuf};
     use std::str::FromStr;
+    use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
     use std::sync::Arc;
+    use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+
+    pub const DEFAULT_FLUSH_INTERVAL_ACCOUNTS: usize = 250_000;
+    /// How often the adaptive flush samples the process RSS
+    const RSS_CHECK_INTERVAL_ACCOUNTS: usize = 10_000;
+    /// How often the account copy checks whether a throughput sample is due
+    const THROUGHPUT_CHECK_INTERVAL_ACCOUNTS: usize = 1_000;
+    const ACCOUNT_STORAGE_OVERHEAD: u64 = 512;
+    /// How often a [`ProgressBar`] redraws on a terminal, and how often it
+    /// prints a new line when stderr is redirected
+    const PROGRESS_BAR_REDRAW_INTERVAL: Duration = Duration::from_millis(250);
+    const PROGRESS_BAR_LINE_INTERVAL: Duration = Duration::from_secs(10);
+    const PROGRESS_BAR_WIDTH: usize = 30;
+    /// How many updates a [`ProgressBar`] takes between clock reads
+    const PROGRESS_BAR_CHECK_INTERVAL: usize = 1_000;
+    /// Duplicate storage entries named in a failed storage check
+    const MAX_REPORTED_DUPLICATES: usize = 5;
+    /// Invalid accounts named in a failed strict validation
+    const MAX_REPORTED_INVALID_ACCOUNTS: usize = 5;
+    /// The runtime's `MAX_PERMITTED_DATA_LENGTH`
+    pub const MAX_ACCOUNT_DATA_LEN: usize = 10 * 1024 * 1024;
+    /// 4 GiB safety margin below the AppendVec cap
+    pub const DEFAULT_SLOT_BYTE_LIMIT: u64 = 4 * 1024 * 1024 * 1024;
+
+    /// When the account copy closes a slot and advances to the next one
+    #[derive(Debug, Clone, Copy, PartialEq, Eq)]
+    pub enum SlotSplit {
+        /// Once the approximate stored bytes reach the limit
+        Bytes(u64),
+        /// Once the slot holds this many accounts
+        Accounts(usize),
+    }
+
+    impl Default for SlotSplit {
+        fn default() -> Self {
+            Self::Bytes(DEFAULT_SLOT_BYTE_LIMIT)
+        }
+    }
+
+    impl SlotSplit {
+        /// Whether a slot holding `accounts` accounts of about `bytes` bytes is
+        /// full; the account that fills it stays in it
+        pub fn is_full(&self, accounts: usize, bytes: u64) -> bool {
+            match self {
+                Self::Bytes(limit) => bytes >= *limit,
+                Self::Accounts(limit) => accounts >= *limit,
+            }
+        }
+    }
+
+    /// Bytes `account` takes in an account storage, data plus overhead
+    pub fn approx_stored_bytes(account: &AccountSharedData) -> u64 {
+        account.data().len() as u64 + ACCOUNT_STORAGE_OVERHEAD
+    }
+
+    /// Number of accounts each slot receives when `accounts` are stored in the
+    /// given order. `add_accounts` stores in map order, or in pubkey order
+    /// with [`CopyOrder::ByPubkey`], and `add_accounts_sharded` always in
+    /// pubkey order, so callers pass the accounts in the order of the copy
+    /// they plan.
+    pub fn plan_slot_sizes<'a>(
+        accounts: impl IntoIterator<Item = &'a AccountSharedData>,
+        slot_split: SlotSplit,
+    ) -> Vec<usize> {
+        let mut sizes = Vec::new();
+        let mut accounts_in_slot = 0;
+        let mut bytes_in_slot = 0;
+        for account in accounts {
+            accounts_in_slot += 1;
+            bytes_in_slot += approx_stored_bytes(account);
+            if slot_split.is_full(accounts_in_slot, bytes_in_slot) {
+                sizes.push(accounts_in_slot);
+                accounts_in_slot = 0;
+                bytes_in_slot = 0;
+            }
+        }
+        if accounts_in_slot > 0 {
+            sizes.push(accounts_in_slot);
+        }
+        sizes
+    }
+
+    /// Rough zstd compression ratio of account storages in a snapshot archive
+    pub const DEFAULT_ARCHIVE_COMPRESSION_RATIO: f64 = 3.0;
+
+    /// Projects the archive size of a snapshot holding `accounts`: their data
+    /// plus per-account storage overhead, divided by `compression_ratio`. Only
+    /// an estimate; accounts full of zeroes compress far better and random
+    /// data such as keys and hashes far worse.
+    pub fn estimate_archive_bytes<'a>(
+        accounts: impl IntoIterator<Item = &'a AccountSharedData>,
+        compression_ratio: f64,
+    ) -> u64 {
+        let stored_bytes: u64 = accounts.into_iter().map(approx_stored_bytes).sum();
+        (stored_bytes as f64 / compression_ratio) as u64
+    }
+
+    /// How well the written archive compressed the bank's account data
+    #[derive(Debug, Clone, Copy, Default, PartialEq, Serialize)]
+    pub struct CompressionReport {
+        /// The bank's accounts data size
+        pub uncompressed_bytes: u64,
+        pub archive_bytes: u64,
+        /// Uncompressed over archive bytes
+        pub ratio: f64,
+        /// Time spent writing the archive
+        pub archive_secs: f64,
+        /// Uncompressed megabytes (10^6 bytes) archived per second
+        pub throughput_mb_per_sec: f64,
+    }
+
+    pub fn compression_report(
+        uncompressed_bytes: u64,
+        archive_bytes: u64,
+        archive_time: Duration,
+    ) -> CompressionReport {
+        let archive_secs = archive_time.as_secs_f64();
+        CompressionReport {
+            uncompressed_bytes,
+            archive_bytes,
+            ratio: if archive_bytes == 0 {
+                0.0
+            } else {
+                uncompressed_bytes as f64 / archive_bytes as f64
+            },
+            archive_secs,
+            throughput_mb_per_sec: if archive_secs == 0.0 {
+                0.0
+            } else {
+                uncompressed_bytes as f64 / 1e6 / archive_secs
+            },
+        }
+    }
+
+    /// Summary of how many accounts went into each slot of a copy
+    #[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize)]
+    pub struct SlotDistribution {
+        pub slots: usize,
+        pub min_accounts: usize,
+        pub max_accounts: usize,
+        pub total_accounts: usize,
+    }
+
+    impl SlotDistribution {
+        pub fn from_sizes(sizes: &[usize]) -> Self {
+            Self {
+                slots: sizes.len(),
+                min_accounts: sizes.iter().copied().min().unwrap_or(0),
+                max_accounts: sizes.iter().copied().max().unwrap_or(0),
+                total_accounts: sizes.iter().sum(),
+            }
+        }
+
+        /// Errors if the copy spans more than `max_slots` slots under
+        /// `slot_split`
+        pub fn check_max_slots(
+            &self,
+            max_slots: usize,
+            slot_split: SlotSplit,
+        ) -> Result<(), MergeError> {
+            if self.slots > max_slots {
+                return Err(MergeError::InvalidInput(format!(
+                    "Copying {} accounts under {:?} takes {} slots, more than the maximum of {}; \
+                     raise --slot-byte-limit or --accounts-per-slot",
+                    self.total_accounts, slot_split, self.slots, max_slots
+                )));
+            }
+            Ok(())
+        }
+    }
+
+    /// Loads the loadable accounts owned by `owner`, starting with
+    /// `get_program_accounts`. Once that scan has found more than
+    /// `fallback_threshold` accounts it is aborted and the owner's accounts are
+    /// collected by `scan_accounts_by_owner` instead.
+    ///
+    /// Without a secondary index for the owner, `get_program_accounts` walks
+    /// the whole accounts index and loads each account just to compare its
+    /// owner, which on a mainnet-sized bank is slow for owners with many
+    /// accounts and can run into scan limits. The full scan streams every
+    /// account once instead, which tends to be faster for such owners but is
+    /// never cheap: an aborted program scan's work is thrown away, so a
+    /// threshold set too low pays for a partial program scan plus a full one.
+    /// `None` never falls back.
+    pub fn program_accounts(
+        bank: &Bank,
+        owner: &Pubkey,
+        fallback_threshold: Option<usize>,
+    ) -> ScanResult<Vec<(Pubkey, AccountSharedData)>> {
+        let Some(threshold) = fallback_threshold else {
+            return bank.get_program_accounts(owner, &ScanConfig::default());
+        };
+        let abort = Arc::new(AtomicBool::new(false));
+        let config = ScanConfig {
+            abort: Some(abort.clone()),
+            ..ScanConfig::default()
+        };
+        let found = AtomicUsize::new(0);
+        let accounts = bank.get_filtered_program_accounts(
+            owner,
+            |_| {
+                if found.fetch_add(1, Ordering::Relaxed) >= threshold {
+                    abort.store(true, Ordering::Relaxed);
+                }
+                true
+            },
+            &config,
+        )?;
+        if !abort.load(Ordering::Relaxed) {
+            return Ok(accounts);
+        }
+        log::info!(
+            "Program scan for {} exceeded {} accounts, falling back to a full account scan",
+            owner,
+            threshold
+        );
+        scan_accounts_by_owner(bank, owner)
+    }
+
+    /// Loads the loadable accounts owned by `owner` with one
+    /// `scan_all_accounts` pass over the whole bank
+    pub fn scan_accounts_by_owner(
+        bank: &Bank,
+        owner: &Pubkey,
+    ) -> ScanResult<Vec<(Pubkey, AccountSharedData)>> {
+        let mut accounts = Vec::new();
+        bank.scan_all_accounts(
+            |item| {
+                if let Some((pubkey, account, _slot)) = item {
+                    if account.lamports() != 0 && account.owner() == owner {
+                        accounts.push((*pubkey, account));
+                    }
+                }
+            },
+            false,
+        )?;
+        Ok(accounts)
+    }
 
     pub fn extract_vote_accounts(
         bank: &Bank,
-    ) -> Result<HashMap<Pubkey, AccountSharedData>, String> {
+        fallback_threshold: Option<usize>,
+    ) -> Result<HashMap<Pubkey, AccountSharedData>, MergeError> {
         log::info!("Extracting vote accounts...");
         let vote_program_id = solana_vote_program::id();
 
-        let accounts = bank
-            .get_program_accounts(
-                &vote_program_id,
-                &solana_accounts_db::accounts_index::ScanConfig::default(),
-            )
-            .map_err(|e| format!("Failed to get vote accounts: {:?}", e))?;
+        let accounts =
+            program_accounts(bank, &vote_program_id, fallback_threshold).map_err(|e| {
+                MergeError::AccountScan(format!("Failed to get vote accounts: {:?}", e))
+            })?;
 
         log::info!("Found {} vote accounts", accounts.len());
         Ok(accounts.into_iter().collect())
@@ -28,16 +304,15 @@ pub mod functions {
 
     pub fn extract_stake_accounts(
         bank: &Bank,
-    ) -> Result<HashMap<Pubkey, AccountSharedData>, String> {
+        fallback_threshold: Option<usize>,
+    ) -> Result<HashMap<Pubkey, AccountSharedData>, MergeError> {
         log::info!("Extracting stake accounts...");
         let stake_program_id = solana_stake_program::id();
 
-        let accounts = bank
-            .get_program_accounts(
-                &stake_program_id,
-                &solana_accounts_db::accounts_index::ScanConfig::default(),
-            )
-            .map_err(|e| format!("Failed to get stake accounts: {:?}", e))?;
+        let accounts =
+            program_accounts(bank, &stake_program_id, fallback_threshold).map_err(|e| {
+                MergeError::AccountScan(format!("Failed to get stake accounts: {:?}", e))
+            })?;
 
         log::info!("Found {} stake accounts", accounts.len());
         Ok(accounts.into_iter().collect())
@@ -45,142 +320,5554 @@ pub mod functions {
 
     pub fn extract_system_accounts(
         bank: &Bank,
-    ) -> Result<HashMap<Pubkey, AccountSharedData>, String> {
+        fallback_threshold: Option<usize>,
+    ) -> Result<HashMap<Pubkey, AccountSharedData>, MergeError> {
         log::info!("Extracting system accounts (owned by System Program)...");
         // System Program ID: 11111111111111111111111111111111
-        let system_program_id = Pubkey::from_str("11111111111111111111111111111111")
-            .map_err(|e| format!("Failed to parse system program ID: {:?}", e))?;
+        let system_program_id =
+            Pubkey::from_str("11111111111111111111111111111111").map_err(|e| {
+                MergeError::AccountScan(format!("Failed to parse system program ID: {:?}", e))
+            })?;
 
-        let accounts = bank
-            .get_program_accounts(
-                &system_program_id,
-                &solana_accounts_db::accounts_index::ScanConfig::default(),
-            )
-            .map_err(|e| format!("Failed to get system accounts: {:?}", e))?;
+        let accounts =
+            program_accounts(bank, &system_program_id, fallback_threshold).map_err(|e| {
+                MergeError::AccountScan(format!("Failed to get system accounts: {:?}", e))
+            })?;
 
         log::info!("Found {} system accounts", accounts.len());
         Ok(accounts.into_iter().collect())
     }
 
-    pub fn remove_vote_accounts(bank: &Bank) -> Result<usize, String> {
+    /// Extracts the feature-gate accounts (owned by the Feature program)
+    pub fn extract_feature_accounts(
+        bank: &Bank,
+        fallback_threshold: Option<usize>,
+    ) -> Result<HashMap<Pubkey, AccountSharedData>, MergeError> {
+        log::info!("Extracting feature accounts...");
+        let accounts = program_accounts(bank, &feature::id(), fallback_threshold).map_err(|e| {
+            MergeError::AccountScan(format!("Failed to get feature accounts: {:?}", e))
+        })?;
+
+        log::info!("Found {} feature accounts", accounts.len());
+        Ok(accounts.into_iter().collect())
+    }
+
+    /// Extracts all accounts owned by `owner`
+    pub fn extract_accounts_by_owner(
+        bank: &Bank,
+        owner: &Pubkey,
+        fallback_threshold: Option<usize>,
+    ) -> Result<HashMap<Pubkey, AccountSharedData>, MergeError> {
+        let accounts = program_accounts(bank, owner, fallback_threshold).map_err(|e| {
+            MergeError::AccountScan(format!(
+                "Failed to get accounts owned by {}: {:?}",
+                owner, e
+            ))
+        })?;
+
+        log::debug!("Found {} accounts owned by {}", accounts.len(), owner);
+        Ok(accounts.into_iter().collect())
+    }
+
+    pub fn remove_vote_accounts(
+        bank: &Bank,
+        fallback_threshold: Option<usize>,
+    ) -> Result<usize, MergeError> {
         log::info!("Removing vote accounts from mainnet bank...");
         let vote_program_id = solana_vote_program::id();
 
-        let accounts = bank
-            .get_program_accounts(
-                &vote_program_id,
-                &solana_accounts_db::accounts_index::ScanConfig::default(),
-            )
-            .map_err(|e| format!("Failed to get vote accounts: {:?}", e))?;
+        let accounts =
+            program_accounts(bank, &vote_program_id, fallback_threshold).map_err(|e| {
+                MergeError::AccountScan(format!("Failed to get vote accounts: {:?}", e))
+            })?;
 
         let count = accounts.len();
         for (pubkey, mut account) in accounts {
+            let old_data_size = account.data().len();
             account.set_lamports(0);
-            bank.store_account(&pubkey, &account);
+            store_account_replacing(bank, &pubkey, &account, old_data_size);
         }
 
         log::info!("Removed {} vote accounts", count);
         Ok(count)
     }
 
-    pub fn remove_stake_accounts(bank: &Bank) -> Result<usize, String> {
+    pub fn remove_stake_accounts(
+        bank: &Bank,
+        fallback_threshold: Option<usize>,
+    ) -> Result<usize, MergeError> {
         log::info!("Removing stake accounts from mainnet bank...");
         let stake_program_id = solana_stake_program::id();
 
-        let accounts = bank
-            .get_program_accounts(
-                &stake_program_id,
-                &solana_accounts_db::accounts_index::ScanConfig::default(),
-            )
-            .map_err(|e| format!("Failed to get stake accounts: {:?}", e))?;
+        let accounts =
+            program_accounts(bank, &stake_program_id, fallback_threshold).map_err(|e| {
+                MergeError::AccountScan(format!("Failed to get stake accounts: {:?}", e))
+            })?;
 
         let count = accounts.len();
         for (pubkey, mut account) in accounts {
+            let old_data_size = account.data().len();
             account.set_lamports(0);
-            bank.store_account(&pubkey, &account);
+            store_account_replacing(bank, &pubkey, &account, old_data_size);
         }
 
         log::info!("Removed {} stake accounts", count);
         Ok(count)
     }
 
-    pub fn add_accounts(
-        starting_bank: Arc<Bank>,
-        accounts: &HashMap<Pubkey, AccountSharedData>,
-        account_type: &str,
-        slot_byte_limit: u64,
-    ) -> Result<Arc<Bank>, String> {
-        log::info!(
-            "Adding {} {} accounts to merged bank...",
-            accounts.len(),
-            account_type
-        );
+    /// Overall progress of a copy that may span several slots
+    #[derive(Debug, Clone)]
+    pub struct CopyProgress {
+        total: usize,
+        copied: usize,
+        started: Instant,
+    }
 
-        const FLUSH_INTERVAL_ACCOUNTS: usize = 250_000;
-        let mut current_bank = starting_bank;
-        let mut count_since_flush = 0usize;
-        let mut bytes_in_current_slot: u64 = 0;
+    impl CopyProgress {
+        pub fn new(total: usize) -> Self {
+            Self {
+                total,
+                copied: 0,
+                started: Instant::now(),
+            }
+        }
 
-        const ACCOUNT_STORAGE_OVERHEAD: u64 = 512;
+        pub fn add(&mut self, accounts: usize) {
+            self.copied += accounts;
+        }
 
-        for (pubkey, account) in accounts {
-            current_bank.store_account(pubkey, account);
-            count_since_flush += 1;
-            let approx_bytes = account.data().len() as u64 + ACCOUNT_STORAGE_OVERHEAD;
-            bytes_in_current_slot += approx_bytes;
+        pub fn copied(&self) -> usize {
+            self.copied
+        }
 
-            if count_since_flush % FLUSH_INTERVAL_ACCOUNTS == 0 {
-                log::info!(
-                    "Progress: {} {} accounts added in slot {} ({} bytes)",
-                    count_since_flush,
-                    account_type,
-                    current_bank.slot(),
-                    bytes_in_current_slot
-                );
-                current_bank.force_flush_accounts_cache();
-            }
+        pub fn total(&self) -> usize {
+            self.total
+        }
 
-            if bytes_in_current_slot >= slot_byte_limit {
-                log::info!(
-                    "Reached byte limit ({}) for slot {}, squashing and advancing to next slot",
-                    bytes_in_current_slot,
-                    current_bank.slot()
-                );
-                current_bank.force_flush_accounts_cache();
-                current_bank.squash();
+        pub fn percent(&self) -> f64 {
+            if self.total == 0 {
+                return 100.0;
+            }
+            self.copied as f64 * 100.0 / self.total as f64
+        }
 
-                let parent = Arc::clone(&current_bank);
-                let next_slot = parent.slot() + 1;
-                let collector_id = parent.collector_id().clone();
-                current_bank = Arc::new(Bank::new_from_parent(parent, &collector_id, next_slot));
-                count_since_flush = 0;
-                bytes_in_current_slot = 0;
+        /// Estimated time remaining given the time spent so far, assuming the
+        /// remaining accounts are copied at the average rate
+        pub fn eta_after(&self, elapsed: Duration) -> Option<Duration> {
+            if self.copied == 0 {
+                return None;
             }
+            let remaining = self.total.saturating_sub(self.copied) as f64;
+            Some(elapsed.mul_f64(remaining / self.copied as f64))
         }
 
-        log::info!(
-            "Final flush after adding {} {} accounts in slot {} ({} bytes)",
-            count_since_flush,
-            account_type,
-            current_bank.slot(),
-            bytes_in_current_slot
-        );
-        current_bank.force_flush_accounts_cache();
+        pub fn eta(&self) -> Option<Duration> {
+            self.eta_after(self.started.elapsed())
+        }
 
-        log::info!("Added {} {} accounts", accounts.len(), account_type);
-        Ok(current_bank)
+        /// Average accounts per second so far
+        pub fn rate(&self) -> f64 {
+            let elapsed = self.started.elapsed().as_secs_f64();
+            if elapsed > 0.0 {
+                self.copied as f64 / elapsed
+            } else {
+                0.0
+            }
+        }
+
+        /// e.g. `1200/5000 (24.0%), ETA 3m10s`
+        pub fn summary(&self) -> String {
+            format!(
+                "{}/{} ({:.1}%), ETA {}",
+                self.copied,
+                self.total,
+                self.percent(),
+                format_eta(self.eta())
+            )
+        }
     }
 
-    /// Counts total accounts in the bank
-    pub fn count_total_accounts(bank: &Bank) -> Result<usize, String> {
-        let mut count = 0;
-        bank.scan_all_accounts(
-            |_| {
-                count += 1;
+    fn format_eta(eta: Option<Duration>) -> String {
+        eta.map_or_else(
+            || "unknown".to_string(),
+            |eta| {
+                let secs = eta.as_secs();
+                if secs >= 3600 {
+                    format!("{}h{:02}m", secs / 3600, secs % 3600 / 60)
+                } else {
+                    format!("{}m{:02}s", secs / 60, secs % 60)
+                }
             },
-            true,
         )
-        .map_err(|e| format!("Failed to scan accounts: {:?}", e))?;
-        Ok(count)
+    }
+
+    /// e.g. `1.5 GiB`, in binary units
+    fn format_bytes(bytes: u64) -> String {
+        const UNITS: [&str; 5] = ["B", "KiB", "MiB", "GiB", "TiB"];
+        let mut value = bytes as f64;
+        let mut unit = 0;
+        while value >= 1024.0 && unit + 1 < UNITS.len() {
+            value /= 1024.0;
+            unit += 1;
+        }
+        if unit == 0 {
+            format!("{} B", bytes)
+        } else {
+            format!("{:.1} {}", value, UNITS[unit])
+        }
+    }
+
+    /// One `--progress` line, e.g.
+    /// `adding mainnet accounts [#######-----] 1200/5000 (24.0%), 1.2 MiB, 400 accounts/s, ETA 0m09s`
+    pub fn progress_bar_line(phase: &str, progress: &CopyProgress, bytes: u64) -> String {
+        let filled = ((progress.percent() / 100.0 * PROGRESS_BAR_WIDTH as f64).round() as usize)
+            .min(PROGRESS_BAR_WIDTH);
+        format!(
+            "{} [{}{}] {}/{} ({:.1}%), {}, {:.0} accounts/s, ETA {}",
+            phase,
+            "#".repeat(filled),
+            "-".repeat(PROGRESS_BAR_WIDTH - filled),
+            progress.copied(),
+            progress.total(),
+            progress.percent(),
+            format_bytes(bytes),
+            progress.rate(),
+            format_eta(progress.eta())
+        )
+    }
+
+    /// The `--progress` display of one phase: a [`progress_bar_line`] redrawn
+    /// in place on stderr when it is a terminal, or printed as a new line
+    /// every `PROGRESS_BAR_LINE_INTERVAL` when it is redirected
+    #[derive(Debug)]
+    pub struct ProgressBar {
+        phase: String,
+        interactive: bool,
+        updates: usize,
+        last_draw: Option<Instant>,
+    }
+
+    impl ProgressBar {
+        pub fn new(phase: &str) -> Self {
+            use std::io::IsTerminal;
+            Self {
+                phase: phase.to_string(),
+                interactive: std::io::stderr().is_terminal(),
+                updates: 0,
+                last_draw: None,
+            }
+        }
+
+        /// Draws the line unless it was drawn within the redraw interval.
+        /// Cheap enough to call for every account.
+        pub fn update(&mut self, progress: &CopyProgress, bytes: u64) {
+            self.updates += 1;
+            if self.last_draw.is_some() && !self.updates.is_multiple_of(PROGRESS_BAR_CHECK_INTERVAL)
+            {
+                return;
+            }
+            let interval = if self.interactive {
+                PROGRESS_BAR_REDRAW_INTERVAL
+            } else {
+                PROGRESS_BAR_LINE_INTERVAL
+            };
+            if self
+                .last_draw
+                .is_none_or(|last_draw| last_draw.elapsed() >= interval)
+            {
+                self.draw(progress, bytes);
+            }
+        }
+
+        /// Draws the final state and ends the line
+        pub fn finish(&mut self, progress: &CopyProgress, bytes: u64) {
+            self.draw(progress, bytes);
+            if self.interactive {
+                eprintln!();
+            }
+        }
+
+        fn draw(&mut self, progress: &CopyProgress, bytes: u64) {
+            let line = progress_bar_line(&self.phase, progress, bytes);
+            if self.interactive {
+                // Back at the start of the line, so a log record written in
+                // between overwrites the bar instead of trailing it
+                eprint!("\r\x1b[2K{}\r", line);
+            } else {
+                eprintln!("{}", line);
+            }
+            self.last_draw = Some(Instant::now());
+        }
+    }
+
+    /// The JSON record a progress file holds
+    pub fn progress_record(
+        phase: &str,
+        accounts_processed: usize,
+        slot: Slot,
+        elapsed: Duration,
+    ) -> serde_json::Value {
+        serde_json::json!({
+            "phase": phase,
+            "accounts_processed": accounts_processed,
+            "slot": slot,
+            "elapsed_secs": elapsed.as_secs_f64(),
+        })
+    }
+
+    /// A file rewritten with the merge's current progress, for monitoring a
+    /// run from outside the process
+    #[derive(Debug)]
+    pub struct ProgressFile {
+        path: PathBuf,
+        started: Instant,
+    }
+
+    impl ProgressFile {
+        pub fn new(path: PathBuf) -> Self {
+            Self {
+                path,
+                started: Instant::now(),
+            }
+        }
+
+        pub fn path(&self) -> &Path {
+            &self.path
+        }
+
+        /// Writes the record to a temporary file next to the progress file and
+        /// renames it into place, so readers never see a partial write
+        pub fn update(
+            &self,
+            phase: &str,
+            accounts_processed: usize,
+            slot: Slot,
+        ) -> Result<(), MergeError> {
+            let record = progress_record(phase, accounts_processed, slot, self.started.elapsed());
+            let mut tmp_name = self.path.file_name().unwrap_or_default().to_os_string();
+            tmp_name.push(".tmp");
+            let tmp_path = self.path.with_file_name(tmp_name);
+            std::fs::write(&tmp_path, format!("{}\n", record)).map_err(|e| {
+                MergeError::Io(format!(
+                    "Failed to write progress file {:?}: {:?}",
+                    tmp_path, e
+                ))
+            })?;
+            std::fs::rename(&tmp_path, &self.path).map_err(|e| {
+                MergeError::Io(format!(
+                    "Failed to replace progress file {:?}: {:?}",
+                    self.path, e
+                ))
+            })
+        }
+
+        /// Like `update`, but only logs a failure: a monitoring hook must not
+        /// abort the merge
+        pub fn report(&self, phase: &str, accounts_processed: usize, slot: Slot) {
+            if let Err(e) = self.update(phase, accounts_processed, slot) {
+                log::warn!("{}", e);
+            }
+        }
+    }
+
+    /// How often a throughput log is sampled unless configured otherwise
+    pub const DEFAULT_THROUGHPUT_INTERVAL: Duration = Duration::from_secs(10);
+
+    /// Column names of a throughput log, written once to an empty file
+    pub const THROUGHPUT_LOG_HEADER: &str =
+        "timestamp,phase,accounts_copied,accounts_per_sec,slot,rss_bytes";
+
+    /// One CSV line of a throughput log. `timestamp` is the time since the
+    /// Unix epoch; the RSS column is empty where it can't be read.
+    pub fn throughput_record(
+        timestamp: Duration,
+        phase: &str,
+        accounts_copied: usize,
+        accounts_per_sec: f64,
+        slot: Slot,
+        rss_bytes: Option<u64>,
+    ) -> String {
+        format!(
+            "{:.3},{},{},{:.1},{},{}",
+            timestamp.as_secs_f64(),
+            phase,
+            accounts_copied,
+            accounts_per_sec,
+            slot,
+            rss_bytes.map(|rss| rss.to_string()).unwrap_or_default()
+        )
+    }
+
+    /// A CSV file the account copy appends a throughput sample to every
+    /// `interval`, for plotting how the copy rate and RSS evolve over a run
+    #[derive(Debug)]
+    pub struct ThroughputLog {
+        path: PathBuf,
+        file: std::fs::File,
+        interval: Duration,
+    }
+
+    impl ThroughputLog {
+        /// Opens `path` for appending, writing the header if it is empty
+        pub fn open(path: PathBuf, interval: Duration) -> Result<Self, MergeError> {
+            use std::io::Write;
+            let mut file = std::fs::OpenOptions::new()
+                .create(true)
+                .append(true)
+                .open(&path)
+                .map_err(|e| {
+                    MergeError::Io(format!("Failed to open throughput log {:?}: {:?}", path, e))
+                })?;
+            let is_empty = file
+                .metadata()
+                .map_err(|e| {
+                    MergeError::Io(format!("Failed to read throughput log {:?}: {:?}", path, e))
+                })?
+                .len()
+                == 0;
+            if is_empty {
+                writeln!(file, "{}", THROUGHPUT_LOG_HEADER).map_err(|e| {
+                    MergeError::Io(format!(
+                        "Failed to write throughput log {:?}: {:?}",
+                        path, e
+                    ))
+                })?;
+            }
+            Ok(Self {
+                path,
+                file,
+                interval,
+            })
+        }
+
+        pub fn path(&self) -> &Path {
+            &self.path
+        }
+
+        /// Starts sampling one copy phase, counting from zero accounts now
+        pub fn sampler(&self, phase: &str) -> ThroughputSampler<'_> {
+            ThroughputSampler {
+                log: self,
+                phase: phase.to_string(),
+                last_sample: Instant::now(),
+                last_copied: 0,
+            }
+        }
+
+        /// Appends a line; failures are only logged, like `ProgressFile::report`
+        fn append(&self, line: &str) {
+            use std::io::Write;
+            if let Err(e) = writeln!(&self.file, "{}", line) {
+                log::warn!("Failed to write throughput log {:?}: {:?}", self.path, e);
+            }
+        }
+    }
+
+    /// The sampling state of one copy phase of a `ThroughputLog`
+    #[derive(Debug)]
+    pub struct ThroughputSampler<'a> {
+        log: &'a ThroughputLog,
+        phase: String,
+        last_sample: Instant,
+        last_copied: usize,
+    }
+
+    impl ThroughputSampler<'_> {
+        /// Appends a sample if the log's interval has passed since the last one
+        pub fn sample_if_due(&mut self, accounts_copied: usize, slot: Slot) {
+            if self.last_sample.elapsed() >= self.log.interval {
+                self.sample(accounts_copied, slot);
+            }
+        }
+
+        /// Appends a sample whose rate covers the accounts copied since the
+        /// previous sample
+        pub fn sample(&mut self, accounts_copied: usize, slot: Slot) {
+            let now = Instant::now();
+            let elapsed = now.duration_since(self.last_sample).as_secs_f64();
+            let copied = accounts_copied.saturating_sub(self.last_copied);
+            let accounts_per_sec = if elapsed > 0.0 {
+                copied as f64 / elapsed
+            } else {
+                0.0
+            };
+            let timestamp = SystemTime::now()
+                .duration_since(UNIX_EPOCH)
+                .unwrap_or_default();
+            self.log.append(&throughput_record(
+                timestamp,
+                &self.phase,
+                accounts_copied,
+                accounts_per_sec,
+                slot,
+                current_rss_bytes(),
+            ));
+            self.last_sample = now;
+            self.last_copied = accounts_copied;
+        }
+    }
+
+    /// How far an account copy got: the accounts it stores come in a fixed
+    /// order, so everything up to `last_pubkey` was stored
+    #[derive(Debug, Clone, Copy, PartialEq, Eq)]
+    pub struct CopyPosition {
+        pub accounts_stored: usize,
+        pub last_pubkey: Pubkey,
+        /// The slot `last_pubkey` was stored in
+        pub slot: Slot,
+    }
+
+    impl CopyPosition {
+        pub fn to_json(&self) -> serde_json::Value {
+            serde_json::json!({
+                "accounts_stored": self.accounts_stored,
+                "last_pubkey": self.last_pubkey.to_string(),
+                "slot": self.slot,
+            })
+        }
+
+        pub fn from_json(value: &serde_json::Value) -> Option<Self> {
+            Some(Self {
+                accounts_stored: value["accounts_stored"].as_u64()? as usize,
+                last_pubkey: value["last_pubkey"].as_str()?.parse().ok()?,
+                slot: value["slot"].as_u64()?,
+            })
+        }
+    }
+
+    /// A JSON state file rewritten with the copy's [`CopyPosition`] at each
+    /// of its flush points. The object `fields` is written along with it, the
+    /// position under `copy_progress`.
+    #[derive(Debug)]
+    pub struct CopyCheckpoint {
+        path: PathBuf,
+        fields: serde_json::Value,
+    }
+
+    impl CopyCheckpoint {
+        pub fn new(path: PathBuf, fields: serde_json::Value) -> Self {
+            Self { path, fields }
+        }
+
+        pub fn path(&self) -> &Path {
+            &self.path
+        }
+
+        /// Writes the state to a temporary file and renames it into place, so
+        /// the file always holds a complete state
+        pub fn record(&self, position: &CopyPosition) -> Result<(), MergeError> {
+            let mut state = self.fields.clone();
+            state["copy_progress"] = position.to_json();
+            let tmp_path = self.path.with_extension("json.tmp");
+            std::fs::write(&tmp_path, format!("{:#}\n", state))
+                .and_then(|_| std::fs::rename(&tmp_path, &self.path))
+                .map_err(|e| {
+                    MergeError::Io(format!(
+                        "Failed to write checkpoint {:?}: {:?}",
+                        self.path, e
+                    ))
+                })
+        }
+
+        /// Like `record`, but only logs a failure: the copy itself is fine
+        pub fn report(&self, position: &CopyPosition) {
+            if let Err(e) = self.record(position) {
+                log::warn!("{}", e);
+            }
+        }
+    }
+
+    /// The optional monitoring outputs the account copy reports to
+    #[derive(Debug, Clone, Copy, Default)]
+    pub struct CopyReporting<'a> {
+        pub progress_file: Option<&'a ProgressFile>,
+        pub throughput_log: Option<&'a ThroughputLog>,
+        /// Draw a [`ProgressBar`] on stderr
+        pub progress_bar: bool,
+        /// Diff every stored account against the account it replaces
+        pub base_diff: Option<&'a BaseDiffWriter>,
+        /// Record the copy's position at every flush point and once it is done
+        pub checkpoint: Option<&'a CopyCheckpoint>,
+    }
+
+    /// Stores `account` and adds the change in data size to the bank's
+    /// accounts data size, which `Bank::store_account` leaves alone. The
+    /// runtime accounts for its own off-chain stores the same way.
+    pub fn store_account_tracking_data_size(
+        bank: &Bank,
+        pubkey: &Pubkey,
+        account: &AccountSharedData,
+    ) {
+        let old_data_size = bank
+            .get_account(pubkey)
+            .map_or(0, |old_account| old_account.data().len());
+        store_account_replacing(bank, pubkey, account, old_data_size);
+    }
+
+    /// Like [`store_account_tracking_data_size`], for a caller that already
+    /// knows the data size of the account being replaced (0 if there is none).
+    /// A zero-lamport account counts as no data, as it can't be loaded.
+    pub fn store_account_replacing(
+        bank: &Bank,
+        pubkey: &Pubkey,
+        account: &AccountSharedData,
+        old_data_size: usize,
+    ) {
+        let new_data_size = if account.lamports() == 0 {
+            0
+        } else {
+            account.data().len()
+        };
+        bank.store_account(pubkey, account);
+        bank.update_accounts_data_size_delta_off_chain_for_tests(
+            new_data_size as i64 - old_data_size as i64,
+        );
+    }
+
+    /// The data size of every loadable account in `bank`, in one scan, for
+    /// [`add_accounts`] to account for the accounts a copy replaces. Meant for
+    /// the base bank the copy starts from, which is small.
+    pub fn account_data_sizes(bank: &Bank) -> Result<HashMap<Pubkey, usize>, MergeError> {
+        let mut data_sizes = HashMap::new();
+        for_each_account(
+            bank,
+            AccountEnumeration::Scan,
+            ScanConsistency::Latest,
+            |pubkey, account| {
+                data_sizes.insert(pubkey, account.data().len());
+            },
+        )?;
+        Ok(data_sizes)
+    }
+
+    /// Like [`account_data_sizes`] for just `pubkeys`, looking each one up,
+    /// for a copy of a few accounts into a large bank
+    pub fn looked_up_data_sizes<'a>(
+        bank: &Bank,
+        pubkeys: impl IntoIterator<Item = &'a Pubkey>,
+    ) -> HashMap<Pubkey, usize> {
+        pubkeys
+            .into_iter()
+            .filter_map(|pubkey| {
+                bank.get_account(pubkey)
+                    .map(|account| (*pubkey, account.data().len()))
+            })
+            .collect()
+    }
+
+    /// The data size of the account `account` replaces at `pubkey`, from
+    /// `replaced_data_sizes` unless a base diff is being written, which looks
+    /// the replaced account up anyway
+    fn record_replaced_account(
+        bank: &Bank,
+        pubkey: &Pubkey,
+        account: &AccountSharedData,
+        replaced_data_sizes: &HashMap<Pubkey, usize>,
+        base_diff: Option<&BaseDiffWriter>,
+    ) -> usize {
+        match base_diff {
+            Some(base_diff) => {
+                let base = bank.get_account(pubkey);
+                base_diff.record(pubkey, base.as_ref(), account);
+                base.map_or(0, |base| base.data().len())
+            }
+            None => replaced_data_sizes.get(pubkey).copied().unwrap_or(0),
+        }
+    }
+
+    /// The accounts data size of `bank`, recounted from its stored accounts
+    /// as a node loading its snapshot does, checked against `max` bytes. The
+    /// size the copy tracks on the bank as it stores is only compared with
+    /// it, and a difference logged.
+    pub fn check_accounts_data_size(bank: &Bank, max: Option<u64>) -> Result<u64, MergeError> {
+        let accounts_data_size = bank.calculate_accounts_data_size().map_err(|e| {
+            MergeError::AccountScan(format!("Failed to recount the accounts data size: {:?}", e))
+        })?;
+        let tracked = bank.load_accounts_data_size();
+        if tracked != accounts_data_size {
+            log::warn!(
+                "The bank at slot {} tracked an accounts data size of {} bytes, but its \
+                 accounts hold {}",
+                bank.slot(),
+                tracked,
+                accounts_data_size
+            );
+        }
+        match max {
+            Some(max) if accounts_data_size > max => Err(MergeError::Verification(format!(
+                "Accounts data size of the bank at slot {} is {} bytes, above the expected \
+                 maximum of {} bytes",
+                bank.slot(),
+                accounts_data_size,
+                max
+            ))),
+            _ => Ok(accounts_data_size),
+        }
+    }
+
+    /// When the account copy flushes the accounts cache to storage
+    #[derive(Debug, Clone, Copy, PartialEq, Eq)]
+    pub enum FlushPolicy {
+        /// Every this many accounts
+        Interval { accounts: usize },
+        /// Whenever the process RSS is above this many bytes; falls back to
+        /// `Interval` where RSS can't be read
+        Adaptive { rss_threshold_bytes: u64 },
+    }
+
+    impl Default for FlushPolicy {
+        fn default() -> Self {
+            Self::Interval {
+                accounts: DEFAULT_FLUSH_INTERVAL_ACCOUNTS,
+            }
+        }
+    }
+
+    impl FlushPolicy {
+        /// How many accounts the copy stores between progress reports and
+        /// deadline checks, and between flushes under `Interval`
+        pub fn interval_accounts(&self) -> usize {
+            match self {
+                Self::Interval { accounts } => *accounts,
+                Self::Adaptive { .. } => DEFAULT_FLUSH_INTERVAL_ACCOUNTS,
+            }
+        }
+    }
+
+    /// The state of `FlushPolicy::Adaptive` across RSS checks. After a flush
+    /// the next one waits until RSS has either dropped back to the threshold
+    /// or grown a tenth of the threshold past what it was right after the
+    /// flush, so an RSS the flush can't bring down isn't flushed at every check.
+    #[derive(Debug, Clone, Copy, PartialEq, Eq)]
+    pub struct AdaptiveFlush {
+        rss_threshold_bytes: u64,
+        rearm_above_bytes: Option<u64>,
+    }
+
+    impl AdaptiveFlush {
+        pub fn new(rss_threshold_bytes: u64) -> Self {
+            Self {
+                rss_threshold_bytes,
+                rearm_above_bytes: None,
+            }
+        }
+
+        /// Whether an RSS of `rss` bytes calls for a flush
+        pub fn should_flush(&mut self, rss: u64) -> bool {
+            if rss <= self.rss_threshold_bytes {
+                self.rearm_above_bytes = None;
+                return false;
+            }
+            self.rearm_above_bytes
+                .is_none_or(|rearm_above| rss > rearm_above)
+        }
+
+        /// Records the RSS measured right after a flush
+        pub fn flushed(&mut self, rss_after: u64) {
+            self.rearm_above_bytes = Some(rss_after.saturating_add(self.rss_threshold_bytes / 10));
+        }
+    }
+
+    /// The order the account copy stores accounts in
+    #[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+    pub enum CopyOrder {
+        /// Hash map order, which differs between runs
+        #[default]
+        Unordered,
+        /// Ascending pubkey order, so slot and flush boundaries fall on the
+        /// same accounts every run
+        ByPubkey,
+    }
+
+    /// The bytes of a `<field>: <n> kB` line of a `/proc` status file
+    fn parse_proc_kib(text: &str, field: &str) -> Option<u64> {
+        let line = text.lines().find(|line| line.starts_with(field))?;
+        let kib: u64 = line
+            .trim_start_matches(field)
+            .trim()
+            .trim_end_matches("kB")
+            .trim()
+            .parse()
+            .ok()?;
+        Some(kib * 1024)
+    }
+
+    /// Extracts the resident set size in bytes from `/proc/<pid>/status`
+    pub fn parse_vm_rss(status: &str) -> Option<u64> {
+        parse_proc_kib(status, "VmRSS:")
+    }
+
+    /// Extracts the memory available for new allocations in bytes from
+    /// `/proc/meminfo`
+    pub fn parse_mem_available(meminfo: &str) -> Option<u64> {
+        parse_proc_kib(meminfo, "MemAvailable:")
+    }
+
+    /// The host's available memory in bytes, `None` where `/proc` isn't
+    /// available
+    pub fn available_memory_bytes() -> Option<u64> {
+        parse_mem_available(&std::fs::read_to_string("/proc/meminfo").ok()?)
+    }
+
+    /// The process's resident set size in bytes, `None` where `/proc` isn't
+    /// available
+    pub fn current_rss_bytes() -> Option<u64> {
+        parse_vm_rss(&std::fs::read_to_string("/proc/self/status").ok()?)
+    }
+
+    /// Parses a duration made of `<number><unit>` parts with units `h`, `m`
+    /// and `s`, such as `2h30m`, `45m` or `90s`
+    pub fn parse_duration(s: &str) -> Result<Duration, MergeError> {
+        let invalid = || {
+            MergeError::InvalidInput(format!(
+                "Invalid duration '{}', expected e.g. '2h30m', '45m' or '90s'",
+                s
+            ))
+        };
+        let mut secs: u64 = 0;
+        let mut digits = String::new();
+        for c in s.chars() {
+            if c.is_ascii_digit() {
+                digits.push(c);
+                continue;
+            }
+            let unit = match c {
+                'h' => 3600,
+                'm' => 60,
+                's' => 1,
+                _ => return Err(invalid()),
+            };
+            let value: u64 = digits.parse().map_err(|_| invalid())?;
+            secs = value
+                .checked_mul(unit)
+                .and_then(|part| secs.checked_add(part))
+                .ok_or_else(invalid)?;
+            digits.clear();
+        }
+        if !digits.is_empty() || secs == 0 {
+            return Err(invalid());
+        }
+        Ok(Duration::from_secs(secs))
+    }
+
+    /// A wall-clock ceiling on a merge, measured from when the process started
+    #[derive(Debug, Clone, Copy, PartialEq, Eq)]
+    pub struct Deadline {
+        started: Instant,
+        max_runtime: Duration,
+    }
+
+    impl Deadline {
+        pub fn new(started: Instant, max_runtime: Duration) -> Self {
+            Self {
+                started,
+                max_runtime,
+            }
+        }
+
+        pub fn exceeded(&self) -> bool {
+            self.started.elapsed() > self.max_runtime
+        }
+
+        /// Errors once the deadline has passed; `context` says where the merge
+        /// stopped
+        pub fn check(&self, context: &str) -> Result<(), MergeError> {
+            if self.exceeded() {
+                return Err(MergeError::DeadlineExceeded(format!(
+                    "Maximum runtime of {}s exceeded {}",
+                    self.max_runtime.as_secs(),
+                    context
+                )));
+            }
+            Ok(())
+        }
+    }
+
+    /// Cancellation point of the account copy: once `deadline` has passed,
+    /// flushes what `bank` has stored so far and errors
+    fn check_copy_deadline(
+        deadline: Option<&Deadline>,
+        bank: &Bank,
+        account_type: &str,
+        progress: &CopyProgress,
+    ) -> Result<(), MergeError> {
+        let Some(deadline) = deadline.filter(|deadline| deadline.exceeded()) else {
+            return Ok(());
+        };
+        bank.force_flush_accounts_cache();
+        deadline.check(&format!(
+            "while adding {} accounts ({} of {} stored, flushed through slot {})",
+            account_type,
+            progress.copied(),
+            progress.total(),
+            bank.slot()
+        ))
+    }
+
+    /// Stores `accounts` over `starting_bank`, advancing to a new slot as
+    /// `slot_split` asks. Every account is stored as given: conflicts with
+    /// the accounts the bank already holds are resolved beforehand by
+    /// [`resolve_base_conflicts`], since a policy that keeps the base account
+    /// drops it from the copy, and the slots are planned from what is left.
+    /// The other copies into a bank share that step rather than each taking a
+    /// [`ConflictPolicy`].
+    #[allow(clippy::too_many_arguments)]
+    pub fn add_accounts(
+        starting_bank: Arc<Bank>,
+        accounts: &HashMap<Pubkey, AccountSharedData>,
+        replaced_data_sizes: &HashMap<Pubkey, usize>,
+        account_type: &str,
+        slot_split: SlotSplit,
+        flush_policy: FlushPolicy,
+        order: CopyOrder,
+        deadline: Option<&Deadline>,
+        reporting: CopyReporting<'_>,
+    ) -> Result<Arc<Bank>, MergeError> {
+        log::info!(
+            "Adding {} {} accounts to merged bank...",
+            accounts.len(),
+            account_type
+        );
+
+        let mut current_bank = starting_bank;
+        let mut count_since_flush = 0usize;
+        let mut accounts_in_current_slot = 0usize;
+        let mut bytes_in_current_slot: u64 = 0;
+        let mut progress = CopyProgress::new(accounts.len());
+        let mut flush_policy = flush_policy;
+        let mut adaptive_flush = match flush_policy {
+            FlushPolicy::Adaptive {
+                rss_threshold_bytes,
+            } => Some(AdaptiveFlush::new(rss_threshold_bytes)),
+            FlushPolicy::Interval { .. } => None,
+        };
+        let mut throughput = reporting
+            .throughput_log
+            .map(|log| log.sampler(&format!("adding {} accounts", account_type)));
+        let mut progress_bar = reporting
+            .progress_bar
+            .then(|| ProgressBar::new(&format!("adding {} accounts", account_type)));
+        let mut bytes_copied = 0;
+        let mut last_stored = None;
+
+        let ordered: Box<dyn Iterator<Item = (&Pubkey, &AccountSharedData)>> = match order {
+            CopyOrder::Unordered => Box::new(accounts.iter()),
+            CopyOrder::ByPubkey => Box::new(sort_accounts_by_pubkey(accounts).into_iter()),
+        };
+        for (pubkey, account) in ordered {
+            let old_data_size = record_replaced_account(
+                &current_bank,
+                pubkey,
+                account,
+                replaced_data_sizes,
+                reporting.base_diff,
+            );
+            store_account_replacing(&current_bank, pubkey, account, old_data_size);
+            last_stored = Some((*pubkey, current_bank.slot()));
+            count_since_flush += 1;
+            accounts_in_current_slot += 1;
+            progress.add(1);
+            bytes_in_current_slot += approx_stored_bytes(account);
+            bytes_copied += approx_stored_bytes(account);
+
+            if let Some(throughput) = throughput.as_mut().filter(|_| {
+                progress
+                    .copied()
+                    .is_multiple_of(THROUGHPUT_CHECK_INTERVAL_ACCOUNTS)
+            }) {
+                throughput.sample_if_due(progress.copied(), current_bank.slot());
+            }
+            if let Some(progress_bar) = progress_bar.as_mut() {
+                progress_bar.update(&progress, bytes_copied);
+            }
+
+            let at_interval = count_since_flush.is_multiple_of(flush_policy.interval_accounts());
+            if let (
+                FlushPolicy::Adaptive {
+                    rss_threshold_bytes,
+                },
+                Some(adaptive_flush),
+            ) = (flush_policy, adaptive_flush.as_mut())
+            {
+                if progress
+                    .copied()
+                    .is_multiple_of(RSS_CHECK_INTERVAL_ACCOUNTS)
+                {
+                    match current_rss_bytes() {
+                        Some(rss) if adaptive_flush.should_flush(rss) => {
+                            log::info!(
+                                "Adaptive flush in slot {}: RSS {} bytes is above {} bytes",
+                                current_bank.slot(),
+                                rss,
+                                rss_threshold_bytes
+                            );
+                            current_bank.force_flush_accounts_cache();
+                            adaptive_flush.flushed(current_rss_bytes().unwrap_or(rss));
+                        }
+                        Some(_) => {}
+                        None => {
+                            log::warn!(
+                                "Process RSS is unavailable, flushing every {} accounts instead",
+                                DEFAULT_FLUSH_INTERVAL_ACCOUNTS
+                            );
+                            flush_policy = FlushPolicy::default();
+                        }
+                    }
+                }
+            }
+
+            if at_interval {
+                log::info!(
+                    "Progress: {} {} accounts added in slot {} ({} bytes), overall {}",
+                    count_since_flush,
+                    account_type,
+                    current_bank.slot(),
+                    bytes_in_current_slot,
+                    progress.summary()
+                );
+                if matches!(flush_policy, FlushPolicy::Interval { .. }) {
+                    current_bank.force_flush_accounts_cache();
+                }
+                if let Some(progress_file) = reporting.progress_file {
+                    progress_file.report(
+                        &format!("adding {} accounts", account_type),
+                        progress.copied(),
+                        current_bank.slot(),
+                    );
+                }
+                if let Some(checkpoint) = reporting.checkpoint {
+                    checkpoint.report(&CopyPosition {
+                        accounts_stored: progress.copied(),
+                        last_pubkey: *pubkey,
+                        slot: current_bank.slot(),
+                    });
+                }
+                check_copy_deadline(deadline, &current_bank, account_type, &progress)?;
+            }
+
+            if slot_split.is_full(accounts_in_current_slot, bytes_in_current_slot) {
+                log::info!(
+                    "Reached {:?} with {} accounts ({} bytes) in slot {}, squashing and \
+                     advancing to next slot",
+                    slot_split,
+                    accounts_in_current_slot,
+                    bytes_in_current_slot,
+                    current_bank.slot()
+                );
+                current_bank.force_flush_accounts_cache();
+                current_bank.squash();
+                check_copy_deadline(deadline, &current_bank, account_type, &progress)?;
+
+                let parent = Arc::clone(&current_bank);
+                let next_slot = parent.slot() + 1;
+                let collector_id = *parent.collector_id();
+                current_bank = Arc::new(Bank::new_from_parent(parent, &collector_id, next_slot));
+                count_since_flush = 0;
+                accounts_in_current_slot = 0;
+                bytes_in_current_slot = 0;
+            }
+        }
+
+        log::info!(
+            "Final flush after adding {} {} accounts in slot {} ({} bytes)",
+            count_since_flush,
+            account_type,
+            current_bank.slot(),
+            bytes_in_current_slot
+        );
+        current_bank.force_flush_accounts_cache();
+        if let Some(throughput) = throughput.as_mut() {
+            throughput.sample(progress.copied(), current_bank.slot());
+        }
+        if let Some(progress_bar) = progress_bar.as_mut() {
+            progress_bar.finish(&progress, bytes_copied);
+        }
+        if let (Some(checkpoint), Some((last_pubkey, slot))) = (reporting.checkpoint, last_stored) {
+            checkpoint.report(&CopyPosition {
+                accounts_stored: progress.copied(),
+                last_pubkey,
+                slot,
+            });
+        }
+
+        log::info!("Added {} {} accounts", accounts.len(), account_type);
+        Ok(current_bank)
+    }
+
+    /// Which side a field of a colliding account is taken from
+    #[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+    pub enum FieldSource {
+        /// The account already in the base (merge ledger) bank
+        Base,
+        /// The account being copied in (mainnet)
+        #[default]
+        Source,
+    }
+
+    impl FromStr for FieldSource {
+        type Err = String;
+
+        fn from_str(s: &str) -> Result<Self, Self::Err> {
+            match s {
+                "base" => Ok(Self::Base),
+                "source" | "mainnet" => Ok(Self::Source),
+                _ => Err(format!(
+                    "Invalid field source '{}', expected 'base' or 'mainnet'",
+                    s
+                )),
+            }
+        }
+    }
+
+    /// Per-field resolution for accounts present in both the base bank and the
+    /// copied set. The default takes every field from the source, which is a
+    /// whole-account overwrite. `rent_epoch` always follows `lamports`.
+    #[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+    pub struct FieldMergePolicy {
+        pub lamports: FieldSource,
+        pub data: FieldSource,
+        pub owner: FieldSource,
+        pub executable: FieldSource,
+    }
+
+    impl FieldMergePolicy {
+        /// Whether the policy is a plain overwrite with the source account
+        pub fn is_overwrite(&self) -> bool {
+            *self == Self::default()
+        }
+
+        /// Builds the account to store for a collision between `base` and `source`
+        pub fn merge(
+            &self,
+            base: &AccountSharedData,
+            source: &AccountSharedData,
+        ) -> AccountSharedData {
+            let pick = |field: FieldSource| match field {
+                FieldSource::Base => base,
+                FieldSource::Source => source,
+            };
+            let lamports_side = pick(self.lamports);
+            AccountSharedData::from(solana_account::Account {
+                lamports: lamports_side.lamports(),
+                data: pick(self.data).data().to_vec(),
+                owner: *pick(self.owner).owner(),
+                executable: pick(self.executable).executable(),
+                rent_epoch: lamports_side.rent_epoch(),
+            })
+        }
+    }
+
+    impl FromStr for FieldMergePolicy {
+        type Err = String;
+
+        /// Parses `field=side` pairs separated by commas, e.g.
+        /// `lamports=mainnet,data=base`. Unlisted fields come from the source.
+        fn from_str(s: &str) -> Result<Self, Self::Err> {
+            let mut policy = Self::default();
+            for pair in s.split(',').map(str::trim).filter(|pair| !pair.is_empty()) {
+                let (field, side) = pair.split_once('=').ok_or_else(|| {
+                    format!("Invalid field policy '{}', expected FIELD=SIDE", pair)
+                })?;
+                let side = side.trim().parse()?;
+                match field.trim() {
+                    "lamports" => policy.lamports = side,
+                    "data" => policy.data = side,
+                    "owner" => policy.owner = side,
+                    "executable" => policy.executable = side,
+                    other => {
+                        return Err(format!(
+                        "Unknown account field '{}', expected lamports, data, owner or executable",
+                        other
+                    ))
+                    }
+                }
+            }
+            Ok(policy)
+        }
+    }
+
+    /// Rewrites every account in `accounts` that already exists in `base_bank`
+    /// according to `policy`, returning the number of collisions merged
+    pub fn apply_field_merge_policy(
+        base_bank: &Bank,
+        accounts: &mut HashMap<Pubkey, AccountSharedData>,
+        policy: &FieldMergePolicy,
+    ) -> usize {
+        if policy.is_overwrite() {
+            return 0;
+        }
+        let mut merged = 0;
+        for (pubkey, account) in accounts.iter_mut() {
+            if let Some(base) = base_bank.get_account(pubkey) {
+                *account = policy.merge(&base, account);
+                merged += 1;
+            }
+        }
+        log::info!(
+            "Merged {} colliding accounts field by field ({:?})",
+            merged,
+            policy
+        );
+        merged
+    }
+
+    /// How a pubkey held by both sides is resolved, by `merge_account_maps`
+    /// and `resolve_base_conflicts`. Sysvars and builtins, which every bank
+    /// holds, are never conflicts; see [`is_runtime_account`].
+    #[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+    pub enum ConflictPolicy {
+        /// The overlay account replaces the base one
+        #[default]
+        Overwrite,
+        /// The base account is left as is
+        KeepBase,
+        /// Each field is taken from the side the policy names, the overlay
+        /// being the source
+        Fields(FieldMergePolicy),
+        /// The account last written in the higher slot wins, the overlay on a
+        /// tie. Needs the slot of both sides.
+        PreferNewerSlot,
+        /// Any conflict is an error
+        FailOnConflict,
+        /// The overlay account with the lamports of both added up
+        MergeLamports,
+    }
+
+    impl FromStr for ConflictPolicy {
+        type Err = String;
+
+        /// `overwrite` (or `keep-source`), `keep-base`, `prefer-newer-slot`,
+        /// `fail`, `merge-lamports`, or a [`FieldMergePolicy`] spec such as
+        /// `lamports=base,data=mainnet`
+        fn from_str(s: &str) -> Result<Self, Self::Err> {
+            match s {
+                "overwrite" | "keep-source" => Ok(Self::Overwrite),
+                "keep-base" => Ok(Self::KeepBase),
+                "prefer-newer-slot" => Ok(Self::PreferNewerSlot),
+                "fail" => Ok(Self::FailOnConflict),
+                "merge-lamports" => Ok(Self::MergeLamports),
+                _ => s.parse().map(Self::Fields).map_err(|e| {
+                    format!(
+                        "Invalid conflict policy '{}', expected 'overwrite', 'keep-source', \
+                         'keep-base', 'prefer-newer-slot', 'fail', 'merge-lamports' or \
+                         FIELD=SIDE pairs: {}",
+                        s, e
+                    )
+                }),
+            }
+        }
+    }
+
+    /// Whether `account` is one the runtime keeps in every bank: a sysvar or
+    /// a builtin program, owned by the native loader. Each chain holds its own
+    /// version of these, so they aren't conflicts between two ledgers.
+    pub fn is_runtime_account(account: &AccountSharedData) -> bool {
+        *account.owner() == sysvar::id() || *account.owner() == native_loader::id()
+    }
+
+    /// Resolves `pubkey`, held as `base` and `overlay`, with `policy`. Slots
+    /// are the ones each side was last written in, if known; only
+    /// [`ConflictPolicy::PreferNewerSlot`] reads them.
+    pub fn resolve_conflict(
+        policy: ConflictPolicy,
+        pubkey: &Pubkey,
+        base: &AccountSharedData,
+        base_slot: Option<Slot>,
+        overlay: AccountSharedData,
+        overlay_slot: Option<Slot>,
+    ) -> Result<AccountSharedData, MergeError> {
+        match policy {
+            ConflictPolicy::Overwrite => Ok(overlay),
+            ConflictPolicy::KeepBase => Ok(base.clone()),
+            ConflictPolicy::Fields(fields) => Ok(fields.merge(base, &overlay)),
+            ConflictPolicy::PreferNewerSlot => match (base_slot, overlay_slot) {
+                (Some(base_slot), Some(overlay_slot)) if base_slot > overlay_slot => {
+                    Ok(base.clone())
+                }
+                (Some(_), Some(_)) => Ok(overlay),
+                _ => Err(MergeError::Conflict(format!(
+                    "Can't prefer the newer slot for {}: the slot of both accounts isn't known",
+                    pubkey
+                ))),
+            },
+            ConflictPolicy::FailOnConflict => Err(MergeError::Conflict(format!(
+                "{} is held by both sides and the conflict policy is 'fail'",
+                pubkey
+            ))),
+            ConflictPolicy::MergeLamports => {
+                let lamports =
+                    base.lamports()
+                        .checked_add(overlay.lamports())
+                        .ok_or_else(|| {
+                            MergeError::Conflict(format!("Merged lamports of {} overflow", pubkey))
+                        })?;
+                let mut merged = overlay;
+                merged.set_lamports(lamports);
+                Ok(merged)
+            }
+        }
+    }
+
+    #[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+    pub struct AccountMapMergeStats {
+        /// Overlay accounts whose pubkey the base didn't have
+        pub inserted: usize,
+        /// Overlay accounts whose pubkey the base already had, not counting
+        /// runtime accounts
+        pub collisions: usize,
+        /// Collisions whose resolution left the base account unchanged
+        pub unchanged: usize,
+    }
+
+    /// Merges `overlay` into `base`, resolving every pubkey both hold with
+    /// `policy`. The map counterpart of the copy into a bank, for callers that
+    /// hold both account sets in memory. A runtime account held by both
+    /// keeps the base version whatever the policy. The maps carry no slots,
+    /// so [`ConflictPolicy::PreferNewerSlot`] fails on the first collision.
+    pub fn merge_account_maps(
+        base: &mut HashMap<Pubkey, AccountSharedData>,
+        overlay: HashMap<Pubkey, AccountSharedData>,
+        policy: ConflictPolicy,
+    ) -> Result<AccountMapMergeStats, MergeError> {
+        let mut stats = AccountMapMergeStats::default();
+        for (pubkey, account) in overlay {
+            let existing = match base.entry(pubkey) {
+                Entry::Vacant(entry) => {
+                    entry.insert(account);
+                    stats.inserted += 1;
+                    continue;
+                }
+                Entry::Occupied(entry) => entry.into_mut(),
+            };
+            if is_runtime_account(existing) || is_runtime_account(&account) {
+                continue;
+            }
+            stats.collisions += 1;
+            if policy == ConflictPolicy::KeepBase {
+                stats.unchanged += 1;
+                continue;
+            }
+            let resolved = resolve_conflict(policy, &pubkey, existing, None, account, None)?;
+            if resolved == *existing {
+                stats.unchanged += 1;
+            }
+            *existing = resolved;
+        }
+        Ok(stats)
+    }
+
+    /// Outcome of `resolve_base_conflicts`
+    #[derive(Debug, Clone, Default, PartialEq, Eq)]
+    pub struct ConflictReport {
+        /// Every pubkey the merge ledger already held, sorted
+        pub conflicts: Vec<Pubkey>,
+        /// Conflicts resolved to the base account, left out of the copy
+        pub kept_base: usize,
+    }
+
+    /// Resolves the accounts to copy whose pubkey `base_bank` already holds
+    /// with `policy`, before they are stored over the base ones. Conflicts
+    /// resolved to the base account are dropped from `accounts`. Runtime
+    /// accounts aren't conflicts and are copied as they are. `slots` maps
+    /// pubkeys to the slot their account was last written in, for
+    /// [`ConflictPolicy::PreferNewerSlot`]; the base slots are read from
+    /// `base_bank`.
+    pub fn resolve_base_conflicts(
+        base_bank: &Bank,
+        accounts: &mut HashMap<Pubkey, AccountSharedData>,
+        slots: &HashMap<Pubkey, Slot>,
+        policy: ConflictPolicy,
+    ) -> Result<ConflictReport, MergeError> {
+        let mut report = ConflictReport::default();
+        let mut kept_base = Vec::new();
+        for (pubkey, account) in accounts.iter_mut() {
+            let Some((base, base_slot)) = base_bank.get_account_modified_slot(pubkey) else {
+                continue;
+            };
+            if is_runtime_account(&base) || is_runtime_account(account) {
+                continue;
+            }
+            report.conflicts.push(*pubkey);
+            let resolved = resolve_conflict(
+                policy,
+                pubkey,
+                &base,
+                Some(base_slot),
+                account.clone(),
+                slots.get(pubkey).copied(),
+            )?;
+            if resolved == base {
+                kept_base.push(*pubkey);
+            } else {
+                *account = resolved;
+            }
+        }
+        for pubkey in &kept_base {
+            accounts.remove(pubkey);
+        }
+        report.kept_base = kept_base.len();
+        report.conflicts.sort_unstable();
+        log::info!(
+            "Resolved {} accounts already in the merge ledger with {:?}, {} kept from the base",
+            report.conflicts.len(),
+            policy,
+            report.kept_base
+        );
+        Ok(report)
+    }
+
+    /// Writes the pubkeys of `report.conflicts` to `path`, one per line
+    pub fn write_conflict_report(report: &ConflictReport, path: &Path) -> Result<(), MergeError> {
+        use std::io::Write;
+
+        let write_error = |e| {
+            MergeError::Io(format!(
+                "Failed to write conflict report {:?}: {:?}",
+                path, e
+            ))
+        };
+        let file = std::fs::File::create(path).map_err(|e| {
+            MergeError::Io(format!(
+                "Failed to create conflict report {:?}: {:?}",
+                path, e
+            ))
+        })?;
+        let mut writer = std::io::BufWriter::new(file);
+        for pubkey in &report.conflicts {
+            writeln!(writer, "{}", pubkey).map_err(write_error)?;
+        }
+        writer.flush().map_err(write_error)
+    }
+
+    /// Outcome of a `--max-data-bytes-per-owner` cap
+    #[derive(Debug, Clone, PartialEq, Eq)]
+    pub struct OwnerDataCapReport {
+        pub owner: Pubkey,
+        pub cap_bytes: u64,
+        pub bytes_copied: u64,
+        pub accounts_copied: usize,
+        pub bytes_skipped: u64,
+        pub accounts_skipped: usize,
+    }
+
+    /// Parses `<PUBKEY>=<BYTES>`
+    pub fn parse_owner_data_cap(s: &str) -> Result<(Pubkey, u64), MergeError> {
+        let (owner, bytes) = s.split_once('=').ok_or_else(|| {
+            MergeError::InvalidInput(format!(
+                "Invalid owner data cap '{}', expected PUBKEY=BYTES",
+                s
+            ))
+        })?;
+        let owner = Pubkey::from_str(owner.trim())
+            .map_err(|e| MergeError::InvalidInput(format!("Invalid owner '{}': {:?}", owner, e)))?;
+        let bytes = bytes.trim().parse().map_err(|e| {
+            MergeError::InvalidInput(format!("Invalid byte cap '{}': {}", bytes, e))
+        })?;
+        Ok((owner, bytes))
+    }
+
+    /// Parses `<OLD_PUBKEY>=<NEW_PUBKEY>`
+    pub fn parse_owner_remap(s: &str) -> Result<(Pubkey, Pubkey), MergeError> {
+        let (from, to) = s.split_once('=').ok_or_else(|| {
+            MergeError::InvalidInput(format!(
+                "Invalid owner remap '{}', expected OLD_PUBKEY=NEW_PUBKEY",
+                s
+            ))
+        })?;
+        let from = Pubkey::from_str(from.trim())
+            .map_err(|e| MergeError::InvalidInput(format!("Invalid owner '{}': {:?}", from, e)))?;
+        let to = Pubkey::from_str(to.trim())
+            .map_err(|e| MergeError::InvalidInput(format!("Invalid owner '{}': {:?}", to, e)))?;
+        Ok((from, to))
+    }
+
+    /// Parses a list of pubkeys, one per line, with `#` starting a comment
+    /// and blank lines ignored. A YAML block sequence is read the same way:
+    /// `- ` item markers and quotes around a pubkey are stripped, and `---`
+    /// and `key:` lines are skipped. Every malformed line is reported with its
+    /// line number, `kind` naming what the pubkeys are.
+    fn parse_pubkey_list(contents: &str, kind: &str) -> Result<Vec<Pubkey>, MergeError> {
+        let mut pubkeys = Vec::new();
+        let mut malformed = Vec::new();
+        for (index, line) in contents.lines().enumerate() {
+            let entry = line.split('#').next().unwrap_or_default().trim();
+            if entry.is_empty() || entry == "---" || entry.ends_with(':') {
+                continue;
+            }
+            let entry = entry
+                .strip_prefix('-')
+                .map_or(entry, str::trim_start)
+                .trim_matches(|c| c == '"' || c == '\'');
+            match Pubkey::from_str(entry) {
+                Ok(pubkey) => pubkeys.push(pubkey),
+                Err(e) => malformed.push(format!("line {}: '{}' ({:?})", index + 1, entry, e)),
+            }
+        }
+        if malformed.is_empty() {
+            Ok(pubkeys)
+        } else {
+            Err(MergeError::InvalidInput(format!(
+                "{} malformed {} pubkeys: {}",
+                malformed.len(),
+                kind,
+                malformed.join("; ")
+            )))
+        }
+    }
+
+    /// Parses an owner allowlist: one program pubkey per line, see
+    /// [`parse_account_list`] for the accepted syntax
+    pub fn parse_owner_list(contents: &str) -> Result<Vec<Pubkey>, MergeError> {
+        parse_pubkey_list(contents, "owner")
+    }
+
+    /// Parses an `--include-accounts-file` or `--exclude-accounts-file` list:
+    /// one account pubkey per line, with `#` starting a comment and blank lines
+    /// ignored, or a YAML block sequence of pubkeys such as
+    /// `accounts:\n  - <pubkey>`. Every malformed line is reported with its
+    /// line number.
+    pub fn parse_account_list(contents: &str) -> Result<Vec<Pubkey>, MergeError> {
+        parse_pubkey_list(contents, "account")
+    }
+
+    /// Reads an account list file, see [`parse_account_list`]
+    pub fn read_account_list(path: &Path) -> Result<Vec<Pubkey>, MergeError> {
+        let contents = std::fs::read_to_string(path).map_err(|e| {
+            MergeError::InvalidInput(format!("Failed to read account list {:?}: {}", path, e))
+        })?;
+        parse_account_list(&contents)
+            .map_err(|e| MergeError::InvalidInput(format!("Account list {:?} has {}", path, e)))
+    }
+
+    /// Reads an owner allowlist file, see [`parse_owner_list`]
+    pub fn read_owner_list(path: &Path) -> Result<Vec<Pubkey>, MergeError> {
+        let contents = std::fs::read_to_string(path).map_err(|e| {
+            MergeError::InvalidInput(format!("Failed to read owner list {:?}: {}", path, e))
+        })?;
+        parse_owner_list(&contents)
+            .map_err(|e| MergeError::InvalidInput(format!("Owner list {:?} has {}", path, e)))
+    }
+
+    /// Replacement fields for one account, stored over the merged bank's own
+    /// version. Unset fields keep that version's value, or a new System Program
+    /// account's if the bank has none.
+    #[derive(Debug, Clone, Default, PartialEq, Eq)]
+    pub struct AccountOverride {
+        pub pubkey: Pubkey,
+        pub lamports: Option<u64>,
+        pub data: Option<Vec<u8>>,
+        pub owner: Option<Pubkey>,
+        pub executable: Option<bool>,
+        pub rent_epoch: Option<Epoch>,
+    }
+
+    impl AccountOverride {
+        /// The account to store for `existing`, the bank's current version
+        pub fn apply(&self, existing: Option<AccountSharedData>) -> AccountSharedData {
+            let mut account =
+                existing.unwrap_or_else(|| AccountSharedData::new(0, 0, &system_program::id()));
+            if let Some(lamports) = self.lamports {
+                account.set_lamports(lamports);
+            }
+            if let Some(data) = &self.data {
+                account.set_data_from_slice(data);
+            }
+            if let Some(owner) = &self.owner {
+                account.set_owner(*owner);
+            }
+            if let Some(executable) = self.executable {
+                account.set_executable(executable);
+            }
+            if let Some(rent_epoch) = self.rent_epoch {
+                account.set_rent_epoch(rent_epoch);
+            }
+            account
+        }
+    }
+
+    fn parse_account_override(entry: &serde_json::Value) -> Result<AccountOverride, MergeError> {
+        let pubkey = entry["pubkey"]
+            .as_str()
+            .ok_or(MergeError::InvalidInput("no pubkey".to_string()))?;
+        let pubkey = Pubkey::from_str(pubkey)
+            .map_err(|e| MergeError::InvalidInput(format!("pubkey '{}' ({:?})", pubkey, e)))?;
+        let account = entry["account"]
+            .as_object()
+            .ok_or_else(|| MergeError::InvalidInput(format!("{}: no account object", pubkey)))?;
+        let field = |key: &str| account.get(key).filter(|value| !value.is_null());
+        let number = |key: &str| {
+            field(key)
+                .map(|value| {
+                    value.as_u64().ok_or_else(|| {
+                        MergeError::InvalidInput(format!("{}: {} must be a number", pubkey, key))
+                    })
+                })
+                .transpose()
+        };
+        let data = field("data")
+            .map(|data| match data.as_array().map(Vec::as_slice) {
+                Some([encoded, encoding]) if encoding == "base64" => encoded
+                    .as_str()
+                    .ok_or_else(|| {
+                        MergeError::InvalidInput(format!("{}: data must be a string", pubkey))
+                    })
+                    .and_then(|encoded| {
+                        BASE64_STANDARD.decode(encoded).map_err(|e| {
+                            MergeError::InvalidInput(format!(
+                                "{}: invalid base64 data ({})",
+                                pubkey, e
+                            ))
+                        })
+                    }),
+                Some([_, encoding]) => Err(MergeError::InvalidInput(format!(
+                    "{}: data encoding {} is not supported, expected \"base64\"",
+                    pubkey, encoding
+                ))),
+                _ => Err(MergeError::InvalidInput(format!(
+                    "{}: data must be [\"<base64>\", \"base64\"]",
+                    pubkey
+                ))),
+            })
+            .transpose()?;
+        if let Some(data) = &data {
+            if data.len() > MAX_ACCOUNT_DATA_LEN {
+                return Err(MergeError::InvalidInput(format!(
+                    "{}: {} data bytes are above the runtime's maximum of {}",
+                    pubkey,
+                    data.len(),
+                    MAX_ACCOUNT_DATA_LEN
+                )));
+            }
+        }
+        let owner = field("owner")
+            .map(|owner| {
+                owner
+                    .as_str()
+                    .and_then(|owner| Pubkey::from_str(owner).ok())
+                    .ok_or_else(|| {
+                        MergeError::InvalidInput(format!("{}: owner must be a pubkey", pubkey))
+                    })
+            })
+            .transpose()?;
+        let executable = field("executable")
+            .map(|executable| {
+                executable.as_bool().ok_or_else(|| {
+                    MergeError::InvalidInput(format!("{}: executable must be a boolean", pubkey))
+                })
+            })
+            .transpose()?;
+        Ok(AccountOverride {
+            pubkey,
+            lamports: number("lamports")?,
+            data,
+            owner,
+            executable,
+            rent_epoch: number("rentEpoch")?,
+        })
+    }
+
+    /// Parses an `--account-overrides` file: one account in the JSON format
+    /// `solana-test-validator --account` loads (`solana account --output
+    /// json`), or a list of them, written as JSON or as the same structure in
+    /// YAML. Fields left out of an `account` object are not overridden;
+    /// `space` is ignored in favor of the data's length.
+    pub fn parse_account_overrides(contents: &str) -> Result<Vec<AccountOverride>, MergeError> {
+        // JSON is YAML too, but the YAML parser is stricter about whitespace
+        let value: serde_json::Value = serde_json::from_str(contents)
+            .or_else(|_| serde_yaml::from_str(contents))
+            .map_err(|e| MergeError::InvalidInput(format!("invalid JSON or YAML: {}", e)))?;
+        let entries = match value {
+            serde_json::Value::Array(entries) => entries,
+            entry => vec![entry],
+        };
+        let mut seen = HashSet::new();
+        entries
+            .iter()
+            .enumerate()
+            .map(|(index, entry)| {
+                let account_override = parse_account_override(entry).map_err(|e| {
+                    MergeError::InvalidInput(format!("an invalid entry {}: {}", index + 1, e))
+                })?;
+                if !seen.insert(account_override.pubkey) {
+                    return Err(MergeError::InvalidInput(format!(
+                        "a second entry {} for {}",
+                        index + 1,
+                        account_override.pubkey
+                    )));
+                }
+                Ok(account_override)
+            })
+            .collect()
+    }
+
+    /// Reads an account overrides file, see [`parse_account_overrides`]
+    pub fn read_account_overrides(path: &Path) -> Result<Vec<AccountOverride>, MergeError> {
+        let contents = std::fs::read_to_string(path).map_err(|e| {
+            MergeError::InvalidInput(format!(
+                "Failed to read account overrides {:?}: {}",
+                path, e
+            ))
+        })?;
+        parse_account_overrides(&contents).map_err(|e| {
+            MergeError::InvalidInput(format!("Account overrides {:?} have {}", path, e))
+        })
+    }
+
+    /// Accounts changed by `--account-overrides`
+    #[derive(Debug, Clone, Default, PartialEq, Eq)]
+    pub struct AccountOverrideReport {
+        /// Overridden accounts the bank already held
+        pub replaced: Vec<Pubkey>,
+        /// Overridden accounts the bank didn't hold
+        pub created: Vec<Pubkey>,
+    }
+
+    /// Stores each override over the bank's version of its account. The
+    /// caller must recalculate capitalization afterwards.
+    pub fn apply_account_overrides(
+        bank: &Bank,
+        overrides: &[AccountOverride],
+    ) -> AccountOverrideReport {
+        let mut report = AccountOverrideReport::default();
+        for account_override in overrides {
+            let existing = bank.get_account(&account_override.pubkey);
+            if existing.is_some() {
+                report.replaced.push(account_override.pubkey);
+            } else {
+                report.created.push(account_override.pubkey);
+            }
+            let account = account_override.apply(existing);
+            store_account_tracking_data_size(bank, &account_override.pubkey, &account);
+        }
+        report
+    }
+
+    /// The upgrade authority put into copied ProgramData accounts
+    #[derive(Debug, Clone, Default, PartialEq, Eq)]
+    pub struct UpgradeAuthorityOverride {
+        pub authority: Pubkey,
+        /// Programs whose ProgramData is rewritten, every copied one if empty
+        pub programs: HashSet<Pubkey>,
+    }
+
+    /// Sets the upgrade authority of the ProgramData accounts in `accounts`
+    /// that `authority_override` covers, including those of finalized
+    /// programs, which become upgradeable again. A scoped program's
+    /// ProgramData is found at its derived address, so the program account
+    /// itself needn't be among `accounts`. Returns the rewritten ProgramData
+    /// accounts in pubkey order.
+    pub fn override_upgrade_authorities(
+        accounts: &mut HashMap<Pubkey, AccountSharedData>,
+        authority_override: &UpgradeAuthorityOverride,
+    ) -> Result<Vec<Pubkey>, MergeError> {
+        let scope: Option<HashSet<Pubkey>> = (!authority_override.programs.is_empty()).then(|| {
+            authority_override
+                .programs
+                .iter()
+                .map(solana_loader_v3_interface::get_program_data_address)
+                .collect()
+        });
+        let mut rewritten = Vec::new();
+        for (pubkey, account) in accounts.iter_mut() {
+            if account.owner() != &bpf_loader_upgradeable::id()
+                || scope.as_ref().is_some_and(|scope| !scope.contains(pubkey))
+            {
+                continue;
+            }
+            let Ok(UpgradeableLoaderState::ProgramData { slot, .. }) =
+                bincode::deserialize(account.data())
+            else {
+                continue;
+            };
+            // A finalized program's header deserializes from fewer bytes
+            // than the one with an authority takes
+            let header_len = UpgradeableLoaderState::size_of_programdata_metadata();
+            if account.data().len() < header_len {
+                log::warn!(
+                    "ProgramData {} holds {} bytes, too few for a header with an upgrade \
+                     authority, leaving it unchanged",
+                    pubkey,
+                    account.data().len()
+                );
+                continue;
+            }
+            bincode::serialize_into(
+                &mut account.data_as_mut_slice()[..header_len],
+                &UpgradeableLoaderState::ProgramData {
+                    slot,
+                    upgrade_authority_address: Some(authority_override.authority),
+                },
+            )
+            .map_err(|e| {
+                MergeError::AccountData(format!("Failed to rewrite ProgramData {}: {}", pubkey, e))
+            })?;
+            rewritten.push(*pubkey);
+        }
+        rewritten.sort_unstable();
+        log::info!(
+            "Set the upgrade authority of {} ProgramData accounts to {}",
+            rewritten.len(),
+            authority_override.authority
+        );
+        Ok(rewritten)
+    }
+
+    /// The programs `authority_override` is scoped to whose ProgramData
+    /// isn't among `rewritten`, in pubkey order
+    pub fn unmatched_upgrade_authority_programs(
+        authority_override: &UpgradeAuthorityOverride,
+        rewritten: &[Pubkey],
+    ) -> Vec<Pubkey> {
+        let mut unmatched: Vec<_> = authority_override
+            .programs
+            .iter()
+            .filter(|program| {
+                !rewritten.contains(&solana_loader_v3_interface::get_program_data_address(
+                    program,
+                ))
+            })
+            .copied()
+            .collect();
+        unmatched.sort_unstable();
+        unmatched
+    }
+
+    /// Outcome of an `--owner-remap` rule
+    #[derive(Debug, Clone, PartialEq, Eq)]
+    pub struct OwnerRemapReport {
+        pub from: Pubkey,
+        pub to: Pubkey,
+        pub accounts_remapped: usize,
+    }
+
+    /// Rewrites the owner of every account owned by a key of `remaps` to its
+    /// value. Returns one report per rule, ordered by the old owner.
+    pub fn remap_owners(
+        accounts: &mut HashMap<Pubkey, AccountSharedData>,
+        remaps: &HashMap<Pubkey, Pubkey>,
+    ) -> Vec<OwnerRemapReport> {
+        if remaps.is_empty() {
+            return Vec::new();
+        }
+        let mut counts: HashMap<Pubkey, usize> = HashMap::new();
+        for account in accounts.values_mut() {
+            if let Some(to) = remaps.get(account.owner()) {
+                *counts.entry(*account.owner()).or_default() += 1;
+                account.set_owner(*to);
+            }
+        }
+        let mut reports: Vec<_> = remaps
+            .iter()
+            .map(|(from, to)| OwnerRemapReport {
+                from: *from,
+                to: *to,
+                accounts_remapped: counts.get(from).copied().unwrap_or_default(),
+            })
+            .collect();
+        reports.sort_by_key(|report| report.from);
+        for report in &reports {
+            log::info!(
+                "Remapped the owner of {} accounts from {} to {}",
+                report.accounts_remapped,
+                report.from,
+                report.to
+            );
+        }
+        reports
+    }
+
+    /// A named set of owner programs whose accounts make up one snapshot of a
+    /// split merge
+    #[derive(Debug, Clone, PartialEq, Eq)]
+    pub struct OwnerGroup {
+        /// Also the name of the group's output subdirectory
+        pub name: String,
+        pub owners: Vec<Pubkey>,
+    }
+
+    /// Parses `NAME=PUBKEY[,PUBKEY...]`. Names are limited to ASCII letters,
+    /// digits, `-` and `_` since they become directory names.
+    pub fn parse_owner_group(s: &str) -> Result<OwnerGroup, MergeError> {
+        let (name, owners) = s.split_once('=').ok_or_else(|| {
+            MergeError::InvalidInput(format!(
+                "Invalid owner group '{}', expected NAME=PUBKEY[,PUBKEY...]",
+                s
+            ))
+        })?;
+        let name = name.trim();
+        if name.is_empty()
+            || !name
+                .chars()
+                .all(|c| c.is_ascii_alphanumeric() || c == '-' || c == '_')
+        {
+            return Err(MergeError::InvalidInput(format!(
+                "Invalid owner group name '{}', use letters, digits, '-' and '_'",
+                name
+            )));
+        }
+        let owners = owners
+            .split(',')
+            .map(|owner| {
+                Pubkey::from_str(owner.trim()).map_err(|e| {
+                    MergeError::InvalidInput(format!("Invalid owner '{}': {:?}", owner, e))
+                })
+            })
+            .collect::<Result<Vec<_>, _>>()?;
+        Ok(OwnerGroup {
+            name: name.to_string(),
+            owners,
+        })
+    }
+
+    /// Limits the total data bytes kept for each capped owner. Accounts of an
+    /// owner are taken in pubkey order until the next one would exceed the
+    /// cap; it and every later account of that owner are dropped, so the same
+    /// input always yields the same subset.
+    pub fn apply_owner_data_caps(
+        accounts: &mut HashMap<Pubkey, AccountSharedData>,
+        caps: &HashMap<Pubkey, u64>,
+    ) -> Vec<OwnerDataCapReport> {
+        if caps.is_empty() {
+            return Vec::new();
+        }
+        let mut reports: HashMap<Pubkey, OwnerDataCapReport> = caps
+            .iter()
+            .map(|(owner, cap_bytes)| {
+                let report = OwnerDataCapReport {
+                    owner: *owner,
+                    cap_bytes: *cap_bytes,
+                    bytes_copied: 0,
+                    accounts_copied: 0,
+                    bytes_skipped: 0,
+                    accounts_skipped: 0,
+                };
+                (*owner, report)
+            })
+            .collect();
+
+        let mut skipped = Vec::new();
+        for (pubkey, account) in sort_accounts_by_pubkey(accounts) {
+            let Some(report) = reports.get_mut(account.owner()) else {
+                continue;
+            };
+            let data_len = account.data().len() as u64;
+            if report.accounts_skipped == 0 && report.bytes_copied + data_len <= report.cap_bytes {
+                report.bytes_copied += data_len;
+                report.accounts_copied += 1;
+            } else {
+                report.bytes_skipped += data_len;
+                report.accounts_skipped += 1;
+                skipped.push(*pubkey);
+            }
+        }
+        for pubkey in &skipped {
+            accounts.remove(pubkey);
+        }
+
+        let mut reports: Vec<_> = reports.into_values().collect();
+        reports.sort_unstable_by_key(|report| report.owner);
+        for report in &reports {
+            log::info!(
+                "Owner {}: copied {} bytes in {} accounts, skipped {} bytes in {} accounts \
+                 (cap {} bytes)",
+                report.owner,
+                report.bytes_copied,
+                report.accounts_copied,
+                report.bytes_skipped,
+                report.accounts_skipped,
+                report.cap_bytes
+            );
+        }
+        reports
+    }
+
+    /// What to do with mainnet's epoch rewards sysvar
+    #[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+    pub enum EpochRewardsPolicy {
+        /// Copy the sysvar with `active` cleared
+        #[default]
+        Reset,
+        /// Keep the merge ledger's own sysvar
+        Skip,
+    }
+
+    impl FromStr for EpochRewardsPolicy {
+        type Err = String;
+
+        fn from_str(s: &str) -> Result<Self, Self::Err> {
+            match s {
+                "reset" => Ok(Self::Reset),
+                "skip" => Ok(Self::Skip),
+                _ => Err(format!(
+                    "Invalid epoch rewards policy '{}', expected 'reset' or 'skip'",
+                    s
+                )),
+            }
+        }
+    }
+
+    /// Handles the epoch rewards sysvar in the copy set. A mainnet bank captured
+    /// during partitioned rewards distribution has an active sysvar, which the
+    /// merge ledger has no matching distribution state for. Returns whether the
+    /// copied sysvar was active.
+    pub fn apply_epoch_rewards_policy(
+        accounts: &mut HashMap<Pubkey, AccountSharedData>,
+        policy: EpochRewardsPolicy,
+    ) -> Result<bool, MergeError> {
+        let sysvar_id = solana_sdk_ids::sysvar::epoch_rewards::id();
+        let Some(account) = accounts.get_mut(&sysvar_id) else {
+            return Ok(false);
+        };
+        let mut epoch_rewards: EpochRewards =
+            bincode::deserialize(account.data()).map_err(|e| {
+                MergeError::AccountData(format!("Failed to parse epoch rewards sysvar: {:?}", e))
+            })?;
+        let active = epoch_rewards.active;
+        if active {
+            log::warn!(
+                "Mainnet was captured mid epoch rewards distribution ({} of {} lamports \
+                 distributed over {} partitions)",
+                epoch_rewards.distributed_rewards,
+                epoch_rewards.total_rewards,
+                epoch_rewards.num_partitions
+            );
+        }
+
+        match policy {
+            EpochRewardsPolicy::Skip => {
+                accounts.remove(&sysvar_id);
+                log::info!("Skipping the epoch rewards sysvar");
+            }
+            EpochRewardsPolicy::Reset if active => {
+                epoch_rewards.active = false;
+                bincode::serialize_into(account.data_as_mut_slice(), &epoch_rewards).map_err(
+                    |e| {
+                        MergeError::AccountData(format!(
+                            "Failed to write epoch rewards sysvar: {:?}",
+                            e
+                        ))
+                    },
+                )?;
+                log::info!("Reset the epoch rewards sysvar to inactive");
+            }
+            EpochRewardsPolicy::Reset => {}
+        }
+        Ok(active)
+    }
+
+    /// What to do with copied durable nonce accounts, whose stored nonce
+    /// derives from a mainnet blockhash
+    #[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+    pub enum NoncePolicy {
+        /// Copy them unchanged
+        #[default]
+        Keep,
+        /// Leave them out of the copy
+        Drop,
+        /// Reset the stored nonce to the all-zero hash, so no transaction
+        /// signed against mainnet's nonce can use it. A transaction naming
+        /// the all-zero hash as its blockhash still can, so this only guards
+        /// against replayed mainnet transactions; `Drop` leaves none usable.
+        Zero,
+        /// Set the nonce to the one advancing it in the merged bank would
+        Advance,
+    }
+
+    impl FromStr for NoncePolicy {
+        type Err = String;
+
+        fn from_str(s: &str) -> Result<Self, Self::Err> {
+            match s {
+                "keep" => Ok(Self::Keep),
+                "drop" => Ok(Self::Drop),
+                "zero" => Ok(Self::Zero),
+                "advance" => Ok(Self::Advance),
+                _ => Err(format!(
+                    "Invalid nonce policy '{}', expected 'keep', 'drop', 'zero' or 'advance'",
+                    s
+                )),
+            }
+        }
+    }
+
+    /// The data of an initialized durable nonce account
+    fn nonce_data(account: &AccountSharedData) -> Option<NonceData> {
+        if account.owner() != &system_program::id() || account.data().len() != NonceState::size() {
+            return None;
+        }
+        match bincode::deserialize::<NonceVersions>(account.data())
+            .ok()?
+            .state()
+        {
+            NonceState::Initialized(data) => Some(data.clone()),
+            NonceState::Uninitialized => None,
+        }
+    }
+
+    /// Applies `policy` to the initialized durable nonce accounts in
+    /// `accounts`. [`NoncePolicy::Advance`] derives the nonce from `blockhash`
+    /// and charges `lamports_per_signature`, as the merged bank's
+    /// `AdvanceNonceAccount` would. Returns how many nonce accounts were found.
+    pub fn apply_nonce_policy(
+        accounts: &mut HashMap<Pubkey, AccountSharedData>,
+        policy: NoncePolicy,
+        blockhash: &Hash,
+        lamports_per_signature: u64,
+    ) -> Result<usize, MergeError> {
+        let nonces: Vec<_> = accounts
+            .iter()
+            .filter_map(|(pubkey, account)| nonce_data(account).map(|data| (*pubkey, data)))
+            .collect();
+        for (pubkey, data) in &nonces {
+            let (durable_nonce, lamports_per_signature) = match policy {
+                NoncePolicy::Keep => continue,
+                NoncePolicy::Drop => {
+                    accounts.remove(pubkey);
+                    continue;
+                }
+                NoncePolicy::Zero => (DurableNonce::default(), data.get_lamports_per_signature()),
+                NoncePolicy::Advance => (
+                    DurableNonce::from_blockhash(blockhash),
+                    lamports_per_signature,
+                ),
+            };
+            let state = NonceVersions::new(NonceState::new_initialized(
+                &data.authority,
+                durable_nonce,
+                lamports_per_signature,
+            ));
+            let account = accounts.get_mut(pubkey).unwrap();
+            bincode::serialize_into(account.data_as_mut_slice(), &state).map_err(|e| {
+                MergeError::AccountData(format!(
+                    "Failed to rewrite nonce account {}: {}",
+                    pubkey, e
+                ))
+            })?;
+        }
+        if !nonces.is_empty() {
+            log::info!(
+                "Found {} durable nonce accounts, handled with the {:?} policy",
+                nonces.len(),
+                policy
+            );
+        }
+        Ok(nonces.len())
+    }
+
+    #[derive(Debug, Clone, PartialEq, Eq)]
+    pub struct TopAccount {
+        pub pubkey: Pubkey,
+        pub owner: Pubkey,
+        pub lamports: u64,
+    }
+
+    /// The `n` accounts with the most lamports, largest first, ties broken by
+    /// pubkey. Keeps a min-heap of at most `n` entries, so each account costs
+    /// O(log n).
+    pub fn top_accounts_by_lamports<'a>(
+        accounts: impl IntoIterator<Item = (&'a Pubkey, &'a AccountSharedData)>,
+        n: usize,
+    ) -> Vec<TopAccount> {
+        if n == 0 {
+            return Vec::new();
+        }
+        let mut heap = BinaryHeap::with_capacity(n + 1);
+        for (pubkey, account) in accounts {
+            heap.push(Reverse((
+                account.lamports(),
+                Reverse(*pubkey),
+                *account.owner(),
+            )));
+            if heap.len() > n {
+                heap.pop();
+            }
+        }
+        heap.into_sorted_vec()
+            .into_iter()
+            .map(|Reverse((lamports, Reverse(pubkey), owner))| TopAccount {
+                pubkey,
+                owner,
+                lamports,
+            })
+            .collect()
+    }
+
+    /// The `n` largest of two `top_accounts_by_lamports` lists, in the same
+    /// order, for combining the tops of accounts copied in separate chunks
+    pub fn merge_top_accounts(a: Vec<TopAccount>, b: Vec<TopAccount>, n: usize) -> Vec<TopAccount> {
+        let mut merged: Vec<_> = a.into_iter().chain(b).collect();
+        merged.sort_by_key(|account| (Reverse(account.lamports), account.pubkey));
+        merged.truncate(n);
+        merged
+    }
+
+    /// Whether two accounts have the same lamports, owner, executable flag and
+    /// data. `rent_epoch` is not compared.
+    pub fn accounts_equal(a: &AccountSharedData, b: &AccountSharedData) -> bool {
+        a.lamports() == b.lamports()
+            && a.owner() == b.owner()
+            && a.executable() == b.executable()
+            && a.data() == b.data()
+    }
+
+    /// Visits the accounts of `source` that `not_in` lacks or holds with
+    /// different contents, in pubkey order unless `consistency` is
+    /// [`ScanConsistency::Latest`] (see [`accounts_equal`]). `source` is
+    /// streamed through one `scan_all_accounts` pass and each of its pubkeys
+    /// is looked up in `not_in` as it is visited, so neither bank is
+    /// materialized.
+    pub fn accounts_only_in(
+        source: &Bank,
+        not_in: &Bank,
+        consistency: ScanConsistency,
+        mut visit: impl FnMut(Pubkey, AccountSharedData),
+    ) -> Result<(), MergeError> {
+        for_each_account(
+            source,
+            AccountEnumeration::Scan,
+            consistency,
+            |pubkey, account| {
+                if not_in
+                    .get_account(&pubkey)
+                    .is_none_or(|other| !accounts_equal(&other, &account))
+                {
+                    visit(pubkey, account);
+                }
+            },
+        )
+    }
+
+    /// Drops accounts whose lamports, owner, data and executable flag already
+    /// match the account stored under the same pubkey in `base_bank`, so they
+    /// are not stored again. Returns how many were dropped.
+    pub fn remove_unchanged_accounts(
+        base_bank: &Bank,
+        accounts: &mut HashMap<Pubkey, AccountSharedData>,
+    ) -> usize {
+        let before = accounts.len();
+        accounts.retain(|pubkey, account| {
+            base_bank
+                .get_account(pubkey)
+                .is_none_or(|base| !accounts_equal(&base, account))
+        });
+        let skipped = before - accounts.len();
+        log::info!("Skipped {} accounts identical to the base bank", skipped);
+        skipped
+    }
+
+    /// What to do with address lookup table accounts copied from mainnet
+    #[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+    pub enum AltPolicy {
+        /// Copy tables unchanged
+        #[default]
+        Copy,
+        /// Leave tables out of the copy set
+        Skip,
+        /// Copy tables with their slot fields rebased onto the merged bank
+        Refresh,
+    }
+
+    impl FromStr for AltPolicy {
+        type Err = String;
+
+        fn from_str(s: &str) -> Result<Self, Self::Err> {
+            match s {
+                "copy" => Ok(Self::Copy),
+                "skip" => Ok(Self::Skip),
+                "refresh" => Ok(Self::Refresh),
+                _ => Err(format!(
+                    "Invalid ALT policy '{}', expected 'copy', 'skip' or 'refresh'",
+                    s
+                )),
+            }
+        }
+    }
+
+    pub fn is_address_lookup_table(account: &AccountSharedData) -> bool {
+        *account.owner() == address_lookup_table::id()
+    }
+
+    /// Rebases the slot fields of serialized lookup table data onto `slot`.
+    ///
+    /// Mainnet slots are far ahead of the merged ledger, so a table last
+    /// extended at a mainnet slot would look like it was extended in the
+    /// future and hide its newest addresses. `last_extended_slot` is capped at
+    /// `slot`. A deactivated or deactivating table is left as it is and `None`
+    /// returned: its mainnet deactivation slot is missing from the merged
+    /// ledger's slot hashes, so it stays deactivated. The address list is left
+    /// untouched.
+    pub fn refresh_lookup_table(data: &[u8], slot: Slot) -> Result<Option<Vec<u8>>, MergeError> {
+        let table = AddressLookupTable::deserialize(data).map_err(|e| {
+            MergeError::AccountData(format!("Failed to parse address lookup table: {:?}", e))
+        })?;
+        let mut meta = table.meta;
+        if meta.deactivation_slot != Slot::MAX {
+            return Ok(None);
+        }
+        meta.last_extended_slot = meta.last_extended_slot.min(slot);
+
+        let mut refreshed = data.to_vec();
+        AddressLookupTable::overwrite_meta_data(&mut refreshed, meta).map_err(|e| {
+            MergeError::AccountData(format!("Failed to write address lookup table: {:?}", e))
+        })?;
+        Ok(Some(refreshed))
+    }
+
+    /// What `refresh_lookup_tables` did to the lookup tables it was given
+    #[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+    pub struct LookupTableRefresh {
+        pub refreshed: usize,
+        /// Tables that failed to parse, such as uninitialized ones, copied as
+        /// they are
+        pub malformed: usize,
+    }
+
+    /// Refreshes every active lookup table in `accounts` against `slot`
+    pub fn refresh_lookup_tables(
+        accounts: &mut HashMap<Pubkey, AccountSharedData>,
+        slot: Slot,
+    ) -> LookupTableRefresh {
+        let mut refresh = LookupTableRefresh::default();
+        for (pubkey, account) in accounts.iter_mut() {
+            if !is_address_lookup_table(account) {
+                continue;
+            }
+            match refresh_lookup_table(account.data(), slot) {
+                Ok(Some(data)) => {
+                    account.set_data_from_slice(&data);
+                    refresh.refreshed += 1;
+                }
+                Ok(None) => {}
+                Err(e) => {
+                    if refresh.malformed == 0 {
+                        log::warn!("Copying lookup table {} as it is: {}", pubkey, e);
+                    }
+                    refresh.malformed += 1;
+                }
+            }
+        }
+        log::info!(
+            "Refreshed {} address lookup tables to slot {}, {} malformed ones copied as they are",
+            refresh.refreshed,
+            slot,
+            refresh.malformed
+        );
+        refresh
+    }
+
+    /// `rent_epoch` that marks an account rent-exempt once the
+    /// `set_exempt_rent_epoch_max` feature is active
+    pub const RENT_EXEMPT_RENT_EPOCH: Epoch = Epoch::MAX;
+
+    /// The `rent_epoch` `bank` records for rent-exempt accounts. With
+    /// `set_exempt_rent_epoch_max` active that is [`RENT_EXEMPT_RENT_EPOCH`];
+    /// before it, rent collection stamped exempt accounts with the next epoch.
+    pub fn exempt_rent_epoch(bank: &Bank) -> Epoch {
+        if bank
+            .feature_set
+            .is_active(&agave_feature_set::set_exempt_rent_epoch_max::id())
+        {
+            RENT_EXEMPT_RENT_EPOCH
+        } else {
+            bank.epoch() + 1
+        }
+    }
+
+    /// The `rent_epoch` to normalize copied accounts to
+    #[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+    pub enum RentEpochTarget {
+        /// Rent-exempt accounts take [`exempt_rent_epoch`]; rent-paying
+        /// accounts keep theirs, since it records when rent is due
+        #[default]
+        Convention,
+        /// Every account takes [`RENT_EXEMPT_RENT_EPOCH`]
+        Max,
+        /// Every account takes the bank's epoch
+        Current,
+    }
+
+    impl FromStr for RentEpochTarget {
+        type Err = String;
+
+        fn from_str(s: &str) -> Result<Self, Self::Err> {
+            match s {
+                "convention" => Ok(Self::Convention),
+                "max" => Ok(Self::Max),
+                "current" => Ok(Self::Current),
+                _ => Err(format!(
+                    "Invalid rent epoch target '{}', expected 'convention', 'max' or 'current'",
+                    s
+                )),
+            }
+        }
+    }
+
+    /// Rewrites the `rent_epoch` of `accounts` as `target` says for `bank`,
+    /// returning how many changed.
+    ///
+    /// Ledgers from before `set_exempt_rent_epoch_max` hold epoch numbers for
+    /// exempt accounts while newer ones hold `u64::MAX`, and the two sides of
+    /// a merge may disagree; an account carrying the other convention would
+    /// hash and be treated differently from the ones the merged bank writes.
+    /// Epoch numbers from mainnet's schedule also mean nothing under the
+    /// merge ledger's, which `Max` and `Current` replace outright.
+    pub fn normalize_rent_epochs(
+        bank: &Bank,
+        accounts: &mut HashMap<Pubkey, AccountSharedData>,
+        target: RentEpochTarget,
+    ) -> usize {
+        let rent = &bank.rent_collector().rent;
+        let rent_epoch = match target {
+            RentEpochTarget::Convention => exempt_rent_epoch(bank),
+            RentEpochTarget::Max => RENT_EXEMPT_RENT_EPOCH,
+            RentEpochTarget::Current => bank.epoch(),
+        };
+        let mut normalized = 0;
+        for account in accounts.values_mut() {
+            if account.rent_epoch() != rent_epoch
+                && (target != RentEpochTarget::Convention
+                    || rent.is_exempt(account.lamports(), account.data().len()))
+            {
+                account.set_rent_epoch(rent_epoch);
+                normalized += 1;
+            }
+        }
+        log::info!(
+            "Normalized the rent_epoch of {} accounts to {} ({:?})",
+            normalized,
+            rent_epoch,
+            target
+        );
+        normalized
+    }
+
+    /// The SPL Token program
+    pub const SPL_TOKEN_PROGRAM_ID: Pubkey =
+        Pubkey::from_str_const("TokenkegQfeZyiNwAJbNbGKPFXCWuBvf9Ss623VQ5DA");
+    /// The Token-2022 program, whose accounts without extensions share the
+    /// SPL Token layout
+    pub const SPL_TOKEN_2022_PROGRAM_ID: Pubkey =
+        Pubkey::from_str_const("TokenzQdBNbLqP5VEhdkAjS6EEVvpBKiYMVohGomwLr");
+    /// Length of an SPL token account
+    pub const SPL_TOKEN_ACCOUNT_LEN: usize = 165;
+
+    const TOKEN_OWNER_OFFSET: usize = 32;
+    const TOKEN_DELEGATE_OFFSET: usize = 72;
+    const TOKEN_STATE_OFFSET: usize = 108;
+    const TOKEN_DELEGATED_AMOUNT_OFFSET: usize = 121;
+    const TOKEN_CLOSE_AUTHORITY_OFFSET: usize = 129;
+    const TOKEN_STATE_UNINITIALIZED: u8 = 0;
+    const TOKEN_STATE_INITIALIZED: u8 = 1;
+
+    /// Whether `account` is an initialized SPL token account. Token-2022
+    /// accounts only count when they carry no extensions, since anything past
+    /// the base layout could hold authorities of its own.
+    pub fn is_token_account(account: &AccountSharedData) -> bool {
+        let owner = account.owner();
+        (*owner == SPL_TOKEN_PROGRAM_ID || *owner == SPL_TOKEN_2022_PROGRAM_ID)
+            && account.data().len() == SPL_TOKEN_ACCOUNT_LEN
+            && account.data()[TOKEN_STATE_OFFSET] != TOKEN_STATE_UNINITIALIZED
+    }
+
+    /// Rewrites a token account so only its balance carries over: the owner
+    /// becomes `authority`, the delegate, delegated amount and close authority
+    /// are cleared and a frozen account is thawed. The mint, amount and native
+    /// reserve are kept.
+    pub fn reconstruct_token_balance(account: &mut AccountSharedData, authority: &Pubkey) {
+        let data = account.data_as_mut_slice();
+        data[TOKEN_OWNER_OFFSET..TOKEN_OWNER_OFFSET + 32].copy_from_slice(authority.as_ref());
+        // A COption is a 4-byte tag followed by the value; zero both
+        data[TOKEN_DELEGATE_OFFSET..TOKEN_STATE_OFFSET].fill(0);
+        data[TOKEN_STATE_OFFSET] = TOKEN_STATE_INITIALIZED;
+        data[TOKEN_DELEGATED_AMOUNT_OFFSET..TOKEN_CLOSE_AUTHORITY_OFFSET].fill(0);
+        data[TOKEN_CLOSE_AUTHORITY_OFFSET..SPL_TOKEN_ACCOUNT_LEN].fill(0);
+    }
+
+    /// Reduces the token accounts about to be copied to their balances (see
+    /// [`reconstruct_token_balance`]), returning how many were rewritten.
+    /// Other accounts are left alone.
+    pub fn reconstruct_token_balances(
+        accounts: &mut HashMap<Pubkey, AccountSharedData>,
+        authority: &Pubkey,
+    ) -> usize {
+        let mut reconstructed = 0;
+        for account in accounts.values_mut() {
+            if is_token_account(account) {
+                reconstruct_token_balance(account, authority);
+                reconstructed += 1;
+            }
+        }
+        log::info!(
+            "Reconstructed {} token balances under authority {}",
+            reconstructed,
+            authority
+        );
+        reconstructed
+    }
+
+    /// Checks the invariants the runtime keeps for every account it stores:
+    /// - the data is no longer than `MAX_ACCOUNT_DATA_LEN`;
+    /// - the account doesn't own itself; any 32 bytes are a pubkey, but only
+    ///   the program at an address could write an account it owns, and
+    ///   programs are owned by loaders;
+    /// - executable accounts are owned by a loader or the native loader.
+    ///
+    /// A balance below the default rent-exemption minimum is not an error,
+    /// since rent-paying accounts still exist on mainnet; see
+    /// [`log_rent_paying_accounts`].
+    pub fn validate_account(
+        pubkey: &Pubkey,
+        account: &AccountSharedData,
+    ) -> Result<(), MergeError> {
+        let data_len = account.data().len();
+        if data_len > MAX_ACCOUNT_DATA_LEN {
+            return Err(MergeError::AccountData(format!(
+                "data length {} is above the maximum of {}",
+                data_len, MAX_ACCOUNT_DATA_LEN
+            )));
+        }
+        if account.owner() == pubkey {
+            return Err(MergeError::AccountData(
+                "account is its own owner".to_string(),
+            ));
+        }
+        if account.executable()
+            && !LOADER_PROGRAM_IDS.contains(account.owner())
+            && *account.owner() != native_loader::ID
+        {
+            return Err(MergeError::AccountData(format!(
+                "executable account is owned by {}, which is not a loader",
+                account.owner()
+            )));
+        }
+        Ok(())
+    }
+
+    /// Logs one warning for the accounts holding less than the default
+    /// rent-exemption minimum for their data length, naming a few of them,
+    /// and returns how many there are.
+    pub fn log_rent_paying_accounts(accounts: &HashMap<Pubkey, AccountSharedData>) -> usize {
+        let rent = Rent::default();
+        let mut rent_paying: Vec<_> = accounts
+            .iter()
+            .filter(|(_, account)| account.lamports() < rent.minimum_balance(account.data().len()))
+            .map(|(pubkey, account)| (*pubkey, account.lamports(), account.data().len()))
+            .collect();
+        if rent_paying.is_empty() {
+            return 0;
+        }
+        rent_paying.sort_unstable_by_key(|(pubkey, _, _)| *pubkey);
+        let examples: Vec<_> = rent_paying
+            .iter()
+            .take(MAX_REPORTED_INVALID_ACCOUNTS)
+            .map(|(pubkey, lamports, data_len)| {
+                format!("{} ({} lamports for {} bytes)", pubkey, lamports, data_len)
+            })
+            .collect();
+        log::warn!(
+            "{} accounts hold less than the rent-exempt minimum, e.g. {}",
+            rent_paying.len(),
+            examples.join(", ")
+        );
+        rent_paying.len()
+    }
+
+    /// Runs [`validate_account`] over the accounts about to be copied. Invalid
+    /// accounts are dropped and counted, or fail the merge if `strict`; the
+    /// rent-paying ones among the rest are logged in one warning.
+    pub fn remove_invalid_accounts(
+        accounts: &mut HashMap<Pubkey, AccountSharedData>,
+        strict: bool,
+    ) -> Result<usize, MergeError> {
+        let mut invalid: Vec<_> = accounts
+            .iter()
+            .filter_map(|(pubkey, account)| {
+                validate_account(pubkey, account)
+                    .err()
+                    .map(|reason| (*pubkey, reason))
+            })
+            .collect();
+        invalid.sort_unstable_by_key(|(pubkey, _)| *pubkey);
+        if strict && !invalid.is_empty() {
+            let examples: Vec<_> = invalid
+                .iter()
+                .take(MAX_REPORTED_INVALID_ACCOUNTS)
+                .map(|(pubkey, reason)| format!("{}: {}", pubkey, reason))
+                .collect();
+            return Err(MergeError::AccountData(format!(
+                "{} of {} accounts failed validation, e.g. {}",
+                invalid.len(),
+                accounts.len(),
+                examples.join("; ")
+            )));
+        }
+        if !invalid.is_empty() {
+            let examples: Vec<_> = invalid
+                .iter()
+                .take(MAX_REPORTED_INVALID_ACCOUNTS)
+                .map(|(pubkey, reason)| format!("{}: {}", pubkey, reason))
+                .collect();
+            log::warn!(
+                "Skipping {} invalid accounts, e.g. {}",
+                invalid.len(),
+                examples.join("; ")
+            );
+        }
+        for (pubkey, _) in &invalid {
+            accounts.remove(pubkey);
+        }
+        log_rent_paying_accounts(accounts);
+        Ok(invalid.len())
+    }
+
+    /// Drops the accounts whose `rent_epoch` is more than `max_age_epochs`
+    /// behind `current_epoch`, returning how many were dropped. Accounts
+    /// carrying [`RENT_EXEMPT_RENT_EPOCH`] are always kept.
+    pub fn remove_dormant_accounts(
+        accounts: &mut HashMap<Pubkey, AccountSharedData>,
+        current_epoch: Epoch,
+        max_age_epochs: Epoch,
+    ) -> usize {
+        let before = accounts.len();
+        accounts.retain(|_, account| {
+            account.rent_epoch() == RENT_EXEMPT_RENT_EPOCH
+                || account.rent_epoch().saturating_add(max_age_epochs) >= current_epoch
+        });
+        let removed = before - accounts.len();
+        log::info!(
+            "Skipped {} accounts with a rent_epoch more than {} epochs before epoch {}",
+            removed,
+            max_age_epochs,
+            current_epoch
+        );
+        removed
+    }
+
+    pub fn is_feature_account(account: &AccountSharedData) -> bool {
+        account.owner() == &feature::id()
+    }
+
+    /// Whose feature-gate accounts the merged bank ends up with
+    #[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+    pub enum FeatureSetPolicy {
+        /// Leave mainnet's feature accounts out
+        #[default]
+        MergeLedger,
+        /// Copy mainnet's feature accounts and close the merge ledger's own,
+        /// so only features mainnet has stay active
+        Mainnet,
+        /// Copy mainnet's feature accounts except those the merge ledger has
+        /// activated, so a feature active on either side stays active
+        Union,
+        /// Copy only the mainnet feature accounts in `MergeConfig::feature_list`
+        ListFile,
+    }
+
+    impl FromStr for FeatureSetPolicy {
+        type Err = String;
+
+        fn from_str(s: &str) -> Result<Self, Self::Err> {
+            match s {
+                "merge-ledger" => Ok(Self::MergeLedger),
+                "mainnet" => Ok(Self::Mainnet),
+                "union" => Ok(Self::Union),
+                "list-file" => Ok(Self::ListFile),
+                _ => Err(format!(
+                    "Invalid feature set '{}', expected 'merge-ledger', 'mainnet', 'union' or \
+                     'list-file'",
+                    s
+                )),
+            }
+        }
+    }
+
+    impl FeatureSetPolicy {
+        /// Whether the mainnet scan keeps the feature account `pubkey`
+        pub fn copies_from_mainnet(self, pubkey: &Pubkey, feature_list: &HashSet<Pubkey>) -> bool {
+            match self {
+                Self::MergeLedger => false,
+                Self::Mainnet | Self::Union => true,
+                Self::ListFile => feature_list.contains(pubkey),
+            }
+        }
+    }
+
+    /// Applies `policy` to the mainnet feature accounts the scan kept in
+    /// `accounts`, given the merge ledger's `merge_features`. Only
+    /// [`FeatureSetPolicy::Union`] leaves any out here: each feature the merge
+    /// ledger has activated, so its own activation slot stands. Returns how
+    /// many were left out.
+    pub fn filter_feature_accounts(
+        accounts: &mut HashMap<Pubkey, AccountSharedData>,
+        policy: FeatureSetPolicy,
+        merge_features: &HashMap<Pubkey, AccountSharedData>,
+    ) -> usize {
+        if policy != FeatureSetPolicy::Union {
+            return 0;
+        }
+        let before = accounts.len();
+        accounts.retain(|pubkey, account| {
+            !is_feature_account(account)
+                || !merge_features.get(pubkey).is_some_and(|merge_feature| {
+                    solana_feature_gate_interface::from_account(merge_feature)
+                        .is_some_and(|feature| feature.activated_at.is_some())
+                })
+        });
+        before - accounts.len()
+    }
+
+    /// Feature-gate accounts found in a set of accounts to copy
+    #[derive(Debug, Clone, Default, PartialEq, Eq)]
+    pub struct FeatureAccountAudit {
+        pub feature_accounts: usize,
+        /// Feature accounts with an activation slot set
+        pub activated: usize,
+        /// Feature ids the linked runtime does not know, which stay inert
+        pub unknown: Vec<Pubkey>,
+        /// Mainnet feature accounts [`filter_feature_accounts`] left out in
+        /// favor of the merge ledger's activated ones
+        pub kept_merge_ledger: usize,
+        /// Activated feature accounts [`rebase_feature_activations`] moved
+        /// back to the merged bank's slot
+        pub rebased: usize,
+    }
+
+    /// Moves the activation slot of every feature account in `accounts` that
+    /// activates after `slot` back to `slot`. A feature is only active in a
+    /// bank whose slot has reached its activation slot, and mainnet's lie far
+    /// past the merged bank's, so unmoved they would never be active in the
+    /// merged cluster. Returns how many were moved.
+    pub fn rebase_feature_activations(
+        accounts: &mut HashMap<Pubkey, AccountSharedData>,
+        slot: Slot,
+    ) -> usize {
+        let mut rebased = 0;
+        for account in accounts.values_mut() {
+            if !is_feature_account(account) {
+                continue;
+            }
+            let Some(mut feature) = solana_feature_gate_interface::from_account(account)
+                .filter(|feature| feature.activated_at.is_some_and(|at| at > slot))
+            else {
+                continue;
+            };
+            feature.activated_at = Some(slot);
+            if solana_feature_gate_interface::to_account(&feature, account).is_some() {
+                rebased += 1;
+            }
+        }
+        if rebased > 0 {
+            log::info!(
+                "Moved the activation of {} mainnet features back to slot {}",
+                rebased,
+                slot
+            );
+        }
+        rebased
+    }
+
+    /// Counts the feature-gate accounts in `accounts` and logs each feature the
+    /// linked runtime has no name for: the runtime never reads it, so copying
+    /// it changes nothing
+    pub fn audit_feature_accounts(
+        accounts: &HashMap<Pubkey, AccountSharedData>,
+    ) -> FeatureAccountAudit {
+        let mut audit = FeatureAccountAudit::default();
+        for (pubkey, account) in accounts {
+            if !is_feature_account(account) {
+                continue;
+            }
+            audit.feature_accounts += 1;
+            let activated_at = solana_feature_gate_interface::from_account(account)
+                .and_then(|feature| feature.activated_at);
+            if activated_at.is_some() {
+                audit.activated += 1;
+            }
+            if !agave_feature_set::FEATURE_NAMES.contains_key(pubkey) {
+                log::warn!(
+                    "Mainnet feature {} (activated at {:?}) is unknown to this runtime and will be inert",
+                    pubkey,
+                    activated_at
+                );
+                audit.unknown.push(*pubkey);
+            }
+        }
+        audit.unknown.sort_unstable();
+        log::info!(
+            "Copying {} mainnet feature accounts, {} activated, {} unknown to this runtime",
+            audit.feature_accounts,
+            audit.activated,
+            audit.unknown.len()
+        );
+        audit
+    }
+
+    /// Returns the accounts ordered by pubkey
+    pub fn sort_accounts_by_pubkey(
+        accounts: &HashMap<Pubkey, AccountSharedData>,
+    ) -> Vec<(&Pubkey, &AccountSharedData)> {
+        let mut sorted: Vec<_> = accounts.iter().collect();
+        sorted.sort_unstable_by(|a, b| a.0.cmp(b.0));
+        sorted
+    }
+
+    /// Splits pubkey-sorted accounts into per-slot segments with the same
+    /// accounting as `add_accounts`
+    fn split_into_slot_segments<'a>(
+        sorted: &'a [(&'a Pubkey, &'a AccountSharedData)],
+        slot_split: SlotSplit,
+    ) -> Vec<&'a [(&'a Pubkey, &'a AccountSharedData)]> {
+        let sizes = plan_slot_sizes(sorted.iter().map(|(_, account)| *account), slot_split);
+        let mut segments = Vec::with_capacity(sizes.len());
+        let mut start = 0;
+        for size in sizes {
+            segments.push(&sorted[start..start + size]);
+            start += size;
+        }
+        segments
+    }
+
+    /// Adds accounts in pubkey order, storing each slot's accounts from `shards`
+    /// threads in parallel.
+    ///
+    /// Slot boundaries are derived from the pubkey-sorted account list and the
+    /// slot split alone, and each slot is then divided into `shards` contiguous
+    /// pubkey ranges stored concurrently into that slot's bank. Because both the
+    /// slot assignment and the contents of every slot are independent of
+    /// `shards`, the resulting bank is identical for any shard count.
+    ///
+    /// Tradeoffs compared to `add_accounts`:
+    /// - the whole account set is sorted up front, which needs an extra
+    ///   `Vec` of references and O(n log n) time before the first store;
+    /// - parallelism is bounded by slot: threads join at every slot boundary
+    ///   before the bank is flushed, squashed and advanced, so very small
+    ///   slots leave little work per thread;
+    /// - store throughput is ultimately limited by contention in the accounts
+    ///   cache, so shard counts beyond the number of cores rarely help.
+    ///
+    /// The threads also join every [`FlushPolicy::interval_accounts`] accounts
+    /// of a slot to flush, so `FlushPolicy::Adaptive` flushes on the default
+    /// interval here.
+    #[allow(clippy::too_many_arguments)]
+    pub fn add_accounts_sharded(
+        starting_bank: Arc<Bank>,
+        accounts: &HashMap<Pubkey, AccountSharedData>,
+        replaced_data_sizes: &HashMap<Pubkey, usize>,
+        account_type: &str,
+        slot_split: SlotSplit,
+        flush_policy: FlushPolicy,
+        shards: usize,
+        deadline: Option<&Deadline>,
+        reporting: CopyReporting<'_>,
+    ) -> Result<Arc<Bank>, MergeError> {
+        if shards == 0 {
+            return Err(MergeError::InvalidInput(
+                "Shard count must be at least 1".to_string(),
+            ));
+        }
+        log::info!(
+            "Adding {} {} accounts to merged bank using {} pubkey shards...",
+            accounts.len(),
+            account_type,
+            shards
+        );
+
+        let sorted = sort_accounts_by_pubkey(accounts);
+        let segments = split_into_slot_segments(&sorted, slot_split);
+        let mut current_bank = starting_bank;
+        let mut progress = CopyProgress::new(accounts.len());
+        let mut throughput = reporting
+            .throughput_log
+            .map(|log| log.sampler(&format!("adding {} accounts", account_type)));
+        let mut progress_bar = reporting
+            .progress_bar
+            .then(|| ProgressBar::new(&format!("adding {} accounts", account_type)));
+        let mut bytes_copied = 0;
+
+        for (segment_index, segment) in segments.iter().enumerate() {
+            if segment_index > 0 {
+                log::info!(
+                    "Reached {:?} for slot {}, squashing and advancing to next slot",
+                    slot_split,
+                    current_bank.slot()
+                );
+                current_bank.force_flush_accounts_cache();
+                current_bank.squash();
+                check_copy_deadline(deadline, &current_bank, account_type, &progress)?;
+
+                let parent = Arc::clone(&current_bank);
+                let next_slot = parent.slot() + 1;
+                let collector_id = *parent.collector_id();
+                current_bank = Arc::new(Bank::new_from_parent(parent, &collector_id, next_slot));
+            }
+
+            let flush_chunks = segment.chunks(flush_policy.interval_accounts());
+            for (chunk_index, flush_chunk) in flush_chunks.enumerate() {
+                if chunk_index > 0 {
+                    current_bank.force_flush_accounts_cache();
+                    check_copy_deadline(deadline, &current_bank, account_type, &progress)?;
+                }
+                let shard_len = flush_chunk.len().div_ceil(shards).max(1);
+                let bank = current_bank.as_ref();
+                std::thread::scope(|scope| {
+                    for shard in flush_chunk.chunks(shard_len) {
+                        scope.spawn(move || {
+                            for (pubkey, account) in shard {
+                                let old_data_size = record_replaced_account(
+                                    bank,
+                                    pubkey,
+                                    account,
+                                    replaced_data_sizes,
+                                    reporting.base_diff,
+                                );
+                                store_account_replacing(bank, pubkey, account, old_data_size);
+                            }
+                        });
+                    }
+                });
+
+                progress.add(flush_chunk.len());
+                bytes_copied += flush_chunk
+                    .iter()
+                    .map(|(_, account)| approx_stored_bytes(account))
+                    .sum::<u64>();
+                if let Some(progress_bar) = progress_bar.as_mut() {
+                    progress_bar.update(&progress, bytes_copied);
+                }
+            }
+            log::info!(
+                "Stored {} {} accounts in slot {}, overall {}",
+                segment.len(),
+                account_type,
+                current_bank.slot(),
+                progress.summary()
+            );
+            if let Some(progress_file) = reporting.progress_file {
+                progress_file.report(
+                    &format!("adding {} accounts", account_type),
+                    progress.copied(),
+                    current_bank.slot(),
+                );
+            }
+            if let Some(throughput) = throughput.as_mut() {
+                throughput.sample_if_due(progress.copied(), current_bank.slot());
+            }
+        }
+
+        current_bank.force_flush_accounts_cache();
+        if let Some(throughput) = throughput.as_mut() {
+            throughput.sample(progress.copied(), current_bank.slot());
+        }
+        if let Some(progress_bar) = progress_bar.as_mut() {
+            progress_bar.finish(&progress, bytes_copied);
+        }
+
+        log::info!("Added {} {} accounts", accounts.len(), account_type);
+        Ok(current_bank)
+    }
+
+    /// Returns whether a failed archive creation is worth retrying. I/O errors
+    /// are transient unless retrying cannot change the outcome (read-only or
+    /// missing paths, permissions); anything else is treated as permanent.
+    pub fn is_transient_snapshot_error(error: &SnapshotError) -> bool {
+        match error {
+            SnapshotError::Io(e) | SnapshotError::IoWithSource(e, _) => !matches!(
+                e.kind(),
+                std::io::ErrorKind::ReadOnlyFilesystem
+                    | std::io::ErrorKind::PermissionDenied
+                    | std::io::ErrorKind::NotFound
+                    | std::io::ErrorKind::InvalidInput
+                    | std::io::ErrorKind::Unsupported
+            ),
+            SnapshotError::ArchiveGenerationFailure(_) => true,
+            _ => false,
+        }
+    }
+
+    /// Checks that `name` is usable as an archive alias in `output_dir`: a
+    /// plain file name nothing in the directory already has
+    pub fn check_archive_name(output_dir: &Path, name: &str) -> Result<PathBuf, MergeError> {
+        if name.is_empty() || name == "." || name == ".." || name.contains('/') {
+            return Err(MergeError::InvalidInput(format!(
+                "Archive name '{}' must be a plain file name without '/'",
+                name
+            )));
+        }
+        let alias = output_dir.join(name);
+        if alias.symlink_metadata().is_ok() {
+            return Err(MergeError::InvalidInput(format!(
+                "Archive name {:?} already exists",
+                alias
+            )));
+        }
+        Ok(alias)
+    }
+
+    /// Makes `name` in the archive's directory a relative symlink to the
+    /// archive, leaving the canonical slot-and-hash name in place for the
+    /// snapshot loader. Returns the alias path.
+    pub fn link_archive_name(archive: &Path, name: &str) -> Result<PathBuf, MergeError> {
+        let output_dir = archive.parent().unwrap_or(Path::new("."));
+        let alias = check_archive_name(output_dir, name)?;
+        let target = archive.file_name().ok_or_else(|| {
+            MergeError::Io(format!("Archive path {:?} has no file name", archive))
+        })?;
+        std::os::unix::fs::symlink(target, &alias).map_err(|e| {
+            MergeError::Io(format!(
+                "Failed to link {:?} to {:?}: {:?}",
+                alias, archive, e
+            ))
+        })?;
+        Ok(alias)
+    }
+
+    /// The kind of snapshot archive an archive creation writes
+    #[derive(Debug, Clone, Copy, PartialEq, Eq)]
+    pub enum ArchiveKind {
+        Full,
+        Incremental,
+    }
+
+    impl ArchiveKind {
+        pub fn name(&self) -> &'static str {
+            match self {
+                Self::Full => "full",
+                Self::Incremental => "incremental",
+            }
+        }
+
+        /// Whether `file_name` is an archive of this kind for `slot`
+        fn is_archive_of(&self, file_name: &str, slot: Slot) -> bool {
+            match self {
+                Self::Full => file_name.starts_with(&format!("snapshot-{}-", slot)),
+                // incremental-snapshot-<base slot>-<slot>-<hash>
+                Self::Incremental => file_name
+                    .strip_prefix("incremental-snapshot-")
+                    .and_then(|rest| rest.split_once('-'))
+                    .is_some_and(|(_, rest)| rest.starts_with(&format!("{}-", slot))),
+            }
+        }
+    }
+
+    /// Removes what a failed `kind` archive creation for `slot` can leave
+    /// behind: the archive staging entries in `output_dir` and
+    /// `bank_snapshots_dir`, and partial `kind` archives of `slot` in
+    /// `output_dir`. Bank snapshots and finished archives of the other kind are
+    /// left alone. Returns the number of entries removed.
+    pub fn cleanup_partial_snapshot_artifacts(
+        output_dir: &Path,
+        bank_snapshots_dir: &Path,
+        slot: Slot,
+        kind: ArchiveKind,
+    ) -> Result<usize, MergeError> {
+        let mut removed = 0;
+        for (dir, holds_archives) in [(output_dir, true), (bank_snapshots_dir, false)] {
+            let entries = match std::fs::read_dir(dir) {
+                Ok(entries) => entries,
+                Err(e) if e.kind() == std::io::ErrorKind::NotFound => continue,
+                Err(e) => return Err(MergeError::Io(format!("Failed to read {:?}: {:?}", dir, e))),
+            };
+            for entry in entries {
+                let entry = entry
+                    .map_err(|e| MergeError::Io(format!("Failed to read {:?}: {:?}", dir, e)))?;
+                let name = entry.file_name().to_string_lossy().to_string();
+                let partial = name.starts_with(TMP_SNAPSHOT_ARCHIVE_PREFIX)
+                    || (holds_archives && kind.is_archive_of(&name, slot));
+                if !partial {
+                    continue;
+                }
+                let path = entry.path();
+                let result = if path.is_dir() {
+                    std::fs::remove_dir_all(&path)
+                } else {
+                    std::fs::remove_file(&path)
+                };
+                result
+                    .map_err(|e| MergeError::Io(format!("Failed to remove {:?}: {:?}", path, e)))?;
+                log::info!("Removed partial snapshot artifact {:?}", path);
+                removed += 1;
+            }
+        }
+        Ok(removed)
+    }
+
+    /// Runs `archive` until it succeeds, cleaning up after and retrying, after
+    /// `retry_delay` times the attempt, up to `retries` failures that
+    /// [`is_transient_snapshot_error`] deems transient. Only the partial
+    /// artifacts of a `kind` archive of `slot` are cleaned up, so an archive
+    /// of the other kind written before survives. Returns the archive and the
+    /// time the successful attempt took.
+    pub fn archive_with_retries<T>(
+        kind: ArchiveKind,
+        slot: Slot,
+        output_dir: &Path,
+        retries: usize,
+        retry_delay: Duration,
+        mut archive: impl FnMut() -> Result<T, SnapshotError>,
+    ) -> Result<(T, Duration), MergeError> {
+        let bank_snapshots_dir = output_dir.join("bank_snapshots");
+        let mut attempt = 0;
+        loop {
+            log::info!("Creating {} snapshot archive...", kind.name());
+            let started = Instant::now();
+            match archive() {
+                Ok(archive_info) => return Ok((archive_info, started.elapsed())),
+                Err(e) if attempt < retries && is_transient_snapshot_error(&e) => {
+                    attempt += 1;
+                    log::warn!(
+                        "Failed to create {} snapshot archive ({:?}), cleaning up and retrying \
+                         ({}/{})",
+                        kind.name(),
+                        e,
+                        attempt,
+                        retries
+                    );
+                    let removed = cleanup_partial_snapshot_artifacts(
+                        output_dir,
+                        &bank_snapshots_dir,
+                        slot,
+                        kind,
+                    )?;
+                    log::info!("Removed {} partial snapshot artifacts", removed);
+                    std::thread::sleep(retry_delay * attempt as u32);
+                }
+                Err(e) => {
+                    return Err(MergeError::ArchiveCreation(format!(
+                        "Failed to create {} snapshot archive: {:?}",
+                        kind.name(),
+                        e
+                    )))
+                }
+            }
+        }
+    }
+
+    /// Which snapshot archives the merge writes
+    #[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+    pub enum SnapshotType {
+        #[default]
+        Full,
+        /// Only an incremental snapshot on top of the merge ledger's full
+        /// snapshot, for validators that already have that one
+        Incremental,
+        /// The full snapshot and the incremental one
+        Both,
+    }
+
+    impl SnapshotType {
+        pub fn writes_full(self) -> bool {
+            self != Self::Incremental
+        }
+
+        pub fn writes_incremental(self) -> bool {
+            self != Self::Full
+        }
+    }
+
+    impl FromStr for SnapshotType {
+        type Err = String;
+
+        fn from_str(s: &str) -> Result<Self, Self::Err> {
+            match s {
+                "full" => Ok(Self::Full),
+                "incremental" => Ok(Self::Incremental),
+                "both" => Ok(Self::Both),
+                _ => Err(format!(
+                    "Invalid snapshot type '{}', expected 'full', 'incremental' or 'both'",
+                    s
+                )),
+            }
+        }
+    }
+
+    /// Compression of the written snapshot archives
+    #[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+    pub enum OutputArchiveFormat {
+        #[default]
+        TarZstd,
+        /// Faster to write and unpack than zstd, but larger
+        TarLz4,
+    }
+
+    impl OutputArchiveFormat {
+        /// The runtime's archive format, at `zstd_level` or zstd's default
+        /// level for `TarZstd`
+        pub fn archive_format(self, zstd_level: Option<i32>) -> ArchiveFormat {
+            match self {
+                Self::TarZstd => ArchiveFormat::TarZstd {
+                    config: ZstdConfig {
+                        compression_level: zstd_level.unwrap_or_default(),
+                    },
+                },
+                Self::TarLz4 => ArchiveFormat::TarLz4,
+            }
+        }
+    }
+
+    impl FromStr for OutputArchiveFormat {
+        type Err = String;
+
+        fn from_str(s: &str) -> Result<Self, Self::Err> {
+            match s {
+                "tar-zstd" => Ok(Self::TarZstd),
+                "tar-lz4" => Ok(Self::TarLz4),
+                // Validators can't load these either, so there is no point in
+                // writing them
+                "tar-bz2" | "tar" | "dir" => Err(format!(
+                    "Archive format '{}' is not supported: the runtime only reads and writes \
+                     tar-zstd and tar-lz4 snapshot archives",
+                    s
+                )),
+                _ => Err(format!(
+                    "Invalid archive format '{}', expected 'tar-zstd' or 'tar-lz4'",
+                    s
+                )),
+            }
+        }
+    }
+
+    /// Compression levels `--zstd-level` accepts
+    pub const ZSTD_LEVELS: std::ops::RangeInclusive<i32> = 1..=22;
+
+    /// An incremental snapshot archive the merge wrote
+    #[derive(Debug, Clone, PartialEq, Eq)]
+    pub struct IncrementalSnapshot {
+        pub path: String,
+        pub hash: Hash,
+        /// Slot of the full snapshot it applies on top of
+        pub base_slot: Slot,
+    }
+
+    /// Slot and hash of the full snapshot archive at `path`, from its file name
+    pub fn full_snapshot_archive_id(path: &Path) -> Result<(Slot, Hash), MergeError> {
+        FullSnapshotArchiveInfo::new_from_path(path.to_path_buf())
+            .map(|info| (info.slot(), info.hash().0))
+            .map_err(|e| {
+                MergeError::SnapshotLoad(format!(
+                    "{:?} is not named like a full snapshot archive: {:?}",
+                    path, e
+                ))
+            })
+    }
+
+    /// How far the final bank is ticked before it is frozen
+    #[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+    pub enum BankCompletion {
+        /// Up to the slot's max tick height
+        #[default]
+        FillToMax,
+        /// Up to this tick height
+        TickHeight(u64),
+    }
+
+    impl FromStr for BankCompletion {
+        type Err = String;
+
+        fn from_str(s: &str) -> Result<Self, Self::Err> {
+            match s {
+                "max" => Ok(Self::FillToMax),
+                _ => s.parse().map(Self::TickHeight).map_err(|_| {
+                    format!("Invalid tick height '{}', expected a number or 'max'", s)
+                }),
+            }
+        }
+    }
+
+    /// Registers ticks on `bank` until it reaches the height `completion`
+    /// asks for, which must lie between its current and max tick height.
+    /// Returns the final tick height.
+    pub fn complete_bank(bank: &Bank, completion: BankCompletion) -> Result<u64, MergeError> {
+        let max_tick_height = bank.max_tick_height();
+        let target = match completion {
+            BankCompletion::FillToMax => max_tick_height,
+            BankCompletion::TickHeight(height) => height,
+        };
+        if target < bank.tick_height() || target > max_tick_height {
+            return Err(MergeError::InvalidInput(format!(
+                "Tick height {} is outside [{}, {}] for the bank at slot {}",
+                target,
+                bank.tick_height(),
+                max_tick_height,
+                bank.slot()
+            )));
+        }
+        // Chained from the last blockhash rather than random, as the last tick
+        // becomes the bank's blockhash and so feeds its bank hash
+        let scheduler = BankWithScheduler::no_scheduler_available();
+        let mut tick_hash = bank.last_blockhash();
+        while bank.tick_height() < target {
+            tick_hash = hashv(&[tick_hash.as_ref(), &bank.tick_height().to_le_bytes()]);
+            bank.register_tick(&tick_hash, &scheduler);
+        }
+        Ok(bank.tick_height())
+    }
+
+    /// Recalculates the bank's capitalization from its accounts and stores it,
+    /// returning the new value
+    pub fn recalculate_capitalization(bank: &Bank) -> u64 {
+        let capitalization = bank.calculate_capitalization_for_tests();
+        bank.set_capitalization_for_tests(capitalization);
+        capitalization
+    }
+
+    /// How the merged bank's capitalization is set once every account is stored
+    #[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+    pub enum CapitalizationMode {
+        /// Recount it from the accounts
+        #[default]
+        Recalculate,
+        /// Keep the merge ledger's capitalization; the final recount check
+        /// fails if the copied accounts changed the total by more than the
+        /// tolerance
+        PreserveBase,
+        /// Credit or debit `treasury` so the accounts sum to `target`
+        MintDelta { treasury: Pubkey, target: u64 },
+    }
+
+    /// Lamports `CapitalizationMode::MintDelta` moved into (positive) or out
+    /// of (negative) its treasury
+    #[derive(Debug, Clone, Copy, PartialEq, Eq)]
+    pub struct TreasuryAdjustment {
+        pub treasury: Pubkey,
+        pub delta: i128,
+    }
+
+    /// Sets `bank`'s capitalization as `mode` says, `base` being the merge
+    /// ledger's. Modes other than `PreserveBase` leave the recorded value equal
+    /// to a recount of the accounts. Fails when a debit exceeds the treasury's
+    /// balance.
+    pub fn apply_capitalization_mode(
+        bank: &Bank,
+        mode: CapitalizationMode,
+        base: u64,
+    ) -> Result<Option<TreasuryAdjustment>, MergeError> {
+        match mode {
+            CapitalizationMode::Recalculate => {
+                recalculate_capitalization(bank);
+                Ok(None)
+            }
+            CapitalizationMode::PreserveBase => {
+                bank.set_capitalization_for_tests(base);
+                Ok(None)
+            }
+            CapitalizationMode::MintDelta { treasury, target } => {
+                let recounted = bank.calculate_capitalization_for_tests();
+                let delta = target as i128 - recounted as i128;
+                let mut account = bank
+                    .get_account(&treasury)
+                    .unwrap_or_else(|| AccountSharedData::new(0, 0, &system_program::id()));
+                let lamports = u64::try_from(account.lamports() as i128 + delta).map_err(|_| {
+                    MergeError::InvalidInput(format!(
+                        "Treasury {} holds {} lamports, too few to reach capitalization {} \
+                         from {} ({:+} lamports)",
+                        treasury,
+                        account.lamports(),
+                        target,
+                        recounted,
+                        delta
+                    ))
+                })?;
+                account.set_lamports(lamports);
+                bank.store_account(&treasury, &account);
+                let capitalization = recalculate_capitalization(bank);
+                if capitalization != target {
+                    return Err(MergeError::Verification(format!(
+                        "Capitalization is {} after adjusting treasury {}, expected {}",
+                        capitalization, treasury, target
+                    )));
+                }
+                log::info!(
+                    "Adjusted treasury {} by {:+} lamports to reach capitalization {}",
+                    treasury,
+                    delta,
+                    target
+                );
+                Ok(Some(TreasuryAdjustment { treasury, delta }))
+            }
+        }
+    }
+
+    /// The capitalization `bank` would have after storing each of `layers` in
+    /// order, starting from `capitalization`, without storing anything. A
+    /// pubkey in several layers counts with the lamports of the last one.
+    pub fn projected_capitalization(
+        bank: &Bank,
+        capitalization: u64,
+        layers: &[&HashMap<Pubkey, AccountSharedData>],
+    ) -> u64 {
+        let mut stored: HashMap<&Pubkey, u64> = HashMap::new();
+        for layer in layers {
+            for (pubkey, account) in layer.iter() {
+                stored.insert(pubkey, account.lamports());
+            }
+        }
+        let delta: i128 = stored
+            .into_iter()
+            .map(|(pubkey, lamports)| {
+                let replaced = bank.get_account(pubkey).map_or(0, |base| base.lamports());
+                lamports as i128 - replaced as i128
+            })
+            .sum();
+        (capitalization as i128 + delta).clamp(0, u64::MAX as i128) as u64
+    }
+
+    /// The lamports of every account `bank` holds, summed over a scan of its
+    /// accounts. Independent of the runtime's capitalization recount, which
+    /// [`recalculate_capitalization`] sets the recorded value from, so the
+    /// checks against it don't compare the recount with itself.
+    pub fn sum_account_lamports(bank: &Bank) -> Result<u64, MergeError> {
+        let mut lamports: u128 = 0;
+        bank.scan_all_accounts(
+            |item| {
+                if let Some((_, account, _)) = item {
+                    lamports += account.lamports() as u128;
+                }
+            },
+            false,
+        )
+        .map_err(|e| MergeError::AccountScan(format!("Failed to scan accounts: {:?}", e)))?;
+        u64::try_from(lamports).map_err(|_| {
+            MergeError::Verification(format!(
+                "The accounts of the bank at slot {} hold {} lamports, more than a \
+                 capitalization can record",
+                bank.slot(),
+                lamports
+            ))
+        })
+    }
+
+    /// Checks the bank's recorded capitalization against the lamports its
+    /// accounts hold, summed by [`sum_account_lamports`], returning the delta
+    /// (summed minus recorded). Fails when the absolute delta exceeds
+    /// `tolerance` lamports.
+    pub fn verify_capitalization(bank: &Bank, tolerance: u64) -> Result<i128, MergeError> {
+        let recorded = bank.capitalization();
+        let recomputed = sum_account_lamports(bank)?;
+        let delta = recomputed as i128 - recorded as i128;
+        log::info!(
+            "Capitalization recorded {} summed from the accounts {} ({:+})",
+            recorded,
+            recomputed,
+            delta
+        );
+        if delta.unsigned_abs() > tolerance as u128 {
+            return Err(MergeError::Verification(format!(
+                "Capitalization mismatch: recorded {} but accounts sum to {} ({:+} lamports, tolerance {})",
+                recorded, recomputed, delta, tolerance
+            )));
+        }
+        Ok(delta)
+    }
+
+    /// Checks that warping left capitalization at `pre_warp`: both the warped
+    /// bank's recorded capitalization and the lamports of its accounts, summed
+    /// by [`sum_account_lamports`], must equal it. Returns the warped bank's
+    /// capitalization. The warped bank is frozen, so drift can only be
+    /// reported, not corrected in place.
+    pub fn check_warp_capitalization(pre_warp: u64, warped: &Bank) -> Result<u64, MergeError> {
+        let recorded = warped.capitalization();
+        let recomputed = sum_account_lamports(warped)?;
+        if recorded != pre_warp || recomputed != pre_warp {
+            return Err(MergeError::Verification(format!(
+                "Warp to slot {} changed capitalization: {} before, {} recorded and {} \
+                 recounted after ({:+} lamports)",
+                warped.slot(),
+                pre_warp,
+                recorded,
+                recomputed,
+                recomputed as i128 - pre_warp as i128
+            )));
+        }
+        Ok(recorded)
+    }
+
+    /// One vote account of a bank with the stake delegated to it
+    #[derive(Debug, Clone, PartialEq, Eq)]
+    pub struct ValidatorSummary {
+        pub vote_account: Pubkey,
+        pub node_pubkey: Pubkey,
+        pub commission: u8,
+        /// Effective stake of the stake accounts delegated to the vote account
+        /// at the bank's epoch
+        pub active_stake: u64,
+    }
+
+    #[derive(Debug, Clone, Default, PartialEq, Eq)]
+    pub struct ValidatorSetSummary {
+        /// Largest active stake first, ties broken by vote account
+        pub validators: Vec<ValidatorSummary>,
+        pub total_active_stake: u64,
+        /// Active stake delegated to vote accounts the bank doesn't have
+        pub stake_to_missing_vote_accounts: u64,
+    }
+
+    /// The bank's stake history sysvar, empty if the bank has none
+    fn read_stake_history(bank: &Bank) -> Result<StakeHistory, MergeError> {
+        match bank.get_account(&solana_sdk_ids::sysvar::stake_history::id()) {
+            Some(account) => bincode::deserialize::<StakeHistory>(account.data()).map_err(|e| {
+                MergeError::AccountData(format!("Failed to deserialize stake history: {:?}", e))
+            }),
+            None => Ok(StakeHistory::default()),
+        }
+    }
+
+    /// Deserializes every vote account of `bank` and sums the effective stake
+    /// of the stake accounts delegated to each, using the bank's stake history
+    /// sysvar. Vote accounts that don't deserialize are logged and left out.
+    pub fn summarize_validators(
+        bank: &Bank,
+        fallback_threshold: Option<usize>,
+    ) -> Result<ValidatorSetSummary, MergeError> {
+        let mut validators = HashMap::new();
+        for (pubkey, account) in
+            extract_accounts_by_owner(bank, &solana_vote_program::id(), fallback_threshold)?
+        {
+            match VoteStateV3::deserialize(account.data()) {
+                Ok(vote_state) => {
+                    validators.insert(
+                        pubkey,
+                        ValidatorSummary {
+                            vote_account: pubkey,
+                            node_pubkey: vote_state.node_pubkey,
+                            commission: vote_state.commission,
+                            active_stake: 0,
+                        },
+                    );
+                }
+                Err(e) => log::warn!("Failed to deserialize vote account {}: {:?}", pubkey, e),
+            }
+        }
+
+        let stake_history = read_stake_history(bank)?;
+        let new_rate_activation_epoch = bank.new_warmup_cooldown_rate_epoch();
+        let mut summary = ValidatorSetSummary::default();
+        for account in
+            extract_accounts_by_owner(bank, &solana_stake_program::id(), fallback_threshold)?
+                .into_values()
+        {
+            let Some(delegation) = bincode::deserialize::<StakeStateV2>(account.data())
+                .ok()
+                .and_then(|state| state.delegation())
+            else {
+                continue;
+            };
+            let stake = delegation.stake(bank.epoch(), &stake_history, new_rate_activation_epoch);
+            match validators.get_mut(&delegation.voter_pubkey) {
+                Some(validator) => {
+                    validator.active_stake += stake;
+                    summary.total_active_stake += stake;
+                }
+                None => summary.stake_to_missing_vote_accounts += stake,
+            }
+        }
+
+        summary.validators = validators.into_values().collect();
+        summary.validators.sort_unstable_by(|a, b| {
+            b.active_stake
+                .cmp(&a.active_stake)
+                .then(a.vote_account.cmp(&b.vote_account))
+        });
+        Ok(summary)
+    }
+
+    /// Base ledger stake accounts removed by [`prune_deactivated_stakes`]
+    #[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize)]
+    pub struct StakePruneReport {
+        pub pruned: usize,
+        pub lamports_reclaimed: u64,
+    }
+
+    /// Whether `delegation` has no effective, activating or deactivating stake
+    /// left at `epoch`. A delegation that was never deactivated never is.
+    pub fn is_fully_deactivated(
+        delegation: &Delegation,
+        epoch: Epoch,
+        stake_history: &StakeHistory,
+        new_rate_activation_epoch: Option<Epoch>,
+    ) -> bool {
+        if delegation.deactivation_epoch == Epoch::MAX {
+            return false;
+        }
+        let status = delegation.stake_activating_and_deactivating(
+            epoch,
+            stake_history,
+            new_rate_activation_epoch,
+        );
+        status.effective == 0 && status.activating == 0 && status.deactivating == 0
+    }
+
+    /// Removes the stake accounts of `bank` whose delegation is fully
+    /// deactivated as of the bank's epoch, by storing them with zero lamports.
+    /// Undelegated and unparsable stake accounts are kept. The lamports they
+    /// held leave the bank, so its capitalization must be recalculated.
+    pub fn prune_deactivated_stakes(
+        bank: &Bank,
+        fallback_threshold: Option<usize>,
+    ) -> Result<StakePruneReport, MergeError> {
+        let stake_history = read_stake_history(bank)?;
+        let new_rate_activation_epoch = bank.new_warmup_cooldown_rate_epoch();
+        let mut report = StakePruneReport::default();
+        let stake_accounts =
+            extract_accounts_by_owner(bank, &solana_stake_program::id(), fallback_threshold)?;
+        for (pubkey, account) in sort_accounts_by_pubkey(&stake_accounts) {
+            let Some(delegation) = bincode::deserialize::<StakeStateV2>(account.data())
+                .ok()
+                .and_then(|state| state.delegation())
+            else {
+                continue;
+            };
+            if is_fully_deactivated(
+                &delegation,
+                bank.epoch(),
+                &stake_history,
+                new_rate_activation_epoch,
+            ) {
+                // Keeping the owner evicts the delegation from the stakes cache
+                store_account_replacing(
+                    bank,
+                    pubkey,
+                    &AccountSharedData::new(0, 0, &solana_stake_program::id()),
+                    account.data().len(),
+                );
+                report.pruned += 1;
+                report.lamports_reclaimed += account.lamports();
+            }
+        }
+        log::info!(
+            "Pruned {} of {} stake accounts fully deactivated by epoch {}, reclaiming {} lamports",
+            report.pruned,
+            stake_accounts.len(),
+            bank.epoch(),
+            report.lamports_reclaimed
+        );
+        Ok(report)
+    }
+
+    /// Which ledger's vote and stake accounts end up in the merged bank
+    #[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+    pub enum ValidatorSource {
+        /// The merge ledger's, which its genesis and epoch stakes expect
+        #[default]
+        MergeLedger,
+        Mainnet,
+        Both,
+        /// Neither, e.g. for a bank that only serves account state
+        None,
+    }
+
+    impl ValidatorSource {
+        pub fn keeps_mainnet(self) -> bool {
+            matches!(self, Self::Mainnet | Self::Both)
+        }
+
+        pub fn keeps_merge_ledger(self) -> bool {
+            matches!(self, Self::MergeLedger | Self::Both)
+        }
+    }
+
+    impl FromStr for ValidatorSource {
+        type Err = String;
+
+        fn from_str(s: &str) -> Result<Self, Self::Err> {
+            match s {
+                "merge-ledger" => Ok(Self::MergeLedger),
+                "mainnet" => Ok(Self::Mainnet),
+                "both" => Ok(Self::Both),
+                "none" => Ok(Self::None),
+                _ => Err(format!(
+                    "Invalid validator source '{}', expected 'merge-ledger', 'mainnet', 'both' \
+                     or 'none'",
+                    s
+                )),
+            }
+        }
+    }
+
+    /// Vote and stake accounts [`remove_validator_accounts`] dropped
+    #[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize)]
+    pub struct ValidatorRemovalReport {
+        pub vote_accounts: usize,
+        pub stake_accounts: usize,
+        pub lamports_removed: u64,
+    }
+
+    /// Removes every vote and stake account of `bank` by storing it with zero
+    /// lamports under the same owner, which also evicts it from the stakes
+    /// cache. The lamports they held leave the bank, so its capitalization
+    /// must be recalculated.
+    pub fn remove_validator_accounts(
+        bank: &Bank,
+        fallback_threshold: Option<usize>,
+    ) -> Result<ValidatorRemovalReport, MergeError> {
+        let mut report = ValidatorRemovalReport::default();
+        for (owner, removed) in [
+            (solana_vote_program::id(), &mut report.vote_accounts),
+            (solana_stake_program::id(), &mut report.stake_accounts),
+        ] {
+            let accounts = extract_accounts_by_owner(bank, &owner, fallback_threshold)?;
+            for (pubkey, account) in sort_accounts_by_pubkey(&accounts) {
+                store_account_tracking_data_size(
+                    bank,
+                    pubkey,
+                    &AccountSharedData::new(0, 0, &owner),
+                );
+                *removed += 1;
+                report.lamports_removed += account.lamports();
+            }
+        }
+        log::info!(
+            "Removed {} vote and {} stake accounts holding {} lamports",
+            report.vote_accounts,
+            report.stake_accounts,
+            report.lamports_removed
+        );
+        Ok(report)
+    }
+
+    /// Closes the bank's feature-gate accounts, so the features it has
+    /// activated are only active again where stored anew. Returns how many
+    /// were closed.
+    pub fn remove_feature_accounts(
+        bank: &Bank,
+        fallback_threshold: Option<usize>,
+    ) -> Result<usize, MergeError> {
+        let accounts = extract_feature_accounts(bank, fallback_threshold)?;
+        for (pubkey, _) in sort_accounts_by_pubkey(&accounts) {
+            store_account_tracking_data_size(
+                bank,
+                pubkey,
+                &AccountSharedData::new(0, 0, &feature::id()),
+            );
+        }
+        log::info!("Removed {} feature accounts", accounts.len());
+        Ok(accounts.len())
+    }
+
+    /// Epoch stakes [`regenerate_epoch_stakes`] replaced
+    #[derive(Debug, Clone, Default, PartialEq, Eq, Serialize)]
+    pub struct EpochStakesReport {
+        pub epochs: Vec<Epoch>,
+        pub vote_accounts: usize,
+        /// Total stake of the bank's own epoch before and after
+        pub previous_total_stake: u64,
+        pub total_stake: u64,
+    }
+
+    /// Recomputes the epoch stakes of the bank's epoch and of the later
+    /// epochs it holds from the vote accounts and delegated stake of its
+    /// stakes cache, which tracks every stored account. Without this the
+    /// leader schedules of those epochs keep the stakes the merge ledger had
+    /// when the epoch started. Only the vote accounts and their stake are
+    /// kept, which is all the leader schedule and vote verification read.
+    /// Must run before the bank is frozen, so before any warp; see
+    /// [`warp_bank`].
+    ///
+    /// The runtime only computes epoch stakes itself when a bank crosses an
+    /// epoch boundary, and has no other way to replace them than its
+    /// `dev-context-only-utils` constructors, `VersionedEpochStakes::new_for_tests`
+    /// and `Bank::set_epoch_stakes_for_test`. They build the stakes the same
+    /// way, but aren't a stable interface, so this only runs when
+    /// `MergeConfig::regenerate_epoch_stakes` asks for it and says so.
+    pub fn regenerate_epoch_stakes(bank: &mut Bank) -> EpochStakesReport {
+        log::warn!(
+            "Replacing the epoch stakes of the bank at slot {} through the runtime's test-only \
+             constructors; the runtime only recomputes them at an epoch boundary",
+            bank.slot()
+        );
+        let vote_accounts = (*bank.vote_accounts()).clone();
+        let mut epochs: Vec<_> = bank
+            .epoch_stakes_map()
+            .keys()
+            .copied()
+            .filter(|&epoch| epoch >= bank.epoch())
+            .collect();
+        epochs.sort_unstable();
+        let total_stake = |bank: &Bank| {
+            bank.epoch_stakes(bank.epoch())
+                .map_or(0, |stakes| stakes.total_stake())
+        };
+        let previous_total_stake = total_stake(bank);
+        for &epoch in &epochs {
+            bank.set_epoch_stakes_for_test(
+                epoch,
+                VersionedEpochStakes::new_for_tests(vote_accounts.clone(), epoch),
+            );
+        }
+        EpochStakesReport {
+            epochs,
+            vote_accounts: vote_accounts.len(),
+            previous_total_stake,
+            total_stake: total_stake(bank),
+        }
+    }
+
+    /// Warps `bank` to `slot` with [`Bank::warp_from_parent`], which freezes
+    /// both banks. With `regenerate_stakes` the epoch stakes of `bank` are
+    /// regenerated first: the warped bank keeps every epoch `bank` holds
+    /// stakes for, and only computes the ones it reaches past them.
+    pub fn warp_bank(
+        mut bank: Arc<Bank>,
+        slot: Slot,
+        regenerate_stakes: bool,
+    ) -> Result<(Bank, Option<EpochStakesReport>), MergeError> {
+        let regenerated = if regenerate_stakes {
+            let bank = Arc::get_mut(&mut bank).ok_or_else(|| {
+                MergeError::AccountCopy(
+                    "The bank to warp is still shared, so its epoch stakes can't be regenerated"
+                        .to_string(),
+                )
+            })?;
+            Some(regenerate_epoch_stakes(bank))
+        } else {
+            None
+        };
+        let collector_id = *bank.collector_id();
+        Ok((
+            Bank::warp_from_parent(bank, &collector_id, slot),
+            regenerated,
+        ))
+    }
+
+    /// Where the vote or stake accounts of a merged bank came from
+    #[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize)]
+    pub struct ValidatorAccountCounts {
+        pub from_merge_ledger: usize,
+        pub from_mainnet: usize,
+        /// Neither ledger had the account under this owner, e.g. added by a hook
+        pub other: usize,
+    }
+
+    impl ValidatorAccountCounts {
+        pub fn total(&self) -> usize {
+            self.from_merge_ledger + self.from_mainnet + self.other
+        }
+    }
+
+    #[derive(Debug, Clone, Default, PartialEq, Eq)]
+    pub struct ValidatorAccountAudit {
+        pub vote: ValidatorAccountCounts,
+        pub stake: ValidatorAccountCounts,
+        /// Vote and stake accounts of the final bank that only mainnet had
+        pub mainnet_accounts: Vec<Pubkey>,
+    }
+
+    /// Classifies every vote and stake account of `final_bank` by the ledger it
+    /// came from. An address both ledgers had is attributed to the merge
+    /// ledger unless `validator_source` keeps mainnet's validators, whose
+    /// copies replace the merge ledger's; then it is mainnet's unless the final
+    /// account is still the merge ledger's.
+    pub fn audit_validator_accounts(
+        final_bank: &Bank,
+        merge_bank: &Bank,
+        mainnet_vote_accounts: &HashSet<Pubkey>,
+        mainnet_stake_accounts: &HashSet<Pubkey>,
+        validator_source: ValidatorSource,
+        fallback_threshold: Option<usize>,
+    ) -> Result<ValidatorAccountAudit, MergeError> {
+        let mut audit = ValidatorAccountAudit::default();
+        for (owner, mainnet_accounts, counts) in [
+            (
+                solana_vote_program::id(),
+                mainnet_vote_accounts,
+                &mut audit.vote,
+            ),
+            (
+                solana_stake_program::id(),
+                mainnet_stake_accounts,
+                &mut audit.stake,
+            ),
+        ] {
+            let merge_ledger_accounts =
+                extract_accounts_by_owner(merge_bank, &owner, fallback_threshold)?;
+            for (pubkey, account) in
+                extract_accounts_by_owner(final_bank, &owner, fallback_threshold)?
+            {
+                let from_merge_ledger = merge_ledger_accounts.get(&pubkey).is_some_and(|merged| {
+                    !validator_source.keeps_mainnet()
+                        || !mainnet_accounts.contains(&pubkey)
+                        || *merged == account
+                });
+                if from_merge_ledger {
+                    counts.from_merge_ledger += 1;
+                } else if mainnet_accounts.contains(&pubkey) {
+                    counts.from_mainnet += 1;
+                    audit.mainnet_accounts.push(pubkey);
+                } else {
+                    counts.other += 1;
+                }
+            }
+        }
+        audit.mainnet_accounts.sort_unstable();
+        Ok(audit)
+    }
+
+    /// How every account of a bank is enumerated when building the copy set.
+    ///
+    /// Both methods run the same accounts index scan against the bank's
+    /// ancestors, so they observe the same point-in-time view of a frozen bank
+    /// and both visit accounts in the order [`ScanConsistency`] picks. They
+    /// differ in memory:
+    /// `GetAll` materializes every account into a `Vec` before filtering,
+    /// roughly doubling peak usage while the copy set is built, whereas `Scan`
+    /// streams each account through the filter. `Scan` also surfaces
+    /// zero-lamport accounts, which are skipped here to match `GetAll`.
+    #[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+    pub enum AccountEnumeration {
+        /// `Bank::scan_all_accounts`
+        #[default]
+        Scan,
+        /// `Bank::get_all_accounts`
+        GetAll,
+    }
+
+    impl FromStr for AccountEnumeration {
+        type Err = String;
+
+        fn from_str(s: &str) -> Result<Self, Self::Err> {
+            match s {
+                "scan" => Ok(Self::Scan),
+                "get-all" => Ok(Self::GetAll),
+                _ => Err(format!(
+                    "Invalid account enumeration '{}', expected 'scan' or 'get-all'",
+                    s
+                )),
+            }
+        }
+    }
+
+    /// The order account enumerations visit accounts in.
+    ///
+    /// Every scan runs against the bank's own ancestors, so either way it sees
+    /// the accounts as of the loaded bank's slot. A validator still writing
+    /// to the input ledger can't change what a scan returns once the snapshot
+    /// has been unpacked into the tool's scratch directory. It can only race
+    /// the load itself, e.g. by replacing the archive while it's being read.
+    /// "Latest" here means the index's current bin-by-bin order, which
+    /// depends on how the index was built and varies between runs.
+    #[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+    pub enum ScanConsistency {
+        /// Visit accounts sorted by pubkey, the same order every run
+        #[default]
+        Fixed,
+        /// Visit accounts in index order, skipping the sort
+        Latest,
+    }
+
+    impl ScanConsistency {
+        /// The `sort_results` argument of `Bank::scan_all_accounts` and
+        /// `Bank::get_all_accounts`
+        pub fn sort_results(self) -> bool {
+            self == Self::Fixed
+        }
+    }
+
+    impl FromStr for ScanConsistency {
+        type Err = String;
+
+        fn from_str(s: &str) -> Result<Self, Self::Err> {
+            match s {
+                "fixed" => Ok(Self::Fixed),
+                "latest" => Ok(Self::Latest),
+                _ => Err(format!(
+                    "Invalid scan consistency '{}', expected 'fixed' or 'latest'",
+                    s
+                )),
+            }
+        }
+    }
+
+    /// Visits every loadable (non-zero-lamport) account of `bank` using
+    /// `method`, in the order `consistency` gives
+    pub fn for_each_account(
+        bank: &Bank,
+        method: AccountEnumeration,
+        consistency: ScanConsistency,
+        mut visit: impl FnMut(Pubkey, AccountSharedData),
+    ) -> Result<(), MergeError> {
+        for_each_account_version(bank, method, consistency, |pubkey, account, _slot| {
+            visit(pubkey, account)
+        })
+    }
+
+    /// Like [`for_each_account`], also passing the slot each version was
+    /// stored at
+    pub fn for_each_account_version(
+        bank: &Bank,
+        method: AccountEnumeration,
+        consistency: ScanConsistency,
+        mut visit: impl FnMut(Pubkey, AccountSharedData, Slot),
+    ) -> Result<(), MergeError> {
+        match method {
+            AccountEnumeration::Scan => bank
+                .scan_all_accounts(
+                    |item| {
+                        if let Some((pubkey, account, slot)) = item {
+                            if account.lamports() != 0 {
+                                visit(*pubkey, account, slot);
+                            }
+                        }
+                    },
+                    consistency.sort_results(),
+                )
+                .map_err(|e| MergeError::AccountScan(format!("Failed to scan accounts: {:?}", e))),
+            AccountEnumeration::GetAll => {
+                let accounts = bank
+                    .get_all_accounts(consistency.sort_results())
+                    .map_err(|e| {
+                        MergeError::AccountScan(format!("Failed to get all accounts: {:?}", e))
+                    })?;
+                for (pubkey, account, slot) in accounts {
+                    visit(pubkey, account, slot);
+                }
+                Ok(())
+            }
+        }
+    }
+
+    /// Keeps whichever of `account` and the version already in `accounts` was
+    /// stored at the higher slot; on a tie the later one wins. Returns whether
+    /// `pubkey` was already present.
+    pub fn insert_newest(
+        accounts: &mut HashMap<Pubkey, (Slot, AccountSharedData)>,
+        pubkey: Pubkey,
+        account: AccountSharedData,
+        slot: Slot,
+    ) -> bool {
+        match accounts.entry(pubkey) {
+            Entry::Occupied(mut entry) => {
+                if slot >= entry.get().0 {
+                    entry.insert((slot, account));
+                }
+                true
+            }
+            Entry::Vacant(entry) => {
+                entry.insert((slot, account));
+                false
+            }
+        }
+    }
+
+    /// Every loadable (non-zero-lamport) account of `bank` with the slot it
+    /// was stored at, keeping only the highest-slot version of a pubkey an
+    /// enumeration surfaces more than once
+    #[derive(Debug, Default)]
+    pub struct NewestAccounts {
+        pub accounts: HashMap<Pubkey, (Slot, AccountSharedData)>,
+        /// Older versions dropped in favour of a newer one
+        pub duplicates_resolved: usize,
+    }
+
+    pub fn collect_newest_accounts(
+        bank: &Bank,
+        method: AccountEnumeration,
+        consistency: ScanConsistency,
+    ) -> Result<NewestAccounts, MergeError> {
+        let mut newest = NewestAccounts::default();
+        for_each_account_version(bank, method, consistency, |pubkey, account, slot| {
+            if insert_newest(&mut newest.accounts, pubkey, account, slot) {
+                newest.duplicates_resolved += 1;
+            }
+        })?;
+        if newest.duplicates_resolved > 0 {
+            log::warn!(
+                "Enumeration surfaced {} older duplicate account versions, kept the newest",
+                newest.duplicates_resolved
+            );
+        }
+        Ok(newest)
+    }
+
+    /// Collects the pubkeys of every loadable account of `bank`. The accounts
+    /// are streamed through a scan and only their pubkeys are kept.
+    pub fn collect_pubkeys(bank: &Bank) -> Result<HashSet<Pubkey>, MergeError> {
+        let mut pubkeys = HashSet::new();
+        // A set has no order to keep
+        for_each_account(
+            bank,
+            AccountEnumeration::Scan,
+            ScanConsistency::Latest,
+            |pubkey, _| {
+                pubkeys.insert(pubkey);
+            },
+        )?;
+        Ok(pubkeys)
+    }
+
+    /// Rebuilds `merge_bank`'s account set on a fresh bank created from
+    /// `genesis_config`, instead of inheriting the loaded bank's state.
+    ///
+    /// Every loadable non-sysvar account of `merge_bank` is stored explicitly
+    /// into the genesis bank, and the genesis accounts `merge_bank` no longer
+    /// holds are closed. The capitalization is then recomputed from those
+    /// accounts and the bank is warped to `merge_bank`'s slot, which applies
+    /// the stored feature activations and the epoch stakes of the stored
+    /// stake accounts. Sysvars are maintained by the new bank itself, so its
+    /// slot hashes only reach back to the genesis bank and its stake history
+    /// holds only what the warp computed. Warping across epochs from slot 0
+    /// runs one epoch boundary, which starts the inflation rewards of the
+    /// genesis epoch. The accounts cache is flushed every
+    /// [`FlushPolicy::interval_accounts`] stored accounts. Returns the frozen
+    /// rebuilt bank.
+    pub fn rebuild_bank_from_genesis(
+        merge_bank: &Bank,
+        genesis_config: &GenesisConfig,
+        accounts_db_config: AccountsDbConfig,
+        accounts_path: &Path,
+        flush_policy: FlushPolicy,
+    ) -> Result<Arc<Bank>, MergeError> {
+        let is_sysvar = |account: &AccountSharedData| *account.owner() == sysvar::id();
+        let genesis_bank = Bank::new_with_paths(
+            genesis_config,
+            Arc::new(RuntimeConfig::default()),
+            vec![accounts_path.to_path_buf()],
+            None,
+            None,
+            false,
+            Some(accounts_db_config),
+            None,
+            None,
+            Arc::new(AtomicBool::new(false)),
+            None,
+            None,
+        );
+
+        let mut merge_accounts = HashMap::new();
+        for_each_account(
+            merge_bank,
+            AccountEnumeration::Scan,
+            ScanConsistency::Latest,
+            |pubkey, account| {
+                if !is_sysvar(&account) {
+                    merge_accounts.insert(pubkey, account);
+                }
+            },
+        )?;
+        let mut closed = Vec::new();
+        for_each_account(
+            &genesis_bank,
+            AccountEnumeration::Scan,
+            ScanConsistency::Latest,
+            |pubkey, account| {
+                if !is_sysvar(&account) && !merge_accounts.contains_key(&pubkey) {
+                    closed.push((pubkey, *account.owner()));
+                }
+            },
+        )?;
+        for (pubkey, owner) in &closed {
+            // Keeping the owner evicts a vote or stake account from the
+            // stakes cache, which ignores system-owned stores
+            genesis_bank.store_account(pubkey, &AccountSharedData::new(0, 0, owner));
+        }
+        for (stored, (pubkey, account)) in sort_accounts_by_pubkey(&merge_accounts)
+            .into_iter()
+            .enumerate()
+        {
+            genesis_bank.store_account(pubkey, account);
+            if (stored + 1).is_multiple_of(flush_policy.interval_accounts()) {
+                genesis_bank.force_flush_accounts_cache();
+            }
+        }
+        let capitalization = recalculate_capitalization(&genesis_bank);
+        log::info!(
+            "Rebuilt {} merge ledger accounts on a genesis bank ({} genesis accounts closed, \
+             capitalization {})",
+            merge_accounts.len(),
+            closed.len(),
+            capitalization
+        );
+
+        let genesis_bank = Arc::new(genesis_bank);
+        if merge_bank.slot() == genesis_bank.slot() {
+            genesis_bank.freeze();
+            return Ok(genesis_bank);
+        }
+        Ok(Arc::new(Bank::warp_from_parent(
+            genesis_bank,
+            merge_bank.collector_id(),
+            merge_bank.slot(),
+        )))
+    }
+
+    /// One line of the account index: the base58 pubkey, base58 owner,
+    /// lamports and data length, separated by single spaces
+    pub fn account_index_line(
+        pubkey: &str,
+        owner: &Pubkey,
+        lamports: u64,
+        data_len: usize,
+    ) -> String {
+        format!("{} {} {} {}", pubkey, owner, lamports, data_len)
+    }
+
+    /// Writes one [`account_index_line`] per loadable account of `bank` to
+    /// `path`, sorted by the pubkey string so the file can be binary-searched
+    /// or `look`ed up by pubkey. Lines are streamed out as the bank is
+    /// scanned, never held in memory. Returns the number of lines written.
+    pub fn write_account_index(bank: &Bank, path: &Path) -> Result<usize, MergeError> {
+        use std::io::{BufRead, Seek, Write};
+
+        let write_error = |e: std::io::Error| {
+            MergeError::Io(format!("Failed to write account index {:?}: {:?}", path, e))
+        };
+        // A scan in pubkey order is in string order among pubkeys of the same
+        // string length, so the 43- and 44-character pubkeys are spooled as
+        // two sorted runs and merged into the index
+        let spool = || -> Result<_, MergeError> {
+            Ok(std::io::BufWriter::new(
+                tempfile::tempfile().map_err(write_error)?,
+            ))
+        };
+        let (mut short_run, mut long_run) = (spool()?, spool()?);
+        let mut entries = 0;
+        let mut spooled = Ok(());
+        for_each_account(
+            bank,
+            AccountEnumeration::Scan,
+            ScanConsistency::Fixed,
+            |pubkey, account| {
+                if spooled.is_err() {
+                    return;
+                }
+                let pubkey = pubkey.to_string();
+                let run = if pubkey.len() < 44 {
+                    &mut short_run
+                } else {
+                    &mut long_run
+                };
+                spooled = writeln!(
+                    run,
+                    "{}",
+                    account_index_line(
+                        &pubkey,
+                        account.owner(),
+                        account.lamports(),
+                        account.data().len()
+                    )
+                );
+                entries += 1;
+            },
+        )?;
+        spooled.map_err(write_error)?;
+
+        let read_back = |run: std::io::BufWriter<std::fs::File>| -> Result<_, MergeError> {
+            let mut file = run.into_inner().map_err(|e| write_error(e.into_error()))?;
+            file.rewind().map_err(write_error)?;
+            Ok(std::io::BufReader::new(file).lines().peekable())
+        };
+        let (mut short_lines, mut long_lines) = (read_back(short_run)?, read_back(long_run)?);
+        let file = std::fs::File::create(path).map_err(|e| {
+            MergeError::Io(format!(
+                "Failed to create account index {:?}: {:?}",
+                path, e
+            ))
+        })?;
+        let mut writer = std::io::BufWriter::new(file);
+        loop {
+            // A pubkey is followed by a space, which sorts before every
+            // base58 character, so whole lines compare like their pubkeys
+            let next = match (short_lines.peek(), long_lines.peek()) {
+                (Some(Ok(short)), Some(Ok(long))) if short > long => long_lines.next(),
+                (Some(_), _) => short_lines.next(),
+                (None, _) => long_lines.next(),
+            };
+            let Some(line) = next else {
+                break;
+            };
+            writeln!(writer, "{}", line.map_err(write_error)?).map_err(write_error)?;
+        }
+        writer.flush().map_err(write_error)?;
+        Ok(entries)
+    }
+
+    /// Which part of the merge a run performs
+    #[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+    pub enum MergePhase {
+        /// Scan mainnet and produce the merged snapshot in one run
+        #[default]
+        Full,
+        /// Scan and filter mainnet, write the copy set with
+        /// [`write_copy_set`] and stop
+        Extract,
+        /// Read a copy set written by an extract run instead of scanning
+        /// mainnet, and produce the merged snapshot from it
+        Apply,
+    }
+
+    impl FromStr for MergePhase {
+        type Err = String;
+
+        fn from_str(s: &str) -> Result<Self, Self::Err> {
+            match s {
+                "extract" => Ok(Self::Extract),
+                "apply" => Ok(Self::Apply),
+                _ => Err(format!(
+                    "Invalid merge phase '{}', expected 'extract' or 'apply'",
+                    s
+                )),
+            }
+        }
+    }
+
+    /// First bytes of a copy set file
+    pub const COPY_SET_MAGIC: [u8; 8] = *b"SMCOPYST";
+    /// Version of the copy set layout written by [`write_copy_set`]
+    pub const COPY_SET_VERSION: u32 = 5;
+
+    /// The mainnet side of a merge: the filtered accounts to copy and what
+    /// the mainnet scan left out. It is all the apply phase of a two-phase
+    /// merge needs from the mainnet ledger.
+    #[derive(Debug, Clone, Default, PartialEq)]
+    pub struct CopySet {
+        pub mainnet_slot: Slot,
+        pub mainnet_total_accounts: usize,
+        /// Mainnet vote and stake accounts, excluded from the copy
+        pub vote_accounts: HashSet<Pubkey>,
+        pub stake_accounts: HashSet<Pubkey>,
+        pub vote_accounts_excluded: usize,
+        pub stake_accounts_excluded: usize,
+        pub feature_accounts_excluded: usize,
+        pub lookup_tables_skipped: usize,
+        pub accounts_subtracted: usize,
+        pub outside_copy_owners: usize,
+        /// Accounts owned by a program of `MergeConfig::exclude_owners`
+        pub excluded_owner_accounts: usize,
+        /// Accounts listed in `MergeConfig::exclude_accounts`
+        pub excluded_listed_accounts: usize,
+        /// Accounts missing from `MergeConfig::include_accounts`
+        pub outside_include_accounts: usize,
+        /// Accounts with fewer lamports than `MergeConfig::min_lamports`
+        pub dust_accounts_excluded: usize,
+        /// Accounts with no lamports, skipped by
+        /// `MergeConfig::skip_zero_lamport_accounts`
+        pub zero_lamport_accounts_skipped: usize,
+        /// Accounts with more data than `MergeConfig::max_account_data_size`
+        pub oversized_accounts_excluded: usize,
+        pub duplicate_pubkeys_resolved: usize,
+        pub accounts: HashMap<Pubkey, AccountSharedData>,
+        /// The slot each account was last written in, recorded only for
+        /// [`ConflictPolicy::PreferNewerSlot`] and not written to copy set
+        /// files
+        pub account_slots: HashMap<Pubkey, Slot>,
+        /// What `MergeConfig::token_filter` left out and kept, if it was
+        /// active
+        pub tokens: Option<crate::spl::TokenReport>,
+        /// Accounts left out by each `MergeConfig::custom_filters` filter, by
+        /// name; not written to copy set files
+        pub custom_filter_exclusions: BTreeMap<String, usize>,
+    }
+
+    /// Writes `copy_set` to `path` as a sequence of bincode values:
+    /// - [`COPY_SET_MAGIC`] and the `u32` [`COPY_SET_VERSION`];
+    /// - the mainnet slot, total accounts and the thirteen exclusion counts of
+    ///   [`CopySet`] as `u64`s, in field order;
+    /// - the excluded vote and stake pubkeys as two sorted `Vec<Pubkey>`s;
+    /// - the token report, if the token filter was active: the six counts of
+    ///   [`crate::spl::TokenReport`] as `u64`s, in field order, then one
+    ///   `(mint, accounts, amount)` tuple per excluded mint in ascending mint
+    ///   order;
+    /// - the `u64` number of accounts, then one `(pubkey, lamports, owner,
+    ///   executable, rent_epoch, data)` tuple per account in ascending pubkey
+    ///   order.
+    ///
+    /// Accounts are streamed one at a time, so only the sorted references are
+    /// held besides the copy set itself. Returns the number of accounts.
+    pub fn write_copy_set(copy_set: &CopySet, path: &Path) -> Result<usize, MergeError> {
+        use std::io::Write;
+
+        let write_error =
+            |e| MergeError::Io(format!("Failed to write copy set {:?}: {:?}", path, e));
+        let file = std::fs::File::create(path).map_err(|e| {
+            MergeError::Io(format!("Failed to create copy set {:?}: {:?}", path, e))
+        })?;
+        let mut writer = std::io::BufWriter::new(file);
+        let sorted_pubkeys = |pubkeys: &HashSet<Pubkey>| {
+            let mut pubkeys: Vec<_> = pubkeys.iter().copied().collect();
+            pubkeys.sort_unstable();
+            pubkeys
+        };
+        let header = (
+            COPY_SET_MAGIC,
+            COPY_SET_VERSION,
+            copy_set.mainnet_slot,
+            copy_set.mainnet_total_accounts as u64,
+            [
+                copy_set.vote_accounts_excluded,
+                copy_set.stake_accounts_excluded,
+                copy_set.feature_accounts_excluded,
+                copy_set.lookup_tables_skipped,
+                copy_set.accounts_subtracted,
+                copy_set.outside_copy_owners,
+                copy_set.excluded_owner_accounts,
+                copy_set.excluded_listed_accounts,
+                copy_set.outside_include_accounts,
+                copy_set.dust_accounts_excluded,
+                copy_set.zero_lamport_accounts_skipped,
+                copy_set.oversized_accounts_excluded,
+                copy_set.duplicate_pubkeys_resolved,
+            ]
+            .map(|count| count as u64),
+            sorted_pubkeys(&copy_set.vote_accounts),
+            sorted_pubkeys(&copy_set.stake_accounts),
+            copy_set.tokens.as_ref().map(|tokens| {
+                (
+                    [
+                        tokens.token_accounts_copied,
+                        tokens.mints_copied,
+                        tokens.mints_held,
+                        tokens.mints_held_without_mint,
+                        tokens.mints_short_of_supply,
+                        tokens.excluded_below_min_balance,
+                    ]
+                    .map(|count| count as u64),
+                    tokens
+                        .excluded_by_mint
+                        .iter()
+                        .map(|(mint, exclusion)| {
+                            (*mint, exclusion.accounts as u64, exclusion.amount)
+                        })
+                        .collect::<Vec<_>>(),
+                )
+            }),
+            copy_set.accounts.len() as u64,
+        );
+        bincode::serialize_into(&mut writer, &header).map_err(write_error)?;
+        for (pubkey, account) in sort_accounts_by_pubkey(&copy_set.accounts) {
+            let record = (
+                pubkey,
+                account.lamports(),
+                account.owner(),
+                account.executable(),
+                account.rent_epoch(),
+                account.data(),
+            );
+            bincode::serialize_into(&mut writer, &record).map_err(write_error)?;
+        }
+        writer
+            .flush()
+            .map_err(|e| MergeError::Io(format!("Failed to write copy set {:?}: {:?}", path, e)))?;
+        Ok(copy_set.accounts.len())
+    }
+
+    /// Reads a copy set written by [`write_copy_set`]
+    pub fn read_copy_set(path: &Path) -> Result<CopySet, MergeError> {
+        type Header = (
+            [u8; 8],
+            u32,
+            Slot,
+            u64,
+            [u64; 13],
+            Vec<Pubkey>,
+            Vec<Pubkey>,
+            Option<([u64; 6], Vec<(Pubkey, u64, u64)>)>,
+            u64,
+        );
+        type Record = (Pubkey, u64, Pubkey, bool, Epoch, Vec<u8>);
+
+        let read_error = |e| MergeError::Io(format!("Failed to read copy set {:?}: {:?}", path, e));
+        let file = std::fs::File::open(path)
+            .map_err(|e| MergeError::Io(format!("Failed to open copy set {:?}: {:?}", path, e)))?;
+        let mut reader = std::io::BufReader::new(file);
+        let (
+            magic,
+            version,
+            mainnet_slot,
+            mainnet_total_accounts,
+            counts,
+            votes,
+            stakes,
+            tokens,
+            len,
+        ): Header = bincode::deserialize_from(&mut reader).map_err(read_error)?;
+        if magic != COPY_SET_MAGIC {
+            return Err(MergeError::Io(format!("{:?} is not a copy set file", path)));
+        }
+        if version != COPY_SET_VERSION {
+            return Err(MergeError::Io(format!(
+                "Copy set {:?} has version {}, expected {}",
+                path, version, COPY_SET_VERSION
+            )));
+        }
+        let [vote_accounts_excluded, stake_accounts_excluded, feature_accounts_excluded, lookup_tables_skipped, accounts_subtracted, outside_copy_owners, excluded_owner_accounts, excluded_listed_accounts, outside_include_accounts, dust_accounts_excluded, zero_lamport_accounts_skipped, oversized_accounts_excluded, duplicate_pubkeys_resolved] =
+            counts.map(|count| count as usize);
+        let tokens = tokens.map(|(counts, excluded_by_mint)| {
+            let [token_accounts_copied, mints_copied, mints_held, mints_held_without_mint, mints_short_of_supply, excluded_below_min_balance] =
+                counts.map(|count| count as usize);
+            crate::spl::TokenReport {
+                token_accounts_copied,
+                mints_copied,
+                mints_held,
+                mints_held_without_mint,
+                mints_short_of_supply,
+                excluded_by_mint: excluded_by_mint
+                    .into_iter()
+                    .map(|(mint, accounts, amount)| {
+                        let accounts = accounts as usize;
+                        (mint, crate::spl::MintExclusion { accounts, amount })
+                    })
+                    .collect(),
+                excluded_below_min_balance,
+            }
+        });
+        let mut accounts = HashMap::with_capacity(len as usize);
+        for _ in 0..len {
+            let (pubkey, lamports, owner, executable, rent_epoch, data): Record =
+                bincode::deserialize_from(&mut reader).map_err(read_error)?;
+            let account = AccountSharedData::from(solana_account::Account {
+                lamports,
+                data,
+                owner,
+                executable,
+                rent_epoch,
+            });
+            accounts.insert(pubkey, account);
+        }
+        Ok(CopySet {
+            mainnet_slot,
+            mainnet_total_accounts: mainnet_total_accounts as usize,
+            vote_accounts: votes.into_iter().collect(),
+            stake_accounts: stakes.into_iter().collect(),
+            vote_accounts_excluded,
+            stake_accounts_excluded,
+            feature_accounts_excluded,
+            lookup_tables_skipped,
+            accounts_subtracted,
+            outside_copy_owners,
+            excluded_owner_accounts,
+            excluded_listed_accounts,
+            outside_include_accounts,
+            dust_accounts_excluded,
+            zero_lamport_accounts_skipped,
+            oversized_accounts_excluded,
+            duplicate_pubkeys_resolved,
+            accounts,
+            account_slots: HashMap::new(),
+            tokens,
+            custom_filter_exclusions: BTreeMap::new(),
+        })
+    }
+
+    /// Totals of a diff against the base ledger
+    #[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize)]
+    pub struct BaseDiffSummary {
+        /// Stored accounts whose pubkey the base didn't have
+        pub added: usize,
+        /// Stored accounts replacing a different base account
+        pub modified: usize,
+        /// Stored accounts identical to the base account they replace
+        pub unchanged: usize,
+        /// Net change in lamports, i.e. in capitalization
+        pub lamports_delta: i64,
+    }
+
+    /// The diff record of storing `account` over `base`, `None` when the two
+    /// are identical. Modified records name the fields that changed and carry
+    /// the base side's values.
+    pub fn base_diff_record(
+        pubkey: &Pubkey,
+        base: Option<&AccountSharedData>,
+        account: &AccountSharedData,
+    ) -> Option<serde_json::Value> {
+        let mut record = serde_json::json!({
+            "change": "added",
+            "pubkey": pubkey.to_string(),
+            "owner": account.owner().to_string(),
+            "lamports": account.lamports(),
+            "data_len": account.data().len(),
+        });
+        let Some(base) = base else {
+            return Some(record);
+        };
+        if base == account {
+            return None;
+        }
+        let fields_changed: Vec<_> = [
+            ("lamports", base.lamports() != account.lamports()),
+            ("data", base.data() != account.data()),
+            ("owner", base.owner() != account.owner()),
+            ("executable", base.executable() != account.executable()),
+            ("rent_epoch", base.rent_epoch() != account.rent_epoch()),
+        ]
+        .into_iter()
+        .filter_map(|(field, changed)| changed.then_some(field))
+        .collect();
+        record["change"] = "modified".into();
+        record["base_owner"] = base.owner().to_string().into();
+        record["base_lamports"] = base.lamports().into();
+        record["base_data_len"] = base.data().len().into();
+        record["fields_changed"] = fields_changed.into();
+        Some(record)
+    }
+
+    /// The diff file the account copy writes a [`base_diff_record`] to for
+    /// every account that adds to or changes the bank it is stored into, as it
+    /// is stored, so the copy set isn't walked a second time. Shared by the
+    /// threads of a sharded copy; the first write error is kept for
+    /// [`BaseDiffWriter::finish`].
+    #[derive(Debug)]
+    pub struct BaseDiffWriter {
+        path: PathBuf,
+        state: std::sync::Mutex<BaseDiffState>,
+    }
+
+    #[derive(Debug)]
+    struct BaseDiffState {
+        writer: std::io::BufWriter<std::fs::File>,
+        summary: BaseDiffSummary,
+        error: Option<std::io::Error>,
+    }
+
+    impl BaseDiffWriter {
+        pub fn create(path: PathBuf) -> Result<Self, MergeError> {
+            let file = std::fs::File::create(&path).map_err(|e| {
+                MergeError::Io(format!("Failed to create base diff {:?}: {:?}", path, e))
+            })?;
+            Ok(Self {
+                path,
+                state: std::sync::Mutex::new(BaseDiffState {
+                    writer: std::io::BufWriter::new(file),
+                    summary: BaseDiffSummary::default(),
+                    error: None,
+                }),
+            })
+        }
+
+        pub fn path(&self) -> &Path {
+            &self.path
+        }
+
+        /// Records storing `account` at `pubkey` over `base`, the bank's
+        /// account before the store
+        pub fn record(
+            &self,
+            pubkey: &Pubkey,
+            base: Option<&AccountSharedData>,
+            account: &AccountSharedData,
+        ) {
+            use std::io::Write;
+
+            let record = base_diff_record(pubkey, base, account);
+            let mut state = self.state.lock().unwrap_or_else(|e| e.into_inner());
+            let Some(record) = record else {
+                state.summary.unchanged += 1;
+                return;
+            };
+            match base {
+                Some(_) => state.summary.modified += 1,
+                None => state.summary.added += 1,
+            }
+            state.summary.lamports_delta +=
+                account.lamports() as i64 - base.map_or(0, |base| base.lamports()) as i64;
+            if state.error.is_none() {
+                if let Err(e) = writeln!(state.writer, "{}", record) {
+                    state.error = Some(e);
+                }
+            }
+        }
+
+        /// Writes a `{"summary": ...}` line with the totals and returns them
+        pub fn finish(self) -> Result<BaseDiffSummary, MergeError> {
+            use std::io::Write;
+
+            let write_error = |e| {
+                MergeError::Io(format!(
+                    "Failed to write base diff {:?}: {:?}",
+                    self.path, e
+                ))
+            };
+            let mut state = self.state.into_inner().unwrap_or_else(|e| e.into_inner());
+            if let Some(e) = state.error {
+                return Err(write_error(e));
+            }
+            let totals = serde_json::json!({
+                "summary": {
+                    "added": state.summary.added,
+                    "modified": state.summary.modified,
+                    "unchanged": state.summary.unchanged,
+                    "lamports_delta": state.summary.lamports_delta,
+                }
+            });
+            writeln!(state.writer, "{}", totals).map_err(write_error)?;
+            state.writer.flush().map_err(write_error)?;
+            Ok(state.summary)
+        }
+    }
+
+    /// Counts total accounts in the bank
+    pub fn count_total_accounts(bank: &Bank) -> Result<usize, MergeError> {
+        let mut count = 0;
+        bank.scan_all_accounts(
+            |_| {
+                count += 1;
+            },
+            true,
+        )
+        .map_err(|e| MergeError::AccountScan(format!("Failed to scan accounts: {:?}", e)))?;
+        Ok(count)
+    }
+
+    /// Checks that `bank` holds exactly `expected` accounts, counted like
+    /// [`count_total_accounts`]
+    pub fn assert_account_count(bank: &Bank, expected: usize) -> Result<(), MergeError> {
+        let count = count_total_accounts(bank)?;
+        if count != expected {
+            return Err(MergeError::Verification(format!(
+                "Bank at slot {} has {} accounts, expected {}",
+                bank.slot(),
+                count,
+                expected
+            )));
+        }
+        Ok(())
+    }
+
+    /// Checks that no loadable account of `bank` is owned by `owner`, e.g. that
+    /// no mainnet validators were left behind
+    pub fn assert_no_accounts_owned_by(bank: &Bank, owner: &Pubkey) -> Result<(), MergeError> {
+        let mut owned = Vec::new();
+        bank.scan_all_accounts(
+            |item| {
+                if let Some((pubkey, account, _slot)) = item {
+                    if account.lamports() != 0 && account.owner() == owner {
+                        owned.push(*pubkey);
+                    }
+                }
+            },
+            false,
+        )
+        .map_err(|e| MergeError::AccountScan(format!("Failed to scan accounts: {:?}", e)))?;
+        if let Some(pubkey) = owned.iter().min() {
+            return Err(MergeError::Verification(format!(
+                "Bank at slot {} has {} accounts owned by {}, e.g. {}",
+                bank.slot(),
+                owned.len(),
+                owner,
+                pubkey
+            )));
+        }
+        Ok(())
+    }
+
+    /// Freezes `bank` as an explicit step, turning a panic during freeze into an
+    /// error. Returns the bank hash, which must not be the default hash. A bank
+    /// that is already frozen is only checked.
+    pub fn freeze_bank(bank: &Bank) -> Result<Hash, MergeError> {
+        if !bank.is_frozen() {
+            std::panic::catch_unwind(std::panic::AssertUnwindSafe(|| bank.freeze())).map_err(
+                |panic| {
+                    let reason = panic
+                        .downcast_ref::<String>()
+                        .map(String::as_str)
+                        .or_else(|| panic.downcast_ref::<&str>().copied())
+                        .unwrap_or("unknown panic");
+                    MergeError::Verification(format!(
+                        "Freezing bank at slot {} failed: {}",
+                        bank.slot(),
+                        reason
+                    ))
+                },
+            )?;
+        }
+        if !bank.is_frozen() {
+            return Err(MergeError::Verification(format!(
+                "Bank at slot {} did not freeze",
+                bank.slot()
+            )));
+        }
+        let hash = bank.hash();
+        if hash == Hash::default() {
+            return Err(MergeError::Verification(format!(
+                "Bank at slot {} froze with the default hash",
+                bank.slot()
+            )));
+        }
+        Ok(hash)
+    }
+
+    /// Rejects PoH overrides that would stop the cluster from producing slots
+    pub fn validate_poh_overrides(
+        hashes_per_tick: Option<u64>,
+        ticks_per_slot: Option<u64>,
+    ) -> Result<(), MergeError> {
+        if hashes_per_tick == Some(0) {
+            return Err(MergeError::InvalidInput(
+                "hashes_per_tick override must be greater than zero".to_string(),
+            ));
+        }
+        if ticks_per_slot == Some(0) {
+            return Err(MergeError::InvalidInput(
+                "ticks_per_slot override must be greater than zero".to_string(),
+            ));
+        }
+        Ok(())
+    }
+
+    /// Overrides the PoH timing fields of `genesis_config`. Changing them
+    /// changes slot durations, so this is only meant for test clusters. A bank
+    /// keeps the fields of the genesis it was created from, so they only hold
+    /// for a bank created from the overridden genesis.
+    pub fn apply_poh_overrides(
+        genesis_config: &mut GenesisConfig,
+        hashes_per_tick: Option<u64>,
+        ticks_per_slot: Option<u64>,
+    ) -> Result<(), MergeError> {
+        validate_poh_overrides(hashes_per_tick, ticks_per_slot)?;
+        if let Some(hashes_per_tick) = hashes_per_tick {
+            log::info!(
+                "hashes_per_tick: {:?} -> {}",
+                genesis_config.poh_config.hashes_per_tick,
+                hashes_per_tick
+            );
+            genesis_config.poh_config.hashes_per_tick = Some(hashes_per_tick);
+        }
+        if let Some(ticks_per_slot) = ticks_per_slot {
+            log::info!(
+                "ticks_per_slot: {} -> {}",
+                genesis_config.ticks_per_slot,
+                ticks_per_slot
+            );
+            genesis_config.ticks_per_slot = ticks_per_slot;
+        }
+        Ok(())
+    }
+
+    /// Writes `genesis_config` to `genesis.bin` in `dir`, returning its path
+    pub fn write_genesis_config(
+        genesis_config: &GenesisConfig,
+        dir: &Path,
+    ) -> Result<PathBuf, MergeError> {
+        let genesis_path = dir.join("genesis.bin");
+        let genesis_file = std::fs::File::create(&genesis_path)
+            .map_err(|e| MergeError::Io(format!("Failed to create genesis file: {:?}", e)))?;
+        bincode::serialize_into(genesis_file, genesis_config)
+            .map_err(|e| MergeError::Io(format!("Failed to serialize genesis config: {:?}", e)))?;
+        Ok(genesis_path)
+    }
+
+    /// Packs the `genesis.bin` at `genesis_path` into a `genesis.tar.bz2` next
+    /// to it, the form validators and RPC nodes hand the genesis around in
+    pub fn write_genesis_archive(genesis_path: &Path) -> Result<PathBuf, MergeError> {
+        let archive_path = genesis_path.with_file_name(DEFAULT_GENESIS_ARCHIVE);
+        let archive_error = |e: std::io::Error| {
+            MergeError::Io(format!("Failed to write {:?}: {:?}", archive_path, e))
+        };
+        let archive_file = std::fs::File::create(&archive_path).map_err(archive_error)?;
+        let mut archive = tar::Builder::new(bzip2::write::BzEncoder::new(
+            archive_file,
+            bzip2::Compression::best(),
+        ));
+        archive
+            .append_path_with_name(genesis_path, DEFAULT_GENESIS_FILE)
+            .map_err(archive_error)?;
+        archive
+            .into_inner()
+            .and_then(|encoder| encoder.finish())
+            .map_err(archive_error)?;
+        Ok(archive_path)
+    }
+
+    /// Seed of the default provenance memo address
+    pub const PROVENANCE_SEED: &[u8] = b"snapshot-merger-provenance";
+
+    /// Default address of the provenance memo account: the program address
+    /// derived from [`PROVENANCE_SEED`] under the System Program. Nobody can
+    /// sign for it, so no transaction can change the memo after the fact.
+    pub fn provenance_address() -> Pubkey {
+        Pubkey::find_program_address(&[PROVENANCE_SEED], &system_program::id()).0
+    }
+
+    /// Stores `memo` as JSON in a System Program owned account at `address`,
+    /// funded with the rent-exempt minimum for its size. The caller must
+    /// recalculate capitalization afterwards. Returns the lamports used.
+    pub fn store_provenance_memo(
+        bank: &Bank,
+        address: &Pubkey,
+        memo: &serde_json::Value,
+    ) -> Result<u64, MergeError> {
+        let data = serde_json::to_vec(memo).map_err(|e| {
+            MergeError::AccountCopy(format!("Failed to serialize provenance memo: {:?}", e))
+        })?;
+        let lamports = bank.get_minimum_balance_for_rent_exemption(data.len());
+        let mut account = AccountSharedData::new(lamports, data.len(), &system_program::id());
+        account.set_data_from_slice(&data);
+        store_account_tracking_data_size(bank, address, &account);
+        Ok(lamports)
+    }
+
+    /// The ancestry [`verify_bank_lineage`] checked, as recorded by the bank's
+    /// slot hashes sysvar
+    #[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize)]
+    pub struct BankLineage {
+        /// Ancestor slots the slot hashes sysvar lists, the parent first
+        pub depth: usize,
+        /// Oldest ancestor listed, or the bank's own slot if none is
+        pub oldest_slot: Slot,
+        /// The checked bank's slot
+        pub newest_slot: Slot,
+    }
+
+    /// Checks the ancestry of `bank` is well-formed: every bank still linked
+    /// by a parent pointer has the slot and hash its child recorded for it,
+    /// and the slot hashes sysvar, which outlives squashing, lists strictly
+    /// decreasing slots starting with the bank's own parent slot and hash.
+    pub fn verify_bank_lineage(bank: &Bank) -> Result<BankLineage, MergeError> {
+        let mut child = bank.slot();
+        let mut parent_slot = bank.parent_slot();
+        let mut parent_hash = bank.parent_hash();
+        let mut link = bank.parent();
+        while let Some(parent) = link {
+            if parent.slot() != parent_slot || parent.hash() != parent_hash {
+                return Err(MergeError::Verification(format!(
+                    "Bank at slot {} records parent {} ({}) but its parent is slot {} ({})",
+                    child,
+                    parent_slot,
+                    parent_hash,
+                    parent.slot(),
+                    parent.hash()
+                )));
+            }
+            child = parent.slot();
+            parent_slot = parent.parent_slot();
+            parent_hash = parent.parent_hash();
+            link = parent.parent();
+        }
+
+        let slot_hashes: SlotHashes =
+            match bank.get_account(&solana_sdk_ids::sysvar::slot_hashes::id()) {
+                Some(account) => bincode::deserialize(account.data()).map_err(|e| {
+                    MergeError::Verification(format!("Failed to deserialize slot hashes: {:?}", e))
+                })?,
+                None => SlotHashes::default(),
+            };
+        let mut previous = bank.slot();
+        for (slot, _) in slot_hashes.iter() {
+            if *slot >= previous {
+                return Err(MergeError::Verification(format!(
+                    "Slot hashes list slot {} after slot {}; slots must strictly decrease",
+                    slot, previous
+                )));
+            }
+            previous = *slot;
+        }
+        let lineage = BankLineage {
+            depth: slot_hashes.len(),
+            oldest_slot: previous,
+            newest_slot: bank.slot(),
+        };
+        // A genesis bank has no parent to record
+        if bank.slot() == 0 {
+            return Ok(lineage);
+        }
+        match slot_hashes.first() {
+            Some(&(slot, hash)) if slot == bank.parent_slot() && hash == bank.parent_hash() => {
+                Ok(lineage)
+            }
+            Some(&(slot, hash)) => Err(MergeError::Verification(format!(
+                "Bank at slot {} has parent {} ({}) but its slot hashes start with {} ({})",
+                bank.slot(),
+                bank.parent_slot(),
+                bank.parent_hash(),
+                slot,
+                hash
+            ))),
+            None => Err(MergeError::Verification(format!(
+                "Bank at slot {} has no slot hashes for its parent",
+                bank.slot()
+            ))),
+        }
+    }
+
+    /// Storages of the slots a merge created and the account entries they hold
+    #[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize)]
+    pub struct StorageCheck {
+        pub storages: usize,
+        pub entries: usize,
+        /// Entries of accounts the merge stores again on purpose, held by
+        /// more than one storage
+        pub restored_entries: usize,
+    }
+
+    /// Checks that no account is held by more than one storage of the slots
+    /// after `base_slot` that go into `bank`'s snapshot, i.e. that every slot
+    /// the copy created holds the only version of the accounts it wrote.
+    /// `restored` are the accounts the merge stores again after the copy on
+    /// purpose, such as the merge ledger's system accounts, overrides and
+    /// clones, whose duplicates are only counted. Sysvars are left out: the
+    /// runtime rewrites them in every new slot. Storages at or before
+    /// `base_slot` come from the loaded ledger and keep the versions the merge
+    /// superseded until accounts-db cleans them.
+    ///
+    /// Only the pubkeys seen so far are held while scanning; the slots are
+    /// looked up afterwards for the few duplicates reported.
+    pub fn verify_no_duplicate_storage(
+        bank: &Bank,
+        base_slot: Slot,
+        restored: &HashSet<Pubkey>,
+    ) -> Result<StorageCheck, MergeError> {
+        let storages = bank.get_snapshot_storages(Some(base_slot));
+        let scan_storages = |visit: &mut dyn FnMut(&Pubkey, Slot)| {
+            for storage in &storages {
+                storage
+                    .accounts
+                    .scan_pubkeys(|pubkey| visit(pubkey, storage.slot()))
+                    .map_err(|e| {
+                        MergeError::Verification(format!(
+                            "Failed to scan the storage of slot {}: {:?}",
+                            storage.slot(),
+                            e
+                        ))
+                    })?;
+            }
+            Ok::<_, MergeError>(())
+        };
+
+        let mut check = StorageCheck {
+            storages: storages.len(),
+            ..StorageCheck::default()
+        };
+        let mut seen = HashSet::new();
+        let mut duplicates = HashSet::new();
+        scan_storages(&mut |pubkey, _slot| {
+            check.entries += 1;
+            if seen.insert(*pubkey) {
+                return;
+            }
+            if restored.contains(pubkey) {
+                check.restored_entries += 1;
+            } else {
+                duplicates.insert(*pubkey);
+            }
+        })?;
+        drop(seen);
+        duplicates.retain(|pubkey| {
+            bank.get_account(pubkey)
+                .is_none_or(|account| *account.owner() != sysvar::id())
+        });
+        if duplicates.is_empty() {
+            return Ok(check);
+        }
+
+        let mut examples: Vec<_> = duplicates.iter().copied().collect();
+        examples.sort_unstable();
+        examples.truncate(MAX_REPORTED_DUPLICATES);
+        let mut example_slots: BTreeMap<Pubkey, Vec<Slot>> = examples
+            .into_iter()
+            .map(|pubkey| (pubkey, Vec::new()))
+            .collect();
+        scan_storages(&mut |pubkey, slot| {
+            if let Some(slots) = example_slots.get_mut(pubkey) {
+                slots.push(slot);
+            }
+        })?;
+        let examples: Vec<_> = example_slots
+            .into_iter()
+            .map(|(pubkey, mut slots)| {
+                slots.sort_unstable();
+                format!("{} (slots {:?})", pubkey, slots)
+            })
+            .collect();
+        Err(MergeError::Verification(format!(
+            "{} accounts are held by more than one of the {} storages written after slot {}, \
+             e.g. {}",
+            duplicates.len(),
+            check.storages,
+            base_slot,
+            examples.join(", ")
+        )))
+    }
+
+    /// Checks that `bank` was built from `genesis_config`, returning the
+    /// genesis hash. A bank only holds the genesis hash in its blockhash queue
+    /// until it ages out after a few hundred slots, so the genesis fields the
+    /// bank keeps for good are compared as well; a diverging field or, while
+    /// the queue still reaches back to genesis, a different hash fails.
+    pub fn verify_bank_genesis(
+        bank: &Bank,
+        genesis_config: &GenesisConfig,
+    ) -> Result<Hash, MergeError> {
+        let genesis_hash = genesis_config.hash();
+        let mut mismatches = Vec::new();
+        if bank.genesis_creation_time() != genesis_config.creation_time {
+            mismatches.push("creation time");
+        }
+        if bank.ticks_per_slot() != genesis_config.ticks_per_slot {
+            mismatches.push("ticks per slot");
+        }
+        if *bank.hashes_per_tick() != genesis_config.poh_config.hashes_per_tick {
+            mismatches.push("hashes per tick");
+        }
+        if *bank.epoch_schedule() != genesis_config.epoch_schedule {
+            mismatches.push("epoch schedule");
+        }
+        if bank.cluster_type() != genesis_config.cluster_type {
+            mismatches.push("cluster type");
+        }
+        if !mismatches.is_empty() {
+            return Err(MergeError::Verification(format!(
+                "Bank does not match genesis {}: {} differ",
+                genesis_hash,
+                mismatches.join(", ")
+            )));
+        }
+        if bank.slot() <= MAX_RECENT_BLOCKHASHES as Slot
+            && bank.get_hash_age(&genesis_hash).is_none()
+        {
+            return Err(MergeError::Verification(format!(
+                "Genesis hash {} is not in the blockhash queue of the bank at slot {}",
+                genesis_hash,
+                bank.slot()
+            )));
+        }
+        Ok(genesis_hash)
+    }
+
+    /// Reads back the `genesis.bin` at `genesis_path` and checks `bank`
+    /// against it with [`verify_bank_genesis`], so a genesis that was written
+    /// wrong fails here rather than when a validator boots the snapshot.
+    /// Returns the hash of the genesis read back.
+    pub fn verify_written_genesis(bank: &Bank, genesis_path: &Path) -> Result<Hash, MergeError> {
+        let genesis_file = std::fs::File::open(genesis_path)
+            .map_err(|e| MergeError::Io(format!("Failed to open {:?}: {:?}", genesis_path, e)))?;
+        let genesis_config: GenesisConfig =
+            bincode::deserialize_from(genesis_file).map_err(|e| {
+                MergeError::Verification(format!(
+                    "Failed to deserialize {:?}: {:?}",
+                    genesis_path, e
+                ))
+            })?;
+        verify_bank_genesis(bank, &genesis_config)
+            .map_err(|e| e.context(format!("Written {:?}", genesis_path)))
+    }
+
+    /// Checks the snapshot hash of a freshly loaded bank, the hash its archive
+    /// name carries, against `expected`. `label` names the bank in the error.
+    pub fn verify_snapshot_hash(
+        bank: &Bank,
+        expected: &Hash,
+        label: &str,
+    ) -> Result<(), MergeError> {
+        let loaded = bank.get_snapshot_hash().0;
+        if loaded != *expected {
+            return Err(MergeError::Verification(format!(
+                "{} bank at slot {} has snapshot hash {} instead of the expected {}",
+                label,
+                bank.slot(),
+                loaded,
+                expected
+            )));
+        }
+        Ok(())
+    }
+
+    /// Loads a bank directly from a full snapshot archive, unpacking its account
+    /// storages into `scratch_dir`
+    pub fn load_bank_from_snapshot_archive(
+        archive_path: &Path,
+        genesis_config: &GenesisConfig,
+        accounts_index_config: &AccountsIndexConfig,
+        scratch_dir: &Path,
+    ) -> Result<Arc<Bank>, MergeError> {
+        log::info!("Loading bank from snapshot archive {:?}", archive_path);
+        let archive_info = FullSnapshotArchiveInfo::new_from_path(archive_path.to_path_buf())
+            .map_err(|e| {
+                MergeError::SnapshotLoad(format!(
+                    "Invalid snapshot archive {:?}: {:?}",
+                    archive_path, e
+                ))
+            })?;
+
+        let accounts_dir = scratch_dir.join("accounts");
+        let bank_snapshots_dir = scratch_dir.join("bank_snapshots");
+        std::fs::create_dir_all(&accounts_dir).map_err(|e| {
+            MergeError::SnapshotLoad(format!("Failed to create accounts directory: {:?}", e))
+        })?;
+        std::fs::create_dir_all(&bank_snapshots_dir).map_err(|e| {
+            MergeError::SnapshotLoad(format!(
+                "Failed to create bank snapshots directory: {:?}",
+                e
+            ))
+        })?;
+
+        let (bank, _timings) = snapshot_bank_utils::bank_from_snapshot_archives(
+            &[accounts_dir],
+            &bank_snapshots_dir,
+            &archive_info,
+            None,
+            genesis_config,
+            &RuntimeConfig::default(),
+            None,
+            None,
+            None,
+            false,
+            false,
+            false,
+            Some(AccountsDbConfig {
+                index: Some(accounts_index_config.clone()),
+                ..AccountsDbConfig::default()
+            }),
+            None,
+            Arc::new(AtomicBool::new(false)),
+        )
+        .map_err(|e| {
+            MergeError::SnapshotLoad(format!(
+                "Failed to load bank from snapshot archive: {:?}",
+                e
+            ))
+        })?;
+
+        log::info!("Loaded bank at slot {} from archive", bank.slot());
+        Ok(Arc::new(bank))
+    }
+
+    /// An account that differs between the compared banks. `None` means the
+    /// account is missing from that side.
+    #[derive(Debug, Clone, PartialEq)]
+    pub struct AccountDivergence {
+        pub pubkey: Pubkey,
+        pub actual: Option<AccountSharedData>,
+        pub expected: Option<AccountSharedData>,
+    }
+
+    /// Result of comparing a bank against a reference bank
+    #[derive(Debug, Clone)]
+    pub struct BankComparison {
+        pub actual_accounts: usize,
+        pub expected_accounts: usize,
+        pub actual_capitalization: u64,
+        pub expected_capitalization: u64,
+        pub actual_accounts_hash: SnapshotHash,
+        pub expected_accounts_hash: SnapshotHash,
+        /// Number of diverging accounts found by the per-account comparison,
+        /// `None` when it was not run
+        pub divergent_accounts: Option<usize>,
+        /// The first diverging accounts, in pubkey order
+        pub divergences: Vec<AccountDivergence>,
+    }
+
+    impl BankComparison {
+        pub fn is_match(&self) -> bool {
+            self.actual_accounts == self.expected_accounts
+                && self.actual_capitalization == self.expected_capitalization
+                && self.actual_accounts_hash == self.expected_accounts_hash
+                && self.divergent_accounts.unwrap_or(0) == 0
+        }
+    }
+
+    /// Compares `actual` against `expected` on account count, capitalization and
+    /// accounts hash. Both banks should be frozen so their accounts hashes are
+    /// final.
+    ///
+    /// With `deep_compare`, every account is also compared by streaming each bank
+    /// and looking the account up in the other bank. Only the `max_divergences`
+    /// diverging accounts with the lowest pubkeys are kept, so memory stays
+    /// bounded by `max_divergences` rather than the bank size.
+    pub fn compare_banks(
+        actual: &Bank,
+        expected: &Bank,
+        deep_compare: bool,
+        max_divergences: usize,
+    ) -> Result<BankComparison, MergeError> {
+        log::info!(
+            "Comparing bank at slot {} against reference bank at slot {}",
+            actual.slot(),
+            expected.slot()
+        );
+        let mut comparison = BankComparison {
+            actual_accounts: count_total_accounts(actual)?,
+            expected_accounts: count_total_accounts(expected)?,
+            actual_capitalization: actual.capitalization(),
+            expected_capitalization: expected.capitalization(),
+            actual_accounts_hash: actual.get_snapshot_hash(),
+            expected_accounts_hash: expected.get_snapshot_hash(),
+            divergent_accounts: None,
+            divergences: Vec::new(),
+        };
+
+        if deep_compare {
+            let mut divergent_accounts = 0;
+            // The scans don't visit pubkeys in order, so the lowest ones are
+            // only known once both are done
+            let mut lowest = BTreeMap::new();
+            let mut record = |divergence: AccountDivergence| {
+                divergent_accounts += 1;
+                lowest.insert(divergence.pubkey, divergence);
+                if lowest.len() > max_divergences {
+                    lowest.pop_last();
+                }
+            };
+
+            actual
+                .scan_all_accounts(
+                    |item| {
+                        if let Some((pubkey, account, _slot)) = item {
+                            let reference = expected.get_account(pubkey);
+                            if reference.as_ref() != Some(&account) {
+                                record(AccountDivergence {
+                                    pubkey: *pubkey,
+                                    actual: Some(account),
+                                    expected: reference,
+                                });
+                            }
+                        }
+                    },
+                    true,
+                )
+                .map_err(|e| {
+                    MergeError::AccountScan(format!("Failed to scan accounts: {:?}", e))
+                })?;
+            expected
+                .scan_all_accounts(
+                    |item| {
+                        if let Some((pubkey, account, _slot)) = item {
+                            if actual.get_account(pubkey).is_none() {
+                                record(AccountDivergence {
+                                    pubkey: *pubkey,
+                                    actual: None,
+                                    expected: Some(account),
+                                });
+                            }
+                        }
+                    },
+                    true,
+                )
+                .map_err(|e| {
+                    MergeError::AccountScan(format!("Failed to scan reference accounts: {:?}", e))
+                })?;
+
+            comparison.divergences = lowest.into_values().collect();
+            comparison.divergent_accounts = Some(divergent_accounts);
+        }
+
+        Ok(comparison)
+    }
+
+    /// Loader programs whose accounts are deployed programs
+    pub const LOADER_PROGRAM_IDS: [Pubkey; 4] = [
+        bpf_loader_deprecated::ID,
+        bpf_loader::ID,
+        bpf_loader_upgradeable::ID,
+        loader_v4::ID,
+    ];
+
+    /// Human-readable name of a loader program
+    pub fn loader_name(loader: &Pubkey) -> &'static str {
+        if *loader == bpf_loader_deprecated::ID {
+            "bpf-loader-deprecated"
+        } else if *loader == bpf_loader::ID {
+            "bpf-loader"
+        } else if *loader == bpf_loader_upgradeable::ID {
+            "bpf-loader-upgradeable"
+        } else if *loader == loader_v4::ID {
+            "loader-v4"
+        } else {
+            "unknown"
+        }
+    }
+
+    /// A deployed program
+    #[derive(Debug, Clone, PartialEq, Eq)]
+    pub struct ProgramInfo {
+        pub address: Pubkey,
+        pub loader: Pubkey,
+        /// Size of the program's data; for upgradeable programs this is the
+        /// ProgramData account, which holds the ELF
+        pub data_len: usize,
+        pub executable: bool,
+        pub upgrade_authority: Option<Pubkey>,
+    }
+
+    // Loader-v4 program header: slot (u64), authority (Pubkey), status (u64)
+    const LOADER_V4_AUTHORITY_OFFSET: usize = 8;
+    const LOADER_V4_STATUS_OFFSET: usize = 40;
+    const LOADER_V4_HEADER_LEN: usize = 48;
+    const LOADER_V4_STATUS_FINALIZED: u64 = 2;
+
+    fn loader_v4_authority(data: &[u8]) -> Option<Pubkey> {
+        if data.len() < LOADER_V4_HEADER_LEN {
+            return None;
+        }
+        let status = u64::from_le_bytes(
+            data[LOADER_V4_STATUS_OFFSET..LOADER_V4_HEADER_LEN]
+                .try_into()
+                .unwrap(),
+        );
+        if status == LOADER_V4_STATUS_FINALIZED {
+            return None;
+        }
+        let authority: [u8; 32] = data[LOADER_V4_AUTHORITY_OFFSET..LOADER_V4_STATUS_OFFSET]
+            .try_into()
+            .unwrap();
+        Some(Pubkey::new_from_array(authority))
+    }
+
+    /// Lists the programs deployed by the known loaders, largest first.
+    ///
+    /// Upgradeable programs are reported by their program address with the size
+    /// and upgrade authority of their ProgramData account; ProgramData and
+    /// buffer accounts are not listed on their own.
+    pub fn list_programs(bank: &Bank) -> Result<Vec<ProgramInfo>, MergeError> {
+        log::info!("Listing deployed programs...");
+        let mut programs = Vec::new();
+
+        for loader in LOADER_PROGRAM_IDS {
+            let accounts = extract_accounts_by_owner(bank, &loader, None)?;
+            if loader == bpf_loader_upgradeable::ID {
+                for (address, account) in &accounts {
+                    let Ok(UpgradeableLoaderState::Program {
+                        programdata_address,
+                    }) = bincode::deserialize(account.data())
+                    else {
+                        continue;
+                    };
+                    let programdata = accounts.get(&programdata_address);
+                    let upgrade_authority = programdata.and_then(|programdata| {
+                        match bincode::deserialize(programdata.data()) {
+                            Ok(UpgradeableLoaderState::ProgramData {
+                                upgrade_authority_address,
+                                ..
+                            }) => upgrade_authority_address,
+                            _ => None,
+                        }
+                    });
+                    programs.push(ProgramInfo {
+                        address: *address,
+                        loader,
+                        data_len: programdata.map_or(0, |programdata| programdata.data().len()),
+                        executable: account.executable(),
+                        upgrade_authority,
+                    });
+                }
+            } else {
+                for (address, account) in &accounts {
+                    programs.push(ProgramInfo {
+                        address: *address,
+                        loader,
+                        data_len: account.data().len(),
+                        executable: account.executable(),
+                        upgrade_authority: if loader == loader_v4::ID {
+                            loader_v4_authority(account.data())
+                        } else {
+                            None
+                        },
+                    });
+                }
+            }
+        }
+
+        programs.sort_by(|a, b| {
+            b.data_len
+                .cmp(&a.data_len)
+                .then_with(|| a.address.cmp(&b.address))
+        });
+        log::info!("Found {} programs", programs.len());
+        Ok(programs)
+    }
+
+    /// Copied upgradeable programs and ProgramData accounts without their
+    /// other half. A program whose ProgramData is missing fails to load
+    /// on-chain, and a ProgramData no program points to is unreachable.
+    #[derive(Debug, Clone, Default, PartialEq, Eq)]
+    pub struct ProgramPairingReport {
+        pub programs: Vec<Pubkey>,
+        pub program_data: Vec<Pubkey>,
+    }
+
+    impl ProgramPairingReport {
+        pub fn is_empty(&self) -> bool {
+            self.programs.is_empty() && self.program_data.is_empty()
+        }
+
+        pub fn pubkeys(&self) -> impl Iterator<Item = &Pubkey> {
+            self.programs.iter().chain(&self.program_data)
+        }
+    }
+
+    fn upgradeable_state(account: &AccountSharedData) -> Option<UpgradeableLoaderState> {
+        if account.owner() != &bpf_loader_upgradeable::id() {
+            return None;
+        }
+        bincode::deserialize(account.data()).ok()
+    }
+
+    fn is_program_data(account: Option<&AccountSharedData>) -> bool {
+        matches!(
+            account.and_then(upgradeable_state),
+            Some(UpgradeableLoaderState::ProgramData { .. })
+        )
+    }
+
+    /// Finds the upgradeable programs and ProgramData accounts in `copied`
+    /// whose other half is neither in `copied` nor already in `bank`. Only the
+    /// loader state at the start of each account's data is read, so `copied`
+    /// may hold just that header.
+    pub fn find_unpaired_programs(
+        copied: &HashMap<Pubkey, AccountSharedData>,
+        bank: &Bank,
+        fallback_threshold: Option<usize>,
+    ) -> Result<ProgramPairingReport, MergeError> {
+        let mut report = ProgramPairingReport::default();
+        let mut referenced = HashSet::new();
+        let mut copied_program_data = Vec::new();
+        for (pubkey, account) in copied {
+            match upgradeable_state(account) {
+                Some(UpgradeableLoaderState::Program {
+                    programdata_address,
+                }) => {
+                    if is_program_data(copied.get(&programdata_address))
+                        || is_program_data(bank.get_account(&programdata_address).as_ref())
+                    {
+                        referenced.insert(programdata_address);
+                    } else {
+                        report.programs.push(*pubkey);
+                    }
+                }
+                Some(UpgradeableLoaderState::ProgramData { .. }) => {
+                    copied_program_data.push(*pubkey)
+                }
+                _ => {}
+            }
+        }
+        if !copied_program_data.is_empty() {
+            let programs =
+                extract_accounts_by_owner(bank, &bpf_loader_upgradeable::id(), fallback_threshold)?;
+            referenced.extend(programs.values().filter_map(|account| {
+                match upgradeable_state(account) {
+                    Some(UpgradeableLoaderState::Program {
+                        programdata_address,
+                    }) => Some(programdata_address),
+                    _ => None,
+                }
+            }));
+            report.program_data = copied_program_data
+                .into_iter()
+                .filter(|pubkey| !referenced.contains(pubkey))
+                .collect();
+        }
+        report.programs.sort_unstable();
+        report.program_data.sort_unstable();
+        for pubkey in &report.programs {
+            log::warn!(
+                "Dropping program {}: its ProgramData account is not copied",
+                pubkey
+            );
+        }
+        for pubkey in &report.program_data {
+            log::warn!(
+                "Dropping ProgramData {}: no copied program points to it",
+                pubkey
+            );
+        }
+        Ok(report)
+    }
+
+    /// `account` cut down to the header [`find_unpaired_programs`] reads, for
+    /// keeping track of a copied upgradeable-loader account without its ELF
+    pub fn upgradeable_loader_header(account: &AccountSharedData) -> AccountSharedData {
+        let len = account
+            .data()
+            .len()
+            .min(UpgradeableLoaderState::size_of_programdata_metadata());
+        let mut header = AccountSharedData::new(account.lamports(), len, account.owner());
+        header.set_data_from_slice(&account.data()[..len]);
+        header
     }
 }
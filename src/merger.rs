@@ -0,0 +1,5626 @@
+// Merge orchestration - loads both ledgers, copies accounts and writes the merged snapshot
+
+use crate::error::MergeError;
+use crate::fetch;
+use crate::filter;
+use crate::merge::functions;
+use crate::rpc_fetch;
+use crate::spl;
+use crate::sysvars;
+use crate::transform;
+use crate::upload;
+use {
+    log::*,
+    serde::Serialize,
+    solana_account::{AccountSharedData, ReadableAccount},
+    solana_accounts_db::{
+        accounts_db::AccountsDbConfig, accounts_index::AccountsIndexConfig,
+        hardened_unpack::open_genesis_config,
+    },
+    solana_clock::{Epoch, Slot},
+    solana_genesis_config::{GenesisConfig, DEFAULT_GENESIS_ARCHIVE, DEFAULT_GENESIS_FILE},
+    solana_ledger::{
+        bank_forks_utils,
+        blockstore::{Blockstore, BlockstoreError},
+        blockstore_options::{AccessType, BlockstoreOptions},
+        blockstore_processor::ProcessOptions,
+    },
+    solana_pubkey::Pubkey,
+    solana_runtime::{
+        bank::Bank,
+        snapshot_archive_info::{FullSnapshotArchiveInfo, SnapshotArchiveInfoGetter},
+        snapshot_bank_utils,
+        snapshot_config::{SnapshotConfig, SnapshotUsage},
+        snapshot_utils::{self, ArchiveFormat, SnapshotVersion},
+    },
+    solana_sdk::hash::Hash,
+    solana_sdk_ids::bpf_loader_upgradeable,
+    std::{
+        cell::RefCell,
+        collections::{BTreeMap, HashMap, HashSet},
+        path::{Path, PathBuf},
+        sync::Arc,
+        time::{Duration, Instant, SystemTime, UNIX_EPOCH},
+    },
+};
+
+const SNAPSHOT_RETRY_DELAY: Duration = Duration::from_secs(10);
+pub(crate) const MAX_GENESIS_ARCHIVE_UNPACKED_SIZE: u64 = 10485760;
+
+/// Custom processing applied to the merged bank before it is snapshotted.
+///
+/// The hook runs after all accounts have been copied and capitalization has
+/// been recalculated, on the last unfrozen bank: with `--warp-slot` that is
+/// the bank the warp is taken from, because the warped bank is frozen and can
+/// no longer accept stores. Anything the hook stores ends up in the snapshot.
+/// Hooks that change balances must call
+/// [`functions::recalculate_capitalization`] before returning.
+pub type PostMergeHook = Box<dyn FnOnce(&Bank)>;
+
+/// What layering one additional source ledger over the copy set did
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct SourceLayer {
+    pub ledger: PathBuf,
+    pub slot: Slot,
+    /// Accounts the source contributed after filtering
+    pub accounts: usize,
+    pub merge: functions::AccountMapMergeStats,
+}
+
+#[derive(Debug, Default)]
+pub struct MergeStats {
+    pub mainnet_total_accounts: usize,
+    pub merge_total_accounts: usize,
+    pub mainnet_vote_accounts_excluded: usize,
+    pub mainnet_stake_accounts_excluded: usize,
+    /// Mainnet feature-gate accounts the scan left out per
+    /// `MergeConfig::feature_set`
+    pub mainnet_feature_accounts_excluded: usize,
+    /// Feature-gate accounts copied unless `MergeConfig::feature_set` is
+    /// `FeatureSetPolicy::MergeLedger`
+    pub mainnet_features: Option<functions::FeatureAccountAudit>,
+    /// The merge ledger's feature accounts closed with
+    /// `FeatureSetPolicy::Mainnet`
+    pub merge_feature_accounts_removed: usize,
+    pub mainnet_accounts_copied: usize,
+    pub mainnet_accounts_field_merged: usize,
+    /// Accounts to copy the merge ledger already held, when resolved with
+    /// `MergeConfig::conflict_policy`
+    pub base_conflicts: Option<functions::ConflictReport>,
+    pub mainnet_lookup_tables_skipped: usize,
+    /// Per-owner results of `MergeConfig::max_data_bytes_per_owner`
+    pub owner_data_caps: Vec<functions::OwnerDataCapReport>,
+    /// One entry per `MergeConfig::owner_remaps` rule
+    pub owner_remaps: Vec<functions::OwnerRemapReport>,
+    /// ProgramData accounts given `MergeConfig::upgrade_authority_override`
+    pub upgrade_authorities_overridden: Vec<Pubkey>,
+    pub cloned_accounts: Option<rpc_fetch::CloneReport>,
+    pub account_overrides: functions::AccountOverrideReport,
+    /// Accounts per slot of the mainnet copy
+    pub slot_distribution: functions::SlotDistribution,
+    /// Copied accounts with the most lamports, largest first
+    pub top_accounts: Vec<functions::TopAccount>,
+    /// Whether mainnet's epoch rewards sysvar showed a distribution in progress
+    pub mainnet_epoch_rewards_active: bool,
+    /// Upgradeable programs and ProgramData accounts left out of the copy
+    /// because their other half was
+    pub unpaired_programs: functions::ProgramPairingReport,
+    /// Durable nonce accounts found in the copy, handled per
+    /// `MergeConfig::nonce_policy`
+    pub mainnet_nonce_accounts: usize,
+    /// Sysvars the copy replaced, per `MergeConfig::sysvar_policies`
+    pub sysvars: sysvars::SysvarReconciliation,
+    /// Colliding accounts left out because they matched the base account
+    pub accounts_skipped_identical: usize,
+    /// With `MergeConfig::keep_base_accounts_only`, the mainnet accounts whose
+    /// pubkey the merge ledger also holds, and those left out for lacking one
+    pub base_intersection_accounts: Option<usize>,
+    pub accounts_outside_base_skipped: usize,
+    pub mainnet_lookup_tables_refreshed: usize,
+    /// Lookup tables that failed to parse under `functions::AltPolicy::Refresh`
+    /// and were copied as they are
+    pub mainnet_lookup_tables_malformed: usize,
+    /// Mainnet accounts left out by `MergeConfig::max_account_age_epochs`
+    pub accounts_skipped_dormant: usize,
+    /// Mainnet accounts left out because `MergeConfig::subtract_ledger` holds
+    /// their pubkey
+    pub accounts_subtracted: usize,
+    /// Mainnet accounts left out because `MergeConfig::exclude_owners` holds
+    /// their owner
+    pub accounts_excluded_by_owner: usize,
+    /// Token accounts `MergeConfig::token_filter` left out and the token
+    /// supply copied, when it was active
+    pub tokens: Option<spl::TokenReport>,
+    /// Mainnet accounts left out because `MergeConfig::exclude_accounts`
+    /// lists them
+    pub accounts_excluded_by_list: usize,
+    /// Mainnet accounts left out because `MergeConfig::include_accounts`
+    /// doesn't list them
+    pub accounts_outside_include_list: usize,
+    /// Mainnet accounts left out for holding fewer lamports than
+    /// `MergeConfig::min_lamports`
+    pub dust_accounts_excluded: usize,
+    /// Mainnet accounts left out by `MergeConfig::skip_zero_lamport_accounts`
+    pub zero_lamport_accounts_skipped: usize,
+    /// Mainnet accounts left out for holding more data than
+    /// `MergeConfig::max_account_data_size`
+    pub oversized_accounts_excluded: usize,
+    /// Mainnet accounts left out by each `MergeConfig::custom_filters`
+    /// filter, by name
+    pub custom_filter_exclusions: BTreeMap<String, usize>,
+    /// Older mainnet account versions dropped because the enumeration also
+    /// surfaced a higher-slot version of the same pubkey
+    pub duplicate_pubkeys_resolved: usize,
+    /// One entry per `MergeConfig::source_ledgers` ledger, in layering order
+    pub source_layers: Vec<SourceLayer>,
+    /// Copied accounts whose rent_epoch was rewritten
+    pub rent_epochs_normalized: usize,
+    /// Copied token accounts reduced to their mint and amount
+    pub token_balances_reconstructed: usize,
+    /// One entry per `MergeConfig::account_transforms` transform, in order
+    pub account_transforms: Vec<transform::TransformReport>,
+    /// Mainnet accounts dropped by `--validate-accounts`
+    pub invalid_accounts_skipped: usize,
+    pub merge_system_accounts_preserved: usize,
+    pub final_total_accounts: usize,
+    /// The final bank's accounts data size, recounted from its stored
+    /// accounts rather than the size the copy tracks as it stores
+    pub accounts_data_size: u64,
+    /// Time spent loading both input banks
+    pub load_time: Duration,
+    pub capitalization_before: u64,
+    /// Set after the copy as `MergeConfig::capitalization_mode` says, or
+    /// projected from the copy set in a dry run (see
+    /// [`functions::projected_capitalization`])
+    pub capitalization_after: u64,
+    /// Capitalization of the bank warped from and of the warped bank, set
+    /// when `MergeConfig::warp_slot` is
+    pub capitalization_before_warp: Option<u64>,
+    pub capitalization_after_warp: Option<u64>,
+    /// Recomputed minus recorded capitalization of the final bank
+    pub capitalization_check_delta: i128,
+    /// What `functions::CapitalizationMode::MintDelta` did to its treasury
+    pub treasury_adjustment: Option<functions::TreasuryAdjustment>,
+    /// Ancestry of the final bank, see [`functions::verify_bank_lineage`]
+    pub bank_lineage: functions::BankLineage,
+    /// Storages the merge wrote, when checked for duplicate accounts
+    pub storage_check: Option<functions::StorageCheck>,
+    /// Origin of the vote and stake accounts in the final bank
+    pub validator_audit: functions::ValidatorAccountAudit,
+    /// The final bank's validator set, when `MergeConfig::validators_summary`
+    pub validators_summary: Option<functions::ValidatorSetSummary>,
+    /// Fully deactivated base stake accounts removed, when
+    /// `MergeConfig::prune_deactivated_stakes`
+    pub stakes_pruned: Option<functions::StakePruneReport>,
+    /// The merge ledger's validators, dropped unless
+    /// `MergeConfig::validator_source` keeps them
+    pub merge_validators_removed: Option<functions::ValidatorRemovalReport>,
+    /// `MergeConfig::regenerate_epoch_stakes`
+    pub epoch_stakes_regenerated: Option<functions::EpochStakesReport>,
+    /// Totals of `MergeConfig::base_diff_file`, when written
+    pub base_diff: Option<functions::BaseDiffSummary>,
+    /// Projected size of the output archive from the stored accounts, see
+    /// [`functions::estimate_archive_bytes`]
+    pub estimated_archive_bytes: u64,
+    /// Achieved compression and speed of the archive step
+    pub compression: functions::CompressionReport,
+    /// Hash of the written genesis.bin, what validators pass as
+    /// `--expected-genesis-hash`
+    pub genesis_hash: Hash,
+    /// Slot of the bank the snapshot was taken from
+    pub final_slot: Slot,
+    /// The full snapshot archive, or the incremental one if no full one was
+    /// written
+    pub snapshot_path: String,
+    /// Hash of the archive at `snapshot_path`, as its file name and gossip
+    /// carry it
+    pub full_snapshot_hash: Hash,
+    /// Hash of the frozen merged bank, as validators replaying on top of it
+    /// see its parent
+    pub bank_hash: Hash,
+    /// Snapshot hash of the merged bank itself, derived from its accounts
+    /// lattice hash; the same as `full_snapshot_hash` unless no full archive
+    /// was written
+    pub bank_snapshot_hash: Hash,
+    /// The `MergeConfig::archive_name` link to `snapshot_path`
+    pub archive_alias: Option<String>,
+    /// Written under `MergeConfig::snapshot_type` incremental or both
+    pub incremental_snapshot: Option<functions::IncrementalSnapshot>,
+    /// Where `MergeConfig::upload_to` uploaded the snapshot
+    pub upload: Option<upload::UploadReport>,
+    /// Why the upload to `MergeConfig::upload_to` failed. The merge itself
+    /// still succeeds, since its output is complete on disk.
+    pub upload_error: Option<MergeError>,
+}
+
+#[derive(Debug, Clone)]
+pub struct MergeConfig {
+    pub mainnet_ledger: PathBuf,
+    /// Full snapshot archive the mainnet bank is loaded from instead of
+    /// `mainnet_ledger`'s blockstore; `mainnet_ledger` then only holds the
+    /// genesis config and is the default load scratch directory
+    pub mainnet_snapshot: Option<PathBuf>,
+    /// `genesis.bin`, `genesis.tar.bz2` or a directory holding either, opened
+    /// instead of the genesis config in `mainnet_ledger`
+    pub mainnet_genesis: Option<PathBuf>,
+    pub ledger_to_merge: PathBuf,
+    /// Further ledgers scanned like the mainnet ledger and layered over its
+    /// accounts in order, each taking priority over the ones before it
+    pub source_ledgers: Vec<PathBuf>,
+    /// How a pubkey already in the copy set is resolved when a later source
+    /// ledger holds it too; the later source is the overlay
+    pub source_conflict_policy: functions::ConflictPolicy,
+    pub output_directory: PathBuf,
+    pub warp_slot: Option<Slot>,
+    /// Where snapshot loads unpack account storage and bank snapshots, one
+    /// subdirectory per ledger; the ledger directories themselves if unset
+    pub load_scratch_directory: Option<PathBuf>,
+    /// Remove each load's scratch subdirectory once its bank is done with
+    pub cleanup_load_scratch: bool,
+    /// JSON file rewritten with the current phase, accounts processed, slot
+    /// and elapsed time as the merge runs
+    pub progress_file: Option<PathBuf>,
+    /// Draw a progress bar on stderr while scanning mainnet and copying
+    pub progress_bar: bool,
+    /// CSV file the account copy appends a throughput sample to every
+    /// `throughput_interval`; see `functions::ThroughputLog`
+    pub throughput_log: Option<PathBuf>,
+    pub throughput_interval: Duration,
+    /// Sorted one-line-per-account index of the merged bank written next to
+    /// the snapshot; see `functions::write_account_index`
+    pub account_index_file: Option<PathBuf>,
+    /// JSON lines file of the accounts the copy adds to or changes in the
+    /// merge ledger; see `functions::BaseDiffWriter`
+    pub base_diff_file: Option<PathBuf>,
+    /// Load the mainnet and merge ledger banks on two threads at once
+    pub parallel_load: bool,
+    /// Fail unless warping keeps capitalization exactly as it was
+    pub warp_preserve_capitalization: bool,
+    pub copy_shards: Option<usize>,
+    /// Store the mainnet accounts in chunks of at most this many approximate
+    /// stored bytes while scanning, instead of collecting them all first; see
+    /// `check_streaming_options` for what that rules out
+    pub copy_memory_budget: Option<u64>,
+    pub reference_snapshot: Option<PathBuf>,
+    pub deep_compare: bool,
+    pub max_reported_divergences: usize,
+    pub snapshot_retries: usize,
+    /// Extra file name the archive is linked under in the output directory
+    pub archive_name: Option<String>,
+    /// Also create an empty blockstore in the output directory, so with the
+    /// archive and genesis files it is a ledger a validator starts from as is
+    pub ledger_layout: bool,
+    /// Bucket the archives and genesis.bin are uploaded to once written
+    pub upload_to: Option<upload::UploadDestination>,
+    pub snapshot_type: functions::SnapshotType,
+    pub archive_format: functions::OutputArchiveFormat,
+    /// Compression level of a `TarZstd` archive, zstd's default if unset
+    pub zstd_level: Option<i32>,
+    /// Full snapshot archive the incremental snapshot is checked against; it
+    /// must be the merge ledger's, the only base the merged bank descends from
+    pub incremental_base: Option<PathBuf>,
+    /// Tick height the final bank is completed to before it is frozen
+    pub bank_completion: functions::BankCompletion,
+    pub capitalization_tolerance: u64,
+    pub capitalization_mode: functions::CapitalizationMode,
+    pub field_merge_policy: functions::FieldMergePolicy,
+    /// How accounts to copy that the merge ledger already holds are resolved.
+    /// Only [`functions::ConflictPolicy::Overwrite`] skips the lookup.
+    pub conflict_policy: functions::ConflictPolicy,
+    /// File listing every conflicting pubkey, one per line; also forces the
+    /// conflicts to be looked up under the default policy
+    pub conflict_report_file: Option<PathBuf>,
+    /// Accounts index configuration used when loading both ledgers
+    pub accounts_index: AccountsIndexConfig,
+    /// How mainnet accounts are enumerated when building the copy set
+    pub account_enumeration: functions::AccountEnumeration,
+    /// The order the mainnet enumeration visits accounts in
+    pub scan_consistency: functions::ScanConsistency,
+    /// Where the human-readable changelog is written, `MERGE_README.txt` in
+    /// the output directory when unset
+    pub changelog_file: Option<PathBuf>,
+    pub alt_policy: functions::AltPolicy,
+    /// Skip storing mainnet accounts identical to the merge ledger's copy
+    pub skip_unchanged: bool,
+    /// Build the copy set from the mainnet accounts the merge ledger lacks or
+    /// holds differently, instead of enumerating every mainnet account
+    pub copy_diff_only: bool,
+    /// Build the merged bank on a fresh bank from the merge genesis holding
+    /// the merge ledger's accounts, instead of as a child of the loaded bank
+    pub rebuild_from_genesis: bool,
+    /// Copy a mainnet account only if the merge ledger already has an account
+    /// at its pubkey, refreshing existing accounts without adding new ones
+    pub keep_base_accounts_only: bool,
+    /// Remove the merge ledger's stake accounts that are fully deactivated as
+    /// of the merged bank's epoch
+    pub prune_deactivated_stakes: bool,
+    pub validator_source: functions::ValidatorSource,
+    /// Recompute the final bank's epoch stakes from its vote and stake
+    /// accounts, see [`functions::regenerate_epoch_stakes`]
+    pub regenerate_epoch_stakes: bool,
+    /// Go on with a merge that has no mainnet accounts left to copy
+    pub allow_empty_copy: bool,
+    /// Ledger whose account pubkeys are never copied from mainnet
+    pub subtract_ledger: Option<PathBuf>,
+    /// Whose feature-gate accounts the output keeps
+    pub feature_set: functions::FeatureSetPolicy,
+    /// The mainnet features copied with `FeatureSetPolicy::ListFile`
+    pub feature_list: HashSet<Pubkey>,
+    /// Copy only mainnet accounts owned by one of these programs
+    pub copy_owners: Option<HashSet<Pubkey>>,
+    /// Never copy mainnet accounts owned by one of these programs; checked
+    /// before `copy_owners`
+    pub exclude_owners: HashSet<Pubkey>,
+    /// Never copy the mainnet token accounts of these mints, or holding less
+    /// than this balance
+    pub token_filter: spl::TokenFilter,
+    /// Never copy mainnet accounts holding fewer lamports than this
+    pub min_lamports: Option<u64>,
+    /// Never copy mainnet accounts with more data bytes than this
+    pub max_account_data_size: Option<usize>,
+    /// Never copy mainnet accounts holding no lamports; checked before
+    /// `min_lamports`
+    pub skip_zero_lamport_accounts: bool,
+    /// Filters of library users, applied to the mainnet accounts every
+    /// built-in filter kept
+    pub custom_filters: Vec<Arc<dyn filter::AccountFilter + Send + Sync>>,
+    /// Rewrites of library users, applied in order to every mainnet account
+    /// about to be copied
+    pub account_transforms: Vec<Arc<dyn transform::AccountTransform + Send + Sync>>,
+    /// Never copy these mainnet accounts
+    pub exclude_accounts: HashSet<Pubkey>,
+    /// Copy only these mainnet accounts, on top of the other filters
+    pub include_accounts: Option<HashSet<Pubkey>>,
+    /// Skip mainnet accounts whose rent_epoch is more than this many epochs
+    /// behind the merged bank's epoch; the rent-exempt sentinel is always kept
+    pub max_account_age_epochs: Option<Epoch>,
+    /// Rewrite the rent_epoch of copied accounts to this target for the
+    /// merged bank
+    pub normalize_rent_epoch: Option<functions::RentEpochTarget>,
+    /// Copy SPL token accounts as fresh accounts owned by this authority,
+    /// keeping only their mint and amount
+    pub token_balance_authority: Option<Pubkey>,
+    /// Check every account about to be copied with
+    /// `functions::validate_account`, dropping the invalid ones
+    pub validate_accounts: bool,
+    /// Fail the merge on invalid accounts instead of dropping them
+    pub strict_validation: bool,
+    /// Total data bytes copied per owner; further accounts of the owner are
+    /// skipped once the cap is reached
+    pub max_data_bytes_per_owner: HashMap<Pubkey, u64>,
+    /// Owners rewritten on copied mainnet accounts, old owner to new
+    pub owner_remaps: HashMap<Pubkey, Pubkey>,
+    /// Fetched from `rpc_url` and stored over the merged bank's accounts,
+    /// before `account_overrides`
+    pub clone_accounts: Vec<Pubkey>,
+    pub rpc_url: Option<String>,
+    /// Stored over the merged bank's accounts once everything else is
+    pub account_overrides: Vec<functions::AccountOverride>,
+    pub upgrade_authority_override: Option<functions::UpgradeAuthorityOverride>,
+    pub epoch_rewards_policy: functions::EpochRewardsPolicy,
+    pub nonce_policy: functions::NoncePolicy,
+    /// How each reconciled sysvar is handled after the copy, rewritten if
+    /// absent
+    pub sysvar_policies: HashMap<sysvars::Sysvar, sysvars::SysvarPolicy>,
+    /// Number of highest-lamport copied accounts to report, 0 for none
+    pub report_top_accounts: usize,
+    /// Summarize the final bank's vote accounts and their active stake
+    pub validators_summary: bool,
+    /// How the copy is split across slots
+    pub slot_split: functions::SlotSplit,
+    /// Fail when the mainnet copy would span more slots than this under
+    /// `slot_split`, before any account is stored; not with
+    /// `copy_memory_budget`
+    pub max_slots_for_copy: Option<usize>,
+    /// When the copy and `rebuild_from_genesis` flush the accounts cache
+    pub flush_policy: functions::FlushPolicy,
+    /// Copy in pubkey order and keep run-dependent values out of the bank,
+    /// so identical inputs give the same snapshot hash
+    pub deterministic: bool,
+    /// PoH overrides for the written genesis config, for test clusters
+    pub hashes_per_tick: Option<u64>,
+    pub ticks_per_slot: Option<u64>,
+    /// Owner scans that find more accounts than this switch from
+    /// `get_program_accounts` to a full account scan, `None` to never switch
+    pub program_scan_fallback_threshold: Option<usize>,
+    /// Address at which to store a [`provenance_memo`] account, usually
+    /// [`functions::provenance_address`]; `None` stores none
+    pub provenance_memo: Option<Pubkey>,
+    /// Check the final bank against the merge ledger's genesis and the written
+    /// genesis.bin against its expected hash
+    pub verify_genesis_hash: bool,
+    /// Fail unless the loaded mainnet bank has this snapshot hash, see
+    /// [`functions::verify_snapshot_hash`]
+    pub expected_mainnet_snapshot_hash: Option<Hash>,
+    /// Fail unless the loaded merge ledger bank has this snapshot hash
+    pub expected_base_snapshot_hash: Option<Hash>,
+    /// Fail unless each account the merge wrote is held by exactly one of the
+    /// storages after the merge ledger's slot
+    pub verify_no_duplicate_storage: bool,
+    /// Stop once the copy set and its slot plan are known, before anything is
+    /// copied or written; the returned stats only cover that far
+    pub dry_run: bool,
+    /// Compression ratio assumed by the archive size estimate
+    pub archive_compression_ratio: f64,
+    /// Fail if the final bank's accounts data size exceeds this many bytes
+    pub expect_accounts_data_size_max: Option<u64>,
+    /// Abort once this passes, checked between steps and at the account
+    /// copy's flush points
+    pub deadline: Option<functions::Deadline>,
+    /// Run the whole merge, or only the extract or the apply half of it
+    pub phase: functions::MergePhase,
+    /// Where the extract phase writes the copy set and the apply phase reads
+    /// it; [`COPY_SET_FILE`] in the output directory if unset
+    pub copy_set_file: Option<PathBuf>,
+    /// Write the scanned copy set and a [`MergeCheckpoint`] to the output
+    /// directory, rewritten with the copy's position at each of its flush
+    /// points and removed again once the merge completes
+    pub checkpoint: bool,
+    /// Continue from the [`MergeCheckpoint`] of an interrupted merge, if the
+    /// output directory has one, instead of loading and scanning mainnet
+    pub resume: bool,
+}
+
+impl MergeConfig {
+    /// A merge of `mainnet_ledger` into `ledger_to_merge` written to
+    /// `output_directory`, with everything else as the binary defaults it
+    pub fn new(
+        mainnet_ledger: PathBuf,
+        ledger_to_merge: PathBuf,
+        output_directory: PathBuf,
+    ) -> Self {
+        Self {
+            mainnet_ledger,
+            ledger_to_merge,
+            output_directory,
+            mainnet_snapshot: None,
+            mainnet_genesis: None,
+            source_ledgers: Vec::new(),
+            source_conflict_policy: functions::ConflictPolicy::default(),
+            warp_slot: None,
+            warp_preserve_capitalization: false,
+            load_scratch_directory: None,
+            cleanup_load_scratch: false,
+            progress_file: None,
+            progress_bar: false,
+            throughput_log: None,
+            throughput_interval: functions::DEFAULT_THROUGHPUT_INTERVAL,
+            parallel_load: false,
+            account_index_file: None,
+            base_diff_file: None,
+            copy_shards: None,
+            copy_memory_budget: None,
+            reference_snapshot: None,
+            deep_compare: false,
+            max_reported_divergences: 10,
+            snapshot_retries: 0,
+            archive_name: None,
+            ledger_layout: false,
+            upload_to: None,
+            snapshot_type: functions::SnapshotType::default(),
+            archive_format: functions::OutputArchiveFormat::default(),
+            zstd_level: None,
+            incremental_base: None,
+            bank_completion: functions::BankCompletion::default(),
+            capitalization_tolerance: 0,
+            capitalization_mode: functions::CapitalizationMode::default(),
+            field_merge_policy: functions::FieldMergePolicy::default(),
+            conflict_policy: functions::ConflictPolicy::default(),
+            conflict_report_file: None,
+            accounts_index: AccountsIndexConfig::default(),
+            account_enumeration: functions::AccountEnumeration::default(),
+            scan_consistency: functions::ScanConsistency::default(),
+            changelog_file: None,
+            alt_policy: functions::AltPolicy::default(),
+            skip_unchanged: false,
+            copy_diff_only: false,
+            rebuild_from_genesis: false,
+            keep_base_accounts_only: false,
+            prune_deactivated_stakes: false,
+            validator_source: functions::ValidatorSource::default(),
+            regenerate_epoch_stakes: false,
+            subtract_ledger: None,
+            allow_empty_copy: false,
+            copy_owners: None,
+            exclude_owners: HashSet::new(),
+            token_filter: spl::TokenFilter::default(),
+            min_lamports: None,
+            max_account_data_size: None,
+            skip_zero_lamport_accounts: false,
+            custom_filters: Vec::new(),
+            account_transforms: Vec::new(),
+            exclude_accounts: HashSet::new(),
+            include_accounts: None,
+            feature_set: functions::FeatureSetPolicy::default(),
+            feature_list: HashSet::new(),
+            max_account_age_epochs: None,
+            expect_accounts_data_size_max: None,
+            deadline: None,
+            phase: functions::MergePhase::default(),
+            copy_set_file: None,
+            checkpoint: false,
+            resume: false,
+            normalize_rent_epoch: None,
+            token_balance_authority: None,
+            validate_accounts: false,
+            strict_validation: false,
+            max_data_bytes_per_owner: HashMap::new(),
+            owner_remaps: HashMap::new(),
+            clone_accounts: Vec::new(),
+            rpc_url: None,
+            account_overrides: Vec::new(),
+            upgrade_authority_override: None,
+            epoch_rewards_policy: functions::EpochRewardsPolicy::default(),
+            nonce_policy: functions::NoncePolicy::default(),
+            sysvar_policies: HashMap::new(),
+            report_top_accounts: 0,
+            validators_summary: false,
+            slot_split: functions::SlotSplit::default(),
+            max_slots_for_copy: None,
+            flush_policy: functions::FlushPolicy::default(),
+            deterministic: false,
+            hashes_per_tick: None,
+            ticks_per_slot: None,
+            program_scan_fallback_threshold: None,
+            provenance_memo: None,
+            verify_genesis_hash: false,
+            expected_mainnet_snapshot_hash: None,
+            expected_base_snapshot_hash: None,
+            verify_no_duplicate_storage: false,
+            dry_run: false,
+            archive_compression_ratio: functions::DEFAULT_ARCHIVE_COMPRESSION_RATIO,
+        }
+    }
+
+    pub fn copy_set_path(&self) -> PathBuf {
+        self.copy_set_file
+            .clone()
+            .unwrap_or_else(|| self.output_directory.join(COPY_SET_FILE))
+    }
+
+    pub fn checkpoint_path(&self) -> PathBuf {
+        self.output_directory.join(CHECKPOINT_FILE)
+    }
+
+    /// The order the sequential copy stores accounts in. A checkpointed copy
+    /// is ordered too, so its position says which accounts it stored.
+    pub fn copy_order(&self) -> functions::CopyOrder {
+        if self.deterministic || self.checkpoint {
+            functions::CopyOrder::ByPubkey
+        } else {
+            functions::CopyOrder::Unordered
+        }
+    }
+}
+
+pub const DEFAULT_CHANGELOG_FILE: &str = "MERGE_README.txt";
+/// Written to the output directory when `MergeConfig::deadline` aborts a merge
+pub const INCOMPLETE_MARKER_FILE: &str = "MERGE_INCOMPLETE";
+/// Default copy set file of a two-phase merge, in the output directory
+pub const COPY_SET_FILE: &str = "copy-set.bin";
+/// Written to the output directory by `MergeConfig::checkpoint` once mainnet
+/// has been scanned, and updated as the copy goes
+pub const CHECKPOINT_FILE: &str = "MERGE_CHECKPOINT.json";
+
+/// The configured filters that can leave mainnet accounts out of the copy,
+/// beyond the vote, stake and feature accounts always excluded
+pub fn active_copy_filters(config: &MergeConfig) -> Vec<String> {
+    let mut filters = Vec::new();
+    if let Some(copy_owners) = &config.copy_owners {
+        filters.push(format!("owner allowlist of {} programs", copy_owners.len()));
+    }
+    if !config.exclude_accounts.is_empty() {
+        filters.push(format!(
+            "account denylist of {} pubkeys",
+            config.exclude_accounts.len()
+        ));
+    }
+    if let Some(include_accounts) = &config.include_accounts {
+        filters.push(format!(
+            "account allowlist of {} pubkeys",
+            include_accounts.len()
+        ));
+    }
+    if !config.exclude_owners.is_empty() {
+        filters.push(format!(
+            "owner denylist of {} programs",
+            config.exclude_owners.len()
+        ));
+    }
+    if !config.token_filter.exclude_mints.is_empty() {
+        filters.push(format!(
+            "mint denylist of {} mints",
+            config.token_filter.exclude_mints.len()
+        ));
+    }
+    if let Some(min_balance) = config.token_filter.min_balance {
+        filters.push(format!("--min-token-balance {}", min_balance));
+    }
+    if config.skip_zero_lamport_accounts {
+        filters.push("--skip-zero-lamport-accounts".to_string());
+    }
+    if let Some(min_lamports) = config.min_lamports {
+        filters.push(format!("--min-lamports {}", min_lamports));
+    }
+    if let Some(max_data_size) = config.max_account_data_size {
+        filters.push(format!("--max-account-data-size {}", max_data_size));
+    }
+    for custom_filter in &config.custom_filters {
+        filters.push(format!("custom filter {}", custom_filter.name()));
+    }
+    if config.keep_base_accounts_only {
+        filters.push("--keep-accounts-from-base-only".to_string());
+    }
+    if let Some(subtract_ledger) = &config.subtract_ledger {
+        filters.push(format!("--subtract-ledger {}", subtract_ledger.display()));
+    }
+    if config.copy_diff_only {
+        filters.push("--copy-diff-only".to_string());
+    }
+    if config.skip_unchanged {
+        filters.push("--skip-unchanged".to_string());
+    }
+    if let Some(max_age_epochs) = config.max_account_age_epochs {
+        filters.push(format!("--max-account-age-epochs {}", max_age_epochs));
+    }
+    if !config.max_data_bytes_per_owner.is_empty() {
+        filters.push(format!(
+            "--max-data-bytes-per-owner ({} owners)",
+            config.max_data_bytes_per_owner.len()
+        ));
+    }
+    if config.alt_policy == functions::AltPolicy::Skip {
+        filters.push("--alt-policy skip".to_string());
+    }
+    if config.validate_accounts && !config.strict_validation {
+        filters.push("--validate-accounts".to_string());
+    }
+    if config.validator_source.keeps_mainnet() {
+        filters.push(format!(
+            "--validator-source {:?} (mainnet validators copied)",
+            config.validator_source
+        ));
+    }
+    filters
+}
+
+/// Renders `summary` as a table of vote account, node, commission and active
+/// stake, one row per validator, followed by the total
+pub fn render_validators_table(summary: &functions::ValidatorSetSummary) -> String {
+    let mut table = format!(
+        "{:<44}  {:<44}  {:>10}  {:>20}\n",
+        "Vote Account", "Node", "Commission", "Active Stake"
+    );
+    for validator in &summary.validators {
+        table.push_str(&format!(
+            "{:<44}  {:<44}  {:>9}%  {:>20}\n",
+            validator.vote_account.to_string(),
+            validator.node_pubkey.to_string(),
+            validator.commission,
+            validator.active_stake
+        ));
+    }
+    table.push_str(&format!(
+        "{} validators, {} lamports total active stake\n",
+        summary.validators.len(),
+        summary.total_active_stake
+    ));
+    if summary.stake_to_missing_vote_accounts > 0 {
+        table.push_str(&format!(
+            "{} lamports of active stake are delegated to vote accounts not in the bank\n",
+            summary.stake_to_missing_vote_accounts
+        ));
+    }
+    table
+}
+
+/// Renders a plain-text account of a merge run, meant for someone looking at
+/// the output directory later and wanting to know where the snapshot came from
+pub fn render_changelog(config: &MergeConfig, stats: &MergeStats) -> String {
+    let mut changelog = String::new();
+    changelog.push_str("Snapshot merge\n==============\n\n");
+
+    let source = if config.phase == functions::MergePhase::Apply {
+        format!("the copy set at\n{}", config.copy_set_path().display())
+    } else if let Some(archive) = &config.mainnet_snapshot {
+        format!("the snapshot archive at\n{}", archive.display())
+    } else {
+        format!("the ledger at\n{}", config.mainnet_ledger.display())
+    };
+    let kept = if config.validator_source.keeps_merge_ledger() {
+        "The genesis config, validators and stake of the second ledger were kept."
+    } else {
+        "The genesis config of the second ledger was kept."
+    };
+    changelog.push_str(&format!(
+        "This snapshot was produced by merging the accounts of {} into the ledger at {}.\n\
+         {}\n\n",
+        source,
+        config.ledger_to_merge.display(),
+        kept
+    ));
+
+    if config.validator_source.keeps_mainnet() {
+        changelog.push_str(&format!(
+            "The mainnet ledger held {} accounts. Its vote and stake accounts were\n\
+             kept (validator source {:?}), and {} accounts were copied in.\n",
+            stats.mainnet_total_accounts, config.validator_source, stats.mainnet_accounts_copied
+        ));
+    } else {
+        changelog.push_str(&format!(
+            "The mainnet ledger held {} accounts. {} vote accounts and {} stake\n\
+             accounts were excluded, and the remaining {} accounts were copied in.\n",
+            stats.mainnet_total_accounts,
+            stats.mainnet_vote_accounts_excluded,
+            stats.mainnet_stake_accounts_excluded,
+            stats.mainnet_accounts_copied
+        ));
+    }
+    if let Some(regenerated) = &stats.epoch_stakes_regenerated {
+        changelog.push_str(&format!(
+            "The epoch stakes of epochs {:?} were regenerated from the merged bank's\n\
+             {} vote accounts, changing the total stake from {} to {}.\n",
+            regenerated.epochs,
+            regenerated.vote_accounts,
+            regenerated.previous_total_stake,
+            regenerated.total_stake
+        ));
+    }
+    if let Some(removed) = &stats.merge_validators_removed {
+        changelog.push_str(&format!(
+            "The merge ledger's {} vote and {} stake accounts were removed\n\
+             (validator source {:?}), taking {} lamports out of the capitalization.\n",
+            removed.vote_accounts,
+            removed.stake_accounts,
+            config.validator_source,
+            removed.lamports_removed
+        ));
+    }
+    for layer in &stats.source_layers {
+        changelog.push_str(&format!(
+            "The {} accounts of the source ledger at {} (slot {}) were layered\n\
+             on top: {} were new and {} replaced or merged with an account already\n\
+             in the copy set.\n",
+            layer.accounts,
+            layer.ledger.display(),
+            layer.slot,
+            layer.merge.inserted,
+            layer.merge.collisions
+        ));
+    }
+    match &stats.mainnet_features {
+        Some(features) => changelog.push_str(&format!(
+            "{} mainnet feature accounts ({} activated) were copied with the {:?}\n\
+             feature set; {} of them are unknown to the runtime that produced it and\n\
+             stay inert, {} were left to the merge ledger's activated ones and {}\n\
+             activated after the merged bank's slot were moved back to it.\n",
+            features.feature_accounts,
+            features.activated,
+            config.feature_set,
+            features.unknown.len(),
+            features.kept_merge_ledger,
+            features.rebased
+        )),
+        None => changelog.push_str(&format!(
+            "{} mainnet feature accounts were excluded; the merge ledger's feature set\n\
+             was kept.\n",
+            stats.mainnet_feature_accounts_excluded
+        )),
+    }
+    if stats.merge_feature_accounts_removed > 0 {
+        changelog.push_str(&format!(
+            "The merge ledger's {} feature accounts were closed, so only mainnet's\n\
+             features stay active.\n",
+            stats.merge_feature_accounts_removed
+        ));
+    }
+    if config.rebuild_from_genesis {
+        changelog.push_str(
+            "The merge ledger's accounts were stored on a fresh bank built from its\n\
+             genesis rather than inherited from its loaded snapshot.\n",
+        );
+    }
+    if stats.mainnet_accounts_field_merged > 0 {
+        changelog.push_str(&format!(
+            "{} accounts present in both ledgers were merged field by field ({:?}).\n",
+            stats.mainnet_accounts_field_merged, config.field_merge_policy
+        ));
+    }
+    if let Some(conflicts) = &stats.base_conflicts {
+        changelog.push_str(&format!(
+            "{} accounts present in both ledgers were resolved with {:?}, {} keeping the \
+             merge ledger's account.\n",
+            conflicts.conflicts.len(),
+            config.conflict_policy,
+            conflicts.kept_base
+        ));
+    }
+    if stats.mainnet_lookup_tables_skipped > 0 {
+        changelog.push_str(&format!(
+            "{} address lookup tables were left out.\n",
+            stats.mainnet_lookup_tables_skipped
+        ));
+    }
+    if stats.mainnet_lookup_tables_refreshed > 0 {
+        changelog.push_str(&format!(
+            "{} address lookup tables had their slots rebased onto the merged ledger.\n",
+            stats.mainnet_lookup_tables_refreshed
+        ));
+    }
+    if stats.mainnet_lookup_tables_malformed > 0 {
+        changelog.push_str(&format!(
+            "{} address lookup tables could not be parsed and were copied as they are.\n",
+            stats.mainnet_lookup_tables_malformed
+        ));
+    }
+    if let Some(subtract_ledger) = &config.subtract_ledger {
+        changelog.push_str(&format!(
+            "{} mainnet accounts were left out because the ledger at {} holds\n\
+             their pubkey.\n",
+            stats.accounts_subtracted,
+            subtract_ledger.display()
+        ));
+    }
+    if stats.accounts_excluded_by_list > 0 {
+        changelog.push_str(&format!(
+            "{} mainnet accounts were left out because --exclude-accounts-file lists\n\
+             them.\n",
+            stats.accounts_excluded_by_list
+        ));
+    }
+    for (name, excluded) in &stats.custom_filter_exclusions {
+        changelog.push_str(&format!(
+            "{} mainnet accounts were left out by the custom filter {}.\n",
+            excluded, name
+        ));
+    }
+    if stats.accounts_outside_include_list > 0 {
+        changelog.push_str(&format!(
+            "{} mainnet accounts were left out because --include-accounts-file doesn't\n\
+             list them.\n",
+            stats.accounts_outside_include_list
+        ));
+    }
+    if stats.accounts_excluded_by_owner > 0 {
+        changelog.push_str(&format!(
+            "{} mainnet accounts were left out because --exclude-owner names their\n\
+             owner program.\n",
+            stats.accounts_excluded_by_owner
+        ));
+    }
+    if stats.zero_lamport_accounts_skipped > 0 {
+        changelog.push_str(&format!(
+            "{} mainnet accounts holding no lamports were left out.\n",
+            stats.zero_lamport_accounts_skipped
+        ));
+    }
+    if stats.dust_accounts_excluded > 0 {
+        changelog.push_str(&format!(
+            "{} mainnet accounts were left out for holding fewer than {} lamports.\n",
+            stats.dust_accounts_excluded,
+            config.min_lamports.unwrap_or_default()
+        ));
+    }
+    if stats.oversized_accounts_excluded > 0 {
+        changelog.push_str(&format!(
+            "{} mainnet accounts were left out for holding more than {} data bytes.\n",
+            stats.oversized_accounts_excluded,
+            config.max_account_data_size.unwrap_or_default()
+        ));
+    }
+    if let Some(tokens) = &stats.tokens {
+        for (mint, exclusion) in &tokens.excluded_by_mint {
+            changelog.push_str(&format!(
+                "{} token accounts of mint {}, holding {} base units, were left out\n\
+                 because --exclude-mint names their mint.\n",
+                exclusion.accounts, mint, exclusion.amount
+            ));
+        }
+        if tokens.excluded_below_min_balance > 0 {
+            changelog.push_str(&format!(
+                "{} token accounts were left out for holding less than {} base units.\n",
+                tokens.excluded_below_min_balance,
+                config.token_filter.min_balance.unwrap_or_default()
+            ));
+        }
+        if tokens.mints_short_of_supply > 0 {
+            changelog.push_str(&format!(
+                "{} copied mints report a larger supply than their copied token accounts\n\
+                 hold.\n",
+                tokens.mints_short_of_supply
+            ));
+        }
+    }
+    if stats.accounts_skipped_dormant > 0 {
+        changelog.push_str(&format!(
+            "{} mainnet accounts were left out because their rent_epoch was more than\n\
+             {} epochs old.\n",
+            stats.accounts_skipped_dormant,
+            config.max_account_age_epochs.unwrap_or_default()
+        ));
+    }
+    if stats.rent_epochs_normalized > 0 {
+        changelog.push_str(&format!(
+            "{} copied accounts had their rent_epoch rewritten ({:?}).\n",
+            stats.rent_epochs_normalized,
+            config.normalize_rent_epoch.unwrap_or_default()
+        ));
+    }
+    if let Some(pruned) = &stats.stakes_pruned {
+        changelog.push_str(&format!(
+            "{} fully deactivated stake accounts of the merge ledger were removed,\n\
+             reclaiming {} lamports from the capitalization.\n",
+            pruned.pruned, pruned.lamports_reclaimed
+        ));
+    }
+    for report in &stats.account_transforms {
+        changelog.push_str(&format!(
+            "{} copied accounts were rewritten by the transform {}.\n",
+            report.accounts_changed, report.name
+        ));
+    }
+    if stats.token_balances_reconstructed > 0 {
+        changelog.push_str(&format!(
+            "{} token accounts were copied as balances owned by {}, without their\n\
+             delegates or close authorities.\n",
+            stats.token_balances_reconstructed,
+            config
+                .token_balance_authority
+                .map(|authority| authority.to_string())
+                .unwrap_or_default()
+        ));
+    }
+    if stats.invalid_accounts_skipped > 0 {
+        changelog.push_str(&format!(
+            "{} mainnet accounts failed validation and were not copied.\n",
+            stats.invalid_accounts_skipped
+        ));
+    }
+    for cap in &stats.owner_data_caps {
+        changelog.push_str(&format!(
+            "Accounts owned by {} were capped at {} data bytes: {} bytes in {}\n\
+             accounts were copied and {} bytes in {} accounts left out.\n",
+            cap.owner,
+            cap.cap_bytes,
+            cap.bytes_copied,
+            cap.accounts_copied,
+            cap.bytes_skipped,
+            cap.accounts_skipped
+        ));
+    }
+    for remap in &stats.owner_remaps {
+        changelog.push_str(&format!(
+            "{} accounts owned by {} on mainnet were reassigned to {}.\n",
+            remap.accounts_remapped, remap.from, remap.to
+        ));
+    }
+    if let Some(authority_override) = &config.upgrade_authority_override {
+        changelog.push_str(&format!(
+            "The upgrade authority of {} copied ProgramData accounts was set to {}.\n",
+            stats.upgrade_authorities_overridden.len(),
+            authority_override.authority
+        ));
+    }
+    if stats.mainnet_nonce_accounts > 0 {
+        changelog.push_str(&format!(
+            "{} durable nonce accounts from mainnet were handled with the {:?} nonce\n\
+             policy.\n",
+            stats.mainnet_nonce_accounts, config.nonce_policy
+        ));
+    }
+    if let Some(cloned) = &stats.cloned_accounts {
+        changelog.push_str(&format!(
+            "{} accounts were cloned from {} at slot {} or later:\n",
+            cloned.cloned.len(),
+            cloned.rpc_url,
+            cloned.slot
+        ));
+        for pubkey in &cloned.cloned {
+            changelog.push_str(&format!("  {}\n", pubkey));
+        }
+    }
+    for (pubkey, change) in stats
+        .account_overrides
+        .replaced
+        .iter()
+        .map(|pubkey| (pubkey, "replaced"))
+        .chain(
+            stats
+                .account_overrides
+                .created
+                .iter()
+                .map(|pubkey| (pubkey, "created")),
+        )
+    {
+        changelog.push_str(&format!(
+            "Account {} was {} from the account overrides.\n",
+            pubkey, change
+        ));
+    }
+    for pubkey in &stats.unpaired_programs.programs {
+        changelog.push_str(&format!(
+            "Upgradeable program {} was left out: its ProgramData account was not copied.\n",
+            pubkey
+        ));
+    }
+    for pubkey in &stats.unpaired_programs.program_data {
+        changelog.push_str(&format!(
+            "ProgramData account {} was left out: no copied program points to it.\n",
+            pubkey
+        ));
+    }
+    for sysvar in &stats.sysvars.rewritten {
+        changelog.push_str(&format!(
+            "The {} sysvar is the merged bank's own; mainnet's was left out of the copy.\n",
+            sysvar
+        ));
+    }
+    for sysvar in &stats.sysvars.kept {
+        changelog.push_str(&format!(
+            "The {} sysvar was kept as copied from mainnet.\n",
+            sysvar
+        ));
+    }
+    if stats.mainnet_epoch_rewards_active {
+        changelog.push_str(&format!(
+            "Mainnet was captured during epoch rewards distribution; its epoch rewards\n\
+             sysvar was handled with the {:?} policy.\n",
+            config.epoch_rewards_policy
+        ));
+    }
+    if let Some(intersection) = stats.base_intersection_accounts {
+        changelog.push_str(&format!(
+            "Only accounts the merge ledger already had were refreshed from mainnet:\n\
+             {} accounts matched, {} mainnet accounts without a base account were\n\
+             left out.\n",
+            intersection, stats.accounts_outside_base_skipped
+        ));
+    }
+    if stats.accounts_skipped_identical > 0 {
+        changelog.push_str(&format!(
+            "{} accounts already identical in the merge ledger were not stored again.\n",
+            stats.accounts_skipped_identical
+        ));
+    }
+    changelog.push_str(&format!(
+        "The merge ledger started with {} accounts; {} of its system accounts\n\
+         were preserved on top of the copied set. The result has {} accounts\n\
+         holding {} bytes of account data.\n\n",
+        stats.merge_total_accounts,
+        stats.merge_system_accounts_preserved,
+        stats.final_total_accounts,
+        stats.accounts_data_size
+    ));
+
+    let audit = &stats.validator_audit;
+    changelog.push_str(&format!(
+        "The result holds {} vote and {} stake accounts: {} and {} from the merge\n\
+         ledger, {} and {} from mainnet, and {} and {} neither ledger had.\n\n",
+        audit.vote.total(),
+        audit.stake.total(),
+        audit.vote.from_merge_ledger,
+        audit.stake.from_merge_ledger,
+        audit.vote.from_mainnet,
+        audit.stake.from_mainnet,
+        audit.vote.other,
+        audit.stake.other
+    ));
+
+    changelog.push_str(&format!(
+        "Capitalization went from {} to {} lamports. The final bank's recorded\n\
+         capitalization differs from a full recount by {:+} lamports\n\
+         (tolerance {}).\n\n",
+        stats.capitalization_before,
+        stats.capitalization_after,
+        stats.capitalization_check_delta,
+        config.capitalization_tolerance
+    ));
+    if let Some(adjustment) = &stats.treasury_adjustment {
+        changelog.push_str(&format!(
+            "Treasury {} was adjusted by {:+} lamports to reach the target\n\
+             capitalization.\n\n",
+            adjustment.treasury, adjustment.delta
+        ));
+    }
+    if let (Some(before), Some(after)) = (
+        stats.capitalization_before_warp,
+        stats.capitalization_after_warp,
+    ) {
+        changelog.push_str(&format!(
+            "Capitalization was {} lamports before the warp and {} after it.\n\n",
+            before, after
+        ));
+    }
+
+    if !stats.top_accounts.is_empty() {
+        changelog.push_str("The copied accounts holding the most lamports were:\n");
+        for account in &stats.top_accounts {
+            changelog.push_str(&format!(
+                "  {} ({} lamports, owner {})\n",
+                account.pubkey, account.lamports, account.owner
+            ));
+        }
+        changelog.push('\n');
+    }
+
+    match config.warp_slot {
+        Some(warp_slot) => changelog.push_str(&format!(
+            "The merged bank was warped to slot {}.\n",
+            warp_slot
+        )),
+        None => changelog.push_str(&format!(
+            "The merged bank was not warped and is at slot {}.\n",
+            stats.final_slot
+        )),
+    }
+    changelog.push_str(&format!("Snapshot archive: {}\n", stats.snapshot_path));
+    if let Some(alias) = &stats.archive_alias {
+        changelog.push_str(&format!("Also linked as: {}\n", alias));
+    }
+    if let Some(upload) = &stats.upload {
+        changelog.push_str(&format!("Uploaded to: {}\n", upload.archive_url));
+        changelog.push_str(&format!("Manifest uploaded to: {}\n", upload.manifest_url));
+    }
+    if let Some(e) = &stats.upload_error {
+        changelog.push_str(&format!("The upload failed: {}\n", e));
+    }
+    match &stats.incremental_snapshot {
+        Some(incremental) if incremental.path == stats.snapshot_path => {
+            changelog.push_str(&format!(
+                "Incremental snapshot hash: {} on top of the full snapshot at slot {} (no full \
+                 snapshot was written)\n",
+                incremental.hash, incremental.base_slot
+            ))
+        }
+        Some(incremental) => {
+            changelog.push_str(&format!(
+                "Full snapshot hash: {}\n",
+                stats.full_snapshot_hash
+            ));
+            changelog.push_str(&format!(
+                "Incremental snapshot: {} (hash {}) on top of the full snapshot at slot {}\n",
+                incremental.path, incremental.hash, incremental.base_slot
+            ));
+        }
+        None => changelog.push_str(&format!(
+            "Full snapshot hash: {} (no incremental snapshot was written)\n",
+            stats.full_snapshot_hash
+        )),
+    }
+    changelog.push_str(&format!(
+        "The archive is {} bytes for {} bytes of account data ({:.2}x), written\n\
+         in {:.1}s ({:.1} MB/s).\n",
+        stats.compression.archive_bytes,
+        stats.compression.uncompressed_bytes,
+        stats.compression.ratio,
+        stats.compression.archive_secs,
+        stats.compression.throughput_mb_per_sec
+    ));
+    changelog.push_str(&format!(
+        "Bank hash: {}\nBank snapshot hash: {}\n",
+        stats.bank_hash, stats.bank_snapshot_hash
+    ));
+    changelog.push_str(&format!(
+        "Genesis hash (for --expected-genesis-hash): {}\n",
+        stats.genesis_hash
+    ));
+    if let Some(reference_snapshot) = &config.reference_snapshot {
+        changelog.push_str(&format!(
+            "It was checked against the reference snapshot {}.\n",
+            reference_snapshot.display()
+        ));
+    }
+    if let Some(address) = &config.provenance_memo {
+        changelog.push_str(&format!(
+            "A JSON provenance memo describing this merge is stored in account {}.\n",
+            address
+        ));
+    }
+
+    changelog
+}
+
+/// JSON metadata stored by `MergeConfig::provenance_memo`: source ledgers and
+/// their slots, the slot of the output, the tool version, the filters applied
+/// and the creation time in Unix seconds
+pub fn provenance_memo(
+    config: &MergeConfig,
+    mainnet_slot: Slot,
+    merge_slot: Slot,
+    output_slot: Slot,
+    created_at: u64,
+) -> serde_json::Value {
+    let sysvar_policies: serde_json::Map<_, _> = config
+        .sysvar_policies
+        .iter()
+        .map(|(sysvar, policy)| {
+            (
+                sysvar.to_string(),
+                serde_json::json!(format!("{:?}", policy)),
+            )
+        })
+        .collect();
+    let max_data_bytes_per_owner: serde_json::Map<_, _> = config
+        .max_data_bytes_per_owner
+        .iter()
+        .map(|(owner, cap)| (owner.to_string(), serde_json::json!(cap)))
+        .collect();
+    let mut exclude_mints: Vec<_> = config
+        .token_filter
+        .exclude_mints
+        .iter()
+        .map(|mint| mint.to_string())
+        .collect();
+    exclude_mints.sort_unstable();
+    serde_json::json!({
+        "tool": "snapshot-merger",
+        "tool_version": env!("CARGO_PKG_VERSION"),
+        "solana_version": solana_version::version!(),
+        "created_at": created_at,
+        "mainnet_ledger": {
+            "path": config.mainnet_ledger.display().to_string(),
+            "snapshot": config
+                .mainnet_snapshot
+                .as_ref()
+                .map(|archive| archive.display().to_string()),
+            "slot": mainnet_slot,
+            "copy_set": (config.phase == functions::MergePhase::Apply)
+                .then(|| config.copy_set_path().display().to_string()),
+        },
+        "source_ledgers": config
+            .source_ledgers
+            .iter()
+            .map(|ledger| ledger.display().to_string())
+            .collect::<Vec<_>>(),
+        "merge_ledger": {
+            "path": config.ledger_to_merge.display().to_string(),
+            "slot": merge_slot,
+        },
+        "output_slot": output_slot,
+        "warp_slot": config.warp_slot,
+        "filters": {
+            "excluded_mainnet_vote_accounts": !config.validator_source.keeps_mainnet(),
+            "excluded_mainnet_stake_accounts": !config.validator_source.keeps_mainnet(),
+            "validator_source": format!("{:?}", config.validator_source),
+            "alt_policy": format!("{:?}", config.alt_policy),
+            "field_merge_policy": format!("{:?}", config.field_merge_policy),
+            "conflict_policy": format!("{:?}", config.conflict_policy),
+            "epoch_rewards_policy": format!("{:?}", config.epoch_rewards_policy),
+            "feature_set": format!("{:?}", config.feature_set),
+            "nonce_policy": format!("{:?}", config.nonce_policy),
+            "capitalization_mode": format!("{:?}", config.capitalization_mode),
+            "normalize_rent_epoch": config
+                .normalize_rent_epoch
+                .map(|target| format!("{:?}", target)),
+            "upgrade_authority_override": config
+                .upgrade_authority_override
+                .as_ref()
+                .map(|authority_override| authority_override.authority.to_string()),
+            "sysvar_policies": sysvar_policies,
+            "skip_unchanged": config.skip_unchanged,
+            "copy_diff_only": config.copy_diff_only,
+            "rebuild_from_genesis": config.rebuild_from_genesis,
+            "deterministic": config.deterministic,
+            "keep_base_accounts_only": config.keep_base_accounts_only,
+            "max_data_bytes_per_owner": max_data_bytes_per_owner,
+            "exclude_mints": exclude_mints,
+            "min_token_balance": config.token_filter.min_balance,
+            "min_lamports": config.min_lamports,
+            "max_account_data_size": config.max_account_data_size,
+            "skip_zero_lamport_accounts": config.skip_zero_lamport_accounts,
+            "custom_filters": config
+                .custom_filters
+                .iter()
+                .map(|custom_filter| custom_filter.name())
+                .collect::<Vec<_>>(),
+            "account_transforms": config
+                .account_transforms
+                .iter()
+                .map(|account_transform| account_transform.name())
+                .collect::<Vec<_>>(),
+        },
+    })
+}
+
+fn open_blockstore(ledger_path: &Path) -> Result<Blockstore, BlockstoreError> {
+    info!("Opening blockstore at {:?}", ledger_path);
+    Blockstore::open_with_options(
+        ledger_path,
+        BlockstoreOptions {
+            access_type: AccessType::Secondary,
+            enforce_ulimit_nofile: false,
+            ..BlockstoreOptions::default()
+        },
+    )
+}
+
+/// The directory a snapshot load of `ledger_path` unpacks account storage and
+/// bank snapshots under: the `label` subdirectory of `scratch_dir` if one is
+/// given, otherwise the ledger directory itself
+pub fn load_scratch_dir(ledger_path: &Path, scratch_dir: Option<&Path>, label: &str) -> PathBuf {
+    scratch_dir.map_or_else(|| ledger_path.to_path_buf(), |dir| dir.join(label))
+}
+
+/// Removes a load's scratch directory when dropped. Declared before the bank
+/// loaded into it, so the bank is gone first, on early returns too.
+struct ScratchCleanup(Option<PathBuf>);
+
+impl ScratchCleanup {
+    fn new(config: &MergeConfig, scratch: &Path) -> Self {
+        let remove = config.cleanup_load_scratch && config.load_scratch_directory.is_some();
+        Self(remove.then(|| scratch.to_path_buf()))
+    }
+}
+
+impl Drop for ScratchCleanup {
+    fn drop(&mut self) {
+        if let Some(scratch) = self.0.take() {
+            info!("Removing load scratch directory {:?}", scratch);
+            if let Err(e) = std::fs::remove_dir_all(&scratch) {
+                warn!(
+                    "Failed to remove load scratch directory {:?}: {:?}",
+                    scratch, e
+                );
+            }
+        }
+    }
+}
+
+fn load_bank_from_snapshot(
+    ledger_path: &Path,
+    genesis_config: &GenesisConfig,
+    accounts_index_config: &AccountsIndexConfig,
+    scratch: &Path,
+) -> Result<Arc<Bank>, MergeError> {
+    info!(
+        "Loading snapshot from {:?} (scratch {:?})",
+        ledger_path, scratch
+    );
+    std::fs::create_dir_all(scratch).map_err(|e| {
+        MergeError::Io(format!(
+            "Failed to create load scratch directory {:?}: {:?}",
+            scratch, e
+        ))
+    })?;
+
+    let blockstore =
+        Arc::new(open_blockstore(ledger_path).map_err(|e| {
+            MergeError::BlockstoreOpen(format!("Failed to open blockstore: {:?}", e))
+        })?);
+
+    let snapshot_config = SnapshotConfig {
+        usage: SnapshotUsage::LoadOnly,
+        full_snapshot_archives_dir: ledger_path.to_path_buf(),
+        incremental_snapshot_archives_dir: ledger_path.to_path_buf(),
+        bank_snapshots_dir: scratch.join("bank_snapshots"),
+        ..SnapshotConfig::default()
+    };
+
+    // Use minimal accounts DB config for loading
+    let accounts_db_config = Some(AccountsDbConfig {
+        index: Some(accounts_index_config.clone()),
+        ..AccountsDbConfig::default()
+    });
+    let process_options = ProcessOptions {
+        accounts_db_config,
+        ..ProcessOptions::default()
+    };
+
+    let (bank_forks, _leader_schedule_cache, _starting_snapshot_hashes, ..) =
+        bank_forks_utils::load_bank_forks(
+            genesis_config,
+            &blockstore,
+            vec![scratch.join("accounts")],
+            &snapshot_config,
+            &process_options,
+            None,
+            None,
+            None,
+            Arc::new(std::sync::atomic::AtomicBool::new(false)),
+        )
+        .map_err(|e| MergeError::SnapshotLoad(format!("Failed to load bank forks: {:?}", e)))?;
+
+    let bank = bank_forks.read().unwrap().working_bank();
+    info!("Loaded bank at slot {}", bank.slot());
+
+    Ok(bank)
+}
+
+/// Opens the mainnet genesis config from `MergeConfig::mainnet_genesis`, a
+/// `genesis.bin` or `genesis.tar.bz2` file or a directory holding either, or
+/// from `mainnet_ledger` when unset
+fn open_mainnet_genesis_config(config: &MergeConfig) -> Result<GenesisConfig, MergeError> {
+    let open_error = |path: &Path, e| {
+        MergeError::Genesis(format!(
+            "Failed to open mainnet genesis config {:?}: {:?}",
+            path, e
+        ))
+    };
+    let genesis = config
+        .mainnet_genesis
+        .as_deref()
+        .unwrap_or(&config.mainnet_ledger);
+    if genesis.is_dir() {
+        return open_genesis_config(genesis, MAX_GENESIS_ARCHIVE_UNPACKED_SIZE)
+            .map_err(|e| open_error(genesis, e));
+    }
+    // A genesis archive is unpacked next to itself, as in a ledger directory
+    if genesis.file_name() == Some(DEFAULT_GENESIS_ARCHIVE.as_ref()) {
+        let directory = genesis.parent().unwrap_or(Path::new("."));
+        return open_genesis_config(directory, MAX_GENESIS_ARCHIVE_UNPACKED_SIZE)
+            .map_err(|e| open_error(genesis, e));
+    }
+    let genesis_file = std::fs::File::open(genesis)
+        .map_err(|e| MergeError::Genesis(format!("Failed to open {:?}: {:?}", genesis, e)))?;
+    bincode::deserialize_from(genesis_file)
+        .map_err(|e| MergeError::Genesis(format!("Failed to deserialize {:?}: {:?}", genesis, e)))
+}
+
+/// Opens the genesis config of the ledger at `ledger_path`, `name` telling
+/// which ledger it is in the error
+fn open_ledger_genesis_config(name: &str, ledger_path: &Path) -> Result<GenesisConfig, MergeError> {
+    open_genesis_config(ledger_path, MAX_GENESIS_ARCHIVE_UNPACKED_SIZE).map_err(|e| {
+        MergeError::Genesis(format!(
+            "Failed to open {} {:?} genesis config: {:?}",
+            name, ledger_path, e
+        ))
+    })
+}
+
+/// Loads the mainnet bank from `MergeConfig::mainnet_snapshot` if set,
+/// otherwise from the latest snapshot of `mainnet_ledger`, and checks it
+/// against `MergeConfig::expected_mainnet_snapshot_hash`
+fn load_mainnet_bank(
+    config: &MergeConfig,
+    genesis_config: &GenesisConfig,
+    scratch: &Path,
+) -> Result<Arc<Bank>, MergeError> {
+    let bank = match &config.mainnet_snapshot {
+        Some(archive) => functions::load_bank_from_snapshot_archive(
+            archive,
+            genesis_config,
+            &config.accounts_index,
+            scratch,
+        ),
+        None => load_bank_from_snapshot(
+            &config.mainnet_ledger,
+            genesis_config,
+            &config.accounts_index,
+            scratch,
+        ),
+    }?;
+    if let Some(expected) = &config.expected_mainnet_snapshot_hash {
+        functions::verify_snapshot_hash(&bank, expected, "Mainnet")?;
+        info!("✓ Mainnet bank has the expected snapshot hash {}", expected);
+    }
+    Ok(bank)
+}
+
+/// Opens a ledger's genesis config and loads its latest snapshot
+pub fn load_ledger(ledger_path: &Path) -> Result<(GenesisConfig, Arc<Bank>), MergeError> {
+    let genesis_config = open_ledger_genesis_config("ledger", ledger_path)?;
+    let bank = load_bank_from_snapshot(
+        ledger_path,
+        &genesis_config,
+        &AccountsIndexConfig::default(),
+        ledger_path,
+    )?;
+    Ok((genesis_config, bank))
+}
+
+/// Writes the snapshot archives of `bank` to `output_dir`: the full one if
+/// `write_full`, and an incremental one on top of `incremental_base_slot` if
+/// set. Returns the path, hash and archive time of the full archive, or of
+/// the incremental one without a full archive, and the incremental archive.
+fn create_snapshot_from_bank(
+    bank: &Bank,
+    output_dir: &Path,
+    retries: usize,
+    completion: functions::BankCompletion,
+    archive_format: ArchiveFormat,
+    write_full: bool,
+    incremental_base_slot: Option<Slot>,
+) -> Result<
+    (
+        String,
+        Hash,
+        Duration,
+        Option<functions::IncrementalSnapshot>,
+    ),
+    MergeError,
+> {
+    info!("Preparing bank for snapshot at slot {}", bank.slot());
+
+    if !bank.is_complete() {
+        info!(
+            "Bank is not complete, registering ticks ({:?})...",
+            completion
+        );
+        functions::complete_bank(bank, completion)?;
+    }
+    info!(
+        "Bank tick height {} / max tick height {}",
+        bank.tick_height(),
+        bank.max_tick_height()
+    );
+    // Archiving asserts on an incomplete bank, so stop here instead
+    if !bank.is_complete() {
+        return Err(MergeError::ArchiveCreation(format!(
+            "Bank at slot {} stopped at tick height {} of {}; a snapshot archive needs a \
+             complete bank",
+            bank.slot(),
+            bank.tick_height(),
+            bank.max_tick_height()
+        )));
+    }
+
+    info!("Freezing bank...");
+    let bank_hash = functions::freeze_bank(bank)?;
+    info!("✓ Bank frozen with hash {}", bank_hash);
+
+    // Force flush accounts cache to ensure all accounts are written to storage
+    info!("Flushing accounts cache to disk...");
+    bank.force_flush_accounts_cache();
+    info!("Accounts cache flushed");
+
+    // Create necessary subdirectories
+    let bank_snapshots_dir = output_dir.join("bank_snapshots");
+    std::fs::create_dir_all(&bank_snapshots_dir).map_err(|e| {
+        MergeError::ArchiveCreation(format!(
+            "Failed to create bank snapshots directory: {:?}",
+            e
+        ))
+    })?;
+
+    // Before the full snapshot, whose clean could drop the zero-lamport
+    // accounts the incremental one needs to delete their base versions
+    let incremental = match incremental_base_slot {
+        Some(base_slot) => {
+            let (archive_info, archive_time) = archive_with_retries(
+                functions::ArchiveKind::Incremental,
+                bank,
+                output_dir,
+                retries,
+                || {
+                    snapshot_bank_utils::bank_to_incremental_snapshot_archive(
+                        &bank_snapshots_dir,
+                        bank,
+                        base_slot,
+                        Some(SnapshotVersion::default()),
+                        output_dir,
+                        output_dir,
+                        archive_format,
+                    )
+                },
+            )?;
+            let incremental = functions::IncrementalSnapshot {
+                path: archive_info.path().to_string_lossy().to_string(),
+                hash: archive_info.hash().0,
+                base_slot,
+            };
+            info!(
+                "Successfully created incremental snapshot archive on top of slot {}: {} (hash {}) \
+                 in {:.1}s",
+                base_slot,
+                incremental.path,
+                incremental.hash,
+                archive_time.as_secs_f64()
+            );
+            Some((incremental, archive_time))
+        }
+        None => None,
+    };
+    if !write_full {
+        let (incremental, archive_time) = incremental.ok_or_else(|| {
+            MergeError::InvalidInput(
+                "Neither a full nor an incremental snapshot archive was requested".to_string(),
+            )
+        })?;
+        return Ok((
+            incremental.path.clone(),
+            incremental.hash,
+            archive_time,
+            Some(incremental),
+        ));
+    }
+
+    let (snapshot_archive_info, archive_time) = archive_with_retries(
+        functions::ArchiveKind::Full,
+        bank,
+        output_dir,
+        retries,
+        || {
+            snapshot_bank_utils::bank_to_full_snapshot_archive(
+                &bank_snapshots_dir,
+                bank,
+                Some(SnapshotVersion::default()),
+                output_dir,
+                output_dir,
+                archive_format,
+            )
+        },
+    )?;
+
+    let snapshot_path = snapshot_archive_info.path().to_string_lossy().to_string();
+    let snapshot_hash = snapshot_archive_info.hash().0;
+    info!(
+        "Successfully created snapshot archive: {} (hash {}) in {:.1}s",
+        snapshot_path,
+        snapshot_hash,
+        archive_time.as_secs_f64()
+    );
+
+    Ok((
+        snapshot_path,
+        snapshot_hash,
+        archive_time,
+        incremental.map(|(incremental, _)| incremental),
+    ))
+}
+
+/// [`functions::archive_with_retries`] for an archive of `bank`
+fn archive_with_retries<T>(
+    kind: functions::ArchiveKind,
+    bank: &Bank,
+    output_dir: &Path,
+    retries: usize,
+    archive: impl FnMut() -> snapshot_utils::Result<T>,
+) -> Result<(T, Duration), MergeError> {
+    functions::archive_with_retries(
+        kind,
+        bank.slot(),
+        output_dir,
+        retries,
+        SNAPSHOT_RETRY_DELAY,
+        archive,
+    )
+}
+
+fn compare_to_reference_snapshot(
+    final_bank: &Bank,
+    reference_snapshot: &Path,
+    genesis_config: &GenesisConfig,
+    output_snapshot_dir: &Path,
+    config: &MergeConfig,
+) -> Result<(), MergeError> {
+    let scratch_dir = output_snapshot_dir.join("reference-load");
+    let reference_bank = functions::load_bank_from_snapshot_archive(
+        reference_snapshot,
+        genesis_config,
+        &config.accounts_index,
+        &scratch_dir,
+    )?;
+    let comparison = functions::compare_banks(
+        final_bank,
+        &reference_bank,
+        config.deep_compare,
+        config.max_reported_divergences,
+    )?;
+    drop(reference_bank);
+    if let Err(e) = std::fs::remove_dir_all(&scratch_dir) {
+        warn!("Failed to remove {:?}: {:?}", scratch_dir, e);
+    }
+
+    info!(
+        "Accounts: {} (reference {})",
+        comparison.actual_accounts, comparison.expected_accounts
+    );
+    info!(
+        "Capitalization: {} (reference {})",
+        comparison.actual_capitalization, comparison.expected_capitalization
+    );
+    info!(
+        "Accounts hash: {} (reference {})",
+        comparison.actual_accounts_hash.0, comparison.expected_accounts_hash.0
+    );
+    if let Some(divergent_accounts) = comparison.divergent_accounts {
+        info!("Divergent accounts: {}", divergent_accounts);
+    }
+    for divergence in &comparison.divergences {
+        warn!(
+            "  {}: merged {:?}, reference {:?}",
+            divergence.pubkey, divergence.actual, divergence.expected
+        );
+    }
+
+    if !comparison.is_match() {
+        return Err(MergeError::Verification(format!(
+            "Merged snapshot does not match reference snapshot {:?}",
+            reference_snapshot
+        )));
+    }
+    info!("✓ Merged snapshot matches reference snapshot");
+    Ok(())
+}
+
+pub const SPLIT_MANIFEST_FILE: &str = "split-manifest.json";
+/// Written to the output directory of every merge, see
+/// [`render_snapshot_manifest`]
+pub const SNAPSHOT_MANIFEST_FILE: &str = "merged-snapshot-manifest.json";
+
+/// Name, size and SHA-256 of one file of the merged snapshot
+fn manifest_file_entry(path: &Path) -> Result<serde_json::Value, MergeError> {
+    let bytes = std::fs::metadata(path)
+        .map_err(|e| MergeError::Io(format!("Failed to read size of {:?}: {:?}", path, e)))?
+        .len();
+    Ok(serde_json::json!({
+        "file": path.file_name().map(|name| name.to_string_lossy().to_string()),
+        "bytes": bytes,
+        "sha256": fetch::sha256_file(path)?,
+    }))
+}
+
+/// Manifest of a merged snapshot: the hashes of the merged bank and the
+/// size and SHA-256 of each archive and of the genesis archive, for
+/// consumers to check what they downloaded against
+pub fn render_snapshot_manifest(
+    stats: &MergeStats,
+    genesis_archive: &Path,
+) -> Result<serde_json::Value, MergeError> {
+    let incremental = stats.incremental_snapshot.as_ref();
+    let full_snapshot = match incremental {
+        Some(incremental) if incremental.path == stats.snapshot_path => None,
+        _ => {
+            let mut entry = manifest_file_entry(Path::new(&stats.snapshot_path))?;
+            entry["hash"] = serde_json::json!(stats.full_snapshot_hash.to_string());
+            Some(entry)
+        }
+    };
+    let incremental_snapshot = incremental
+        .map(|incremental| {
+            let mut entry = manifest_file_entry(Path::new(&incremental.path))?;
+            entry["hash"] = serde_json::json!(incremental.hash.to_string());
+            entry["base_slot"] = serde_json::json!(incremental.base_slot);
+            Ok::<_, MergeError>(entry)
+        })
+        .transpose()?;
+    Ok(serde_json::json!({
+        "slot": stats.final_slot,
+        "bank_hash": stats.bank_hash.to_string(),
+        "bank_snapshot_hash": stats.bank_snapshot_hash.to_string(),
+        "genesis_hash": stats.genesis_hash.to_string(),
+        "full_snapshot": full_snapshot,
+        "incremental_snapshot": incremental_snapshot,
+        "genesis": manifest_file_entry(genesis_archive)?,
+    }))
+}
+
+/// Manifest of a split merge: for each group its owners, snapshot archive,
+/// genesis config and number of copied accounts
+pub fn render_split_manifest(
+    config: &MergeConfig,
+    groups: &[functions::OwnerGroup],
+    stats: &[MergeStats],
+) -> serde_json::Value {
+    let groups: Vec<_> = groups
+        .iter()
+        .zip(stats)
+        .map(|(group, stats)| {
+            let group_dir = config.output_directory.join(&group.name);
+            serde_json::json!({
+                "name": group.name,
+                "owners": group.owners.iter().map(Pubkey::to_string).collect::<Vec<_>>(),
+                "snapshot": stats.snapshot_path,
+                "snapshot_alias": stats.archive_alias,
+                "full_snapshot_hash": stats.full_snapshot_hash.to_string(),
+                "incremental_snapshot_hash": stats
+                    .incremental_snapshot
+                    .as_ref()
+                    .map(|incremental| incremental.hash.to_string()),
+                "genesis": group_dir.join("genesis.bin").display().to_string(),
+                "genesis_hash": stats.genesis_hash.to_string(),
+                "accounts_copied": stats.mainnet_accounts_copied,
+                "compression": {
+                    "uncompressed_bytes": stats.compression.uncompressed_bytes,
+                    "archive_bytes": stats.compression.archive_bytes,
+                    "ratio": stats.compression.ratio,
+                    "archive_secs": stats.compression.archive_secs,
+                    "throughput_mb_per_sec": stats.compression.throughput_mb_per_sec,
+                },
+            })
+        })
+        .collect();
+    serde_json::json!({
+        "mainnet_ledger": config.mainnet_ledger.display().to_string(),
+        "merge_ledger": config.ledger_to_merge.display().to_string(),
+        "groups": groups,
+    })
+}
+
+fn pubkey_strings(pubkeys: &[Pubkey]) -> Vec<String> {
+    pubkeys.iter().map(Pubkey::to_string).collect()
+}
+
+/// The document [`stats_json`] renders. Domain reports with a JSON shape of
+/// their own are embedded as they are; pubkeys and hashes are base58 strings.
+#[derive(Serialize)]
+struct StatsDocument<'a> {
+    snapshot_path: &'a str,
+    archive_alias: Option<&'a str>,
+    upload: Option<&'a upload::UploadReport>,
+    upload_error: Option<&'a str>,
+    full_snapshot_hash: String,
+    bank_hash: String,
+    bank_snapshot_hash: String,
+    incremental_snapshot: Option<IncrementalSnapshotDocument<'a>>,
+    genesis_hash: String,
+    final_slot: Slot,
+    load_secs: f64,
+    accounts: AccountsDocument,
+    excluded: ExcludedDocument<'a>,
+    rewritten: RewrittenDocument<'a>,
+    tokens: Option<TokensDocument>,
+    base_conflicts: Option<BaseConflictsDocument>,
+    base_intersection_accounts: Option<usize>,
+    mainnet_features: Option<FeaturesDocument>,
+    merge_feature_accounts_removed: usize,
+    mainnet_epoch_rewards_active: bool,
+    unpaired_programs: UnpairedProgramsDocument,
+    sysvars: SysvarsDocument,
+    owner_data_caps: Vec<OwnerDataCapDocument>,
+    mainnet_nonce_accounts: usize,
+    upgrade_authorities_overridden: Vec<String>,
+    owner_remaps: Vec<OwnerRemapDocument>,
+    cloned_accounts: Option<ClonedAccountsDocument<'a>>,
+    account_overrides: AccountOverridesDocument,
+    source_layers: Vec<SourceLayerDocument>,
+    top_accounts: Vec<TopAccountDocument>,
+    slot_distribution: &'a functions::SlotDistribution,
+    capitalization: CapitalizationDocument,
+    stakes_pruned: Option<&'a functions::StakePruneReport>,
+    epoch_stakes_regenerated: Option<&'a functions::EpochStakesReport>,
+    merge_validators_removed: Option<&'a functions::ValidatorRemovalReport>,
+    base_diff: Option<&'a functions::BaseDiffSummary>,
+    validator_audit: ValidatorAuditDocument<'a>,
+    validators_summary: Option<ValidatorsSummaryDocument>,
+    bank_lineage: &'a functions::BankLineage,
+    storage_check: Option<&'a functions::StorageCheck>,
+    estimated_archive_bytes: u64,
+    compression: &'a functions::CompressionReport,
+}
+
+#[derive(Serialize)]
+struct IncrementalSnapshotDocument<'a> {
+    path: &'a str,
+    hash: String,
+    base_slot: Slot,
+}
+
+#[derive(Serialize)]
+struct AccountsDocument {
+    mainnet_total: usize,
+    merge_total: usize,
+    final_total: usize,
+    mainnet_copied: usize,
+    merge_system_preserved: usize,
+    data_size: u64,
+}
+
+#[derive(Serialize)]
+struct ExcludedDocument<'a> {
+    mainnet_vote_accounts: usize,
+    mainnet_stake_accounts: usize,
+    mainnet_feature_accounts: usize,
+    lookup_tables: usize,
+    identical: usize,
+    outside_base: usize,
+    dormant: usize,
+    subtracted: usize,
+    by_owner: usize,
+    by_list: usize,
+    outside_include_list: usize,
+    dust: usize,
+    zero_lamport: usize,
+    oversized: usize,
+    custom: &'a BTreeMap<String, usize>,
+    invalid: usize,
+}
+
+#[derive(Serialize)]
+struct RewrittenDocument<'a> {
+    field_merged: usize,
+    lookup_tables_refreshed: usize,
+    lookup_tables_malformed: usize,
+    rent_epochs_normalized: usize,
+    token_balances_reconstructed: usize,
+    transforms: BTreeMap<&'a str, usize>,
+    duplicate_pubkeys_resolved: usize,
+}
+
+#[derive(Serialize)]
+struct TokensDocument {
+    token_accounts_copied: usize,
+    mints_copied: usize,
+    mints_held: usize,
+    mints_held_without_mint: usize,
+    mints_short_of_supply: usize,
+    excluded_by_mint: BTreeMap<String, spl::MintExclusion>,
+    excluded_below_min_balance: usize,
+}
+
+#[derive(Serialize)]
+struct BaseConflictsDocument {
+    conflicts: usize,
+    kept_base: usize,
+}
+
+#[derive(Serialize)]
+struct FeaturesDocument {
+    feature_accounts: usize,
+    activated: usize,
+    unknown: Vec<String>,
+    kept_merge_ledger: usize,
+    rebased: usize,
+}
+
+#[derive(Serialize)]
+struct UnpairedProgramsDocument {
+    programs: Vec<String>,
+    program_data: Vec<String>,
+}
+
+#[derive(Serialize)]
+struct SysvarsDocument {
+    rewritten: Vec<&'static str>,
+    kept: Vec<&'static str>,
+}
+
+#[derive(Serialize)]
+struct OwnerDataCapDocument {
+    owner: String,
+    cap_bytes: u64,
+    bytes_copied: u64,
+    accounts_copied: usize,
+    bytes_skipped: u64,
+    accounts_skipped: usize,
+}
+
+#[derive(Serialize)]
+struct OwnerRemapDocument {
+    from: String,
+    to: String,
+    accounts_remapped: usize,
+}
+
+#[derive(Serialize)]
+struct ClonedAccountsDocument<'a> {
+    rpc_url: &'a str,
+    slot: Slot,
+    accounts: Vec<String>,
+}
+
+#[derive(Serialize)]
+struct AccountOverridesDocument {
+    replaced: Vec<String>,
+    created: Vec<String>,
+}
+
+#[derive(Serialize)]
+struct SourceLayerDocument {
+    ledger: String,
+    slot: Slot,
+    accounts: usize,
+    inserted: usize,
+    collisions: usize,
+    unchanged: usize,
+}
+
+#[derive(Serialize)]
+struct TopAccountDocument {
+    pubkey: String,
+    owner: String,
+    lamports: u64,
+}
+
+#[derive(Serialize)]
+struct CapitalizationDocument {
+    before: u64,
+    after: u64,
+    delta: i128,
+    before_warp: Option<u64>,
+    after_warp: Option<u64>,
+    check_delta: i128,
+    treasury_adjustment: Option<TreasuryAdjustmentDocument>,
+}
+
+#[derive(Serialize)]
+struct TreasuryAdjustmentDocument {
+    treasury: String,
+    delta: i128,
+}
+
+#[derive(Serialize)]
+struct ValidatorAuditDocument<'a> {
+    vote: &'a functions::ValidatorAccountCounts,
+    stake: &'a functions::ValidatorAccountCounts,
+    mainnet_accounts: usize,
+}
+
+#[derive(Serialize)]
+struct ValidatorsSummaryDocument {
+    validators: usize,
+    total_active_stake: u64,
+    stake_to_missing_vote_accounts: u64,
+}
+
+/// Renders `stats` as one JSON object for `--output-format json` and
+/// `--stats-file`. Pubkey lists that can grow with the ledgers, such as the
+/// base conflicts, are given as counts; lamport deltas are signed integers.
+pub fn stats_json(stats: &MergeStats) -> serde_json::Value {
+    let document = StatsDocument {
+        snapshot_path: &stats.snapshot_path,
+        archive_alias: stats.archive_alias.as_deref(),
+        upload: stats.upload.as_ref(),
+        upload_error: stats.upload_error.as_ref().map(MergeError::message),
+        full_snapshot_hash: stats.full_snapshot_hash.to_string(),
+        bank_hash: stats.bank_hash.to_string(),
+        bank_snapshot_hash: stats.bank_snapshot_hash.to_string(),
+        incremental_snapshot: stats.incremental_snapshot.as_ref().map(|incremental| {
+            IncrementalSnapshotDocument {
+                path: &incremental.path,
+                hash: incremental.hash.to_string(),
+                base_slot: incremental.base_slot,
+            }
+        }),
+        genesis_hash: stats.genesis_hash.to_string(),
+        final_slot: stats.final_slot,
+        load_secs: stats.load_time.as_secs_f64(),
+        accounts: AccountsDocument {
+            mainnet_total: stats.mainnet_total_accounts,
+            merge_total: stats.merge_total_accounts,
+            final_total: stats.final_total_accounts,
+            mainnet_copied: stats.mainnet_accounts_copied,
+            merge_system_preserved: stats.merge_system_accounts_preserved,
+            data_size: stats.accounts_data_size,
+        },
+        excluded: ExcludedDocument {
+            mainnet_vote_accounts: stats.mainnet_vote_accounts_excluded,
+            mainnet_stake_accounts: stats.mainnet_stake_accounts_excluded,
+            mainnet_feature_accounts: stats.mainnet_feature_accounts_excluded,
+            lookup_tables: stats.mainnet_lookup_tables_skipped,
+            identical: stats.accounts_skipped_identical,
+            outside_base: stats.accounts_outside_base_skipped,
+            dormant: stats.accounts_skipped_dormant,
+            subtracted: stats.accounts_subtracted,
+            by_owner: stats.accounts_excluded_by_owner,
+            by_list: stats.accounts_excluded_by_list,
+            outside_include_list: stats.accounts_outside_include_list,
+            dust: stats.dust_accounts_excluded,
+            zero_lamport: stats.zero_lamport_accounts_skipped,
+            oversized: stats.oversized_accounts_excluded,
+            custom: &stats.custom_filter_exclusions,
+            invalid: stats.invalid_accounts_skipped,
+        },
+        rewritten: RewrittenDocument {
+            field_merged: stats.mainnet_accounts_field_merged,
+            lookup_tables_refreshed: stats.mainnet_lookup_tables_refreshed,
+            lookup_tables_malformed: stats.mainnet_lookup_tables_malformed,
+            rent_epochs_normalized: stats.rent_epochs_normalized,
+            token_balances_reconstructed: stats.token_balances_reconstructed,
+            transforms: stats
+                .account_transforms
+                .iter()
+                .map(|report| (report.name.as_str(), report.accounts_changed))
+                .collect(),
+            duplicate_pubkeys_resolved: stats.duplicate_pubkeys_resolved,
+        },
+        tokens: stats.tokens.as_ref().map(|tokens| TokensDocument {
+            token_accounts_copied: tokens.token_accounts_copied,
+            mints_copied: tokens.mints_copied,
+            mints_held: tokens.mints_held,
+            mints_held_without_mint: tokens.mints_held_without_mint,
+            mints_short_of_supply: tokens.mints_short_of_supply,
+            excluded_by_mint: tokens
+                .excluded_by_mint
+                .iter()
+                .map(|(mint, exclusion)| (mint.to_string(), *exclusion))
+                .collect(),
+            excluded_below_min_balance: tokens.excluded_below_min_balance,
+        }),
+        base_conflicts: stats
+            .base_conflicts
+            .as_ref()
+            .map(|conflicts| BaseConflictsDocument {
+                conflicts: conflicts.conflicts.len(),
+                kept_base: conflicts.kept_base,
+            }),
+        base_intersection_accounts: stats.base_intersection_accounts,
+        mainnet_features: stats
+            .mainnet_features
+            .as_ref()
+            .map(|features| FeaturesDocument {
+                feature_accounts: features.feature_accounts,
+                activated: features.activated,
+                unknown: pubkey_strings(&features.unknown),
+                kept_merge_ledger: features.kept_merge_ledger,
+                rebased: features.rebased,
+            }),
+        merge_feature_accounts_removed: stats.merge_feature_accounts_removed,
+        mainnet_epoch_rewards_active: stats.mainnet_epoch_rewards_active,
+        unpaired_programs: UnpairedProgramsDocument {
+            programs: pubkey_strings(&stats.unpaired_programs.programs),
+            program_data: pubkey_strings(&stats.unpaired_programs.program_data),
+        },
+        sysvars: SysvarsDocument {
+            rewritten: stats
+                .sysvars
+                .rewritten
+                .iter()
+                .copied()
+                .map(sysvars::Sysvar::name)
+                .collect(),
+            kept: stats
+                .sysvars
+                .kept
+                .iter()
+                .copied()
+                .map(sysvars::Sysvar::name)
+                .collect(),
+        },
+        owner_data_caps: stats
+            .owner_data_caps
+            .iter()
+            .map(|cap| OwnerDataCapDocument {
+                owner: cap.owner.to_string(),
+                cap_bytes: cap.cap_bytes,
+                bytes_copied: cap.bytes_copied,
+                accounts_copied: cap.accounts_copied,
+                bytes_skipped: cap.bytes_skipped,
+                accounts_skipped: cap.accounts_skipped,
+            })
+            .collect(),
+        mainnet_nonce_accounts: stats.mainnet_nonce_accounts,
+        upgrade_authorities_overridden: pubkey_strings(&stats.upgrade_authorities_overridden),
+        owner_remaps: stats
+            .owner_remaps
+            .iter()
+            .map(|remap| OwnerRemapDocument {
+                from: remap.from.to_string(),
+                to: remap.to.to_string(),
+                accounts_remapped: remap.accounts_remapped,
+            })
+            .collect(),
+        cloned_accounts: stats
+            .cloned_accounts
+            .as_ref()
+            .map(|cloned| ClonedAccountsDocument {
+                rpc_url: &cloned.rpc_url,
+                slot: cloned.slot,
+                accounts: pubkey_strings(&cloned.cloned),
+            }),
+        account_overrides: AccountOverridesDocument {
+            replaced: pubkey_strings(&stats.account_overrides.replaced),
+            created: pubkey_strings(&stats.account_overrides.created),
+        },
+        source_layers: stats
+            .source_layers
+            .iter()
+            .map(|layer| SourceLayerDocument {
+                ledger: layer.ledger.display().to_string(),
+                slot: layer.slot,
+                accounts: layer.accounts,
+                inserted: layer.merge.inserted,
+                collisions: layer.merge.collisions,
+                unchanged: layer.merge.unchanged,
+            })
+            .collect(),
+        top_accounts: stats
+            .top_accounts
+            .iter()
+            .map(|account| TopAccountDocument {
+                pubkey: account.pubkey.to_string(),
+                owner: account.owner.to_string(),
+                lamports: account.lamports,
+            })
+            .collect(),
+        slot_distribution: &stats.slot_distribution,
+        capitalization: CapitalizationDocument {
+            before: stats.capitalization_before,
+            after: stats.capitalization_after,
+            delta: stats.capitalization_after as i128 - stats.capitalization_before as i128,
+            before_warp: stats.capitalization_before_warp,
+            after_warp: stats.capitalization_after_warp,
+            check_delta: stats.capitalization_check_delta,
+            treasury_adjustment: stats.treasury_adjustment.map(|adjustment| {
+                TreasuryAdjustmentDocument {
+                    treasury: adjustment.treasury.to_string(),
+                    delta: adjustment.delta,
+                }
+            }),
+        },
+        stakes_pruned: stats.stakes_pruned.as_ref(),
+        epoch_stakes_regenerated: stats.epoch_stakes_regenerated.as_ref(),
+        merge_validators_removed: stats.merge_validators_removed.as_ref(),
+        base_diff: stats.base_diff.as_ref(),
+        validator_audit: ValidatorAuditDocument {
+            vote: &stats.validator_audit.vote,
+            stake: &stats.validator_audit.stake,
+            mainnet_accounts: stats.validator_audit.mainnet_accounts.len(),
+        },
+        validators_summary: stats.validators_summary.as_ref().map(|summary| {
+            ValidatorsSummaryDocument {
+                validators: summary.validators.len(),
+                total_active_stake: summary.total_active_stake,
+                stake_to_missing_vote_accounts: summary.stake_to_missing_vote_accounts,
+            }
+        }),
+        bank_lineage: &stats.bank_lineage,
+        storage_check: stats.storage_check.as_ref(),
+        estimated_archive_bytes: stats.estimated_archive_bytes,
+        compression: &stats.compression,
+    };
+    // Every map is keyed by strings, which is all that could fail
+    serde_json::to_value(document).expect("merge stats serialize to JSON")
+}
+
+fn check_owner_groups(groups: &[functions::OwnerGroup]) -> Result<(), MergeError> {
+    let mut names = HashSet::new();
+    match groups.iter().find(|group| !names.insert(&group.name)) {
+        Some(duplicate) => Err(MergeError::InvalidInput(format!(
+            "Owner group '{}' is given twice",
+            duplicate.name
+        ))),
+        None => Ok(()),
+    }
+}
+
+fn has_genesis(directory: &Path) -> bool {
+    directory.join(DEFAULT_GENESIS_FILE).is_file()
+        || directory.join(DEFAULT_GENESIS_ARCHIVE).is_file()
+}
+
+/// Checks a ledger can be loaded, returning the slot of its highest full
+/// snapshot archive
+fn preflight_ledger(name: &str, ledger_path: &Path) -> Result<Slot, MergeError> {
+    if !ledger_path.is_dir() {
+        return Err(MergeError::InvalidInput(format!(
+            "{} {:?} is not a directory",
+            name, ledger_path
+        )));
+    }
+    if !has_genesis(ledger_path) {
+        return Err(MergeError::InvalidInput(format!(
+            "{} {:?} has neither {} nor {}",
+            name, ledger_path, DEFAULT_GENESIS_FILE, DEFAULT_GENESIS_ARCHIVE
+        )));
+    }
+    snapshot_utils::get_highest_full_snapshot_archive_info(ledger_path)
+        .map(|archive| archive.slot())
+        .ok_or_else(|| {
+            MergeError::InvalidInput(format!(
+                "{} {:?} has no full snapshot archive",
+                name, ledger_path
+            ))
+        })
+}
+
+/// Checks the mainnet bank can be loaded: its ledger as [`preflight_ledger`]
+/// does, or its snapshot archive and a genesis config to load it with
+fn preflight_mainnet(config: &MergeConfig) -> Result<(), MergeError> {
+    if let Some(genesis) = config
+        .mainnet_genesis
+        .as_ref()
+        .filter(|path| !path.exists())
+    {
+        return Err(MergeError::InvalidInput(format!(
+            "Mainnet genesis {:?} does not exist",
+            genesis
+        )));
+    }
+    let Some(archive) = &config.mainnet_snapshot else {
+        return preflight_ledger("Mainnet ledger", &config.mainnet_ledger).map(drop);
+    };
+    if !archive.is_file() {
+        return Err(MergeError::InvalidInput(format!(
+            "Mainnet snapshot {:?} is not a file",
+            archive
+        )));
+    }
+    FullSnapshotArchiveInfo::new_from_path(archive.clone()).map_err(|e| {
+        MergeError::InvalidInput(format!(
+            "Mainnet snapshot {:?} is not a full snapshot archive: {:?}",
+            archive, e
+        ))
+    })?;
+    if config.mainnet_genesis.is_none() && !has_genesis(&config.mainnet_ledger) {
+        return Err(MergeError::InvalidInput(format!(
+            "{:?} has neither {} nor {}; pass the mainnet genesis explicitly",
+            config.mainnet_ledger, DEFAULT_GENESIS_FILE, DEFAULT_GENESIS_ARCHIVE
+        )));
+    }
+    Ok(())
+}
+
+/// Warns when the unpacked size of both ledgers' snapshot archives, as
+/// estimated from their compressed size, exceeds the host's available memory
+fn warn_if_parallel_load_exceeds_memory(ledgers: [&Path; 2]) {
+    let estimated_bytes: u64 = ledgers
+        .iter()
+        .filter_map(snapshot_utils::get_highest_full_snapshot_archive_info)
+        .filter_map(|archive| std::fs::metadata(archive.path()).ok())
+        .map(|metadata| {
+            (metadata.len() as f64 * functions::DEFAULT_ARCHIVE_COMPRESSION_RATIO) as u64
+        })
+        .sum();
+    match functions::available_memory_bytes() {
+        Some(available) if estimated_bytes > available => warn!(
+            "Parallel load holds both banks at once: an estimated {} bytes unpacked against {} \
+             bytes of available memory",
+            estimated_bytes, available
+        ),
+        Some(_) => {}
+        None => warn!(
+            "Available memory is unknown; parallel load holds both banks at once (an estimated \
+             {} bytes unpacked)",
+            estimated_bytes
+        ),
+    }
+}
+
+/// Validates `config` and the split `groups` without loading any bank:
+/// input paths, the directories written to, option combinations and values
+/// only known to be wrong once a merge is underway. Returns every problem
+/// found, none if the merge is ready to run.
+pub fn preflight(config: &MergeConfig, groups: &[functions::OwnerGroup]) -> Vec<String> {
+    preflight_checks(config, groups, None)
+}
+
+/// [`preflight`] for a merge that first downloads its mainnet snapshot from
+/// `url`: the URL is checked instead of the archive, which isn't there yet
+pub fn preflight_with_mainnet_url(
+    config: &MergeConfig,
+    groups: &[functions::OwnerGroup],
+    url: &str,
+) -> Vec<String> {
+    preflight_checks(config, groups, Some(url))
+}
+
+/// Checks the options of a merge against each other and against the merge
+/// ledger's snapshot slot, before anything is loaded: every problem for
+/// [`preflight`] to report, the first of which `run_merge` fails with
+fn check_options(config: &MergeConfig) -> Vec<MergeError> {
+    let mut problems = Vec::new();
+    let mut check = |result: Result<(), MergeError>| {
+        if let Err(e) = result {
+            problems.push(e);
+        }
+    };
+
+    if config.phase != functions::MergePhase::Full {
+        check(check_two_phase_options(config));
+    }
+    if config.copy_memory_budget.is_some() {
+        check(check_streaming_options(config));
+    }
+    if config.checkpoint || config.resume {
+        check(check_checkpoint_options(config));
+    }
+    if config.deterministic {
+        check(check_deterministic_options(config));
+    }
+    check(check_sysvar_options(config));
+    check(check_custom_filter_options(config));
+    check(check_poh_overrides(config));
+    if !config.clone_accounts.is_empty() && config.rpc_url.is_none() {
+        check(Err(MergeError::InvalidInput(
+            "Cloning accounts needs an RPC URL".to_string(),
+        )));
+    }
+    // Missing credentials would otherwise only show once the merge is done
+    if let Some(destination) = &config.upload_to {
+        check(upload::Credentials::from_env(destination.service).map(drop));
+    }
+    // Fail before the merge rather than after archiving
+    if let Some(name) = &config.archive_name {
+        check(functions::check_archive_name(&config.output_directory, name).map(drop));
+    }
+    if config.copy_shards == Some(0) {
+        check(Err(MergeError::InvalidInput(
+            "--copy-shards (--copy-threads) must be at least 1".to_string(),
+        )));
+    }
+    let mut identity_remaps: Vec<_> = config
+        .owner_remaps
+        .iter()
+        .filter(|(from, to)| from == to)
+        .map(|(from, _)| from.to_string())
+        .collect();
+    identity_remaps.sort_unstable();
+    for owner in identity_remaps {
+        check(Err(MergeError::InvalidInput(format!(
+            "Owner remap of {} maps it to itself",
+            owner
+        ))));
+    }
+    if config.phase != functions::MergePhase::Extract {
+        match preflight_ledger("Ledger to merge", &config.ledger_to_merge) {
+            // The merged bank is a child of the merge ledger's snapshot
+            Ok(merge_slot) => {
+                if let Some(warp_slot) = config.warp_slot.filter(|&slot| slot <= merge_slot + 1) {
+                    check(Err(MergeError::InvalidInput(format!(
+                        "Warp slot {} is not past the merged bank's slot {}",
+                        warp_slot,
+                        merge_slot + 1
+                    ))));
+                }
+                if config.snapshot_type.writes_incremental() {
+                    check(check_incremental_options(config, merge_slot));
+                }
+                // Otherwise only caught once the merged bank is ticked before
+                // archiving
+                if config.bank_completion != functions::BankCompletion::FillToMax {
+                    check(
+                        open_ledger_genesis_config("ledger to merge", &config.ledger_to_merge)
+                            .and_then(|genesis_config| {
+                                check_bank_completion(
+                                    config,
+                                    merge_slot,
+                                    genesis_config.ticks_per_slot,
+                                )
+                            }),
+                    );
+                }
+            }
+            Err(e) => check(Err(e)),
+        }
+    }
+    problems
+}
+
+fn preflight_checks(
+    config: &MergeConfig,
+    groups: &[functions::OwnerGroup],
+    mainnet_url: Option<&str>,
+) -> Vec<String> {
+    let mut problems: Vec<String> = check_options(config)
+        .iter()
+        .map(ToString::to_string)
+        .collect();
+
+    if config.phase != functions::MergePhase::Apply {
+        let mainnet = match mainnet_url {
+            Some(url) => fetch::resolve_url(url).map(drop),
+            None => preflight_mainnet(config),
+        };
+        if let Err(e) = mainnet {
+            problems.push(e.to_string());
+        }
+    }
+    if config.phase == functions::MergePhase::Apply && !config.copy_set_path().is_file() {
+        problems.push(format!(
+            "Copy set {:?} does not exist; run --phase extract first",
+            config.copy_set_path()
+        ));
+    }
+    if let Some(subtract_ledger) = &config.subtract_ledger {
+        if let Err(e) = preflight_ledger("Subtract ledger", subtract_ledger) {
+            problems.push(e.to_string());
+        }
+    }
+    for source_ledger in &config.source_ledgers {
+        if let Err(e) = preflight_ledger("Source ledger", source_ledger) {
+            problems.push(e.to_string());
+        }
+    }
+    if let Some(reference_snapshot) = &config.reference_snapshot {
+        if !reference_snapshot.is_file() {
+            problems.push(format!(
+                "Reference snapshot {:?} is not a file",
+                reference_snapshot
+            ));
+        }
+    }
+
+    for (name, dir) in [
+        ("Output directory", Some(&config.output_directory)),
+        (
+            "Load scratch directory",
+            config.load_scratch_directory.as_ref(),
+        ),
+    ] {
+        if let Some(dir) = dir.filter(|dir| dir.exists() && !dir.is_dir()) {
+            problems.push(format!("{} {:?} is not a directory", name, dir));
+        }
+    }
+    // Files written outside the output directory need an existing parent
+    for (name, file) in [
+        ("Changelog file", config.changelog_file.as_ref()),
+        ("Progress file", config.progress_file.as_ref()),
+        ("Copy set file", config.copy_set_file.as_ref()),
+        ("Throughput log", config.throughput_log.as_ref()),
+        ("Account index file", config.account_index_file.as_ref()),
+        ("Base diff file", config.base_diff_file.as_ref()),
+        ("Conflict report file", config.conflict_report_file.as_ref()),
+    ] {
+        let Some(parent) = file.and_then(|file| file.parent()) else {
+            continue;
+        };
+        if !parent.as_os_str().is_empty()
+            && !parent.starts_with(&config.output_directory)
+            && !parent.is_dir()
+        {
+            problems.push(format!("{} directory {:?} does not exist", name, parent));
+        }
+    }
+
+    if let Err(e) = check_owner_groups(groups) {
+        problems.push(e.to_string());
+    }
+    problems
+}
+
+/// Runs one merge per owner group, each into its own subdirectory of the
+/// output directory and each starting from the unmodified merge ledger with
+/// only the group's mainnet accounts copied in, then writes
+/// [`SPLIT_MANIFEST_FILE`] mapping the groups to their archives. Mainnet
+/// accounts whose owner is in no group are not copied anywhere. Every merge
+/// loads both ledgers again, since the merged banks can't share the merge
+/// ledger's accounts database.
+pub fn merge_snapshots_split_by_owner(
+    config: &MergeConfig,
+    groups: &[functions::OwnerGroup],
+) -> Result<Vec<MergeStats>, MergeError> {
+    check_owner_groups(groups)?;
+
+    let mut all_stats = Vec::with_capacity(groups.len());
+    for group in groups {
+        info!(
+            "\n=== Split merge for owner group '{}' ({} owners) ===",
+            group.name,
+            group.owners.len()
+        );
+        let group_config = MergeConfig {
+            output_directory: config.output_directory.join(&group.name),
+            changelog_file: None,
+            // Each group's index goes next to its own archive
+            account_index_file: config.account_index_file.as_ref().map(|file| {
+                config
+                    .output_directory
+                    .join(&group.name)
+                    .join(file.file_name().unwrap_or_default())
+            }),
+            base_diff_file: config.base_diff_file.as_ref().map(|file| {
+                config
+                    .output_directory
+                    .join(&group.name)
+                    .join(file.file_name().unwrap_or_default())
+            }),
+            conflict_report_file: config.conflict_report_file.as_ref().map(|file| {
+                config
+                    .output_directory
+                    .join(&group.name)
+                    .join(file.file_name().unwrap_or_default())
+            }),
+            copy_owners: Some(group.owners.iter().copied().collect()),
+            ..config.clone()
+        };
+        let stats = merge_snapshots(&group_config, None)
+            .map_err(|e| e.context(format!("Owner group '{}'", group.name)))?;
+        all_stats.push(stats);
+    }
+
+    let manifest_path = config.output_directory.join(SPLIT_MANIFEST_FILE);
+    let manifest = render_split_manifest(config, groups, &all_stats);
+    std::fs::write(&manifest_path, format!("{:#}\n", manifest))
+        .map_err(|e| MergeError::Io(format!("Failed to write {:?}: {:?}", manifest_path, e)))?;
+    info!("Split manifest written to: {:?}", manifest_path);
+    Ok(all_stats)
+}
+
+/// Merges the mainnet ledger into the merge ledger as `config` describes.
+/// When the merge runs past `config.deadline`, [`INCOMPLETE_MARKER_FILE`] is
+/// left in the output directory with the reason before the error is returned.
+pub fn merge_snapshots(
+    config: &MergeConfig,
+    post_merge_hook: Option<PostMergeHook>,
+) -> Result<MergeStats, MergeError> {
+    let result = run_merge(config, post_merge_hook);
+    if let (Err(e), Some(deadline)) = (&result, &config.deadline) {
+        if deadline.exceeded() {
+            write_incomplete_marker(&config.output_directory, e.message());
+        }
+    }
+    result
+}
+
+/// Builds and runs a merge from library code, as the binary does from its
+/// arguments:
+///
+/// ```no_run
+/// # use snapshot_merger::merger::SnapshotMerger;
+/// let report = SnapshotMerger::new("ledgers/mainnet", "ledgers/base")
+///     .exclude_validators()
+///     .warp_to(500_000)
+///     .output("ledgers/merged")
+///     .run()?;
+/// println!("{}", report.stats.snapshot_path);
+/// # Ok::<(), snapshot_merger::error::MergeError>(())
+/// ```
+///
+/// Settings without a method of their own are reached through
+/// [`SnapshotMerger::configure`].
+pub struct SnapshotMerger {
+    config: MergeConfig,
+    post_merge_hook: Option<PostMergeHook>,
+}
+
+impl SnapshotMerger {
+    /// A merge of `source`'s accounts into the `base` ledger, with every
+    /// setting as [`MergeConfig::new`] defaults it, whose argument order it
+    /// shares. An output directory must be given with
+    /// [`SnapshotMerger::output`] before running.
+    pub fn new(source: impl Into<PathBuf>, base: impl Into<PathBuf>) -> Self {
+        Self::from_config(MergeConfig::new(source.into(), base.into(), PathBuf::new()))
+    }
+
+    pub fn from_config(config: MergeConfig) -> Self {
+        Self {
+            config,
+            post_merge_hook: None,
+        }
+    }
+
+    /// Keep only the base ledger's validators, which is also the default
+    pub fn exclude_validators(self) -> Self {
+        self.validator_source(functions::ValidatorSource::MergeLedger)
+    }
+
+    pub fn validator_source(mut self, source: functions::ValidatorSource) -> Self {
+        self.config.validator_source = source;
+        self
+    }
+
+    pub fn warp_to(mut self, slot: Slot) -> Self {
+        self.config.warp_slot = Some(slot);
+        self
+    }
+
+    pub fn output(mut self, directory: impl Into<PathBuf>) -> Self {
+        self.config.output_directory = directory.into();
+        self
+    }
+
+    /// Applies `f` to the underlying [`MergeConfig`]
+    pub fn configure(mut self, f: impl FnOnce(&mut MergeConfig)) -> Self {
+        f(&mut self.config);
+        self
+    }
+
+    /// Leaves the mainnet accounts `filter` excludes out of the copy, after
+    /// the built-in filters and any filter added before it
+    pub fn filter(mut self, filter: impl filter::AccountFilter + Send + Sync + 'static) -> Self {
+        self.config.custom_filters.push(Arc::new(filter));
+        self
+    }
+
+    /// Rewrites every mainnet account about to be copied with `transform`,
+    /// after any transform added before it
+    pub fn transform(
+        mut self,
+        transform: impl transform::AccountTransform + Send + Sync + 'static,
+    ) -> Self {
+        self.config.account_transforms.push(Arc::new(transform));
+        self
+    }
+
+    pub fn post_merge_hook(mut self, hook: PostMergeHook) -> Self {
+        self.post_merge_hook = Some(hook);
+        self
+    }
+
+    pub fn config(&self) -> &MergeConfig {
+        &self.config
+    }
+
+    /// Runs the merge as [`merge_snapshots`] does
+    pub fn run(self) -> Result<MergeReport, MergeError> {
+        if self.config.output_directory.as_os_str().is_empty() {
+            return Err(MergeError::InvalidInput(
+                "No output directory given for the merge".to_string(),
+            ));
+        }
+        let stats = merge_snapshots(&self.config, self.post_merge_hook)?;
+        Ok(MergeReport {
+            config: self.config,
+            stats,
+        })
+    }
+}
+
+/// What [`SnapshotMerger::run`] produced, with the configuration it ran with
+#[derive(Debug)]
+pub struct MergeReport {
+    pub config: MergeConfig,
+    pub stats: MergeStats,
+}
+
+impl MergeReport {
+    /// The stats as `--output-format json` prints them
+    pub fn to_json(&self) -> serde_json::Value {
+        stats_json(&self.stats)
+    }
+
+    /// The changelog the merge writes next to its archive
+    pub fn changelog(&self) -> String {
+        render_changelog(&self.config, &self.stats)
+    }
+}
+
+/// Best effort: the merge has already failed, so a failure here is only logged
+fn write_incomplete_marker(output_dir: &Path, reason: &str) {
+    let marker_path = output_dir.join(INCOMPLETE_MARKER_FILE);
+    let contents = format!(
+        "This merge was aborted and its output is incomplete: {}\n\
+         Any snapshot archive in this directory predates the aborted run.\n",
+        reason
+    );
+    match std::fs::create_dir_all(output_dir).and_then(|_| std::fs::write(&marker_path, contents)) {
+        Ok(()) => info!("Incomplete merge marker written to: {:?}", marker_path),
+        Err(e) => warn!("Failed to write {:?}: {:?}", marker_path, e),
+    }
+}
+
+/// How far an interrupted merge got: mainnet was scanned and its copy set
+/// written, so a resumed run only has to load the merge ledger and copy.
+/// Rewritten with the copy's position as the mainnet accounts are stored.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct MergeCheckpoint {
+    pub mainnet_ledger: PathBuf,
+    pub ledger_to_merge: PathBuf,
+    pub copy_set: PathBuf,
+    pub mainnet_slot: Slot,
+    pub accounts: usize,
+    /// `active_copy_filters` of the run that scanned, which a resumed run
+    /// must match since the copy set was filtered with them
+    pub copy_filters: Vec<String>,
+    /// What the scan reported beyond the copy set
+    pub outside_base_count: usize,
+    pub source_layers: Vec<SourceLayer>,
+    /// The last position the mainnet copy recorded, if it started. The
+    /// merged bank only lives in memory, so a resumed run stores those
+    /// accounts again.
+    pub copy_progress: Option<functions::CopyPosition>,
+}
+
+impl MergeCheckpoint {
+    pub fn to_json(&self) -> serde_json::Value {
+        serde_json::json!({
+            "mainnet_ledger": self.mainnet_ledger.display().to_string(),
+            "ledger_to_merge": self.ledger_to_merge.display().to_string(),
+            "copy_set": self.copy_set.display().to_string(),
+            "mainnet_slot": self.mainnet_slot,
+            "accounts": self.accounts,
+            "copy_filters": self.copy_filters,
+            "outside_base_count": self.outside_base_count,
+            "source_layers": self
+                .source_layers
+                .iter()
+                .map(|layer| serde_json::json!({
+                    "ledger": layer.ledger.display().to_string(),
+                    "slot": layer.slot,
+                    "accounts": layer.accounts,
+                    "inserted": layer.merge.inserted,
+                    "collisions": layer.merge.collisions,
+                    "unchanged": layer.merge.unchanged,
+                }))
+                .collect::<Vec<_>>(),
+            "copy_progress": self.copy_progress.as_ref().map(functions::CopyPosition::to_json),
+        })
+    }
+
+    pub fn from_json(value: &serde_json::Value) -> Result<Self, MergeError> {
+        let path = |key: &str| {
+            value[key]
+                .as_str()
+                .map(PathBuf::from)
+                .ok_or_else(|| MergeError::InvalidInput(format!("Checkpoint has no {}", key)))
+        };
+        let number = |key: &str| {
+            value[key]
+                .as_u64()
+                .ok_or_else(|| MergeError::InvalidInput(format!("Checkpoint has no {}", key)))
+        };
+        Ok(Self {
+            mainnet_ledger: path("mainnet_ledger")?,
+            ledger_to_merge: path("ledger_to_merge")?,
+            copy_set: path("copy_set")?,
+            mainnet_slot: number("mainnet_slot")?,
+            accounts: number("accounts")? as usize,
+            outside_base_count: number("outside_base_count")? as usize,
+            source_layers: value["source_layers"]
+                .as_array()
+                .ok_or_else(|| {
+                    MergeError::InvalidInput("Checkpoint has no source_layers".to_string())
+                })?
+                .iter()
+                .map(|layer| {
+                    let count = |key: &str| layer[key].as_u64().map(|count| count as usize);
+                    Some(SourceLayer {
+                        ledger: PathBuf::from(layer["ledger"].as_str()?),
+                        slot: layer["slot"].as_u64()?,
+                        accounts: count("accounts")?,
+                        merge: functions::AccountMapMergeStats {
+                            inserted: count("inserted")?,
+                            collisions: count("collisions")?,
+                            unchanged: count("unchanged")?,
+                        },
+                    })
+                })
+                .collect::<Option<_>>()
+                .ok_or_else(|| {
+                    MergeError::InvalidInput("Checkpoint has a malformed source layer".to_string())
+                })?,
+            copy_progress: match &value["copy_progress"] {
+                serde_json::Value::Null => None,
+                progress => {
+                    Some(functions::CopyPosition::from_json(progress).ok_or_else(|| {
+                        MergeError::InvalidInput(
+                            "Checkpoint has a malformed copy_progress".to_string(),
+                        )
+                    })?)
+                }
+            },
+            copy_filters: value["copy_filters"]
+                .as_array()
+                .ok_or_else(|| {
+                    MergeError::InvalidInput("Checkpoint has no copy_filters".to_string())
+                })?
+                .iter()
+                .map(|filter| filter.as_str().map(str::to_string))
+                .collect::<Option<_>>()
+                .ok_or_else(|| {
+                    MergeError::InvalidInput("Checkpoint copy_filters must be strings".to_string())
+                })?,
+        })
+    }
+}
+
+/// Reads `path`, `None` if there is no checkpoint
+pub fn read_checkpoint(path: &Path) -> Result<Option<MergeCheckpoint>, MergeError> {
+    let contents = match std::fs::read_to_string(path) {
+        Ok(contents) => contents,
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => return Ok(None),
+        Err(e) => {
+            return Err(MergeError::Io(format!(
+                "Failed to read checkpoint {:?}: {:?}",
+                path, e
+            )))
+        }
+    };
+    let value = serde_json::from_str(&contents).map_err(|e| {
+        MergeError::InvalidInput(format!("Failed to parse checkpoint {:?}: {}", path, e))
+    })?;
+    MergeCheckpoint::from_json(&value)
+        .map(Some)
+        .map_err(|e| e.context(format!("{:?}", path)))
+}
+
+/// Writes the scanned `copy_set` to the output directory and a checkpoint
+/// naming it, and returns the checkpoint. The checkpoint goes through a
+/// temporary file renamed into place, so it only ever names a completely
+/// written copy set.
+fn write_checkpoint(
+    config: &MergeConfig,
+    copy_set: &functions::CopySet,
+    outside_base_count: usize,
+    source_layers: &[SourceLayer],
+) -> Result<MergeCheckpoint, MergeError> {
+    std::fs::create_dir_all(&config.output_directory).map_err(|e| {
+        MergeError::Io(format!(
+            "Failed to create output directory {:?}: {:?}",
+            config.output_directory, e
+        ))
+    })?;
+    let copy_set_path = config.output_directory.join(COPY_SET_FILE);
+    let accounts = functions::write_copy_set(copy_set, &copy_set_path)?;
+    let checkpoint = MergeCheckpoint {
+        mainnet_ledger: config.mainnet_ledger.clone(),
+        ledger_to_merge: config.ledger_to_merge.clone(),
+        copy_set: copy_set_path,
+        mainnet_slot: copy_set.mainnet_slot,
+        accounts,
+        copy_filters: active_copy_filters(config),
+        outside_base_count,
+        source_layers: source_layers.to_vec(),
+        copy_progress: None,
+    };
+    let path = config.checkpoint_path();
+    let tmp_path = path.with_extension("json.tmp");
+    std::fs::write(&tmp_path, format!("{:#}\n", checkpoint.to_json()))
+        .and_then(|_| std::fs::rename(&tmp_path, &path))
+        .map_err(|e| MergeError::Io(format!("Failed to write checkpoint {:?}: {:?}", path, e)))?;
+    info!(
+        "Checkpoint written to {:?}: {} mainnet accounts from slot {} in {:?}",
+        path, accounts, checkpoint.mainnet_slot, checkpoint.copy_set
+    );
+    Ok(checkpoint)
+}
+
+/// The checkpoint to resume from, `None` to start from scratch. Fails if the
+/// checkpoint was written for other ledgers or filters.
+fn resume_checkpoint(config: &MergeConfig) -> Result<Option<MergeCheckpoint>, MergeError> {
+    let path = config.checkpoint_path();
+    let Some(checkpoint) = read_checkpoint(&path)? else {
+        info!("No checkpoint at {:?}, starting from scratch", path);
+        return Ok(None);
+    };
+    if checkpoint.mainnet_ledger != config.mainnet_ledger
+        || checkpoint.ledger_to_merge != config.ledger_to_merge
+    {
+        return Err(MergeError::InvalidInput(format!(
+            "Checkpoint {:?} is for merging {:?} into {:?}; remove it to start over",
+            path, checkpoint.mainnet_ledger, checkpoint.ledger_to_merge
+        )));
+    }
+    let copy_filters = active_copy_filters(config);
+    if checkpoint.copy_filters != copy_filters {
+        return Err(MergeError::InvalidInput(format!(
+            "Checkpoint {:?} was scanned with the filters [{}], not [{}]; remove it to start over",
+            path,
+            checkpoint.copy_filters.join(", "),
+            copy_filters.join(", ")
+        )));
+    }
+    info!(
+        "Resuming from checkpoint {:?}: {} mainnet accounts scanned at slot {}",
+        path, checkpoint.accounts, checkpoint.mainnet_slot
+    );
+    if let Some(progress) = &checkpoint.copy_progress {
+        info!(
+            "The interrupted run had stored {} of them, through {} in slot {}; the merged bank \
+             only lived in its memory, so they are stored again",
+            progress.accounts_stored, progress.last_pubkey, progress.slot
+        );
+    }
+    Ok(Some(checkpoint))
+}
+
+/// Best effort: the merge is complete, so a failure here is only logged
+fn remove_checkpoint(config: &MergeConfig) {
+    let path = config.checkpoint_path();
+    let checkpoint = match read_checkpoint(&path) {
+        Ok(Some(checkpoint)) => checkpoint,
+        Ok(None) => return,
+        Err(e) => {
+            warn!("{}", e);
+            return;
+        }
+    };
+    for file in [&checkpoint.copy_set, &path] {
+        match std::fs::remove_file(file) {
+            Ok(()) => info!("Removed checkpoint file {:?}", file),
+            Err(e) => warn!("Failed to remove checkpoint file {:?}: {:?}", file, e),
+        }
+    }
+}
+
+/// Logs what [`functions::regenerate_epoch_stakes`] did to a bank of `epoch`
+fn log_regenerated_epoch_stakes(report: &functions::EpochStakesReport, epoch: Epoch) {
+    info!(
+        "✓ Epoch stakes of epochs {:?} regenerated from {} vote accounts, total stake of epoch \
+         {} {} -> {}",
+        report.epochs, report.vote_accounts, epoch, report.previous_total_stake, report.total_stake
+    );
+}
+
+/// Fetches `MergeConfig::clone_accounts` once before the merge starts, so an
+/// unreachable endpoint or a pubkey it has no account for fails the merge
+/// before the mainnet copy rather than after it. The accounts are fetched
+/// again for [`clone_rpc_accounts`], so the stored versions stay fresh.
+fn check_clone_accounts(config: &MergeConfig) -> Result<(), MergeError> {
+    let rpc_url = config.rpc_url.as_deref().unwrap_or_default();
+    info!(
+        "Checking the {} accounts to clone are on {}",
+        config.clone_accounts.len(),
+        rpc_url
+    );
+    let fetched = rpc_fetch::fetch_accounts(rpc_url, &config.clone_accounts)?;
+    fetched.check_complete(rpc_url)?;
+    info!(
+        "{} has all {} accounts to clone at slot {}",
+        rpc_url,
+        config.clone_accounts.len(),
+        fetched.slot
+    );
+    Ok(())
+}
+
+/// Fetches `MergeConfig::clone_accounts` and stores them in `bank`. The
+/// caller must recalculate capitalization afterwards.
+fn clone_rpc_accounts(
+    config: &MergeConfig,
+    bank: &Bank,
+) -> Result<rpc_fetch::CloneReport, MergeError> {
+    let rpc_url = config.rpc_url.as_deref().unwrap_or_default();
+    info!(
+        "Fetching {} accounts to clone from {}",
+        config.clone_accounts.len(),
+        rpc_url
+    );
+    let fetched = rpc_fetch::fetch_accounts(rpc_url, &config.clone_accounts)?;
+    fetched.check_complete(rpc_url)?;
+    let mut cloned: Vec<_> = fetched.accounts.keys().copied().collect();
+    cloned.sort();
+    for pubkey in &cloned {
+        functions::store_account_tracking_data_size(bank, pubkey, &fetched.accounts[pubkey]);
+    }
+    info!(
+        "Cloned {} accounts from {} at slot {} or later",
+        cloned.len(),
+        rpc_url,
+        fetched.slot
+    );
+    Ok(rpc_fetch::CloneReport {
+        rpc_url: rpc_url.to_string(),
+        slot: fetched.slot,
+        cloned,
+    })
+}
+
+/// Rejects PoH overrides the merged bank can't take on. A bank keeps the
+/// ticks per slot and hashes per tick of the genesis it was created from, and
+/// a validator refuses a snapshot whose ticks per slot differ from its
+/// genesis, so the overrides need the bank rebuilt from the overridden genesis.
+fn check_poh_overrides(config: &MergeConfig) -> Result<(), MergeError> {
+    functions::validate_poh_overrides(config.hashes_per_tick, config.ticks_per_slot)?;
+    if config.hashes_per_tick.is_none() && config.ticks_per_slot.is_none() {
+        return Ok(());
+    }
+    if !config.rebuild_from_genesis {
+        return Err(MergeError::InvalidInput(
+            "--hashes-per-tick and --ticks-per-slot need --rebuild-from-genesis, which creates \
+             the merged bank from the overridden genesis"
+                .to_string(),
+        ));
+    }
+    warn!(
+        "Overriding PoH timing in the output genesis and the merged bank; slot durations will \
+         change, which is only appropriate for test networks"
+    );
+    Ok(())
+}
+
+/// Rejects a `MergeConfig::bank_completion` tick height the final bank can
+/// never be archived at. A snapshot archive needs a complete bank, so the
+/// height has to be the final slot's max tick height. Only a warp fixes the
+/// final slot before the copy, which otherwise spans as many slots as the
+/// accounts need, so a height is only accepted with one and must be the warp
+/// slot's.
+fn check_bank_completion(
+    config: &MergeConfig,
+    merge_slot: Slot,
+    genesis_ticks_per_slot: u64,
+) -> Result<(), MergeError> {
+    let functions::BankCompletion::TickHeight(height) = config.bank_completion else {
+        return Ok(());
+    };
+    let ticks_per_slot = config.ticks_per_slot.unwrap_or(genesis_ticks_per_slot);
+    let Some(warp_slot) = config.warp_slot else {
+        return Err(MergeError::InvalidInput(format!(
+            "--complete-to-tick-height {} needs --warp-slot: without a warp the final slot, \
+             and so the only tick height a snapshot archive accepts, is only known once the \
+             copy is done; use 'max'",
+            height
+        )));
+    };
+    let max_tick_height = (warp_slot + 1) * ticks_per_slot;
+    if height != max_tick_height {
+        return Err(MergeError::InvalidInput(format!(
+            "--complete-to-tick-height {} never completes the final bank, which a snapshot \
+             archive needs; use 'max' or the warp slot's max tick height, {}",
+            height, max_tick_height
+        )));
+    }
+    Ok(())
+}
+
+/// Rejects an incremental snapshot the merged bank can't be based on.
+/// `merge_slot` is the slot of the merge ledger's highest full snapshot.
+fn check_incremental_options(config: &MergeConfig, merge_slot: Slot) -> Result<(), MergeError> {
+    if config.rebuild_from_genesis {
+        return Err(MergeError::InvalidInput(
+            "--rebuild-from-genesis starts the merged bank over from genesis, so no full \
+             snapshot is a base for an incremental one"
+                .to_string(),
+        ));
+    }
+    if let Some(base) = &config.incremental_base {
+        let (base_slot, base_hash) = functions::full_snapshot_archive_id(base)?;
+        if base_slot != merge_slot {
+            return Err(MergeError::InvalidInput(format!(
+                "Incremental base {:?} is at slot {}, but the merged bank descends from the \
+                 merge ledger's full snapshot at slot {}",
+                base, base_slot, merge_slot
+            )));
+        }
+        // Same slot, but a different bank, e.g. from another cluster
+        if let Some(merge_snapshot) =
+            snapshot_utils::get_highest_full_snapshot_archive_info(&config.ledger_to_merge)
+                .filter(|archive| archive.hash().0 != base_hash)
+        {
+            return Err(MergeError::InvalidInput(format!(
+                "Incremental base {:?} has hash {}, but the merge ledger's full snapshot at \
+                 slot {} has hash {}",
+                base,
+                base_hash,
+                merge_slot,
+                merge_snapshot.hash().0
+            )));
+        }
+    }
+    Ok(())
+}
+
+/// Rejects custom filters named like a built-in one, whose exclusions would
+/// be counted as the built-in filter's
+fn check_custom_filter_options(config: &MergeConfig) -> Result<(), MergeError> {
+    match config
+        .custom_filters
+        .iter()
+        .find(|custom_filter| filter::BUILTIN_NAMES.contains(&custom_filter.name()))
+    {
+        Some(custom_filter) => Err(MergeError::InvalidInput(format!(
+            "Custom filter '{}' is named like a built-in filter; give it another name",
+            custom_filter.name()
+        ))),
+        None => Ok(()),
+    }
+}
+
+/// Rejects sysvar policies the merged bank can't be verified with
+fn check_sysvar_options(config: &MergeConfig) -> Result<(), MergeError> {
+    if sysvars::policy(&config.sysvar_policies, sysvars::Sysvar::SlotHashes)
+        == sysvars::SysvarPolicy::Keep
+        && config.warp_slot.is_none()
+    {
+        return Err(MergeError::InvalidInput(
+            "--sysvar-policy slot-hashes=keep needs --warp-slot: mainnet's slot hashes don't \
+             list the merged bank's parents, so the bank fails its lineage check"
+                .to_string(),
+        ));
+    }
+    Ok(())
+}
+
+/// Rejects the options a checkpointed merge can't honor
+fn check_checkpoint_options(config: &MergeConfig) -> Result<(), MergeError> {
+    if config.phase != functions::MergePhase::Full {
+        return Err(MergeError::InvalidInput(
+            "--checkpoint and --resume need a single-phase merge; a two-phase merge already \
+             keeps its copy set"
+                .to_string(),
+        ));
+    }
+    if config.copy_memory_budget.is_some() {
+        return Err(MergeError::InvalidInput(
+            "--checkpoint and --resume keep the whole copy set, which --copy-memory-budget \
+             never collects"
+                .to_string(),
+        ));
+    }
+    if config.copy_shards.is_some() {
+        return Err(MergeError::InvalidInput(
+            "--checkpoint and --resume record the position of the copy, which --copy-shards \
+             spreads over several threads"
+                .to_string(),
+        ));
+    }
+    if config.conflict_policy == functions::ConflictPolicy::PreferNewerSlot {
+        return Err(MergeError::InvalidInput(
+            "--conflict-policy prefer-newer-slot needs the mainnet slot of each account, which \
+             checkpoints don't record"
+                .to_string(),
+        ));
+    }
+    Ok(())
+}
+
+fn log_genesis_config(name: &str, genesis_config: &GenesisConfig) {
+    info!(
+        "{} genesis config creation time: {}",
+        name, genesis_config.creation_time
+    );
+    info!("{} genesis config hash: {}", name, genesis_config.hash());
+}
+
+/// Rejects the options a two-phase merge can't honor: filters that compare
+/// mainnet against the merge ledger while scanning, which neither phase has
+/// both of, and a dry run of the extract phase, which copies nothing anyway
+fn check_two_phase_options(config: &MergeConfig) -> Result<(), MergeError> {
+    if config.phase == functions::MergePhase::Extract && config.dry_run {
+        return Err(MergeError::InvalidInput(
+            "--dry-run can't be combined with --phase extract".to_string(),
+        ));
+    }
+    if config.phase == functions::MergePhase::Apply
+        && config.conflict_policy == functions::ConflictPolicy::PreferNewerSlot
+    {
+        return Err(MergeError::InvalidInput(
+            "--conflict-policy prefer-newer-slot needs the mainnet slot of each account, which \
+             copy sets don't record"
+                .to_string(),
+        ));
+    }
+    if config.phase == functions::MergePhase::Apply && !config.source_ledgers.is_empty() {
+        return Err(MergeError::InvalidInput(
+            "--source-ledger is scanned by the extract phase, not --phase apply".to_string(),
+        ));
+    }
+    if config.phase == functions::MergePhase::Apply
+        && config.expected_mainnet_snapshot_hash.is_some()
+    {
+        return Err(MergeError::InvalidInput(
+            "--expected-mainnet-snapshot-hash is checked by the extract phase, which loads \
+             mainnet, not --phase apply"
+                .to_string(),
+        ));
+    }
+    if config.phase == functions::MergePhase::Extract
+        && config.expected_base_snapshot_hash.is_some()
+    {
+        return Err(MergeError::InvalidInput(
+            "--expected-base-snapshot-hash is checked by the apply phase, which loads the merge \
+             ledger, not --phase extract"
+                .to_string(),
+        ));
+    }
+    for (flag, set) in [
+        ("--copy-diff-only", config.copy_diff_only),
+        (
+            "--keep-accounts-from-base-only",
+            config.keep_base_accounts_only,
+        ),
+    ] {
+        if set {
+            return Err(MergeError::InvalidInput(format!(
+                "{} compares mainnet against the merge ledger while scanning, so it needs a \
+                 single-phase merge rather than --phase extract or apply",
+                flag
+            )));
+        }
+    }
+    Ok(())
+}
+
+/// Receives the accounts `scan_mainnet` keeps, with their slots, whenever
+/// they reach `budget` approximate stored bytes and once more at the end
+struct CopyStream<'a> {
+    budget: u64,
+    store: &'a mut dyn FnMut(
+        HashMap<Pubkey, AccountSharedData>,
+        HashMap<Pubkey, Slot>,
+    ) -> Result<(), MergeError>,
+}
+
+/// Rejects the options a streamed copy can't honor: those that need every
+/// mainnet account to copy at once, and a two-phase merge, whose copy set
+/// holds them all anyway
+fn check_streaming_options(config: &MergeConfig) -> Result<(), MergeError> {
+    if config.copy_memory_budget == Some(0) {
+        return Err(MergeError::InvalidInput(
+            "--copy-memory-budget must be at least 1 byte".to_string(),
+        ));
+    }
+    if config.phase != functions::MergePhase::Full {
+        return Err(MergeError::InvalidInput(
+            "--copy-memory-budget streams mainnet straight into the merged bank, so it needs a \
+             single-phase merge rather than --phase extract or apply"
+                .to_string(),
+        ));
+    }
+    for (flag, set) in [
+        ("--source-ledger", !config.source_ledgers.is_empty()),
+        ("--copy-diff-only", config.copy_diff_only),
+        (
+            "--copy-shards (--copy-threads)",
+            config.copy_shards.is_some(),
+        ),
+        (
+            "--max-data-bytes-per-owner",
+            !config.max_data_bytes_per_owner.is_empty(),
+        ),
+        ("--dump-diff-against-base", config.base_diff_file.is_some()),
+        ("--conflict-report", config.conflict_report_file.is_some()),
+        ("--dry-run", config.dry_run),
+        ("--max-slots-for-copy", config.max_slots_for_copy.is_some()),
+    ] {
+        if set {
+            return Err(MergeError::InvalidInput(format!(
+                "{} needs every mainnet account to copy at once, so it can't be combined with \
+                 --copy-memory-budget",
+                flag
+            )));
+        }
+    }
+    Ok(())
+}
+
+/// Rejects the options whose outcome depends on the run rather than the
+/// inputs: an RSS-driven flush, and streamed chunks cut from an unsorted scan
+fn check_deterministic_options(config: &MergeConfig) -> Result<(), MergeError> {
+    if matches!(config.flush_policy, functions::FlushPolicy::Adaptive { .. }) {
+        return Err(MergeError::InvalidInput(
+            "--adaptive-flush flushes whenever the process RSS is high, so it can't be combined \
+             with --deterministic"
+                .to_string(),
+        ));
+    }
+    if config.copy_memory_budget.is_some()
+        && config.scan_consistency == functions::ScanConsistency::Latest
+    {
+        return Err(MergeError::InvalidInput(
+            "--copy-memory-budget with --scan-consistency latest streams accounts in index \
+             order, so it can't be combined with --deterministic"
+                .to_string(),
+        ));
+    }
+    Ok(())
+}
+
+/// Steps 4-5: extracts the mainnet accounts to copy, leaving out validators
+/// and whatever the mainnet-side filters exclude. `merge_bank` is only read
+/// by the filters that compare against the merge ledger. Returns the copy set
+/// and the number of accounts skipped for being outside the merge ledger.
+/// With a `stream` the kept accounts are handed to it instead, leaving the
+/// copy set with the counts only.
+fn scan_mainnet(
+    config: &MergeConfig,
+    mainnet_bank: &Bank,
+    merge_bank: Option<&Bank>,
+    subtract_pubkeys: &HashSet<Pubkey>,
+    report_progress: &dyn Fn(&str, usize, Slot),
+    mut stream: Option<CopyStream>,
+) -> Result<(functions::CopySet, usize), MergeError> {
+    let mainnet_total_accounts = functions::count_total_accounts(mainnet_bank)?;
+    info!(
+        "Mainnet bank loaded with {} total accounts",
+        mainnet_total_accounts
+    );
+
+    // Extract mainnet vote and stake accounts (to filter them out, or to
+    // audit the copied ones)
+    let keep_validators = config.validator_source.keeps_mainnet();
+    let validators_action = if keep_validators { "copy" } else { "exclude" };
+    info!(
+        "\n=== Step 4: Extracting Mainnet Validators (to {}) ===",
+        validators_action
+    );
+    let mainnet_vote_accounts: HashSet<Pubkey> =
+        functions::extract_vote_accounts(mainnet_bank, config.program_scan_fallback_threshold)?
+            .into_keys()
+            .collect();
+    let mainnet_stake_accounts: HashSet<Pubkey> =
+        functions::extract_stake_accounts(mainnet_bank, config.program_scan_fallback_threshold)?
+            .into_keys()
+            .collect();
+    info!(
+        "Found {} vote and {} stake accounts in mainnet to {}",
+        mainnet_vote_accounts.len(),
+        mainnet_stake_accounts.len(),
+        validators_action
+    );
+
+    // Get ALL mainnet accounts and filter out vote/stake
+    info!("\n=== Step 5: Extracting Mainnet Accounts (excluding validators) ===");
+    report_progress("extracting mainnet accounts", 0, mainnet_bank.slot());
+    if let Some(deadline) = &config.deadline {
+        deadline.check("before extracting mainnet accounts")?;
+    }
+    info!(
+        "Enumerating mainnet accounts with {:?} ({:?} order)",
+        config.account_enumeration, config.scan_consistency
+    );
+    let mut copy_set = functions::CopySet {
+        mainnet_slot: mainnet_bank.slot(),
+        mainnet_total_accounts,
+        ..functions::CopySet::default()
+    };
+    let record_slots = config.conflict_policy == functions::ConflictPolicy::PreferNewerSlot;
+    // Only `GetAll` can surface a pubkey more than once, and it holds every
+    // account in memory already, so a slot per pubkey costs little on top.
+    // A scan visits each pubkey once and is filtered as it goes. A streamed
+    // copy stores a newer version again over an older one an earlier chunk
+    // stored, and drops an older one surfacing later.
+    let mut newest_slots: Option<HashMap<Pubkey, Slot>> =
+        (config.account_enumeration == functions::AccountEnumeration::GetAll).then(HashMap::new);
+    let token_accounting = config.token_filter.is_active().then(|| {
+        let filter = config.token_filter.clone();
+        RefCell::new(if newest_slots.is_some() {
+            spl::TokenAccounting::replacing_versions(filter)
+        } else {
+            spl::TokenAccounting::new(filter)
+        })
+    });
+    let mut filters = copy_filters(
+        config,
+        merge_bank,
+        subtract_pubkeys,
+        (!keep_validators).then_some((&mainnet_vote_accounts, &mainnet_stake_accounts)),
+        token_accounting.as_ref(),
+    );
+    let builtin_filters = filters.len();
+    for custom_filter in &config.custom_filters {
+        filters.push(Arc::clone(custom_filter));
+    }
+    let mut buffered_bytes = 0;
+    let mut stream_error = None;
+    let mut scanned = functions::CopyProgress::new(mainnet_total_accounts);
+    let mut scanned_bytes = 0;
+    let mut progress_bar = config
+        .progress_bar
+        .then(|| functions::ProgressBar::new("scanning mainnet accounts"));
+
+    let mut visit = |pubkey: Pubkey, account: AccountSharedData, slot: Option<Slot>| {
+        if stream_error.is_some() {
+            return;
+        }
+        scanned.add(1);
+        scanned_bytes += functions::approx_stored_bytes(&account);
+        if let Some(progress_bar) = progress_bar.as_mut() {
+            progress_bar.update(&scanned, scanned_bytes);
+        }
+        if let (Some(newest_slots), Some(slot)) = (newest_slots.as_mut(), slot) {
+            if let Some(newest) = newest_slots.insert(pubkey, slot) {
+                copy_set.duplicate_pubkeys_resolved += 1;
+                if newest > slot {
+                    newest_slots.insert(pubkey, newest);
+                    return;
+                }
+                // The newer version decides, so an older one kept earlier
+                // goes even if the newer one is filtered out
+                copy_set.accounts.remove(&pubkey);
+                copy_set.account_slots.remove(&pubkey);
+                if let Some(token_accounting) = &token_accounting {
+                    token_accounting.borrow_mut().forget(&pubkey);
+                }
+            }
+        }
+        if !filters.keep(&pubkey, &account) {
+            return;
+        }
+        if let Some(slot) = slot.filter(|_| record_slots) {
+            copy_set.account_slots.insert(pubkey, slot);
+        }
+        buffered_bytes += functions::approx_stored_bytes(&account);
+        copy_set.accounts.insert(pubkey, account);
+        if let Some(stream) = stream
+            .as_mut()
+            .filter(|stream| buffered_bytes >= stream.budget)
+        {
+            buffered_bytes = 0;
+            if let Err(e) = (stream.store)(
+                std::mem::take(&mut copy_set.accounts),
+                std::mem::take(&mut copy_set.account_slots),
+            ) {
+                stream_error = Some(e);
+            }
+        }
+    };
+    match merge_bank.filter(|_| config.copy_diff_only) {
+        Some(merge_bank) => {
+            info!("Copying only accounts the merge ledger lacks or holds differently");
+            functions::accounts_only_in(
+                mainnet_bank,
+                merge_bank,
+                config.scan_consistency,
+                |pubkey, account| visit(pubkey, account, None),
+            )?;
+        }
+        None => {
+            functions::for_each_account_version(
+                mainnet_bank,
+                config.account_enumeration,
+                config.scan_consistency,
+                |pubkey, account, slot| visit(pubkey, account, Some(slot)),
+            )
+            .map_err(|e| {
+                MergeError::AccountScan(format!("Failed to enumerate mainnet accounts: {}", e))
+            })?;
+        }
+    }
+    if let Some(progress_bar) = progress_bar.as_mut() {
+        progress_bar.finish(&scanned, scanned_bytes);
+    }
+    if copy_set.duplicate_pubkeys_resolved > 0 {
+        warn!(
+            "Enumeration surfaced {} older duplicate account versions, kept the newest",
+            copy_set.duplicate_pubkeys_resolved
+        );
+    }
+    if let Some(stream) = stream {
+        if let Some(e) = stream_error {
+            return Err(e);
+        }
+        (stream.store)(
+            std::mem::take(&mut copy_set.accounts),
+            std::mem::take(&mut copy_set.account_slots),
+        )?;
+    }
+    copy_set.vote_accounts_excluded = filters.excluded_by(filter::VOTE_ACCOUNTS);
+    copy_set.stake_accounts_excluded = filters.excluded_by(filter::STAKE_ACCOUNTS);
+    copy_set.accounts_subtracted = filters.excluded_by(filter::SUBTRACT_LEDGER);
+    copy_set.excluded_listed_accounts = filters.excluded_by(filter::ACCOUNT_DENYLIST);
+    copy_set.outside_include_accounts = filters.excluded_by(filter::IncludePubkeys::NAME);
+    copy_set.feature_accounts_excluded = filters.excluded_by(filter::FeatureAccounts::NAME);
+    copy_set.lookup_tables_skipped = filters.excluded_by(filter::LookupTables::NAME);
+    copy_set.excluded_owner_accounts = filters.excluded_by(filter::ExcludeOwners::NAME);
+    copy_set.outside_copy_owners = filters.excluded_by(filter::IncludeOwners::NAME);
+    copy_set.zero_lamport_accounts_skipped = filters.excluded_by(filter::ZeroLamports::NAME);
+    copy_set.dust_accounts_excluded = filters.excluded_by(filter::MinLamports::NAME);
+    copy_set.oversized_accounts_excluded = filters.excluded_by(filter::MaxDataSize::NAME);
+    let outside_base_count = filters.excluded_by(filter::InBank::NAME);
+    for (name, excluded) in filters.exclusions().skip(builtin_filters) {
+        *copy_set
+            .custom_filter_exclusions
+            .entry(name.to_string())
+            .or_default() += excluded;
+    }
+    drop(filters);
+    copy_set.vote_accounts = mainnet_vote_accounts;
+    copy_set.stake_accounts = mainnet_stake_accounts;
+    copy_set.tokens = token_accounting.map(|accounting| accounting.into_inner().finish());
+    Ok((copy_set, outside_base_count))
+}
+
+/// The built-in filters of the mainnet copy, in the order they apply: the
+/// `validators` vote and stake accounts when they are left out, then the
+/// pubkey lists, feature accounts, sysvars, lookup tables, owner lists, the lamport and
+/// data size limits, the merge ledger lookup of
+/// `MergeConfig::keep_base_accounts_only` and last the `token_accounting`
+/// filter, so its supply tallies only cover accounts otherwise copied
+fn copy_filters<'a>(
+    config: &'a MergeConfig,
+    merge_bank: Option<&'a Bank>,
+    subtract_pubkeys: &'a HashSet<Pubkey>,
+    validators: Option<(&'a HashSet<Pubkey>, &'a HashSet<Pubkey>)>,
+    token_accounting: Option<&'a RefCell<spl::TokenAccounting>>,
+) -> filter::FilterChain<'a> {
+    let mut filters = filter::FilterChain::new();
+    if let Some((vote_accounts, stake_accounts)) = validators {
+        filters.push(filter::ExcludePubkeys {
+            name: filter::VOTE_ACCOUNTS,
+            pubkeys: vote_accounts,
+        });
+        filters.push(filter::ExcludePubkeys {
+            name: filter::STAKE_ACCOUNTS,
+            pubkeys: stake_accounts,
+        });
+    }
+    if !subtract_pubkeys.is_empty() {
+        filters.push(filter::ExcludePubkeys {
+            name: filter::SUBTRACT_LEDGER,
+            pubkeys: subtract_pubkeys,
+        });
+    }
+    if !config.exclude_accounts.is_empty() {
+        filters.push(filter::ExcludePubkeys {
+            name: filter::ACCOUNT_DENYLIST,
+            pubkeys: &config.exclude_accounts,
+        });
+    }
+    if let Some(include_accounts) = &config.include_accounts {
+        filters.push(filter::IncludePubkeys(include_accounts));
+    }
+    filters.push(filter::FeatureAccounts {
+        policy: config.feature_set,
+        feature_list: &config.feature_list,
+    });
+    filters.push(filter::RewrittenSysvars(&config.sysvar_policies));
+    if config.alt_policy == functions::AltPolicy::Skip {
+        filters.push(filter::LookupTables);
+    }
+    if !config.exclude_owners.is_empty() {
+        filters.push(filter::ExcludeOwners(&config.exclude_owners));
+    }
+    if let Some(copy_owners) = &config.copy_owners {
+        filters.push(filter::IncludeOwners(copy_owners));
+    }
+    if config.skip_zero_lamport_accounts {
+        filters.push(filter::ZeroLamports);
+    }
+    if let Some(min_lamports) = config.min_lamports {
+        filters.push(filter::MinLamports(min_lamports));
+    }
+    if let Some(max_data_size) = config.max_account_data_size {
+        filters.push(filter::MaxDataSize(max_data_size));
+    }
+    if let Some(merge_bank) = merge_bank.filter(|_| config.keep_base_accounts_only) {
+        filters.push(filter::InBank(merge_bank));
+    }
+    if let Some(token_accounting) = token_accounting {
+        filters.push(filter::TokenAccounts(token_accounting));
+    }
+    filters
+}
+
+/// The pubkeys of `MergeConfig::subtract_ledger`, left out of the copy
+fn load_subtract_pubkeys(config: &MergeConfig) -> Result<HashSet<Pubkey>, MergeError> {
+    match &config.subtract_ledger {
+        Some(subtract_ledger) => {
+            info!("Loading subtract ledger {:?}", subtract_ledger);
+            let subtract_genesis_config =
+                open_ledger_genesis_config("subtract ledger", subtract_ledger)?;
+            let subtract_scratch = load_scratch_dir(
+                subtract_ledger,
+                config.load_scratch_directory.as_deref(),
+                "subtract",
+            );
+            let _subtract_cleanup = ScratchCleanup::new(config, &subtract_scratch);
+            let subtract_bank = load_bank_from_snapshot(
+                subtract_ledger,
+                &subtract_genesis_config,
+                &config.accounts_index,
+                &subtract_scratch,
+            )?;
+            let pubkeys = functions::collect_pubkeys(&subtract_bank)?;
+            info!(
+                "Found {} accounts in the subtract ledger to exclude",
+                pubkeys.len()
+            );
+            Ok(pubkeys)
+        }
+        None => Ok(HashSet::new()),
+    }
+}
+
+/// Scans every `MergeConfig::source_ledgers` entry like the mainnet ledger and
+/// layers its accounts over `copy_set` in order, resolving pubkeys already in
+/// the copy set with `MergeConfig::source_conflict_policy`. Each bank is
+/// dropped once scanned. Returns the accounts skipped for being outside the
+/// merge ledger and one entry per layer.
+fn layer_source_ledgers(
+    config: &MergeConfig,
+    copy_set: &mut functions::CopySet,
+    merge_bank: Option<&Bank>,
+    subtract_pubkeys: &HashSet<Pubkey>,
+    report_progress: &dyn Fn(&str, usize, Slot),
+) -> Result<(usize, Vec<SourceLayer>), MergeError> {
+    let mut outside_base_count = 0;
+    let mut layers = Vec::with_capacity(config.source_ledgers.len());
+    let mut source_token_reports = Vec::new();
+    for (index, source_ledger) in config.source_ledgers.iter().enumerate() {
+        info!(
+            "\n=== Layering Source Ledger {} of {}: {:?} ===",
+            index + 1,
+            config.source_ledgers.len(),
+            source_ledger
+        );
+        let genesis_config = open_ledger_genesis_config("source ledger", source_ledger)?;
+        let scratch = load_scratch_dir(
+            source_ledger,
+            config.load_scratch_directory.as_deref(),
+            &format!("source-{}", index + 1),
+        );
+        let _cleanup = ScratchCleanup::new(config, &scratch);
+        report_progress("loading source ledger", 0, copy_set.mainnet_slot);
+        let bank = load_bank_from_snapshot(
+            source_ledger,
+            &genesis_config,
+            &config.accounts_index,
+            &scratch,
+        )?;
+        let (source, outside_base) = scan_mainnet(
+            config,
+            &bank,
+            merge_bank,
+            subtract_pubkeys,
+            report_progress,
+            None,
+        )?;
+        drop(bank);
+
+        let layer = SourceLayer {
+            ledger: source_ledger.clone(),
+            slot: source.mainnet_slot,
+            accounts: source.accounts.len(),
+            merge: functions::merge_account_maps(
+                &mut copy_set.accounts,
+                source.accounts,
+                config.source_conflict_policy,
+            )
+            .map_err(|e| e.context(format!("Source ledger {:?}", source_ledger)))?,
+        };
+        info!(
+            "Layered {} accounts from slot {}: {} new, {} conflicts resolved with {:?} ({} unchanged)",
+            layer.accounts,
+            layer.slot,
+            layer.merge.inserted,
+            layer.merge.collisions,
+            config.source_conflict_policy,
+            layer.merge.unchanged
+        );
+        // A layered account was last written no earlier than either side
+        for (pubkey, slot) in source.account_slots {
+            let newest = copy_set.account_slots.entry(pubkey).or_insert(slot);
+            *newest = (*newest).max(slot);
+        }
+        copy_set.vote_accounts.extend(source.vote_accounts);
+        copy_set.stake_accounts.extend(source.stake_accounts);
+        copy_set.vote_accounts_excluded += source.vote_accounts_excluded;
+        copy_set.stake_accounts_excluded += source.stake_accounts_excluded;
+        copy_set.feature_accounts_excluded += source.feature_accounts_excluded;
+        copy_set.lookup_tables_skipped += source.lookup_tables_skipped;
+        copy_set.accounts_subtracted += source.accounts_subtracted;
+        copy_set.outside_copy_owners += source.outside_copy_owners;
+        copy_set.excluded_owner_accounts += source.excluded_owner_accounts;
+        copy_set.excluded_listed_accounts += source.excluded_listed_accounts;
+        copy_set.outside_include_accounts += source.outside_include_accounts;
+        copy_set.dust_accounts_excluded += source.dust_accounts_excluded;
+        copy_set.zero_lamport_accounts_skipped += source.zero_lamport_accounts_skipped;
+        copy_set.oversized_accounts_excluded += source.oversized_accounts_excluded;
+        for (name, excluded) in source.custom_filter_exclusions {
+            *copy_set.custom_filter_exclusions.entry(name).or_default() += excluded;
+        }
+        copy_set.duplicate_pubkeys_resolved += source.duplicate_pubkeys_resolved;
+        source_token_reports.extend(source.tokens);
+        outside_base_count += outside_base;
+        layers.push(layer);
+    }
+    // Counted over the layered accounts, so an account several ledgers kept
+    // counts once
+    if !source_token_reports.is_empty() {
+        let reports = copy_set
+            .tokens
+            .take()
+            .into_iter()
+            .chain(source_token_reports);
+        copy_set.tokens = Some(spl::TokenReport::layered(reports, &copy_set.accounts));
+    }
+    Ok((outside_base_count, layers))
+}
+
+/// The extract phase of a two-phase merge: scans the mainnet ledger and
+/// writes the copy set to `MergeConfig::copy_set_path` for a later apply
+fn extract_copy_set(
+    config: &MergeConfig,
+    mainnet_genesis_config: &GenesisConfig,
+    report_progress: &dyn Fn(&str, usize, Slot),
+) -> Result<MergeStats, MergeError> {
+    let mainnet_scratch = load_scratch_dir(
+        &config.mainnet_ledger,
+        config.load_scratch_directory.as_deref(),
+        "mainnet",
+    );
+    let _mainnet_cleanup = ScratchCleanup::new(config, &mainnet_scratch);
+    let load_started = Instant::now();
+    info!("\n=== Step 2: Loading Mainnet Snapshot ===");
+    report_progress("loading mainnet snapshot", 0, 0);
+    let mainnet_bank = load_mainnet_bank(config, mainnet_genesis_config, &mainnet_scratch)?;
+    let load_time = load_started.elapsed();
+
+    let subtract_pubkeys = load_subtract_pubkeys(config)?;
+    let (mut copy_set, _) = scan_mainnet(
+        config,
+        &mainnet_bank,
+        None,
+        &subtract_pubkeys,
+        report_progress,
+        None,
+    )?;
+    drop(mainnet_bank);
+    let (_, source_layers) = layer_source_ledgers(
+        config,
+        &mut copy_set,
+        None,
+        &subtract_pubkeys,
+        report_progress,
+    )?;
+
+    let copy_set_path = config.copy_set_path();
+    info!("\n=== Writing Copy Set {:?} ===", copy_set_path);
+    report_progress(
+        "writing copy set",
+        copy_set.accounts.len(),
+        copy_set.mainnet_slot,
+    );
+    std::fs::create_dir_all(&config.output_directory).map_err(|e| {
+        MergeError::Io(format!(
+            "Failed to create output directory {:?}: {:?}",
+            config.output_directory, e
+        ))
+    })?;
+    let written = functions::write_copy_set(&copy_set, &copy_set_path)?;
+    info!(
+        "Wrote {} mainnet accounts from slot {} to {:?}",
+        written, copy_set.mainnet_slot, copy_set_path
+    );
+    Ok(MergeStats {
+        mainnet_total_accounts: copy_set.mainnet_total_accounts,
+        mainnet_vote_accounts_excluded: copy_set.vote_accounts_excluded,
+        mainnet_stake_accounts_excluded: copy_set.stake_accounts_excluded,
+        mainnet_feature_accounts_excluded: copy_set.feature_accounts_excluded,
+        mainnet_accounts_copied: written,
+        mainnet_lookup_tables_skipped: copy_set.lookup_tables_skipped,
+        accounts_subtracted: copy_set.accounts_subtracted,
+        accounts_excluded_by_owner: copy_set.excluded_owner_accounts,
+        tokens: copy_set.tokens,
+        accounts_excluded_by_list: copy_set.excluded_listed_accounts,
+        accounts_outside_include_list: copy_set.outside_include_accounts,
+        dust_accounts_excluded: copy_set.dust_accounts_excluded,
+        zero_lamport_accounts_skipped: copy_set.zero_lamport_accounts_skipped,
+        oversized_accounts_excluded: copy_set.oversized_accounts_excluded,
+        custom_filter_exclusions: copy_set.custom_filter_exclusions,
+        duplicate_pubkeys_resolved: copy_set.duplicate_pubkeys_resolved,
+        source_layers,
+        load_time,
+        ..MergeStats::default()
+    })
+}
+
+/// Outcome of the rewrites and skips applied to the accounts to copy once the
+/// merged bank exists
+#[derive(Debug, Default)]
+struct CopyPreparation {
+    accounts_skipped_dormant: usize,
+    owner_remaps: Vec<functions::OwnerRemapReport>,
+    upgrade_authorities_overridden: Vec<Pubkey>,
+    mainnet_nonce_accounts: usize,
+    mainnet_accounts_field_merged: usize,
+    base_conflicts: Option<functions::ConflictReport>,
+    mainnet_lookup_tables_refreshed: usize,
+    mainnet_lookup_tables_malformed: usize,
+    rent_epochs_normalized: usize,
+    token_balances_reconstructed: usize,
+    account_transforms: Vec<transform::TransformReport>,
+    invalid_accounts_skipped: usize,
+    accounts_skipped_identical: usize,
+}
+
+impl CopyPreparation {
+    /// Adds the outcome of preparing another chunk of the same copy
+    fn absorb(&mut self, other: Self) {
+        self.accounts_skipped_dormant += other.accounts_skipped_dormant;
+        // Every chunk reports every rule, in the same order
+        if self.owner_remaps.is_empty() {
+            self.owner_remaps = other.owner_remaps;
+        } else {
+            for (report, other) in self.owner_remaps.iter_mut().zip(other.owner_remaps) {
+                report.accounts_remapped += other.accounts_remapped;
+            }
+        }
+        self.upgrade_authorities_overridden
+            .extend(other.upgrade_authorities_overridden);
+        self.upgrade_authorities_overridden.sort_unstable();
+        self.mainnet_nonce_accounts += other.mainnet_nonce_accounts;
+        self.mainnet_accounts_field_merged += other.mainnet_accounts_field_merged;
+        if let Some(other) = other.base_conflicts {
+            let report = self.base_conflicts.get_or_insert_with(Default::default);
+            report.conflicts.extend(other.conflicts);
+            report.conflicts.sort_unstable();
+            report.kept_base += other.kept_base;
+        }
+        self.mainnet_lookup_tables_refreshed += other.mainnet_lookup_tables_refreshed;
+        self.mainnet_lookup_tables_malformed += other.mainnet_lookup_tables_malformed;
+        self.rent_epochs_normalized += other.rent_epochs_normalized;
+        self.token_balances_reconstructed += other.token_balances_reconstructed;
+        if self.account_transforms.is_empty() {
+            self.account_transforms = other.account_transforms;
+        } else {
+            for (report, other) in self
+                .account_transforms
+                .iter_mut()
+                .zip(other.account_transforms)
+            {
+                report.accounts_changed += other.accounts_changed;
+            }
+        }
+        self.invalid_accounts_skipped += other.invalid_accounts_skipped;
+        self.accounts_skipped_identical += other.accounts_skipped_identical;
+    }
+}
+
+/// Rewrites `accounts` for the merged bank and drops the ones it shouldn't
+/// receive, as configured. `slots` holds the mainnet slot of the accounts
+/// for `functions::ConflictPolicy::PreferNewerSlot`.
+fn prepare_copy_accounts(
+    config: &MergeConfig,
+    merged_bank: &Bank,
+    accounts: &mut HashMap<Pubkey, AccountSharedData>,
+    slots: &HashMap<Pubkey, Slot>,
+) -> Result<CopyPreparation, MergeError> {
+    let accounts_skipped_dormant = match config.max_account_age_epochs {
+        Some(max_age_epochs) => {
+            functions::remove_dormant_accounts(accounts, merged_bank.epoch(), max_age_epochs)
+        }
+        None => 0,
+    };
+
+    let owner_remaps = functions::remap_owners(accounts, &config.owner_remaps);
+
+    let upgrade_authorities_overridden = match &config.upgrade_authority_override {
+        Some(authority_override) => {
+            functions::override_upgrade_authorities(accounts, authority_override)?
+        }
+        None => Vec::new(),
+    };
+
+    let (blockhash, lamports_per_signature) =
+        merged_bank.last_blockhash_and_lamports_per_signature();
+    let mainnet_nonce_accounts = functions::apply_nonce_policy(
+        accounts,
+        config.nonce_policy,
+        &blockhash,
+        lamports_per_signature,
+    )?;
+
+    let mainnet_accounts_field_merged =
+        functions::apply_field_merge_policy(merged_bank, accounts, &config.field_merge_policy);
+
+    let base_conflicts = if config.conflict_policy != functions::ConflictPolicy::Overwrite
+        || config.conflict_report_file.is_some()
+    {
+        Some(functions::resolve_base_conflicts(
+            merged_bank,
+            accounts,
+            slots,
+            config.conflict_policy,
+        )?)
+    } else {
+        None
+    };
+
+    let lookup_tables = if config.alt_policy == functions::AltPolicy::Refresh {
+        functions::refresh_lookup_tables(accounts, merged_bank.slot())
+    } else {
+        functions::LookupTableRefresh::default()
+    };
+
+    let rent_epochs_normalized = match config.normalize_rent_epoch {
+        Some(target) => functions::normalize_rent_epochs(merged_bank, accounts, target),
+        None => 0,
+    };
+
+    let token_balances_reconstructed = match &config.token_balance_authority {
+        Some(authority) => functions::reconstruct_token_balances(accounts, authority),
+        None => 0,
+    };
+
+    let account_transforms = transform::apply_transforms(accounts, &config.account_transforms);
+
+    let invalid_accounts_skipped = if config.validate_accounts {
+        functions::remove_invalid_accounts(accounts, config.strict_validation)?
+    } else {
+        0
+    };
+
+    let accounts_skipped_identical = if config.skip_unchanged {
+        functions::remove_unchanged_accounts(merged_bank, accounts)
+    } else {
+        0
+    };
+
+    Ok(CopyPreparation {
+        accounts_skipped_dormant,
+        owner_remaps,
+        upgrade_authorities_overridden,
+        mainnet_nonce_accounts,
+        mainnet_accounts_field_merged,
+        base_conflicts,
+        mainnet_lookup_tables_refreshed: lookup_tables.refreshed,
+        mainnet_lookup_tables_malformed: lookup_tables.malformed,
+        rent_epochs_normalized,
+        token_balances_reconstructed,
+        account_transforms,
+        invalid_accounts_skipped,
+        accounts_skipped_identical,
+    })
+}
+
+/// The merged bank before any mainnet account is copied into it
+struct MergedBase {
+    /// The merge ledger's system accounts, re-applied after the copy
+    system_accounts: HashMap<Pubkey, AccountSharedData>,
+    bank: Arc<Bank>,
+    capitalization_before: u64,
+    stakes_pruned: Option<functions::StakePruneReport>,
+    merge_validators_removed: Option<functions::ValidatorRemovalReport>,
+    merge_feature_accounts_removed: usize,
+}
+
+/// Steps 6-7: extracts the merge ledger's system accounts and creates the
+/// merged bank as a child of `merge_bank`, pruning its deactivated stakes if
+/// configured
+fn create_merged_bank(
+    config: &MergeConfig,
+    merge_bank: &Arc<Bank>,
+    merge_genesis_config: &GenesisConfig,
+    merge_scratch: &Path,
+) -> Result<MergedBase, MergeError> {
+    // Extract system accounts from merge ledger (to preserve them)
+    info!("\n=== Step 6: Extracting System Accounts from Merge Ledger ===");
+    let system_accounts =
+        functions::extract_system_accounts(merge_bank, config.program_scan_fallback_threshold)?;
+    info!(
+        "Found {} system accounts in merge ledger to preserve",
+        system_accounts.len()
+    );
+
+    // Create child bank from merge ledger (this keeps merge ledger genesis and validators)
+    info!("\n=== Step 7: Creating Child Bank from Merge Ledger ===");
+    let parent_bank = if config.rebuild_from_genesis {
+        info!("Rebuilding the merge ledger bank from its genesis...");
+        if let Some(deadline) = &config.deadline {
+            deadline.check("before rebuilding the merge ledger bank")?;
+        }
+        // The PoH overrides only hold for a bank created from them
+        let mut genesis_config = merge_genesis_config.clone();
+        functions::apply_poh_overrides(
+            &mut genesis_config,
+            config.hashes_per_tick,
+            config.ticks_per_slot,
+        )?;
+        let rebuilt = functions::rebuild_bank_from_genesis(
+            merge_bank,
+            &genesis_config,
+            AccountsDbConfig {
+                index: Some(config.accounts_index.clone()),
+                ..AccountsDbConfig::default()
+            },
+            &merge_scratch.join("rebuilt-accounts"),
+            config.flush_policy,
+        )?;
+        info!(
+            "Rebuilt bank at slot {} with hash {}",
+            rebuilt.slot(),
+            rebuilt.hash()
+        );
+        rebuilt
+    } else {
+        merge_bank.clone()
+    };
+    let bank = Arc::new(Bank::new_from_parent(
+        parent_bank,
+        merge_bank.collector_id(),
+        merge_bank.slot() + 1,
+    ));
+    info!("Created child bank at slot {}", bank.slot());
+    info!(
+        "Merged bank genesis creation time: {} (should match merge ledger: {})",
+        bank.genesis_creation_time(),
+        merge_genesis_config.creation_time
+    );
+
+    let capitalization_before = bank.capitalization();
+
+    // Capitalization is recalculated in Step 10, which accounts for the
+    // reclaimed lamports
+    let stakes_pruned = if config.prune_deactivated_stakes {
+        Some(functions::prune_deactivated_stakes(
+            &bank,
+            config.program_scan_fallback_threshold,
+        )?)
+    } else {
+        None
+    };
+    // Before the copy, so mainnet validators at the same addresses survive
+    let merge_validators_removed = if config.validator_source.keeps_merge_ledger() {
+        None
+    } else {
+        info!(
+            "Removing the merge ledger's validators (--validator-source {:?})",
+            config.validator_source
+        );
+        if !config.regenerate_epoch_stakes {
+            warn!(
+                "The merged bank's epoch stakes and leader schedule still name the merge \
+                 ledger's validators until they are recomputed at an epoch boundary; see \
+                 --regenerate-epoch-stakes"
+            );
+        }
+        Some(functions::remove_validator_accounts(
+            &bank,
+            config.program_scan_fallback_threshold,
+        )?)
+    };
+    // Before the copy too, so the mainnet features stored over them stay
+    let merge_feature_accounts_removed =
+        if config.feature_set == functions::FeatureSetPolicy::Mainnet {
+            info!("Removing the merge ledger's feature accounts (--feature-set mainnet)");
+            functions::remove_feature_accounts(&bank, config.program_scan_fallback_threshold)?
+        } else {
+            0
+        };
+    Ok(MergedBase {
+        system_accounts,
+        bank,
+        capitalization_before,
+        stakes_pruned,
+        merge_validators_removed,
+        merge_feature_accounts_removed,
+    })
+}
+
+/// The merge ledger's feature accounts, which `FeatureSetPolicy::Union`
+/// prefers over mainnet's once activated; empty for the other policies
+fn union_merge_features(
+    config: &MergeConfig,
+    merge_bank: &Bank,
+) -> Result<HashMap<Pubkey, AccountSharedData>, MergeError> {
+    if config.feature_set != functions::FeatureSetPolicy::Union {
+        return Ok(HashMap::new());
+    }
+    functions::extract_feature_accounts(merge_bank, config.program_scan_fallback_threshold)
+}
+
+/// What a streamed copy stored, summed over its chunks
+#[derive(Debug, Default)]
+struct StreamedCopy {
+    chunks: usize,
+    /// Accounts the scan kept, before each chunk was prepared
+    accounts_kept: usize,
+    accounts_copied: usize,
+    preparation: CopyPreparation,
+    features: functions::FeatureAccountAudit,
+    epoch_rewards_active: bool,
+    top_accounts: Vec<functions::TopAccount>,
+    slot_sizes: Vec<usize>,
+    estimated_archive_bytes: u64,
+    /// Headers of the stored upgradeable-loader accounts, paired up once
+    /// every chunk is stored
+    upgradeable_headers: HashMap<Pubkey, AccountSharedData>,
+    unpaired_programs: functions::ProgramPairingReport,
+}
+
+/// Steps 4-5 and 8 with `MergeConfig::copy_memory_budget` set: scans mainnet
+/// in chunks of about that many stored bytes, preparing and storing each into
+/// `merged_bank` before scanning on, so only one chunk is held at a time.
+/// Returns the copy set, which holds no accounts, the accounts skipped for
+/// being outside the merge ledger and the copy totals.
+#[allow(clippy::too_many_arguments)]
+fn stream_mainnet_copy(
+    config: &MergeConfig,
+    mainnet_bank: &Bank,
+    merge_bank: &Bank,
+    merged_bank: &mut Arc<Bank>,
+    budget: u64,
+    subtract_pubkeys: &HashSet<Pubkey>,
+    report_progress: &dyn Fn(&str, usize, Slot),
+    reporting: functions::CopyReporting<'_>,
+) -> Result<(functions::CopySet, usize, StreamedCopy), MergeError> {
+    info!(
+        "Streaming mainnet accounts into the merged bank in chunks of up to {} bytes",
+        budget
+    );
+    // Chunks hold distinct pubkeys, so only the merged bank's accounts from
+    // before the copy can be replaced
+    let replaced_data_sizes = functions::account_data_sizes(merged_bank)?;
+    let mut streamed = StreamedCopy::default();
+    let merge_features = union_merge_features(config, merge_bank)?;
+    let mut store = |mut accounts: HashMap<Pubkey, AccountSharedData>,
+                     slots: HashMap<Pubkey, Slot>| {
+        streamed.chunks += 1;
+        streamed.accounts_kept += accounts.len();
+        streamed.epoch_rewards_active |=
+            functions::apply_epoch_rewards_policy(&mut accounts, config.epoch_rewards_policy)?;
+        if config.feature_set != functions::FeatureSetPolicy::MergeLedger {
+            let kept_merge_ledger = functions::filter_feature_accounts(
+                &mut accounts,
+                config.feature_set,
+                &merge_features,
+            );
+            let rebased =
+                functions::rebase_feature_activations(&mut accounts, merge_bank.slot() + 1);
+            let audit = functions::audit_feature_accounts(&accounts);
+            streamed.features.feature_accounts += audit.feature_accounts;
+            streamed.features.activated += audit.activated;
+            streamed.features.unknown.extend(audit.unknown);
+            streamed.features.kept_merge_ledger += kept_merge_ledger;
+            streamed.features.rebased += rebased;
+        }
+        streamed.preparation.absorb(prepare_copy_accounts(
+            config,
+            merged_bank,
+            &mut accounts,
+            &slots,
+        )?);
+        streamed.accounts_copied += accounts.len();
+        streamed.top_accounts = functions::merge_top_accounts(
+            std::mem::take(&mut streamed.top_accounts),
+            functions::top_accounts_by_lamports(&accounts, config.report_top_accounts),
+            config.report_top_accounts,
+        );
+        streamed.slot_sizes.extend(functions::plan_slot_sizes(
+            accounts.values(),
+            config.slot_split,
+        ));
+        streamed.estimated_archive_bytes +=
+            functions::estimate_archive_bytes(accounts.values(), config.archive_compression_ratio);
+        streamed.upgradeable_headers.extend(
+            accounts
+                .iter()
+                .filter(|(_, account)| account.owner() == &bpf_loader_upgradeable::id())
+                .map(|(pubkey, account)| (*pubkey, functions::upgradeable_loader_header(account))),
+        );
+        info!(
+            "Storing chunk {} of the mainnet copy ({} accounts, {} so far)",
+            streamed.chunks,
+            accounts.len(),
+            streamed.accounts_copied
+        );
+        *merged_bank = functions::add_accounts(
+            Arc::clone(merged_bank),
+            &accounts,
+            &replaced_data_sizes,
+            "mainnet",
+            config.slot_split,
+            config.flush_policy,
+            config.copy_order(),
+            config.deadline.as_ref(),
+            reporting,
+        )?;
+        Ok(())
+    };
+    let (copy_set, outside_base_count) = scan_mainnet(
+        config,
+        mainnet_bank,
+        Some(merge_bank),
+        subtract_pubkeys,
+        report_progress,
+        Some(CopyStream {
+            budget,
+            store: &mut store,
+        }),
+    )?;
+    streamed.features.unknown.sort_unstable();
+    // A program and its ProgramData can land in different chunks, so they are
+    // paired up once every chunk is stored, setting each unpaired half back
+    // to the merge ledger's account
+    let unpaired = functions::find_unpaired_programs(
+        &std::mem::take(&mut streamed.upgradeable_headers),
+        merged_bank,
+        config.program_scan_fallback_threshold,
+    )?;
+    for pubkey in unpaired.pubkeys() {
+        let account = merge_bank
+            .get_account(pubkey)
+            .unwrap_or_else(|| AccountSharedData::new(0, 0, &bpf_loader_upgradeable::id()));
+        functions::store_account_tracking_data_size(merged_bank, pubkey, &account);
+        streamed.accounts_copied -= 1;
+    }
+    streamed.unpaired_programs = unpaired;
+    info!(
+        "Streamed {} mainnet accounts into the merged bank in {} chunks",
+        streamed.accounts_copied, streamed.chunks
+    );
+    Ok((copy_set, outside_base_count, streamed))
+}
+
+fn run_merge(
+    config: &MergeConfig,
+    post_merge_hook: Option<PostMergeHook>,
+) -> Result<MergeStats, MergeError> {
+    let mainnet_ledger = config.mainnet_ledger.as_path();
+    let ledger_to_merge = config.ledger_to_merge.as_path();
+    let output_snapshot_dir = config.output_directory.as_path();
+    let warp_slot = config.warp_slot;
+    if let Some(e) = check_options(config).into_iter().next() {
+        return Err(e);
+    }
+    let progress_file = config
+        .progress_file
+        .clone()
+        .map(functions::ProgressFile::new);
+    let report_progress = |phase: &str, accounts_processed: usize, slot: Slot| {
+        if let Some(progress_file) = &progress_file {
+            progress_file.report(phase, accounts_processed, slot);
+        }
+    };
+    let check_deadline = |phase: &str| match &config.deadline {
+        Some(deadline) => deadline.check(&format!("before {}", phase)),
+        None => Ok(()),
+    };
+    let throughput_log = config
+        .throughput_log
+        .clone()
+        .map(|path| functions::ThroughputLog::open(path, config.throughput_interval))
+        .transpose()?;
+    let reporting = functions::CopyReporting {
+        progress_file: progress_file.as_ref(),
+        throughput_log: throughput_log.as_ref(),
+        progress_bar: config.progress_bar,
+        base_diff: None,
+    };
+    // Written as the mainnet accounts are stored; the accounts stored after
+    // them aren't part of the diff
+    let base_diff = config
+        .base_diff_file
+        .clone()
+        .filter(|_| !config.dry_run)
+        .map(functions::BaseDiffWriter::create)
+        .transpose()?;
+    let mainnet_reporting = functions::CopyReporting {
+        base_diff: base_diff.as_ref(),
+        ..reporting
+    };
+
+    info!("=== Starting Snapshot Merge ===");
+    info!("Mainnet ledger: {:?}", mainnet_ledger);
+    if let Some(archive) = &config.mainnet_snapshot {
+        info!("Mainnet snapshot: {:?}", archive);
+    }
+    info!("Ledger to merge: {:?}", ledger_to_merge);
+    info!("Output directory: {:?}", output_snapshot_dir);
+    info!(
+        "Accounts index: memory limit {:?}, bins {:?}, disk index paths {:?}",
+        config.accounts_index.index_limit_mb,
+        config.accounts_index.bins,
+        config.accounts_index.drives
+    );
+
+    if !config.clone_accounts.is_empty() && config.phase != functions::MergePhase::Extract {
+        check_clone_accounts(config)?;
+    }
+    let resumed = if config.resume {
+        resume_checkpoint(config)?
+    } else {
+        None
+    };
+    // Checked with the options above
+    let incremental_base_slot = if config.phase != functions::MergePhase::Extract
+        && config.snapshot_type.writes_incremental()
+    {
+        Some(preflight_ledger("Ledger to merge", ledger_to_merge)?)
+    } else {
+        None
+    };
+    let load_scratch_directory = config.load_scratch_directory.as_deref();
+    let merge_scratch = load_scratch_dir(ledger_to_merge, load_scratch_directory, "merge");
+    let open_merge_genesis_config =
+        || open_ledger_genesis_config("ledger to merge", ledger_to_merge);
+    if config.phase == functions::MergePhase::Extract {
+        info!("\n=== Step 1: Loading Mainnet Genesis Config ===");
+        let mainnet_genesis_config = open_mainnet_genesis_config(config)?;
+        log_genesis_config("Mainnet", &mainnet_genesis_config);
+        return extract_copy_set(config, &mainnet_genesis_config, &report_progress);
+    }
+
+    // Kept until the end: the merged bank stores its accounts in this load
+    let _merge_cleanup = ScratchCleanup::new(config, &merge_scratch);
+    let load_merge = |merge_genesis_config: &GenesisConfig| {
+        let bank = load_bank_from_snapshot(
+            ledger_to_merge,
+            merge_genesis_config,
+            &config.accounts_index,
+            &merge_scratch,
+        )?;
+        if let Some(expected) = &config.expected_base_snapshot_hash {
+            functions::verify_snapshot_hash(&bank, expected, "Merge ledger")?;
+            info!(
+                "✓ Merge ledger bank has the expected snapshot hash {}",
+                expected
+            );
+        }
+        Ok::<_, MergeError>(bank)
+    };
+    let load_started = Instant::now();
+    let (
+        copy_set,
+        outside_base_count,
+        source_layers,
+        merge_genesis_config,
+        merge_bank,
+        merged_base,
+        streamed,
+        checkpoint,
+    ) = if config.phase == functions::MergePhase::Apply || resumed.is_some() {
+        info!("\n=== Step 1: Loading Merge Ledger Genesis Config ===");
+        let merge_genesis_config = open_merge_genesis_config()?;
+        let copy_set_path = match &resumed {
+            Some(checkpoint) => checkpoint.copy_set.clone(),
+            None => config.copy_set_path(),
+        };
+        info!("\n=== Step 2: Reading Copy Set {:?} ===", copy_set_path);
+        report_progress("reading copy set", 0, 0);
+        let copy_set = functions::read_copy_set(&copy_set_path)?;
+        info!(
+            "Read {} mainnet accounts extracted at slot {}",
+            copy_set.accounts.len(),
+            copy_set.mainnet_slot
+        );
+
+        info!("\n=== Step 3: Loading Ledger to Merge ===");
+        report_progress("loading ledger to merge", 0, copy_set.mainnet_slot);
+        check_deadline("loading ledger to merge")?;
+        let merge_bank = load_merge(&merge_genesis_config)?;
+        let (outside_base_count, source_layers) = match &resumed {
+            Some(checkpoint) => (
+                checkpoint.outside_base_count,
+                checkpoint.source_layers.clone(),
+            ),
+            None => (0, Vec::new()),
+        };
+        (
+            copy_set,
+            outside_base_count,
+            source_layers,
+            merge_genesis_config,
+            merge_bank,
+            None,
+            None,
+            resumed,
+        )
+    } else {
+        // Load genesis configs
+        info!("\n=== Step 1: Loading Genesis Configs ===");
+        let mainnet_genesis_config = open_mainnet_genesis_config(config)?;
+        let merge_genesis_config = open_merge_genesis_config()?;
+        info!("Loaded both genesis configs successfully");
+
+        let mainnet_scratch = load_scratch_dir(mainnet_ledger, load_scratch_directory, "mainnet");
+        let mainnet_cleanup = ScratchCleanup::new(config, &mainnet_scratch);
+        let load_mainnet = || load_mainnet_bank(config, &mainnet_genesis_config, &mainnet_scratch);
+        let (mainnet_bank, merge_bank) = if config.parallel_load {
+            info!("\n=== Steps 2-3: Loading Mainnet Snapshot and Ledger to Merge in Parallel ===");
+            report_progress("loading snapshots in parallel", 0, 0);
+            warn_if_parallel_load_exceeds_memory([mainnet_ledger, ledger_to_merge]);
+            let (mainnet_bank, merge_bank) = std::thread::scope(|scope| {
+                let mainnet = scope.spawn(load_mainnet);
+                let merge_bank = load_merge(&merge_genesis_config);
+                let mainnet_bank = mainnet.join().unwrap_or_else(|_| {
+                    Err(MergeError::SnapshotLoad(
+                        "Loading the mainnet snapshot panicked".to_string(),
+                    ))
+                });
+                (mainnet_bank, merge_bank)
+            });
+            (mainnet_bank?, merge_bank?)
+        } else {
+            // Load mainnet snapshot
+            info!("\n=== Step 2: Loading Mainnet Snapshot ===");
+            report_progress("loading mainnet snapshot", 0, 0);
+            let mainnet_bank = load_mainnet()?;
+
+            // Load merge ledger snapshot (this will be our base)
+            info!("\n=== Step 3: Loading Ledger to Merge ===");
+            report_progress("loading ledger to merge", 0, mainnet_bank.slot());
+            check_deadline("loading ledger to merge")?;
+            (mainnet_bank, load_merge(&merge_genesis_config)?)
+        };
+        info!(
+            "Loaded both banks in {:.1}s ({})",
+            load_started.elapsed().as_secs_f64(),
+            if config.parallel_load {
+                "in parallel"
+            } else {
+                "sequentially"
+            }
+        );
+        log_genesis_config("Mainnet", &mainnet_genesis_config);
+        info!(
+            "Mainnet bank genesis creation time: {}",
+            mainnet_bank.genesis_creation_time()
+        );
+
+        let subtract_pubkeys = load_subtract_pubkeys(config)?;
+        if let Some(budget) = config.copy_memory_budget {
+            // The accounts are stored as they're scanned, so the merged bank
+            // has to exist first
+            let mut merged_base =
+                create_merged_bank(config, &merge_bank, &merge_genesis_config, &merge_scratch)?;
+            let (copy_set, outside_base_count, streamed) = stream_mainnet_copy(
+                config,
+                &mainnet_bank,
+                &merge_bank,
+                &mut merged_base.bank,
+                budget,
+                &subtract_pubkeys,
+                &report_progress,
+                mainnet_reporting,
+            )?;
+            drop(mainnet_bank);
+            drop(mainnet_cleanup);
+            (
+                copy_set,
+                outside_base_count,
+                Vec::new(),
+                merge_genesis_config,
+                merge_bank,
+                Some(merged_base),
+                Some(streamed),
+                None,
+            )
+        } else {
+            let (mut copy_set, mut outside_base_count) = scan_mainnet(
+                config,
+                &mainnet_bank,
+                Some(&merge_bank),
+                &subtract_pubkeys,
+                &report_progress,
+                None,
+            )?;
+            // Everything needed from mainnet has been copied out
+            drop(mainnet_bank);
+            drop(mainnet_cleanup);
+            let (source_outside_base_count, source_layers) = layer_source_ledgers(
+                config,
+                &mut copy_set,
+                Some(&merge_bank),
+                &subtract_pubkeys,
+                &report_progress,
+            )?;
+            outside_base_count += source_outside_base_count;
+            let checkpoint = if config.checkpoint {
+                Some(write_checkpoint(
+                    config,
+                    &copy_set,
+                    outside_base_count,
+                    &source_layers,
+                )?)
+            } else {
+                None
+            };
+            (
+                copy_set,
+                outside_base_count,
+                source_layers,
+                merge_genesis_config,
+                merge_bank,
+                None,
+                None,
+                checkpoint,
+            )
+        }
+    };
+    let load_time = load_started.elapsed();
+
+    let merge_total_accounts = functions::count_total_accounts(&merge_bank)?;
+    info!(
+        "Merge ledger loaded with {} total accounts",
+        merge_total_accounts
+    );
+    info!(
+        "Merge bank genesis creation time: {}",
+        merge_bank.genesis_creation_time()
+    );
+    log_genesis_config("Merge", &merge_genesis_config);
+
+    let functions::CopySet {
+        mainnet_slot,
+        mainnet_total_accounts,
+        vote_accounts: mainnet_vote_accounts,
+        stake_accounts: mainnet_stake_accounts,
+        vote_accounts_excluded: filtered_vote_count,
+        stake_accounts_excluded: filtered_stake_count,
+        feature_accounts_excluded: filtered_feature_count,
+        lookup_tables_skipped: skipped_lookup_table_count,
+        accounts_subtracted: subtracted_count,
+        outside_copy_owners: outside_copy_owners_count,
+        excluded_owner_accounts: excluded_owner_count,
+        excluded_listed_accounts: excluded_listed_count,
+        outside_include_accounts: outside_include_count,
+        dust_accounts_excluded,
+        zero_lamport_accounts_skipped,
+        oversized_accounts_excluded,
+        duplicate_pubkeys_resolved,
+        accounts: mut mainnet_accounts_to_copy,
+        account_slots: mainnet_account_slots,
+        tokens,
+        custom_filter_exclusions,
+    } = copy_set;
+    let skip_lookup_tables = config.alt_policy == functions::AltPolicy::Skip;
+
+    info!(
+        "Prepared {} mainnet accounts to copy (excluded {} vote, {} stake accounts)",
+        mainnet_accounts_to_copy.len()
+            + streamed
+                .as_ref()
+                .map_or(0, |streamed| streamed.accounts_kept),
+        filtered_vote_count,
+        filtered_stake_count
+    );
+    let mut streamed = streamed;
+    let mainnet_features = if config.feature_set != functions::FeatureSetPolicy::MergeLedger {
+        Some(match streamed.as_mut() {
+            Some(streamed) => std::mem::take(&mut streamed.features),
+            None => {
+                let kept_merge_ledger = functions::filter_feature_accounts(
+                    &mut mainnet_accounts_to_copy,
+                    config.feature_set,
+                    &union_merge_features(config, &merge_bank)?,
+                );
+                // The merged bank is the merge ledger bank's child
+                let rebased = functions::rebase_feature_activations(
+                    &mut mainnet_accounts_to_copy,
+                    merge_bank.slot() + 1,
+                );
+                functions::FeatureAccountAudit {
+                    kept_merge_ledger,
+                    rebased,
+                    ..functions::audit_feature_accounts(&mainnet_accounts_to_copy)
+                }
+            }
+        })
+    } else {
+        info!(
+            "Excluded {} mainnet feature accounts, keeping the merge ledger's feature set",
+            filtered_feature_count
+        );
+        None
+    };
+    if config.subtract_ledger.is_some() {
+        info!(
+            "Subtracted {} mainnet accounts held by the subtract ledger",
+            subtracted_count
+        );
+    }
+    if config.copy_owners.is_some() {
+        info!(
+            "Skipped {} mainnet accounts not owned by the selected programs",
+            outside_copy_owners_count
+        );
+    }
+    if !config.exclude_accounts.is_empty() {
+        info!(
+            "Excluded {} of the {} mainnet accounts in --exclude-accounts-file",
+            excluded_listed_count,
+            config.exclude_accounts.len()
+        );
+    }
+    if config.include_accounts.is_some() {
+        info!(
+            "Skipped {} mainnet accounts missing from --include-accounts-file",
+            outside_include_count
+        );
+    }
+    if !config.exclude_owners.is_empty() {
+        info!(
+            "Excluded {} mainnet accounts owned by the {} --exclude-owner programs",
+            excluded_owner_count,
+            config.exclude_owners.len()
+        );
+    }
+    let base_intersection_accounts = config.keep_base_accounts_only.then(|| {
+        let kept = mainnet_accounts_to_copy.len()
+            + streamed
+                .as_ref()
+                .map_or(0, |streamed| streamed.accounts_kept);
+        info!(
+            "Keeping base accounts only: {} mainnet accounts exist in the merge ledger, \
+             {} do not and were skipped",
+            kept, outside_base_count
+        );
+        kept
+    });
+    let owner_data_caps = functions::apply_owner_data_caps(
+        &mut mainnet_accounts_to_copy,
+        &config.max_data_bytes_per_owner,
+    );
+    let mainnet_epoch_rewards_active = functions::apply_epoch_rewards_policy(
+        &mut mainnet_accounts_to_copy,
+        config.epoch_rewards_policy,
+    )? || streamed
+        .as_ref()
+        .is_some_and(|streamed| streamed.epoch_rewards_active);
+    if skip_lookup_tables {
+        info!(
+            "Skipped {} address lookup tables",
+            skipped_lookup_table_count
+        );
+    }
+
+    let MergedBase {
+        system_accounts: merge_system_accounts,
+        bank: mut merged_bank,
+        capitalization_before,
+        stakes_pruned,
+        merge_validators_removed,
+        merge_feature_accounts_removed,
+    } = match merged_base {
+        Some(merged_base) => merged_base,
+        None => create_merged_bank(config, &merge_bank, &merge_genesis_config, &merge_scratch)?,
+    };
+
+    let mut preparation = prepare_copy_accounts(
+        config,
+        &merged_bank,
+        &mut mainnet_accounts_to_copy,
+        &mainnet_account_slots,
+    )?;
+    drop(mainnet_account_slots);
+    if let Some(streamed) = streamed.as_mut() {
+        preparation.absorb(std::mem::take(&mut streamed.preparation));
+    }
+    // Last, once no other filter can drop one half of a pair
+    let unpaired_programs = match streamed.as_mut() {
+        Some(streamed) => std::mem::take(&mut streamed.unpaired_programs),
+        None => {
+            let unpaired = functions::find_unpaired_programs(
+                &mainnet_accounts_to_copy,
+                &merged_bank,
+                config.program_scan_fallback_threshold,
+            )?;
+            for pubkey in unpaired.pubkeys() {
+                mainnet_accounts_to_copy.remove(pubkey);
+            }
+            unpaired
+        }
+    };
+    if let (Some(conflict_report_file), Some(report)) =
+        (&config.conflict_report_file, &preparation.base_conflicts)
+    {
+        functions::write_conflict_report(report, conflict_report_file)?;
+        info!(
+            "Conflict report written to {:?}: {} pubkeys",
+            conflict_report_file,
+            report.conflicts.len()
+        );
+    }
+    let CopyPreparation {
+        accounts_skipped_dormant,
+        owner_remaps,
+        upgrade_authorities_overridden,
+        mainnet_nonce_accounts,
+        mainnet_accounts_field_merged,
+        base_conflicts,
+        mainnet_lookup_tables_refreshed,
+        mainnet_lookup_tables_malformed,
+        rent_epochs_normalized,
+        token_balances_reconstructed,
+        account_transforms,
+        invalid_accounts_skipped,
+        accounts_skipped_identical,
+    } = preparation;
+    // Most likely a mistyped program id
+    if let Some(authority_override) = &config.upgrade_authority_override {
+        let unmatched = functions::unmatched_upgrade_authority_programs(
+            authority_override,
+            &upgrade_authorities_overridden,
+        );
+        if !unmatched.is_empty() {
+            return Err(MergeError::InvalidInput(format!(
+                "--override-upgrade-authority found no copied ProgramData for --program {}",
+                unmatched
+                    .iter()
+                    .map(Pubkey::to_string)
+                    .collect::<Vec<_>>()
+                    .join(", ")
+            )));
+        }
+    }
+    let mainnet_accounts_copied = mainnet_accounts_to_copy.len()
+        + streamed
+            .as_ref()
+            .map_or(0, |streamed| streamed.accounts_copied);
+
+    if mainnet_accounts_copied == 0 && !config.allow_empty_copy {
+        let filters = active_copy_filters(config);
+        return Err(MergeError::InvalidInput(format!(
+            "The filters excluded all {} mainnet accounts, so the merge would only reproduce \
+             the merge ledger (active filters: {}); pass --allow-empty-copy if that is intended",
+            mainnet_total_accounts,
+            if filters.is_empty() {
+                "none".to_string()
+            } else {
+                filters.join(", ")
+            }
+        )));
+    }
+
+    let top_accounts = match streamed.as_mut() {
+        Some(streamed) => std::mem::take(&mut streamed.top_accounts),
+        None => functions::top_accounts_by_lamports(
+            &mainnet_accounts_to_copy,
+            config.report_top_accounts,
+        ),
+    };
+
+    // Add all non-validator accounts from mainnet
+    info!("\n=== Step 8: Adding Mainnet Accounts (excluding validators) ===");
+    // Planned in the order the copy stores the accounts, so the slots match
+    let by_pubkey =
+        config.copy_shards.is_some() || config.copy_order() == functions::CopyOrder::ByPubkey;
+    let mainnet_slot_sizes = match (streamed.as_mut(), by_pubkey) {
+        (Some(streamed), _) => std::mem::take(&mut streamed.slot_sizes),
+        (None, true) => functions::plan_slot_sizes(
+            functions::sort_accounts_by_pubkey(&mainnet_accounts_to_copy)
+                .into_iter()
+                .map(|(_, account)| account),
+            config.slot_split,
+        ),
+        (None, false) => {
+            functions::plan_slot_sizes(mainnet_accounts_to_copy.values(), config.slot_split)
+        }
+    };
+    let slot_distribution = functions::SlotDistribution::from_sizes(&mainnet_slot_sizes);
+    if let Some(max_slots) = config.max_slots_for_copy {
+        slot_distribution.check_max_slots(max_slots, config.slot_split)?;
+    }
+    let estimated_archive_bytes = functions::estimate_archive_bytes(
+        mainnet_accounts_to_copy
+            .values()
+            .chain(merge_system_accounts.values()),
+        config.archive_compression_ratio,
+    ) + streamed
+        .as_ref()
+        .map_or(0, |streamed| streamed.estimated_archive_bytes);
+    info!(
+        "Estimated archive size: {} bytes (assuming {}x compression)",
+        estimated_archive_bytes, config.archive_compression_ratio
+    );
+    if config.dry_run {
+        // Pruned stakes are already gone from the bank but still counted in
+        // its recorded capitalization
+        let capitalization_after = match config.capitalization_mode {
+            functions::CapitalizationMode::Recalculate => functions::projected_capitalization(
+                &merged_bank,
+                capitalization_before
+                    - stakes_pruned
+                        .as_ref()
+                        .map_or(0, |pruned| pruned.lamports_reclaimed),
+                &[&mainnet_accounts_to_copy, &merge_system_accounts],
+            ),
+            functions::CapitalizationMode::PreserveBase => capitalization_before,
+            functions::CapitalizationMode::MintDelta { target, .. } => target,
+        };
+        info!(
+            "Dry run: stopping before any accounts are copied, capitalization would change \
+             from {} to {} ({:+})",
+            capitalization_before,
+            capitalization_after,
+            capitalization_after as i128 - capitalization_before as i128
+        );
+        return Ok(MergeStats {
+            mainnet_total_accounts,
+            merge_total_accounts,
+            mainnet_vote_accounts_excluded: filtered_vote_count,
+            mainnet_stake_accounts_excluded: filtered_stake_count,
+            mainnet_feature_accounts_excluded: filtered_feature_count,
+            mainnet_features,
+            mainnet_accounts_copied,
+            mainnet_accounts_field_merged,
+            base_conflicts,
+            mainnet_lookup_tables_skipped: skipped_lookup_table_count,
+            owner_data_caps,
+            owner_remaps,
+            upgrade_authorities_overridden,
+            mainnet_nonce_accounts,
+            slot_distribution,
+            top_accounts,
+            mainnet_epoch_rewards_active,
+            accounts_skipped_identical,
+            base_intersection_accounts,
+            accounts_outside_base_skipped: outside_base_count,
+            mainnet_lookup_tables_refreshed,
+            mainnet_lookup_tables_malformed,
+            accounts_skipped_dormant,
+            accounts_subtracted: subtracted_count,
+            accounts_excluded_by_owner: excluded_owner_count,
+            tokens,
+            custom_filter_exclusions,
+            accounts_excluded_by_list: excluded_listed_count,
+            accounts_outside_include_list: outside_include_count,
+            dust_accounts_excluded,
+            zero_lamport_accounts_skipped,
+            oversized_accounts_excluded,
+            duplicate_pubkeys_resolved,
+            source_layers,
+            rent_epochs_normalized,
+            stakes_pruned,
+            merge_validators_removed,
+            merge_feature_accounts_removed,
+            unpaired_programs,
+            token_balances_reconstructed,
+            account_transforms,
+            invalid_accounts_skipped,
+            merge_system_accounts_preserved: merge_system_accounts.len(),
+            capitalization_before,
+            capitalization_after,
+            load_time,
+            estimated_archive_bytes,
+            ..MergeStats::default()
+        });
+    }
+    info!(
+        "Copying into {} slots of {} to {} accounts ({:?})",
+        slot_distribution.slots,
+        slot_distribution.min_accounts,
+        slot_distribution.max_accounts,
+        config.slot_split
+    );
+    let replaced_data_sizes = if streamed.is_some() {
+        HashMap::new()
+    } else {
+        functions::account_data_sizes(&merged_bank)?
+    };
+    // Rewritten with the copy's position at each of its flush points
+    let copy_checkpoint = checkpoint.map(|checkpoint| {
+        functions::CopyCheckpoint::new(config.checkpoint_path(), checkpoint.to_json())
+    });
+    merged_bank = match config.copy_shards {
+        // A streamed copy has stored its chunks already
+        _ if streamed.is_some() => merged_bank,
+        Some(shards) => functions::add_accounts_sharded(
+            Arc::clone(&merged_bank),
+            &mainnet_accounts_to_copy,
+            &replaced_data_sizes,
+            "mainnet",
+            config.slot_split,
+            config.flush_policy,
+            shards,
+            config.deadline.as_ref(),
+            mainnet_reporting,
+        )?,
+        None => functions::add_accounts(
+            Arc::clone(&merged_bank),
+            &mainnet_accounts_to_copy,
+            &replaced_data_sizes,
+            "mainnet",
+            config.slot_split,
+            config.flush_policy,
+            config.copy_order(),
+            config.deadline.as_ref(),
+            functions::CopyReporting {
+                checkpoint: copy_checkpoint.as_ref(),
+                ..mainnet_reporting
+            },
+        )?,
+    };
+
+    let base_diff = match base_diff {
+        Some(base_diff) => {
+            let path = base_diff.path().to_path_buf();
+            let summary = base_diff.finish()?;
+            info!(
+                "Base diff written to {:?}: {} added, {} modified, {} unchanged, {:+} lamports",
+                path, summary.added, summary.modified, summary.unchanged, summary.lamports_delta
+            );
+            Some(summary)
+        }
+        None => None,
+    };
+
+    // Re-apply system accounts from merge ledger (to preserve funded accounts like validator identities)
+    info!("\n=== Step 9: Preserving System Accounts from Merge Ledger ===");
+    merged_bank = functions::add_accounts(
+        Arc::clone(&merged_bank),
+        &merge_system_accounts,
+        &functions::looked_up_data_sizes(&merged_bank, merge_system_accounts.keys()),
+        "merge ledger system",
+        config.slot_split,
+        config.flush_policy,
+        config.copy_order(),
+        config.deadline.as_ref(),
+        reporting,
+    )?;
+    let sysvars = sysvars::SysvarReconciliation::from_policies(&config.sysvar_policies);
+
+    let cloned_accounts = if config.clone_accounts.is_empty() {
+        None
+    } else {
+        check_deadline("cloning accounts")?;
+        Some(clone_rpc_accounts(config, &merged_bank)?)
+    };
+    // Last, so neither the mainnet nor the system accounts replace them
+    let account_overrides =
+        functions::apply_account_overrides(&merged_bank, &config.account_overrides);
+    if !config.account_overrides.is_empty() {
+        info!(
+            "Applied {} account overrides: {} replaced, {} created",
+            config.account_overrides.len(),
+            account_overrides.replaced.len(),
+            account_overrides.created.len()
+        );
+    }
+
+    if let Some(address) = &config.provenance_memo {
+        // The memo is part of the bank, so a deterministic merge dates it
+        // by the bank's clock rather than when it ran
+        let created_at = if config.deterministic {
+            merged_bank.clock().unix_timestamp.max(0) as u64
+        } else {
+            SystemTime::now()
+                .duration_since(UNIX_EPOCH)
+                .map(|elapsed| elapsed.as_secs())
+                .unwrap_or_default()
+        };
+        let memo = provenance_memo(
+            config,
+            mainnet_slot,
+            merge_bank.slot(),
+            warp_slot.unwrap_or(merged_bank.slot()),
+            created_at,
+        );
+        let lamports = functions::store_provenance_memo(&merged_bank, address, &memo)?;
+        info!(
+            "Stored provenance memo at {} ({} lamports)",
+            address, lamports
+        );
+    }
+
+    // Recalculate capitalization
+    info!(
+        "\n=== Step 10: Setting Capitalization ({:?}) ===",
+        config.capitalization_mode
+    );
+    report_progress(
+        "recalculating capitalization",
+        mainnet_accounts_copied,
+        merged_bank.slot(),
+    );
+    check_deadline("recalculating capitalization")?;
+    let treasury_adjustment = functions::apply_capitalization_mode(
+        &merged_bank,
+        config.capitalization_mode,
+        capitalization_before,
+    )?;
+    let capitalization_after = merged_bank.capitalization();
+
+    info!(
+        "Capitalization changed from {} to {} ({:+})",
+        capitalization_before,
+        capitalization_after,
+        capitalization_after as i128 - capitalization_before as i128
+    );
+
+    if let Some(post_merge_hook) = post_merge_hook {
+        info!(
+            "Running post-merge hook on bank at slot {}",
+            merged_bank.slot()
+        );
+        post_merge_hook(&merged_bank);
+    }
+
+    // Warp if requested, otherwise squash the merged bank
+    let mut capitalization_before_warp = None;
+    let mut capitalization_after_warp = None;
+    let mut epoch_stakes_regenerated = None;
+    let final_bank = if let Some(warp_slot) = warp_slot {
+        info!("\n=== Step 11: Warping to Slot {} ===", warp_slot);
+        info!("Squashing merged bank before warp...");
+        merged_bank.squash();
+        merged_bank.force_flush_accounts_cache();
+        let pre_warp = merged_bank.capitalization();
+        let merged_epoch = merged_bank.epoch();
+        if config.regenerate_epoch_stakes {
+            info!(
+                "Regenerating epoch stakes of bank at slot {} before the warp freezes it...",
+                merged_bank.slot()
+            );
+        }
+        let (warped, regenerated) =
+            functions::warp_bank(merged_bank, warp_slot, config.regenerate_epoch_stakes)?;
+        if let Some(report) = &regenerated {
+            log_regenerated_epoch_stakes(report, merged_epoch);
+        }
+        epoch_stakes_regenerated = regenerated;
+        let warped = Arc::new(warped);
+        info!(
+            "Warped bank genesis creation time: {} (should still match merge ledger: {})",
+            warped.genesis_creation_time(),
+            merge_genesis_config.creation_time
+        );
+        info!(
+            "Capitalization before warp {}, after warp {} ({:+})",
+            pre_warp,
+            warped.capitalization(),
+            warped.capitalization() as i128 - pre_warp as i128
+        );
+        if config.warp_preserve_capitalization {
+            functions::check_warp_capitalization(pre_warp, &warped)?;
+            info!("✓ Warp preserved capitalization");
+        }
+        capitalization_before_warp = Some(pre_warp);
+        capitalization_after_warp = Some(warped.capitalization());
+        warped
+    } else {
+        info!("\n=== Step 11: Finalizing Bank ===");
+        info!("Squashing merged bank...");
+        merged_bank.squash();
+        merged_bank.force_flush_accounts_cache();
+        info!("Bank squashed and accounts cache flushed");
+        if config.regenerate_epoch_stakes {
+            let bank = Arc::get_mut(&mut merged_bank).ok_or_else(|| {
+                MergeError::AccountCopy(
+                    "The merged bank is still shared, so its epoch stakes can't be regenerated"
+                        .to_string(),
+                )
+            })?;
+            info!(
+                "Regenerating epoch stakes of bank at slot {}...",
+                bank.slot()
+            );
+            let report = functions::regenerate_epoch_stakes(bank);
+            log_regenerated_epoch_stakes(&report, bank.epoch());
+            epoch_stakes_regenerated = Some(report);
+        }
+        merged_bank
+    };
+
+    let final_total_accounts = functions::count_total_accounts(&final_bank)?;
+    let accounts_data_size =
+        functions::check_accounts_data_size(&final_bank, config.expect_accounts_data_size_max)?;
+    info!(
+        "Final bank accounts data size: {} bytes",
+        accounts_data_size
+    );
+
+    // Verify the bank's genesis creation time matches what we're going to write
+    info!("\n=== Step 12: Verifying Genesis Consistency ===");
+    let mut output_genesis_config = merge_genesis_config.clone();
+    functions::apply_poh_overrides(
+        &mut output_genesis_config,
+        config.hashes_per_tick,
+        config.ticks_per_slot,
+    )?;
+    let bank_genesis_creation_time = final_bank.genesis_creation_time();
+    let merge_genesis_creation_time = merge_genesis_config.creation_time;
+    let merge_genesis_hash = merge_genesis_config.hash();
+
+    info!(
+        "Final bank genesis creation time: {}",
+        bank_genesis_creation_time
+    );
+    info!(
+        "Merge ledger genesis creation time: {}",
+        merge_genesis_creation_time
+    );
+    info!("Merge ledger genesis hash: {}", merge_genesis_hash);
+
+    if bank_genesis_creation_time != merge_genesis_creation_time {
+        return Err(MergeError::Verification(format!(
+            "Genesis creation time mismatch! Bank has creation time {} but merge ledger has {}. This indicates the bank was created from the wrong genesis.",
+            bank_genesis_creation_time, merge_genesis_creation_time
+        )));
+    }
+    info!("✓ Genesis creation times match");
+    if config.verify_genesis_hash {
+        let genesis_hash = functions::verify_bank_genesis(&final_bank, &output_genesis_config)?;
+        info!("✓ Final bank matches genesis {}", genesis_hash);
+    }
+    let bank_lineage = functions::verify_bank_lineage(&final_bank)?;
+    info!(
+        "✓ Bank lineage consistent: {} ancestors, slots {} to {}",
+        bank_lineage.depth, bank_lineage.oldest_slot, bank_lineage.newest_slot
+    );
+
+    let capitalization_check_delta =
+        functions::verify_capitalization(&final_bank, config.capitalization_tolerance)?;
+    info!(
+        "✓ Capitalization consistent (delta {:+}, tolerance {})",
+        capitalization_check_delta, config.capitalization_tolerance
+    );
+
+    let validator_audit = functions::audit_validator_accounts(
+        &final_bank,
+        &merge_bank,
+        &mainnet_vote_accounts,
+        &mainnet_stake_accounts,
+        config.validator_source,
+        config.program_scan_fallback_threshold,
+    )?;
+    info!(
+        "Final bank vote accounts: {} from merge ledger, {} from mainnet, {} other",
+        validator_audit.vote.from_merge_ledger,
+        validator_audit.vote.from_mainnet,
+        validator_audit.vote.other
+    );
+    info!(
+        "Final bank stake accounts: {} from merge ledger, {} from mainnet, {} other",
+        validator_audit.stake.from_merge_ledger,
+        validator_audit.stake.from_mainnet,
+        validator_audit.stake.other
+    );
+    if config.validator_source.keeps_mainnet() {
+        info!(
+            "Kept {} mainnet vote and {} mainnet stake accounts",
+            validator_audit.vote.from_mainnet, validator_audit.stake.from_mainnet
+        );
+    } else if !validator_audit.mainnet_accounts.is_empty() {
+        return Err(MergeError::Verification(format!(
+            "{} mainnet vote/stake accounts ended up in the merged bank, e.g. {}",
+            validator_audit.mainnet_accounts.len(),
+            validator_audit.mainnet_accounts[0]
+        )));
+    } else {
+        info!("✓ No mainnet vote or stake accounts in the merged bank");
+    }
+    let validators_summary = if config.validators_summary {
+        let summary =
+            functions::summarize_validators(&final_bank, config.program_scan_fallback_threshold)?;
+        info!(
+            "Final bank validator set: {} validators, {} lamports active stake",
+            summary.validators.len(),
+            summary.total_active_stake
+        );
+        Some(summary)
+    } else {
+        None
+    };
+
+    // Create snapshot
+    info!("\n=== Step 13: Creating Merged Snapshot ===");
+    report_progress(
+        "creating snapshot",
+        mainnet_accounts_copied,
+        final_bank.slot(),
+    );
+    check_deadline("creating snapshot")?;
+    std::fs::create_dir_all(output_snapshot_dir)
+        .map_err(|e| MergeError::Io(format!("Failed to create output directory: {:?}", e)))?;
+
+    let (snapshot_path, full_snapshot_hash, archive_time, incremental_snapshot) =
+        create_snapshot_from_bank(
+            &final_bank,
+            output_snapshot_dir,
+            config.snapshot_retries,
+            config.bank_completion,
+            config.archive_format.archive_format(config.zstd_level),
+            config.snapshot_type.writes_full(),
+            incremental_base_slot,
+        )?;
+    let bank_hash = final_bank.hash();
+    let bank_snapshot_hash = final_bank.get_snapshot_hash().0;
+    info!(
+        "Merged bank hash {}, bank snapshot hash {}",
+        bank_hash, bank_snapshot_hash
+    );
+    let archive_bytes = std::fs::metadata(&snapshot_path)
+        .map_err(|e| MergeError::Io(format!("Failed to read size of {}: {:?}", snapshot_path, e)))?
+        .len();
+    let storage_check = if config.verify_no_duplicate_storage {
+        // The accounts stored again after the copy on purpose
+        let mut restored: HashSet<Pubkey> = merge_system_accounts.keys().copied().collect();
+        restored.extend(
+            account_overrides
+                .replaced
+                .iter()
+                .chain(&account_overrides.created),
+        );
+        if let Some(cloned_accounts) = &cloned_accounts {
+            restored.extend(&cloned_accounts.cloned);
+        }
+        restored.extend(config.provenance_memo);
+        restored.extend(
+            treasury_adjustment
+                .as_ref()
+                .map(|adjustment| adjustment.treasury),
+        );
+        restored.extend(unpaired_programs.pubkeys());
+        let check =
+            functions::verify_no_duplicate_storage(&final_bank, merge_bank.slot(), &restored)?;
+        info!(
+            "✓ {} account entries in {} storages after slot {}, no account in more than one \
+             except {} entries of accounts the merge stores again",
+            check.entries,
+            check.storages,
+            merge_bank.slot(),
+            check.restored_entries
+        );
+        Some(check)
+    } else {
+        None
+    };
+    let archive_alias = match &config.archive_name {
+        Some(name) => {
+            let alias = functions::link_archive_name(Path::new(&snapshot_path), name)?;
+            info!("Archive linked as: {:?}", alias);
+            Some(alias.display().to_string())
+        }
+        None => None,
+    };
+    let compression =
+        functions::compression_report(accounts_data_size, archive_bytes, archive_time);
+    info!(
+        "Archive is {} bytes for {} bytes of account data: {:.2}x compression at {:.1} MB/s \
+         (estimated {} bytes)",
+        compression.archive_bytes,
+        compression.uncompressed_bytes,
+        compression.ratio,
+        compression.throughput_mb_per_sec,
+        estimated_archive_bytes
+    );
+
+    // Write the merge ledger genesis config to the output directory
+    info!("Writing merge ledger genesis config to output directory...");
+    let genesis_path =
+        functions::write_genesis_config(&output_genesis_config, output_snapshot_dir)?;
+    info!("Genesis config saved to: {:?}", genesis_path);
+    let genesis_archive_path = functions::write_genesis_archive(&genesis_path)?;
+    info!("Genesis archive saved to: {:?}", genesis_archive_path);
+    if config.ledger_layout {
+        // Validators open the blockstore under the ledger path on start;
+        // creating it leaves the output directory ready to pass as `--ledger`
+        Blockstore::open_with_options(output_snapshot_dir, BlockstoreOptions::default()).map_err(
+            |e| {
+                MergeError::BlockstoreOpen(format!(
+                    "Failed to create blockstore in {:?}: {:?}",
+                    output_snapshot_dir, e
+                ))
+            },
+        )?;
+        info!("Blockstore created in: {:?}", output_snapshot_dir);
+    }
+    let genesis_hash = output_genesis_config.hash();
+    if config.verify_genesis_hash {
+        functions::verify_written_genesis(&final_bank, &genesis_path)?;
+        info!("✓ Final bank matches written genesis {}", genesis_hash);
+    }
+
+    if let Some(account_index_file) = &config.account_index_file {
+        let entries = functions::write_account_index(&final_bank, account_index_file)?;
+        info!(
+            "Account index of {} accounts written to: {:?}",
+            entries, account_index_file
+        );
+    }
+
+    if let Some(reference_snapshot) = &config.reference_snapshot {
+        info!("\n=== Step 14: Comparing Against Reference Snapshot ===");
+        compare_to_reference_snapshot(
+            &final_bank,
+            reference_snapshot,
+            &merge_genesis_config,
+            output_snapshot_dir,
+            config,
+        )?;
+    }
+
+    let mut stats = MergeStats {
+        mainnet_total_accounts,
+        merge_total_accounts,
+        mainnet_vote_accounts_excluded: filtered_vote_count,
+        mainnet_stake_accounts_excluded: filtered_stake_count,
+        mainnet_feature_accounts_excluded: filtered_feature_count,
+        mainnet_features,
+        mainnet_accounts_copied,
+        mainnet_accounts_field_merged,
+        base_conflicts,
+        mainnet_lookup_tables_skipped: skipped_lookup_table_count,
+        owner_data_caps,
+        owner_remaps,
+        upgrade_authorities_overridden,
+        mainnet_nonce_accounts,
+        cloned_accounts,
+        account_overrides,
+        slot_distribution,
+        top_accounts,
+        mainnet_epoch_rewards_active,
+        sysvars,
+        accounts_skipped_identical,
+        base_intersection_accounts,
+        accounts_outside_base_skipped: outside_base_count,
+        mainnet_lookup_tables_refreshed,
+        mainnet_lookup_tables_malformed,
+        accounts_skipped_dormant,
+        accounts_subtracted: subtracted_count,
+        accounts_excluded_by_owner: excluded_owner_count,
+        tokens,
+        custom_filter_exclusions,
+        accounts_excluded_by_list: excluded_listed_count,
+        accounts_outside_include_list: outside_include_count,
+        dust_accounts_excluded,
+        zero_lamport_accounts_skipped,
+        oversized_accounts_excluded,
+        duplicate_pubkeys_resolved,
+        source_layers,
+        rent_epochs_normalized,
+        stakes_pruned,
+        merge_validators_removed,
+        merge_feature_accounts_removed,
+        unpaired_programs,
+        epoch_stakes_regenerated,
+        token_balances_reconstructed,
+        account_transforms,
+        invalid_accounts_skipped,
+        merge_system_accounts_preserved: merge_system_accounts.len(),
+        final_total_accounts,
+        accounts_data_size,
+        capitalization_before,
+        capitalization_after,
+        capitalization_before_warp,
+        capitalization_after_warp,
+        capitalization_check_delta,
+        treasury_adjustment,
+        bank_lineage,
+        storage_check,
+        validator_audit,
+        validators_summary,
+        base_diff,
+        load_time,
+        estimated_archive_bytes,
+        compression,
+        genesis_hash,
+        final_slot: final_bank.slot(),
+        snapshot_path,
+        full_snapshot_hash,
+        bank_hash,
+        bank_snapshot_hash,
+        archive_alias,
+        incremental_snapshot,
+        upload: None,
+        upload_error: None,
+    };
+
+    // Before the upload, so a failed upload leaves it in place
+    let manifest_path = output_snapshot_dir.join(SNAPSHOT_MANIFEST_FILE);
+    let manifest = render_snapshot_manifest(&stats, &genesis_archive_path)?;
+    std::fs::write(&manifest_path, format!("{:#}\n", manifest))
+        .map_err(|e| MergeError::Io(format!("Failed to write {:?}: {:?}", manifest_path, e)))?;
+    info!("Snapshot manifest written to: {:?}", manifest_path);
+
+    // The snapshot is complete by now, so a failed upload is reported in the
+    // stats rather than failing the merge
+    if let Some(destination) = &config.upload_to {
+        info!("\n=== Step 15: Uploading to {} ===", destination);
+        report_progress(
+            "uploading snapshot",
+            mainnet_accounts_copied,
+            stats.final_slot,
+        );
+        let incremental = stats
+            .incremental_snapshot
+            .as_ref()
+            .map(|incremental| Path::new(&incremental.path))
+            .filter(|&path| path != Path::new(&stats.snapshot_path));
+        match upload::upload_merged_snapshot(
+            destination,
+            Path::new(&stats.snapshot_path),
+            &genesis_path,
+            incremental,
+            &manifest_path,
+        ) {
+            Ok(report) => {
+                info!("Snapshot uploaded to: {}", report.archive_url);
+                stats.upload = Some(report);
+            }
+            Err(e) => {
+                error!("Upload to {} failed: {}", destination, e);
+                stats.upload_error = Some(e);
+            }
+        }
+    }
+
+    let changelog_path = config
+        .changelog_file
+        .clone()
+        .unwrap_or_else(|| output_snapshot_dir.join(DEFAULT_CHANGELOG_FILE));
+    std::fs::write(&changelog_path, render_changelog(config, &stats)).map_err(|e| {
+        MergeError::Io(format!(
+            "Failed to write changelog {:?}: {:?}",
+            changelog_path, e
+        ))
+    })?;
+    info!("Changelog written to: {:?}", changelog_path);
+    if config.checkpoint {
+        remove_checkpoint(config);
+    }
+
+    report_progress("complete", stats.mainnet_accounts_copied, stats.final_slot);
+    info!("\n=== Merge Complete ===");
+    info!("Statistics:");
+    info!("  Mainnet total accounts: {}", stats.mainnet_total_accounts);
+    info!(
+        "  Merge ledger total accounts: {}",
+        stats.merge_total_accounts
+    );
+    info!(
+        "  Mainnet vote accounts excluded: {}",
+        stats.mainnet_vote_accounts_excluded
+    );
+    info!(
+        "  Mainnet stake accounts excluded: {}",
+        stats.mainnet_stake_accounts_excluded
+    );
+    match &stats.mainnet_features {
+        Some(features) => info!(
+            "  Mainnet feature accounts copied: {} ({} unknown to this runtime)",
+            features.feature_accounts,
+            features.unknown.len()
+        ),
+        None => info!(
+            "  Mainnet feature accounts excluded: {}",
+            stats.mainnet_feature_accounts_excluded
+        ),
+    }
+    info!(
+        "  Mainnet accounts copied: {}",
+        stats.mainnet_accounts_copied
+    );
+    for layer in &stats.source_layers {
+        info!(
+            "  Source ledger {:?}: {} accounts, {} new, {} conflicts",
+            layer.ledger, layer.accounts, layer.merge.inserted, layer.merge.collisions
+        );
+    }
+    info!(
+        "  Mainnet accounts merged field by field: {}",
+        stats.mainnet_accounts_field_merged
+    );
+    info!(
+        "  Mainnet lookup tables skipped/refreshed/malformed: {}/{}/{}",
+        stats.mainnet_lookup_tables_skipped,
+        stats.mainnet_lookup_tables_refreshed,
+        stats.mainnet_lookup_tables_malformed
+    );
+    info!(
+        "  Accounts skipped as dormant: {}",
+        stats.accounts_skipped_dormant
+    );
+    info!("  Accounts subtracted: {}", stats.accounts_subtracted);
+    info!(
+        "  Accounts excluded by owner: {}",
+        stats.accounts_excluded_by_owner
+    );
+    if let Some(tokens) = &stats.tokens {
+        info!(
+            "  Token accounts excluded by mint/below the minimum balance: {}/{}",
+            tokens.accounts_excluded() - tokens.excluded_below_min_balance,
+            tokens.excluded_below_min_balance
+        );
+    }
+    info!(
+        "  Accounts excluded by list: {}",
+        stats.accounts_excluded_by_list
+    );
+    info!(
+        "  Accounts outside the include list: {}",
+        stats.accounts_outside_include_list
+    );
+    info!(
+        "  Accounts excluded as zero-lamport/dust/oversized: {}/{}/{}",
+        stats.zero_lamport_accounts_skipped,
+        stats.dust_accounts_excluded,
+        stats.oversized_accounts_excluded
+    );
+    info!(
+        "  Duplicate pubkeys resolved: {}",
+        stats.duplicate_pubkeys_resolved
+    );
+    info!("  Rent epochs normalized: {}", stats.rent_epochs_normalized);
+    info!(
+        "  Token balances reconstructed: {}",
+        stats.token_balances_reconstructed
+    );
+    for report in &stats.account_transforms {
+        info!(
+            "  Accounts rewritten by {}: {}",
+            report.name, report.accounts_changed
+        );
+    }
+    info!(
+        "  Invalid accounts skipped: {}",
+        stats.invalid_accounts_skipped
+    );
+    info!(
+        "  Accounts skipped as identical: {}",
+        stats.accounts_skipped_identical
+    );
+    info!(
+        "  Merge ledger system accounts preserved: {}",
+        stats.merge_system_accounts_preserved
+    );
+    info!(
+        "  Mainnet copy slots: {} ({} to {} accounts per slot)",
+        stats.slot_distribution.slots,
+        stats.slot_distribution.min_accounts,
+        stats.slot_distribution.max_accounts
+    );
+    info!("  Load time: {:.1}s", stats.load_time.as_secs_f64());
+    info!("  Final total accounts: {}", stats.final_total_accounts);
+    info!("  Accounts data size: {} bytes", stats.accounts_data_size);
+    info!(
+        "  Capitalization before: {} lamports",
+        stats.capitalization_before
+    );
+    info!(
+        "  Capitalization after: {} lamports",
+        stats.capitalization_after
+    );
+    if let (Some(before), Some(after)) = (
+        stats.capitalization_before_warp,
+        stats.capitalization_after_warp,
+    ) {
+        info!(
+            "  Capitalization before/after warp: {}/{} lamports",
+            before, after
+        );
+    }
+    for account in &stats.top_accounts {
+        info!(
+            "  Top account {}: {} lamports (owner {})",
+            account.pubkey, account.lamports, account.owner
+        );
+    }
+
+    Ok(stats)
+}
@@ -0,0 +1,210 @@
+// RPC fetch - pulls live account state from a JSON-RPC endpoint, for accounts
+// that should be fresher than the mainnet snapshot
+
+use {
+    crate::error::MergeError,
+    base64::{prelude::BASE64_STANDARD, Engine},
+    log::*,
+    solana_account::{AccountSharedData, ReadableAccount},
+    solana_clock::Slot,
+    solana_loader_v3_interface::state::UpgradeableLoaderState,
+    solana_pubkey::Pubkey,
+    solana_sdk_ids::bpf_loader_upgradeable,
+    std::{collections::HashMap, str::FromStr, time::Duration},
+};
+
+/// The most pubkeys `getMultipleAccounts` takes in one request
+pub const MAX_ACCOUNTS_PER_REQUEST: usize = 100;
+const REQUEST_TIMEOUT: Duration = Duration::from_secs(30);
+
+/// Accounts fetched from an RPC endpoint
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct FetchedAccounts {
+    /// Lowest context slot of the responses; every account is at least this
+    /// recent
+    pub slot: Slot,
+    pub accounts: HashMap<Pubkey, AccountSharedData>,
+    /// Requested pubkeys the endpoint has no account for
+    pub missing: Vec<Pubkey>,
+}
+
+impl FetchedAccounts {
+    /// Fails if the endpoint at `rpc_url` had no account for some pubkey
+    pub fn check_complete(&self, rpc_url: &str) -> Result<(), MergeError> {
+        if self.missing.is_empty() {
+            return Ok(());
+        }
+        Err(MergeError::Rpc(format!(
+            "{} has no account for {}",
+            rpc_url,
+            self.missing
+                .iter()
+                .map(Pubkey::to_string)
+                .collect::<Vec<_>>()
+                .join(", ")
+        )))
+    }
+}
+
+/// Accounts `--clone-account` stored in the merged bank
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct CloneReport {
+    pub rpc_url: String,
+    /// See [`FetchedAccounts::slot`]
+    pub slot: Slot,
+    /// The requested accounts and the program data fetched for them
+    pub cloned: Vec<Pubkey>,
+}
+
+/// The `getMultipleAccounts` request body for `pubkeys`
+pub fn get_multiple_accounts_request(pubkeys: &[Pubkey]) -> serde_json::Value {
+    serde_json::json!({
+        "jsonrpc": "2.0",
+        "id": 1,
+        "method": "getMultipleAccounts",
+        "params": [
+            pubkeys.iter().map(Pubkey::to_string).collect::<Vec<_>>(),
+            { "encoding": "base64", "commitment": "confirmed" },
+        ],
+    })
+}
+
+fn parse_account(value: &serde_json::Value) -> Result<AccountSharedData, MergeError> {
+    let number = |key: &str| {
+        value[key]
+            .as_u64()
+            .ok_or_else(|| MergeError::Rpc(format!("account has no {}", key)))
+    };
+    let data = match value["data"].as_array().map(Vec::as_slice) {
+        Some([encoded, encoding]) if encoding == "base64" => BASE64_STANDARD
+            .decode(encoded.as_str().unwrap_or_default())
+            .map_err(|e| MergeError::Rpc(format!("account data is not base64 ({})", e)))?,
+        _ => {
+            return Err(MergeError::Rpc(
+                "account data is not [\"<base64>\", \"base64\"]".to_string(),
+            ))
+        }
+    };
+    let owner = value["owner"]
+        .as_str()
+        .and_then(|owner| Pubkey::from_str(owner).ok())
+        .ok_or_else(|| MergeError::Rpc("account has no owner".to_string()))?;
+    Ok(AccountSharedData::from(solana_account::Account {
+        lamports: number("lamports")?,
+        data,
+        owner,
+        executable: value["executable"]
+            .as_bool()
+            .ok_or_else(|| MergeError::Rpc("account has no executable flag".to_string()))?,
+        // u64::MAX does not fit in a JSON number for every client, so some
+        // endpoints leave it out
+        rent_epoch: value["rentEpoch"].as_u64().unwrap_or(u64::MAX),
+    }))
+}
+
+/// Parses a `getMultipleAccounts` response to a request for `pubkeys` into
+/// `fetched`
+pub fn parse_get_multiple_accounts_response(
+    pubkeys: &[Pubkey],
+    response: &serde_json::Value,
+    fetched: &mut FetchedAccounts,
+) -> Result<(), MergeError> {
+    if let Some(error) = response.get("error") {
+        return Err(MergeError::Rpc(format!(
+            "getMultipleAccounts failed: {}",
+            error
+        )));
+    }
+    let result = &response["result"];
+    let slot = result["context"]["slot"].as_u64().ok_or_else(|| {
+        MergeError::Rpc("getMultipleAccounts response has no context slot".to_string())
+    })?;
+    let values = result["value"].as_array().ok_or_else(|| {
+        MergeError::Rpc("getMultipleAccounts response has no value list".to_string())
+    })?;
+    if values.len() != pubkeys.len() {
+        return Err(MergeError::Rpc(format!(
+            "getMultipleAccounts returned {} accounts for {} pubkeys",
+            values.len(),
+            pubkeys.len()
+        )));
+    }
+    fetched.slot = if fetched.accounts.is_empty() && fetched.missing.is_empty() {
+        slot
+    } else {
+        fetched.slot.min(slot)
+    };
+    for (pubkey, value) in pubkeys.iter().zip(values) {
+        if value.is_null() {
+            fetched.missing.push(*pubkey);
+        } else {
+            let account =
+                parse_account(value).map_err(|e| MergeError::Rpc(format!("{}: {}", pubkey, e)))?;
+            fetched.accounts.insert(*pubkey, account);
+        }
+    }
+    Ok(())
+}
+
+/// Program data accounts of the upgradeable programs among `accounts` that
+/// weren't fetched yet; a program can't run without its program data
+pub fn missing_program_data(accounts: &HashMap<Pubkey, AccountSharedData>) -> Vec<Pubkey> {
+    let mut program_data: Vec<_> = accounts
+        .values()
+        .filter(|account| account.owner() == &bpf_loader_upgradeable::id())
+        .filter_map(|account| match bincode::deserialize(account.data()) {
+            Ok(UpgradeableLoaderState::Program {
+                programdata_address,
+            }) => Some(programdata_address),
+            _ => None,
+        })
+        .filter(|address| !accounts.contains_key(address))
+        .collect();
+    program_data.sort();
+    program_data.dedup();
+    program_data
+}
+
+fn fetch_batch(
+    client: &reqwest::blocking::Client,
+    rpc_url: &str,
+    pubkeys: &[Pubkey],
+    fetched: &mut FetchedAccounts,
+) -> Result<(), MergeError> {
+    let response: serde_json::Value = client
+        .post(rpc_url)
+        .json(&get_multiple_accounts_request(pubkeys))
+        .send()
+        .and_then(|response| response.error_for_status())
+        .and_then(|response| response.json())
+        .map_err(|e| {
+            MergeError::Rpc(format!("Failed to fetch accounts from {}: {}", rpc_url, e))
+        })?;
+    parse_get_multiple_accounts_response(pubkeys, &response, fetched)
+        .map_err(|e| MergeError::Rpc(format!("{}: {}", rpc_url, e)))
+}
+
+/// Fetches `pubkeys` from the JSON-RPC endpoint at `rpc_url` at confirmed
+/// commitment, along with the program data of any upgradeable program among
+/// them
+pub fn fetch_accounts(rpc_url: &str, pubkeys: &[Pubkey]) -> Result<FetchedAccounts, MergeError> {
+    let client = reqwest::blocking::Client::builder()
+        .timeout(REQUEST_TIMEOUT)
+        .build()
+        .map_err(|e| MergeError::Rpc(format!("Failed to create RPC client: {}", e)))?;
+    let mut fetched = FetchedAccounts::default();
+    for batch in pubkeys.chunks(MAX_ACCOUNTS_PER_REQUEST) {
+        fetch_batch(&client, rpc_url, batch, &mut fetched)?;
+    }
+    let program_data = missing_program_data(&fetched.accounts);
+    if !program_data.is_empty() {
+        info!(
+            "Fetching the program data of {} upgradeable programs",
+            program_data.len()
+        );
+        for batch in program_data.chunks(MAX_ACCOUNTS_PER_REQUEST) {
+            fetch_batch(&client, rpc_url, batch, &mut fetched)?;
+        }
+    }
+    Ok(fetched)
+}
@@ -0,0 +1,289 @@
+// Self-test - builds two small synthetic ledgers and runs a full merge between them
+
+use crate::error::MergeError;
+use crate::merge::functions;
+use crate::merger::{merge_snapshots, MergeConfig, MergeStats, MAX_GENESIS_ARCHIVE_UNPACKED_SIZE};
+use {
+    log::*,
+    solana_account::{AccountSharedData, ReadableAccount},
+    solana_accounts_db::accounts_index::AccountsIndexConfig,
+    solana_genesis_config::GenesisConfig,
+    solana_ledger::{blockstore::create_new_ledger, blockstore_options::LedgerColumnOptions},
+    solana_pubkey::Pubkey,
+    solana_runtime::{
+        bank::Bank,
+        genesis_utils::{create_genesis_config, GenesisConfigInfo},
+        snapshot_bank_utils,
+        snapshot_utils::{ArchiveFormat, ZstdConfig},
+    },
+    solana_signer::Signer,
+    std::{collections::HashMap, path::Path, sync::Arc},
+};
+
+const MINT_LAMPORTS: u64 = 1_000_000_000_000;
+const SYNTHETIC_DATA_ACCOUNTS: usize = 100;
+const SYNTHETIC_VALIDATOR_ACCOUNTS: usize = 5;
+
+/// Outcome of one step of the self-test
+#[derive(Debug)]
+pub struct SelfTestStep {
+    pub name: &'static str,
+    /// Details on success, the failure otherwise
+    pub result: Result<String, MergeError>,
+}
+
+#[derive(Debug, Default)]
+pub struct SelfTestReport {
+    pub steps: Vec<SelfTestStep>,
+}
+
+impl SelfTestReport {
+    pub fn passed(&self) -> bool {
+        self.steps.iter().all(|step| step.result.is_ok())
+    }
+
+    fn record<T>(
+        &mut self,
+        name: &'static str,
+        result: Result<T, MergeError>,
+        details: impl FnOnce(&T) -> String,
+    ) -> Option<T> {
+        match result {
+            Ok(value) => {
+                self.steps.push(SelfTestStep {
+                    name,
+                    result: Ok(details(&value)),
+                });
+                Some(value)
+            }
+            Err(e) => {
+                self.steps.push(SelfTestStep {
+                    name,
+                    result: Err(e),
+                });
+                None
+            }
+        }
+    }
+}
+
+/// The accounts the synthetic mainnet ledger was built with
+struct SyntheticMainnet {
+    data_accounts: HashMap<Pubkey, AccountSharedData>,
+    validator_accounts: Vec<Pubkey>,
+    bootstrap_vote_account: Pubkey,
+}
+
+/// Writes a ledger at `ledger_path` whose only snapshot is `bank`
+pub fn write_ledger(
+    ledger_path: &Path,
+    genesis_config: &GenesisConfig,
+    bank: &Bank,
+) -> Result<(), MergeError> {
+    create_new_ledger(
+        ledger_path,
+        genesis_config,
+        MAX_GENESIS_ARCHIVE_UNPACKED_SIZE,
+        LedgerColumnOptions::default(),
+    )
+    .map_err(|e| {
+        MergeError::Io(format!(
+            "Failed to create ledger at {:?}: {:?}",
+            ledger_path, e
+        ))
+    })?;
+
+    functions::recalculate_capitalization(bank);
+    functions::complete_bank(bank, functions::BankCompletion::FillToMax)?;
+
+    // Keep the working bank snapshot out of the ledger so loading it goes
+    // through the archive, like a downloaded snapshot would
+    let bank_snapshots_dir = ledger_path.join("self-test-bank-snapshots");
+    std::fs::create_dir_all(&bank_snapshots_dir).map_err(|e| {
+        MergeError::Io(format!(
+            "Failed to create bank snapshots directory: {:?}",
+            e
+        ))
+    })?;
+    snapshot_bank_utils::bank_to_full_snapshot_archive(
+        &bank_snapshots_dir,
+        bank,
+        None,
+        ledger_path,
+        ledger_path,
+        ArchiveFormat::TarZstd {
+            config: ZstdConfig::default(),
+        },
+    )
+    .map_err(|e| MergeError::Io(format!("Failed to create snapshot archive: {:?}", e)))?;
+    std::fs::remove_dir_all(&bank_snapshots_dir).map_err(|e| {
+        MergeError::Io(format!(
+            "Failed to remove bank snapshots directory: {:?}",
+            e
+        ))
+    })?;
+    Ok(())
+}
+
+fn create_mainnet_ledger(ledger_path: &Path) -> Result<SyntheticMainnet, MergeError> {
+    let GenesisConfigInfo {
+        genesis_config,
+        voting_keypair,
+        ..
+    } = create_genesis_config(MINT_LAMPORTS);
+    let root = Arc::new(Bank::new_for_tests(&genesis_config));
+    let bank = Bank::new_from_parent(root, &Pubkey::default(), 1);
+
+    let data_accounts: HashMap<_, _> = (0..SYNTHETIC_DATA_ACCOUNTS)
+        .map(|i| {
+            let mut account =
+                AccountSharedData::new(1_000_000 + i as u64, 32 + i, &Pubkey::new_unique());
+            account.set_data_from_slice(&vec![i as u8; 32 + i]);
+            (Pubkey::new_unique(), account)
+        })
+        .collect();
+    for (pubkey, account) in &data_accounts {
+        bank.store_account(pubkey, account);
+    }
+
+    // Empty accounts owned by the vote and stake programs stand in for
+    // mainnet validators; they must be excluded like real ones
+    let mut validator_accounts = Vec::new();
+    for owner in [solana_vote_program::id(), solana_stake_program::id()] {
+        for _ in 0..SYNTHETIC_VALIDATOR_ACCOUNTS {
+            let pubkey = Pubkey::new_unique();
+            bank.store_account(&pubkey, &AccountSharedData::new(1_000_000, 0, &owner));
+            validator_accounts.push(pubkey);
+        }
+    }
+
+    write_ledger(ledger_path, &genesis_config, &bank)?;
+    Ok(SyntheticMainnet {
+        data_accounts,
+        validator_accounts,
+        bootstrap_vote_account: voting_keypair.pubkey(),
+    })
+}
+
+/// Creates the merge base ledger, returning its genesis config and the vote
+/// account of its bootstrap validator
+fn create_merge_base_ledger(ledger_path: &Path) -> Result<(GenesisConfig, Pubkey), MergeError> {
+    let GenesisConfigInfo {
+        genesis_config,
+        voting_keypair,
+        ..
+    } = create_genesis_config(MINT_LAMPORTS);
+    let root = Arc::new(Bank::new_for_tests(&genesis_config));
+    let bank = Bank::new_from_parent(root, &Pubkey::default(), 1);
+    write_ledger(ledger_path, &genesis_config, &bank)?;
+    Ok((genesis_config, voting_keypair.pubkey()))
+}
+
+fn verify_output(
+    stats: &MergeStats,
+    mainnet: &SyntheticMainnet,
+    merge_genesis_config: &GenesisConfig,
+    merge_vote_account: &Pubkey,
+    scratch_dir: &Path,
+) -> Result<usize, MergeError> {
+    let bank = functions::load_bank_from_snapshot_archive(
+        Path::new(&stats.snapshot_path),
+        merge_genesis_config,
+        &AccountsIndexConfig::default(),
+        scratch_dir,
+    )?;
+
+    let mut problems = Vec::new();
+    for (pubkey, expected) in &mainnet.data_accounts {
+        match bank.get_account(pubkey) {
+            Some(actual)
+                if actual.data() == expected.data() && actual.owner() == expected.owner() => {}
+            Some(_) => problems.push(format!("data account {} differs", pubkey)),
+            None => problems.push(format!("data account {} is missing", pubkey)),
+        }
+    }
+    for pubkey in mainnet
+        .validator_accounts
+        .iter()
+        .chain(std::iter::once(&mainnet.bootstrap_vote_account))
+    {
+        if bank.get_account(pubkey).is_some() {
+            problems.push(format!("mainnet validator account {} was copied", pubkey));
+        }
+    }
+    if bank.get_account(merge_vote_account).is_none() {
+        problems.push(format!(
+            "merge base vote account {} is missing",
+            merge_vote_account
+        ));
+    }
+
+    if problems.is_empty() {
+        Ok(mainnet.data_accounts.len())
+    } else {
+        Err(MergeError::Verification(format!(
+            "{} problems in the merged snapshot, e.g. {}",
+            problems.len(),
+            problems[..problems.len().min(3)].join("; ")
+        )))
+    }
+}
+
+/// Generates a synthetic "mainnet" ledger with data, vote and stake accounts
+/// and a minimal merge base ledger under `work_dir`, merges them and checks
+/// the merged snapshot kept the data accounts, dropped the mainnet validators
+/// and kept the base validator. Stops at the first failing step.
+pub fn run_self_test(work_dir: &Path) -> SelfTestReport {
+    let mut report = SelfTestReport::default();
+    let mainnet_ledger = work_dir.join("mainnet-ledger");
+    let merge_ledger = work_dir.join("merge-ledger");
+    let output_directory = work_dir.join("output");
+
+    let Some(mainnet) = report.record(
+        "create synthetic mainnet ledger",
+        create_mainnet_ledger(&mainnet_ledger),
+        |mainnet| {
+            format!(
+                "{} data and {} vote/stake accounts",
+                mainnet.data_accounts.len(),
+                mainnet.validator_accounts.len()
+            )
+        },
+    ) else {
+        return report;
+    };
+    let Some((merge_genesis_config, merge_vote_account)) = report.record(
+        "create synthetic merge base ledger",
+        create_merge_base_ledger(&merge_ledger),
+        |(_, vote_account)| format!("bootstrap vote account {}", vote_account),
+    ) else {
+        return report;
+    };
+
+    info!("Running self-test merge in {:?}", work_dir);
+    let config = MergeConfig {
+        verify_genesis_hash: true,
+        ..MergeConfig::new(mainnet_ledger, merge_ledger, output_directory.clone())
+    };
+    let Some(stats) = report.record("merge", merge_snapshots(&config, None), |stats| {
+        format!(
+            "copied {} accounts, snapshot {}",
+            stats.mainnet_accounts_copied, stats.snapshot_path
+        )
+    }) else {
+        return report;
+    };
+
+    report.record(
+        "verify merged snapshot",
+        verify_output(
+            &stats,
+            &mainnet,
+            &merge_genesis_config,
+            &merge_vote_account,
+            &output_directory.join("self-test-load"),
+        ),
+        |checked| format!("{} data accounts match, validators replaced", checked),
+    );
+    report
+}
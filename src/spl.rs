@@ -0,0 +1,273 @@
+// SPL tokens - leaves token accounts of unwanted mints, or below a balance,
+// out of the mainnet copy and accounts for the token supply that is copied
+
+use {
+    crate::merge::functions::{SPL_TOKEN_2022_PROGRAM_ID, SPL_TOKEN_PROGRAM_ID},
+    serde::Serialize,
+    solana_account::{AccountSharedData, ReadableAccount},
+    solana_pubkey::Pubkey,
+    std::collections::{BTreeMap, HashMap, HashSet},
+};
+
+/// Length of an SPL mint
+pub const MINT_LEN: usize = 82;
+/// Length of an SPL token account; Token-2022 accounts with extensions are
+/// longer and carry their account type right after it
+pub const TOKEN_ACCOUNT_LEN: usize = 165;
+const ACCOUNT_TYPE_MINT: u8 = 1;
+const ACCOUNT_TYPE_ACCOUNT: u8 = 2;
+const TOKEN_AMOUNT_OFFSET: usize = 64;
+const TOKEN_STATE_OFFSET: usize = 108;
+const MINT_SUPPLY_OFFSET: usize = 36;
+const MINT_INITIALIZED_OFFSET: usize = 45;
+
+fn is_token_program(owner: &Pubkey) -> bool {
+    *owner == SPL_TOKEN_PROGRAM_ID || *owner == SPL_TOKEN_2022_PROGRAM_ID
+}
+
+/// Whether `data` is laid out as the Token-2022 account type `account_type`
+/// with extensions past the base token account
+fn has_extended_type(data: &[u8], account_type: u8) -> bool {
+    data.len() > TOKEN_ACCOUNT_LEN && data[TOKEN_ACCOUNT_LEN] == account_type
+}
+
+fn read_u64(data: &[u8], offset: usize) -> u64 {
+    u64::from_le_bytes(data[offset..offset + 8].try_into().unwrap())
+}
+
+/// The mint and amount of an initialized SPL Token or Token-2022 account,
+/// with or without extensions
+pub fn parse_token_account(account: &AccountSharedData) -> Option<(Pubkey, u64)> {
+    let data = account.data();
+    let is_account =
+        data.len() == TOKEN_ACCOUNT_LEN || has_extended_type(data, ACCOUNT_TYPE_ACCOUNT);
+    (is_token_program(account.owner()) && is_account && data[TOKEN_STATE_OFFSET] != 0).then(|| {
+        (
+            Pubkey::try_from(&data[..32]).unwrap(),
+            read_u64(data, TOKEN_AMOUNT_OFFSET),
+        )
+    })
+}
+
+/// The supply of an initialized SPL Token or Token-2022 mint
+pub fn parse_mint_supply(account: &AccountSharedData) -> Option<u64> {
+    let data = account.data();
+    let is_mint = data.len() == MINT_LEN || has_extended_type(data, ACCOUNT_TYPE_MINT);
+    (is_token_program(account.owner()) && is_mint && data[MINT_INITIALIZED_OFFSET] != 0)
+        .then(|| read_u64(data, MINT_SUPPLY_OFFSET))
+}
+
+/// Which mainnet token accounts the merge leaves out
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct TokenFilter {
+    /// Token accounts of these mints are left out; the mints themselves are
+    /// still copied
+    pub exclude_mints: HashSet<Pubkey>,
+    /// Token accounts holding fewer base units than this are left out
+    pub min_balance: Option<u64>,
+}
+
+impl TokenFilter {
+    pub fn is_active(&self) -> bool {
+        !self.exclude_mints.is_empty() || self.min_balance.is_some()
+    }
+}
+
+/// The token accounts of one `TokenFilter::exclude_mints` mint left out
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize)]
+pub struct MintExclusion {
+    pub accounts: usize,
+    /// Base units held by those accounts, at most the mint's `u64` supply
+    pub amount: u64,
+}
+
+/// What the token filter left out and kept
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct TokenReport {
+    /// Token accounts kept for the copy
+    pub token_accounts_copied: usize,
+    /// Mint accounts kept for the copy
+    pub mints_copied: usize,
+    /// Distinct mints of the token accounts kept
+    pub mints_held: usize,
+    /// Mints the kept token accounts hold without their mint account being
+    /// kept
+    pub mints_held_without_mint: usize,
+    /// Kept mints whose kept token accounts hold less than their supply, e.g.
+    /// because the filter left some out: their supply overstates what the
+    /// merged bank holds
+    pub mints_short_of_supply: usize,
+    pub excluded_by_mint: BTreeMap<Pubkey, MintExclusion>,
+    pub excluded_below_min_balance: usize,
+}
+
+impl TokenReport {
+    pub fn accounts_excluded(&self) -> usize {
+        self.excluded_by_mint
+            .values()
+            .map(|exclusion| exclusion.accounts)
+            .sum::<usize>()
+            + self.excluded_below_min_balance
+    }
+
+    /// Combines the reports of scans whose kept accounts were layered into
+    /// `kept`: what each scan left out adds up, while what was kept is counted
+    /// again from `kept`, where a pubkey several scans kept counts once with
+    /// the version the layering resolved it to
+    pub fn layered(
+        reports: impl IntoIterator<Item = TokenReport>,
+        kept: &HashMap<Pubkey, AccountSharedData>,
+    ) -> TokenReport {
+        let mut accounting = TokenAccounting::default();
+        for (pubkey, account) in kept {
+            accounting.keep(pubkey, account);
+        }
+        let mut layered = accounting.finish();
+        for report in reports {
+            for (mint, exclusion) in report.excluded_by_mint {
+                let layered_exclusion = layered.excluded_by_mint.entry(mint).or_default();
+                layered_exclusion.accounts += exclusion.accounts;
+                layered_exclusion.amount =
+                    layered_exclusion.amount.saturating_add(exclusion.amount);
+            }
+            layered.excluded_below_min_balance += report.excluded_below_min_balance;
+        }
+        layered
+    }
+}
+
+/// How [`TokenAccounting::keep`] counted one token account
+#[derive(Debug, Clone, Copy)]
+enum Counted {
+    Copied { mint: Pubkey, amount: u64 },
+    ExcludedByMint { mint: Pubkey, amount: u64 },
+    BelowMinBalance,
+}
+
+/// Applies a [`TokenFilter`] to mainnet accounts one at a time, tallying the
+/// token supply of the ones kept
+#[derive(Debug, Default)]
+pub struct TokenAccounting {
+    filter: TokenFilter,
+    /// Token accounts kept and the base units they hold, by mint
+    held: HashMap<Pubkey, (usize, u64)>,
+    supplies: HashMap<Pubkey, u64>,
+    /// What each token account counted towards, kept only by
+    /// [`TokenAccounting::replacing_versions`]
+    counted: Option<HashMap<Pubkey, Counted>>,
+    report: TokenReport,
+}
+
+impl TokenAccounting {
+    pub fn new(filter: TokenFilter) -> Self {
+        Self {
+            filter,
+            ..Self::default()
+        }
+    }
+
+    /// Accounting for an enumeration that can surface a pubkey more than
+    /// once: a later version passed to [`TokenAccounting::keep`] replaces
+    /// what an earlier one counted instead of adding to it. It remembers
+    /// every token account seen, so it is for enumerations holding all
+    /// accounts in memory anyway.
+    pub fn replacing_versions(filter: TokenFilter) -> Self {
+        Self {
+            counted: Some(HashMap::new()),
+            ..Self::new(filter)
+        }
+    }
+
+    /// Whether the filter keeps the account at `pubkey`; accounts that are
+    /// neither token accounts nor mints are always kept
+    pub fn keep(&mut self, pubkey: &Pubkey, account: &AccountSharedData) -> bool {
+        self.forget(pubkey);
+        let counted = if let Some((mint, amount)) = parse_token_account(account) {
+            if self.filter.exclude_mints.contains(&mint) {
+                Counted::ExcludedByMint { mint, amount }
+            } else if self.filter.min_balance.is_some_and(|min| amount < min) {
+                Counted::BelowMinBalance
+            } else {
+                Counted::Copied { mint, amount }
+            }
+        } else {
+            if let Some(supply) = parse_mint_supply(account) {
+                self.supplies.insert(*pubkey, supply);
+            }
+            return true;
+        };
+        match counted {
+            Counted::Copied { mint, amount } => {
+                self.report.token_accounts_copied += 1;
+                let (accounts, held) = self.held.entry(mint).or_default();
+                *accounts += 1;
+                *held = held.saturating_add(amount);
+            }
+            Counted::ExcludedByMint { mint, amount } => {
+                let exclusion = self.report.excluded_by_mint.entry(mint).or_default();
+                exclusion.accounts += 1;
+                exclusion.amount = exclusion.amount.saturating_add(amount);
+            }
+            Counted::BelowMinBalance => self.report.excluded_below_min_balance += 1,
+        }
+        if let Some(counted_accounts) = self.counted.as_mut() {
+            counted_accounts.insert(*pubkey, counted);
+        }
+        matches!(counted, Counted::Copied { .. })
+    }
+
+    /// Takes back what an earlier version of `pubkey` counted, if versions
+    /// are being replaced; for a newer version the other filters leave out
+    pub fn forget(&mut self, pubkey: &Pubkey) {
+        self.supplies.remove(pubkey);
+        let Some(counted) = self
+            .counted
+            .as_mut()
+            .and_then(|counted| counted.remove(pubkey))
+        else {
+            return;
+        };
+        match counted {
+            Counted::Copied { mint, amount } => {
+                self.report.token_accounts_copied -= 1;
+                if let Some((accounts, held)) = self.held.get_mut(&mint) {
+                    *accounts -= 1;
+                    *held = held.saturating_sub(amount);
+                    if *accounts == 0 {
+                        self.held.remove(&mint);
+                    }
+                }
+            }
+            Counted::ExcludedByMint { mint, amount } => {
+                if let Some(exclusion) = self.report.excluded_by_mint.get_mut(&mint) {
+                    exclusion.accounts -= 1;
+                    exclusion.amount = exclusion.amount.saturating_sub(amount);
+                    if exclusion.accounts == 0 {
+                        self.report.excluded_by_mint.remove(&mint);
+                    }
+                }
+            }
+            Counted::BelowMinBalance => self.report.excluded_below_min_balance -= 1,
+        }
+    }
+
+    pub fn finish(self) -> TokenReport {
+        let Self {
+            held,
+            supplies,
+            mut report,
+            ..
+        } = self;
+        report.mints_copied = supplies.len();
+        report.mints_held = held.len();
+        report.mints_held_without_mint = held
+            .keys()
+            .filter(|mint| !supplies.contains_key(mint))
+            .count();
+        report.mints_short_of_supply = supplies
+            .iter()
+            .filter(|(mint, &supply)| held.get(mint).map_or(0, |&(_, amount)| amount) < supply)
+            .count();
+        report
+    }
+}
@@ -0,0 +1,157 @@
+// Sysvar reconciliation - keeps the sysvars a merge would copy from mainnet
+// agreeing with the merge ledger's genesis and the merged bank's slot
+
+use {
+    crate::error::MergeError,
+    log::*,
+    solana_pubkey::Pubkey,
+    solana_sdk_ids::sysvar,
+    std::{collections::HashMap, fmt, str::FromStr},
+};
+
+/// The sysvars the merge reconciles
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, PartialOrd, Ord)]
+pub enum Sysvar {
+    Clock,
+    Rent,
+    EpochSchedule,
+    SlotHashes,
+    StakeHistory,
+    RecentBlockhashes,
+}
+
+impl Sysvar {
+    pub const ALL: [Self; 6] = [
+        Self::Clock,
+        Self::Rent,
+        Self::EpochSchedule,
+        Self::SlotHashes,
+        Self::StakeHistory,
+        Self::RecentBlockhashes,
+    ];
+
+    pub fn id(self) -> Pubkey {
+        match self {
+            Self::Clock => sysvar::clock::id(),
+            Self::Rent => sysvar::rent::id(),
+            Self::EpochSchedule => sysvar::epoch_schedule::id(),
+            Self::SlotHashes => sysvar::slot_hashes::id(),
+            Self::StakeHistory => sysvar::stake_history::id(),
+            Self::RecentBlockhashes => sysvar::recent_blockhashes::id(),
+        }
+    }
+
+    /// The name `FromStr` takes
+    pub fn name(self) -> &'static str {
+        match self {
+            Self::Clock => "clock",
+            Self::Rent => "rent",
+            Self::EpochSchedule => "epoch-schedule",
+            Self::SlotHashes => "slot-hashes",
+            Self::StakeHistory => "stake-history",
+            Self::RecentBlockhashes => "recent-blockhashes",
+        }
+    }
+}
+
+impl fmt::Display for Sysvar {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str(self.name())
+    }
+}
+
+impl FromStr for Sysvar {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        Self::ALL
+            .into_iter()
+            .find(|sysvar| sysvar.name() == s)
+            .ok_or_else(|| {
+                format!(
+                    "Invalid sysvar '{}', expected one of {}",
+                    s,
+                    Self::ALL.map(Self::name).join(", ")
+                )
+            })
+    }
+}
+
+/// What to do with a sysvar after the merge
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum SysvarPolicy {
+    /// Leave mainnet's out of the copy, so the merged bank keeps the value
+    /// it derives from the merge ledger
+    #[default]
+    Rewrite,
+    /// Copy mainnet's
+    Keep,
+}
+
+impl FromStr for SysvarPolicy {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "rewrite" => Ok(Self::Rewrite),
+            "keep" => Ok(Self::Keep),
+            _ => Err(format!(
+                "Invalid sysvar policy '{}', expected 'rewrite' or 'keep'",
+                s
+            )),
+        }
+    }
+}
+
+/// Parses `<SYSVAR>=<POLICY>`
+pub fn parse_sysvar_policy(s: &str) -> Result<(Sysvar, SysvarPolicy), MergeError> {
+    let (sysvar, policy) = s.split_once('=').ok_or_else(|| {
+        MergeError::InvalidInput(format!(
+            "Invalid sysvar policy '{}', expected SYSVAR=POLICY",
+            s
+        ))
+    })?;
+    Ok((
+        sysvar.trim().parse().map_err(MergeError::InvalidInput)?,
+        policy.trim().parse().map_err(MergeError::InvalidInput)?,
+    ))
+}
+
+/// The policy of `sysvar` in `policies`, `SysvarPolicy::Rewrite` if it has
+/// none
+pub fn policy(policies: &HashMap<Sysvar, SysvarPolicy>, sysvar: Sysvar) -> SysvarPolicy {
+    policies.get(&sysvar).copied().unwrap_or_default()
+}
+
+/// What the merge did with each sysvar
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct SysvarReconciliation {
+    /// Left out of the mainnet copy, so the merged bank keeps its own, which
+    /// it updates for every slot it advances to
+    pub rewritten: Vec<Sysvar>,
+    /// Copied from mainnet, per `SysvarPolicy::Keep`
+    pub kept: Vec<Sysvar>,
+}
+
+impl SysvarReconciliation {
+    /// Sorts every sysvar by its policy in `policies`. The mainnet copy
+    /// leaves out the sysvars to rewrite rather than restoring them after
+    /// it: a value captured before the copy is stale once the copy has
+    /// advanced the merged bank past its first slot.
+    pub fn from_policies(policies: &HashMap<Sysvar, SysvarPolicy>) -> Self {
+        let mut reconciliation = Self::default();
+        for sysvar in Sysvar::ALL {
+            match policy(policies, sysvar) {
+                SysvarPolicy::Rewrite => reconciliation.rewritten.push(sysvar),
+                SysvarPolicy::Keep => {
+                    warn!(
+                        "Keeping mainnet's {} sysvar, which doesn't match the merged bank's own",
+                        sysvar
+                    );
+                    reconciliation.kept.push(sysvar);
+                }
+            }
+        }
+        reconciliation
+    }
+}
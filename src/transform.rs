@@ -0,0 +1,126 @@
+// Account transforms - rewrite the mainnet accounts to copy just before they
+// are stored, after the built-in rewrites of the copy, for library users
+
+use {
+    crate::error::MergeError,
+    solana_account::{AccountSharedData, ReadableAccount, WritableAccount},
+    solana_pubkey::Pubkey,
+    std::{collections::HashMap, fmt, ops::Range, sync::Arc},
+};
+
+/// Rewrites mainnet accounts before they are stored in the merged bank.
+/// Transforms run after the built-in rewrites of the copy and before
+/// `MergeConfig::validate_accounts` checks the result.
+pub trait AccountTransform {
+    /// Names the transform in logs and in the stats
+    fn name(&self) -> &str;
+
+    /// Rewrites the lamports, data or owner of the `pubkey` account in place.
+    /// Returns whether it changed anything.
+    fn transform(&self, pubkey: &Pubkey, account: &mut AccountSharedData) -> bool;
+}
+
+impl<T: AccountTransform + ?Sized> AccountTransform for Arc<T> {
+    fn name(&self) -> &str {
+        (**self).name()
+    }
+
+    fn transform(&self, pubkey: &Pubkey, account: &mut AccountSharedData) -> bool {
+        (**self).transform(pubkey, account)
+    }
+}
+
+impl fmt::Debug for dyn AccountTransform + Send + Sync {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_tuple("AccountTransform")
+            .field(&self.name())
+            .finish()
+    }
+}
+
+/// How many accounts one transform changed
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct TransformReport {
+    pub name: String,
+    pub accounts_changed: usize,
+}
+
+/// Runs every transform over every account of `accounts`, in order. Returns
+/// one report per transform.
+pub fn apply_transforms<T: AccountTransform + ?Sized>(
+    accounts: &mut HashMap<Pubkey, AccountSharedData>,
+    transforms: &[Arc<T>],
+) -> Vec<TransformReport> {
+    transforms
+        .iter()
+        .map(|transform| {
+            let accounts_changed = accounts
+                .iter_mut()
+                .map(|(pubkey, account)| transform.transform(pubkey, account))
+                .filter(|changed| *changed)
+                .count();
+            TransformReport {
+                name: transform.name().to_string(),
+                accounts_changed,
+            }
+        })
+        .collect()
+}
+
+/// Cuts the data of accounts longer than this down to it
+#[derive(Debug, Clone, Copy)]
+pub struct TruncateData(pub usize);
+
+impl AccountTransform for TruncateData {
+    fn name(&self) -> &str {
+        "truncate data"
+    }
+
+    fn transform(&self, _pubkey: &Pubkey, account: &mut AccountSharedData) -> bool {
+        let truncate = account.data().len() > self.0;
+        if truncate {
+            account.resize(self.0, 0);
+        }
+        truncate
+    }
+}
+
+/// Zeroes a byte range of the data of accounts owned by `owner`, such as an
+/// authority field. Accounts too short to hold all of `range` are left
+/// alone, and so is every account if `range` starts past its end.
+#[derive(Debug, Clone)]
+pub struct ZeroBytes {
+    pub owner: Pubkey,
+    pub range: Range<usize>,
+}
+
+impl ZeroBytes {
+    /// Rejects a `range` that starts past its end
+    pub fn new(owner: Pubkey, range: Range<usize>) -> Result<Self, MergeError> {
+        if range.start > range.end {
+            return Err(MergeError::InvalidInput(format!(
+                "Byte range {}..{} to zero starts past its end",
+                range.start, range.end
+            )));
+        }
+        Ok(Self { owner, range })
+    }
+}
+
+impl AccountTransform for ZeroBytes {
+    fn name(&self) -> &str {
+        "zero bytes"
+    }
+
+    fn transform(&self, _pubkey: &Pubkey, account: &mut AccountSharedData) -> bool {
+        if account.owner() != &self.owner {
+            return false;
+        }
+        let Some(bytes) = account.data_as_mut_slice().get_mut(self.range.clone()) else {
+            return false;
+        };
+        let changed = bytes.iter().any(|byte| *byte != 0);
+        bytes.fill(0);
+        changed
+    }
+}
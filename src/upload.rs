@@ -0,0 +1,551 @@
+// Upload - copies the merged snapshot to an S3 or GCS bucket as multipart
+// uploads, for handing it to the validators of a test cluster
+
+use {
+    crate::error::MergeError,
+    hmac::{Hmac, Mac},
+    log::*,
+    reqwest::{
+        blocking::{Client, Response},
+        header::AUTHORIZATION,
+        Method, Url,
+    },
+    serde::Serialize,
+    sha2::{Digest, Sha256},
+    std::{
+        fmt,
+        io::Read,
+        path::Path,
+        str::FromStr,
+        time::{Duration, SystemTime, UNIX_EPOCH},
+    },
+};
+
+/// Bytes per uploaded part. S3 takes at most 10,000 parts per object, every
+/// one but the last at least 5 MiB.
+pub const DEFAULT_PART_SIZE: usize = 64 * 1024 * 1024;
+const REQUEST_TIMEOUT: Duration = Duration::from_secs(300);
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum StorageService {
+    S3,
+    /// Google Cloud Storage through its S3-compatible XML API
+    Gcs,
+}
+
+impl StorageService {
+    fn scheme(self) -> &'static str {
+        match self {
+            Self::S3 => "s3",
+            Self::Gcs => "gs",
+        }
+    }
+}
+
+/// The bucket and key prefix `--upload-to` uploads the merged snapshot under
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct UploadDestination {
+    pub service: StorageService,
+    pub bucket: String,
+    /// Without leading or trailing `/`, empty for the root of the bucket
+    pub prefix: String,
+}
+
+impl FromStr for UploadDestination {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let (service, rest) = if let Some(rest) = s.strip_prefix("s3://") {
+            (StorageService::S3, rest)
+        } else if let Some(rest) = s.strip_prefix("gs://") {
+            (StorageService::Gcs, rest)
+        } else {
+            return Err(format!(
+                "Invalid upload destination '{}': expected s3://<bucket>[/<prefix>] or \
+                 gs://<bucket>[/<prefix>]",
+                s
+            ));
+        };
+        let (bucket, prefix) = rest.split_once('/').unwrap_or((rest, ""));
+        if bucket.is_empty() {
+            return Err(format!("Invalid upload destination '{}': no bucket", s));
+        }
+        Ok(Self {
+            service,
+            bucket: bucket.to_string(),
+            prefix: prefix.trim_matches('/').to_string(),
+        })
+    }
+}
+
+impl fmt::Display for UploadDestination {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}://{}", self.service.scheme(), self.bucket)?;
+        if !self.prefix.is_empty() {
+            write!(f, "/{}", self.prefix)?;
+        }
+        Ok(())
+    }
+}
+
+impl UploadDestination {
+    pub fn object_key(&self, file_name: &str) -> String {
+        if self.prefix.is_empty() {
+            file_name.to_string()
+        } else {
+            format!("{}/{}", self.prefix, file_name)
+        }
+    }
+
+    /// The `s3://` or `gs://` URL `file_name` is uploaded to
+    pub fn object_url(&self, file_name: &str) -> String {
+        format!(
+            "{}://{}/{}",
+            self.service.scheme(),
+            self.bucket,
+            self.object_key(file_name)
+        )
+    }
+}
+
+/// The HMAC key requests are signed with, and where they are sent
+#[derive(Clone, PartialEq, Eq)]
+pub struct Credentials {
+    pub access_key_id: String,
+    pub secret_access_key: String,
+    pub session_token: Option<String>,
+    pub region: String,
+    /// Used instead of the service's own endpoint, with the bucket as the
+    /// first path segment, e.g. for an S3-compatible store
+    pub endpoint: Option<Url>,
+}
+
+impl Credentials {
+    /// Reads the credentials for `service` from the environment: for S3
+    /// `AWS_ACCESS_KEY_ID`, `AWS_SECRET_ACCESS_KEY` and optionally
+    /// `AWS_SESSION_TOKEN`, `AWS_REGION` (or `AWS_DEFAULT_REGION`, `us-east-1`
+    /// if neither is set) and `AWS_ENDPOINT_URL`; for GCS an HMAC key in
+    /// `GCS_HMAC_ACCESS_KEY_ID` and `GCS_HMAC_SECRET`
+    pub fn from_env(service: StorageService) -> Result<Self, MergeError> {
+        let var = |name: &str| std::env::var(name).ok().filter(|value| !value.is_empty());
+        let required = |name: &str| {
+            var(name).ok_or_else(|| {
+                MergeError::InvalidInput(format!(
+                    "Uploading to {} needs {} to be set",
+                    service.scheme(),
+                    name
+                ))
+            })
+        };
+        match service {
+            StorageService::S3 => Ok(Self {
+                access_key_id: required("AWS_ACCESS_KEY_ID")?,
+                secret_access_key: required("AWS_SECRET_ACCESS_KEY")?,
+                session_token: var("AWS_SESSION_TOKEN"),
+                region: var("AWS_REGION")
+                    .or_else(|| var("AWS_DEFAULT_REGION"))
+                    .unwrap_or_else(|| "us-east-1".to_string()),
+                endpoint: var("AWS_ENDPOINT_URL")
+                    .map(|endpoint| {
+                        Url::parse(&endpoint).map_err(|e| {
+                            MergeError::InvalidInput(format!(
+                                "Invalid AWS_ENDPOINT_URL {}: {}",
+                                endpoint, e
+                            ))
+                        })
+                    })
+                    .transpose()?,
+            }),
+            StorageService::Gcs => Ok(Self {
+                access_key_id: required("GCS_HMAC_ACCESS_KEY_ID")?,
+                secret_access_key: required("GCS_HMAC_SECRET")?,
+                session_token: None,
+                region: "auto".to_string(),
+                endpoint: None,
+            }),
+        }
+    }
+
+    fn object_url(&self, destination: &UploadDestination, key: &str) -> Result<Url, MergeError> {
+        let key = uri_encode(key, false);
+        let url = match (&self.endpoint, destination.service) {
+            (Some(endpoint), _) => format!(
+                "{}/{}/{}",
+                endpoint.as_str().trim_end_matches('/'),
+                destination.bucket,
+                key
+            ),
+            (None, StorageService::S3) => format!(
+                "https://{}.s3.{}.amazonaws.com/{}",
+                destination.bucket, self.region, key
+            ),
+            (None, StorageService::Gcs) => format!(
+                "https://storage.googleapis.com/{}/{}",
+                destination.bucket, key
+            ),
+        };
+        Url::parse(&url)
+            .map_err(|e| MergeError::InvalidInput(format!("Invalid object URL {}: {}", url, e)))
+    }
+}
+
+/// Percent-encodes everything but the unreserved characters, and `/` unless
+/// `encode_slash`, as Signature Version 4 expects
+fn uri_encode(value: &str, encode_slash: bool) -> String {
+    value
+        .bytes()
+        .map(|byte| match byte {
+            b'A'..=b'Z' | b'a'..=b'z' | b'0'..=b'9' | b'-' | b'_' | b'.' | b'~' => {
+                (byte as char).to_string()
+            }
+            b'/' if !encode_slash => "/".to_string(),
+            _ => format!("%{:02X}", byte),
+        })
+        .collect()
+}
+
+fn hex(bytes: &[u8]) -> String {
+    bytes.iter().map(|byte| format!("{:02x}", byte)).collect()
+}
+
+fn hmac_sha256(key: &[u8], data: &str) -> Vec<u8> {
+    let mut mac = Hmac::<Sha256>::new_from_slice(key).expect("HMAC takes keys of any length");
+    mac.update(data.as_bytes());
+    mac.finalize().into_bytes().to_vec()
+}
+
+/// `time` as the `YYYYMMDDTHHMMSSZ` of the `x-amz-date` header
+pub fn amz_date(time: SystemTime) -> String {
+    let seconds = time
+        .duration_since(UNIX_EPOCH)
+        .unwrap_or_default()
+        .as_secs();
+    let (days, time_of_day) = (seconds / 86_400, seconds % 86_400);
+    // Days since the epoch to a civil date, after Howard Hinnant's
+    // `civil_from_days`
+    let z = days as i64 + 719_468;
+    let era = z.div_euclid(146_097);
+    let day_of_era = z.rem_euclid(146_097);
+    let year_of_era =
+        (day_of_era - day_of_era / 1_460 + day_of_era / 36_524 - day_of_era / 146_096) / 365;
+    let day_of_year = day_of_era - (365 * year_of_era + year_of_era / 4 - year_of_era / 100);
+    let month_index = (5 * day_of_year + 2) / 153;
+    let day = day_of_year - (153 * month_index + 2) / 5 + 1;
+    let month = if month_index < 10 {
+        month_index + 3
+    } else {
+        month_index - 9
+    };
+    let year = year_of_era + era * 400 + i64::from(month <= 2);
+    format!(
+        "{:04}{:02}{:02}T{:02}{:02}{:02}Z",
+        year,
+        month,
+        day,
+        time_of_day / 3_600,
+        time_of_day / 60 % 60,
+        time_of_day % 60
+    )
+}
+
+/// The `Authorization` header of a Signature Version 4 signed request.
+/// `headers` are the signed headers with lowercase names, including `host`,
+/// `x-amz-content-sha256` and `x-amz-date`.
+pub fn authorization(
+    credentials: &Credentials,
+    method: &str,
+    url: &Url,
+    headers: &[(&str, String)],
+    payload_sha256: &str,
+    amz_date: &str,
+) -> String {
+    let mut headers = headers.to_vec();
+    headers.sort();
+    let mut query: Vec<_> = url
+        .query_pairs()
+        .map(|(key, value)| format!("{}={}", uri_encode(&key, true), uri_encode(&value, true)))
+        .collect();
+    query.sort();
+    let signed_headers = headers
+        .iter()
+        .map(|(name, _)| *name)
+        .collect::<Vec<_>>()
+        .join(";");
+    let canonical_request = format!(
+        "{}\n{}\n{}\n{}\n{}\n{}",
+        method,
+        url.path(),
+        query.join("&"),
+        headers
+            .iter()
+            .map(|(name, value)| format!("{}:{}\n", name, value.trim()))
+            .collect::<String>(),
+        signed_headers,
+        payload_sha256
+    );
+
+    let date = &amz_date[..8];
+    let scope = format!("{}/{}/s3/aws4_request", date, credentials.region);
+    let string_to_sign = format!(
+        "AWS4-HMAC-SHA256\n{}\n{}\n{}",
+        amz_date,
+        scope,
+        hex(&Sha256::digest(canonical_request.as_bytes()))
+    );
+    let signing_key = ["s3", "aws4_request"].iter().fold(
+        hmac_sha256(
+            &hmac_sha256(
+                format!("AWS4{}", credentials.secret_access_key).as_bytes(),
+                date,
+            ),
+            &credentials.region,
+        ),
+        |key, part| hmac_sha256(&key, part),
+    );
+    format!(
+        "AWS4-HMAC-SHA256 Credential={}/{}, SignedHeaders={}, Signature={}",
+        credentials.access_key_id,
+        scope,
+        signed_headers,
+        hex(&hmac_sha256(&signing_key, &string_to_sign))
+    )
+}
+
+fn send(
+    client: &Client,
+    credentials: &Credentials,
+    method: Method,
+    url: Url,
+    body: Vec<u8>,
+) -> Result<Response, MergeError> {
+    let payload_sha256 = hex(&Sha256::digest(&body));
+    let date = amz_date(SystemTime::now());
+    let host = match (url.host_str(), url.port()) {
+        (Some(host), Some(port)) => format!("{}:{}", host, port),
+        (host, None) => host.unwrap_or_default().to_string(),
+        (None, Some(_)) => String::new(),
+    };
+    let mut headers = vec![
+        ("host", host),
+        ("x-amz-content-sha256", payload_sha256.clone()),
+        ("x-amz-date", date.clone()),
+    ];
+    if let Some(token) = &credentials.session_token {
+        headers.push(("x-amz-security-token", token.clone()));
+    }
+    let authorization = authorization(
+        credentials,
+        method.as_str(),
+        &url,
+        &headers,
+        &payload_sha256,
+        &date,
+    );
+
+    let mut request = client
+        .request(method.clone(), url.clone())
+        .header(AUTHORIZATION, authorization)
+        .body(body);
+    // reqwest sets the host header itself, to the same value
+    for (name, value) in headers.iter().filter(|(name, _)| *name != "host") {
+        request = request.header(*name, value);
+    }
+    let response = request
+        .send()
+        .map_err(|e| MergeError::Upload(format!("{} {} failed: {}", method, url, e)))?;
+    if !response.status().is_success() {
+        let status = response.status();
+        return Err(MergeError::Upload(format!(
+            "{} {} returned {}: {}",
+            method,
+            url,
+            status,
+            response.text().unwrap_or_default()
+        )));
+    }
+    Ok(response)
+}
+
+/// The text of the first `<element>` in `xml`
+fn xml_element<'a>(xml: &'a str, element: &str) -> Option<&'a str> {
+    let start = xml.find(&format!("<{}>", element))? + element.len() + 2;
+    let end = start + xml[start..].find(&format!("</{}>", element))?;
+    Some(&xml[start..end])
+}
+
+fn upload_parts(
+    client: &Client,
+    credentials: &Credentials,
+    object: &Url,
+    upload_id: &str,
+    path: &Path,
+    part_size: usize,
+) -> Result<Vec<String>, MergeError> {
+    let read_error = |e| MergeError::Io(format!("Failed to read {:?}: {:?}", path, e));
+    let mut file = std::fs::File::open(path).map_err(read_error)?;
+    let mut etags = Vec::new();
+    loop {
+        let mut part = Vec::with_capacity(part_size);
+        file.by_ref()
+            .take(part_size as u64)
+            .read_to_end(&mut part)
+            .map_err(read_error)?;
+        // An empty file is still uploaded as one empty part
+        if part.is_empty() && !etags.is_empty() {
+            return Ok(etags);
+        }
+        let last = part.len() < part_size;
+        let mut url = object.clone();
+        url.query_pairs_mut()
+            .append_pair("partNumber", &(etags.len() + 1).to_string())
+            .append_pair("uploadId", upload_id);
+        let response = send(client, credentials, Method::PUT, url, part)?;
+        let etag = response
+            .headers()
+            .get("etag")
+            .and_then(|etag| etag.to_str().ok())
+            .ok_or_else(|| {
+                MergeError::Upload(format!(
+                    "Part {} of {:?} has no ETag",
+                    etags.len() + 1,
+                    path
+                ))
+            })?;
+        etags.push(etag.to_string());
+        if last {
+            return Ok(etags);
+        }
+    }
+}
+
+/// Uploads the file at `path` under `destination` as a multipart upload of
+/// `part_size` byte parts, aborting the upload if a part fails. Returns the
+/// `s3://` or `gs://` URL of the object.
+pub fn upload_file(
+    client: &Client,
+    credentials: &Credentials,
+    destination: &UploadDestination,
+    path: &Path,
+    part_size: usize,
+) -> Result<String, MergeError> {
+    let file_name = path
+        .file_name()
+        .and_then(|name| name.to_str())
+        .ok_or_else(|| MergeError::InvalidInput(format!("{:?} has no file name", path)))?;
+    let object = credentials.object_url(destination, &destination.object_key(file_name))?;
+    let with_query = |query: &[(&str, &str)]| {
+        let mut url = object.clone();
+        url.query_pairs_mut().extend_pairs(query);
+        url
+    };
+
+    info!(
+        "Uploading {:?} to {}",
+        path,
+        destination.object_url(file_name)
+    );
+    let created = send(
+        client,
+        credentials,
+        Method::POST,
+        with_query(&[("uploads", "")]),
+        Vec::new(),
+    )?
+    .text()
+    .map_err(|e| MergeError::Upload(format!("Failed to read upload of {:?}: {}", path, e)))?;
+    let upload_id = xml_element(&created, "UploadId").ok_or_else(|| {
+        MergeError::Upload(format!("No upload ID for {:?} in: {}", path, created))
+    })?;
+
+    let completed = upload_parts(client, credentials, &object, upload_id, path, part_size)
+        .and_then(|etags| {
+            let parts: String = etags
+                .iter()
+                .enumerate()
+                .map(|(index, etag)| {
+                    format!(
+                        "<Part><PartNumber>{}</PartNumber><ETag>{}</ETag></Part>",
+                        index + 1,
+                        etag
+                    )
+                })
+                .collect();
+            let body = format!(
+                "<CompleteMultipartUpload>{}</CompleteMultipartUpload>",
+                parts
+            );
+            let response = send(
+                client,
+                credentials,
+                Method::POST,
+                with_query(&[("uploadId", upload_id)]),
+                body.into_bytes(),
+            )?
+            .text()
+            .unwrap_or_default();
+            // Completing can fail after the response has started, with a 200
+            match xml_element(&response, "Message") {
+                Some(message) if response.contains("<Error>") => Err(MergeError::Upload(format!(
+                    "Completing the upload of {:?} failed: {}",
+                    path, message
+                ))),
+                _ => Ok(etags.len()),
+            }
+        });
+    match completed {
+        Ok(parts) => {
+            info!("Uploaded {:?} in {} parts", path, parts);
+            Ok(destination.object_url(file_name))
+        }
+        Err(e) => {
+            let abort = send(
+                client,
+                credentials,
+                Method::DELETE,
+                with_query(&[("uploadId", upload_id)]),
+                Vec::new(),
+            );
+            if let Err(abort_error) = abort {
+                warn!("Failed to abort the upload of {:?}: {}", path, abort_error);
+            }
+            Err(e)
+        }
+    }
+}
+
+/// Where `MergeConfig::upload_to` put the merged snapshot
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+pub struct UploadReport {
+    /// `MergeStats::snapshot_path`
+    pub archive_url: String,
+    pub genesis_url: String,
+    /// The incremental archive, when written next to a full one
+    pub incremental_url: Option<String>,
+    /// The snapshot manifest, uploaded last so it only shows up next to
+    /// complete archives
+    pub manifest_url: String,
+}
+
+/// Uploads the merged snapshot's archives, genesis.bin and `manifest` under
+/// `destination`, with the credentials [`Credentials::from_env`] reads
+pub fn upload_merged_snapshot(
+    destination: &UploadDestination,
+    archive: &Path,
+    genesis: &Path,
+    incremental: Option<&Path>,
+    manifest: &Path,
+) -> Result<UploadReport, MergeError> {
+    let credentials = Credentials::from_env(destination.service)?;
+    let client = Client::builder()
+        .timeout(REQUEST_TIMEOUT)
+        .build()
+        .map_err(|e| MergeError::Upload(format!("Failed to create HTTP client: {}", e)))?;
+    let upload =
+        |path: &Path| upload_file(&client, &credentials, destination, path, DEFAULT_PART_SIZE);
+    Ok(UploadReport {
+        archive_url: upload(archive)?,
+        genesis_url: upload(genesis)?,
+        incremental_url: incremental.map(upload).transpose()?,
+        manifest_url: upload(manifest)?,
+    })
+}